@@ -1,16 +1,21 @@
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use winit::window::Window;
+use glam::Vec3;
+use winit::dpi::PhysicalSize;
+use winit::window::{Window, WindowAttributes};
 
+use crate::cli::Args;
 use crate::common::util::search_files;
-use crate::common::{App, CameraController, ImGuiContext, PerformanceMetrics, Texture, WGPUContext};
+use crate::common::{App, CameraController, HdrImage, ImGuiContext, PerformanceMetrics, Texture, WGPUContext};
 
-use crate::pathtracing::envmap::EnvMap;
-use crate::pathtracing::scene::{Scene, SceneBuffers};
+use crate::pathtracing::envmap::{self, Background, EnvMap, EnvSource};
+use crate::pathtracing::scene::{Scene, SceneBuffers, SceneSettings};
 use crate::pathtracing::blit_renderer::BlitRenderer;
 use crate::pathtracing::mesh_renderer::MeshRenderer;
 use crate::pathtracing::pathtracer::Pathtracer;
+use crate::pathtracing::sky::Sky;
 
 #[allow(dead_code)]
 pub struct MainApp {
@@ -29,34 +34,72 @@ pub struct MainApp {
 
     scenes: Vec<PathBuf>,
     scene_index: usize,
-    envmaps: Vec<PathBuf>,
-    envmap_index: usize,
+    environments: Vec<EnvSource>,
+    environment_index: usize,
+    scene_settings: SceneSettings,
+    /// Scene primitive whose instance is moved in the UI
+    instance_index: u32,
+    export_path: String,
+    export_png: bool,
     err_msg: String,
 }
 
 // TODO: Cleanup
 impl App for MainApp {
-    async fn new(window: Arc<Window>) -> Self {
+    type Config = Args;
+
+    fn window_attributes(args: &Args) -> WindowAttributes {
+        let attributes = Window::default_attributes();
+        match (args.width, args.height) {
+            (Some(width), Some(height)) => attributes.with_inner_size(PhysicalSize::new(width, height)),
+            _ => attributes,
+        }
+    }
+
+    async fn new(window: Arc<Window>, args: &Args) -> Self {
         let wgpu = WGPUContext::new(Arc::clone(&window)).await;
         let imgui = ImGuiContext::new(Arc::clone(&window), &wgpu);
         let metrics = PerformanceMetrics::default();
 
-        let scenes = search_files("assets", "glb").expect("Failed to search for scenes");
-        let scene_index = 0;
-        let envmaps = search_files("assets", "dds").expect("Failed to search for environment maps");
-        let envmap_index = 0;
+        let mut scenes = search_files("assets", &["glb"]).expect("Failed to search for scenes");
+        let scene_index = select_or_insert(&mut scenes, args.scene.as_ref());
+        let mut envmaps = search_files("assets", envmap::EXTENSIONS).expect("Failed to search for environment maps");
+        // Note: The procedural environments come first and the sky is used if there are no environment maps
+        let mut environment_index = select_or_insert(&mut envmaps, args.envmap.as_ref());
+        if !envmaps.is_empty() {
+            environment_index += 2;
+        }
+        let environments: Vec<_> = [EnvSource::Sky(Sky::default()), EnvSource::Uniform(Vec3::splat(0.5))].into_iter()
+            .chain(envmaps.into_iter().map(EnvSource::File))
+            .collect();
 
-        let mut scene_data = Scene::default();
-        scene_data.parse_gltf(&scenes[scene_index]).unwrap();
-        let scene = SceneBuffers::from_scene(&wgpu, &mut scene_data);
+        let scene_settings = args.scene_settings();
+        let mut scene_data = Scene::new(scene_settings.clone());
+        let Some(scene_path) = scenes.get(scene_index) else {
+            log::error!("No scene found");
+            std::process::exit(1);
+        };
+        if let Err(e) = scene_data.parse_gltf(scene_path) {
+            log::error!("Failed to load {:?}: {}", scene_path, e);
+            std::process::exit(1);
+        }
+        let scene = SceneBuffers::from_scene(&wgpu, &scene_data);
 
         let camera = CameraController::new(&wgpu);
 
-        let envmap = EnvMap::load(&wgpu, &envmaps[envmap_index]).expect("Failed to load environment map");
+        let envmap = EnvMap::create(&wgpu, &environments[environment_index]).unwrap_or_else(|e| {
+            log::error!("Failed to load {}: {}", environments[environment_index].name(), e);
+            std::process::exit(1);
+        });
 
         let mesh_renderer = MeshRenderer::new(&wgpu, &camera);
         let depth_texture = Texture::create_depth(&wgpu);
-        let pathtracer = Pathtracer::new(&wgpu, &scene, &camera, &envmap);
+        let mut pathtracer = Pathtracer::new(&wgpu, &scene, &camera, &envmap, args.spp);
+        pathtracer.resolution_factor = args.resolution_factor;
+        pathtracer.globals.bounces = args.bounces;
+        pathtracer.globals.contribution_factor = args.contribution_factor;
+        pathtracer.resize(&wgpu);
+        pathtracer.update(&wgpu, &camera, &envmap);
         let fullscreen_renderer = BlitRenderer::new(&wgpu, pathtracer.output_texture());
 
         Self {
@@ -73,8 +116,12 @@ impl App for MainApp {
             pathtracer,
             scenes,
             scene_index,
-            envmaps,
-            envmap_index,
+            environments,
+            environment_index,
+            scene_settings,
+            instance_index: 0,
+            export_path: args.output.as_ref().map_or(String::from("render.exr"), |p| p.to_string_lossy().into_owned()),
+            export_png: false,
             err_msg: String::from("No Error"),
         }
     }
@@ -129,7 +176,7 @@ impl App for MainApp {
                     self.fullscreen_renderer.set_texture(&self.wgpu, self.pathtracer.output_texture());
                 }
                 let mut updated = false;
-                updated |= ui.slider("Bounces", 0, 32, &mut self.pathtracer.globals.bounces);
+                updated |= ui.slider("Bounces", 0, Pathtracer::MAX_BOUNCES, &mut self.pathtracer.globals.bounces);
                 let mut contribution_filtering = 1.0 / self.pathtracer.globals.contribution_factor;
                 if ui.slider("Filtering", 0.0, 1.0, &mut contribution_filtering) {
                     self.pathtracer.globals.contribution_factor = 1.0 / contribution_filtering;
@@ -137,11 +184,15 @@ impl App for MainApp {
                 }
                 if updated { self.pathtracer.invalidate(); }
                 if ui.combo("Scene", &mut self.scene_index, &self.scenes, |x| x.to_string_lossy()) {
-                    let mut scene_data = Scene::default();
+                    let mut scene_data = Scene::new(self.scene_settings.clone());
                     match scene_data.parse_gltf(&self.scenes[self.scene_index]) {
                         Ok(_) => {
-                            self.scene = SceneBuffers::from_scene(&self.wgpu, &mut scene_data);
-                            self.pathtracer.invalidate();
+                            self.scene = SceneBuffers::from_scene(&self.wgpu, &scene_data);
+                            self.instance_index = 0;
+                            // Note: The BLAS of the new scene may fall back to a different layout, see `SceneData::build`
+                            self.pathtracer.set_scene(&self.wgpu, &self.scene);
+                            self.pathtracer.update(&self.wgpu, &self.camera, &self.envmap);
+                            self.fullscreen_renderer.set_texture(&self.wgpu, self.pathtracer.output_texture());
                         },
                         Err(e) => {
                             self.err_msg = e.to_string();
@@ -149,9 +200,52 @@ impl App for MainApp {
                         }
                     }
                 }
-                if ui.combo("Environment", &mut self.envmap_index, &self.envmaps, |x| x.to_string_lossy()) {
-                    match EnvMap::load(&self.wgpu, &self.envmaps[self.envmap_index]) {
-                        Ok(envmap) => {
+                if self.scene.instance_count() > 0 {
+                    ui.slider("Instance", 0, self.scene.instance_count() as u32 - 1, &mut self.instance_index);
+                    let mut transform = self.scene.transform(self.instance_index as usize);
+                    let mut translation = transform.w_axis.truncate().to_array();
+                    if imgui::Drag::new("Translation").speed(0.01).build_array(ui, &mut translation) {
+                        transform.w_axis = Vec3::from(translation).extend(1.0);
+                        self.scene.set_transform(self.instance_index as usize, transform);
+                    }
+                }
+                let source_changed = ui.combo("Environment", &mut self.environment_index, &self.environments, |x| x.name());
+                let mut environment_changed = source_changed;
+                match &mut self.environments[self.environment_index] {
+                    EnvSource::Sky(sky) => {
+                        let mut elevation = sky.elevation.to_degrees();
+                        if ui.slider("Sun Elevation", 0.0, 90.0, &mut elevation) {
+                            sky.elevation = elevation.to_radians();
+                            environment_changed = true;
+                        }
+                        let mut azimuth = sky.azimuth.to_degrees();
+                        if ui.slider("Sun Azimuth", 0.0, 360.0, &mut azimuth) {
+                            sky.azimuth = azimuth.to_radians();
+                            environment_changed = true;
+                        }
+                        environment_changed |= ui.slider("Turbidity", 2.0, 10.0, &mut sky.turbidity);
+                        let mut albedo = sky.ground_albedo.to_array();
+                        if ui.color_edit3("Ground Albedo", &mut albedo) {
+                            sky.ground_albedo = Vec3::from(albedo);
+                            environment_changed = true;
+                        }
+                    },
+                    EnvSource::Uniform(color) => {
+                        let mut radiance = color.to_array();
+                        if ui.color_edit3("Radiance", &mut radiance) {
+                            *color = Vec3::from(radiance);
+                            environment_changed = true;
+                        }
+                    },
+                    EnvSource::File(_) => {},
+                }
+                if environment_changed {
+                    match EnvMap::create(&self.wgpu, &self.environments[self.environment_index]) {
+                        Ok(mut envmap) => {
+                            // Keep the rotation and background while editing a procedural environment
+                            if !source_changed {
+                                envmap.settings = self.envmap.settings;
+                            }
                             self.envmap = envmap;
                             self.pathtracer.update(&self.wgpu, &self.camera, &self.envmap);
                         },
@@ -161,6 +255,56 @@ impl App for MainApp {
                         }
                     }
                 }
+                let settings = &mut self.envmap.settings;
+                let mut yaw = settings.yaw.to_degrees();
+                if ui.slider("Env Yaw", -180.0, 180.0, &mut yaw) {
+                    settings.yaw = yaw.to_radians();
+                }
+                let mut pitch = settings.pitch.to_degrees();
+                if ui.slider("Env Pitch", -90.0, 90.0, &mut pitch) {
+                    settings.pitch = pitch.to_radians();
+                }
+                ui.slider("Exposure", 0.0, 8.0, &mut settings.exposure);
+                let mut background_index = match settings.background {
+                    Background::Environment => 0,
+                    Background::Blurred { .. } => 1,
+                    Background::Solid(_) => 2,
+                };
+                if ui.combo("Background", &mut background_index, &["Environment", "Blurred", "Solid"], |x| Cow::Borrowed(*x)) {
+                    settings.background = match background_index {
+                        1 => Background::Blurred { blur: 0.5 },
+                        2 => Background::Solid(Vec3::ZERO),
+                        _ => Background::Environment,
+                    };
+                }
+                match &mut settings.background {
+                    Background::Blurred { blur } => {
+                        ui.slider("Blur", 0.0, 1.0, blur);
+                    },
+                    Background::Solid(color) => {
+                        let mut background = color.to_array();
+                        if ui.color_edit3("Background Color", &mut background) {
+                            *color = Vec3::from(background);
+                        }
+                    },
+                    Background::Environment => {},
+                }
+                ui.input_text("Output", &mut self.export_path).build();
+                ui.checkbox("PNG Preview", &mut self.export_png);
+                if ui.button("Save") {
+                    let image = HdrImage::from_texture(&self.wgpu, self.pathtracer.output_texture());
+                    let path = Path::new(&self.export_path);
+                    let mut result = image.save(path);
+                    // Note: A .png output already is the preview
+                    let is_png = path.extension().is_some_and(|e| e.eq_ignore_ascii_case("png"));
+                    if self.export_png && !is_png && result.is_ok() {
+                        result = image.save_png(&path.with_extension("png"));
+                    }
+                    if let Err(e) = result {
+                        self.err_msg = e.to_string();
+                        ui.open_popup("Error");
+                    }
+                }
                 ui.modal_popup_config("Error").build(|| {
                     ui.text(self.err_msg.clone());
                     if ui.button("Close") {
@@ -172,12 +316,18 @@ impl App for MainApp {
         if self.camera.update(&self.wgpu) {
             self.pathtracer.invalidate();
         }
+        if self.envmap.update(&self.wgpu) {
+            self.pathtracer.invalidate();
+        }
+        if self.scene.update(&self.wgpu) {
+            self.pathtracer.invalidate();
+        }
     }
 
     fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
         // TODO: Call prepare_render here
 
-        let frame = self.wgpu.surface.get_current_texture()?;
+        let frame = self.wgpu.surface.as_ref().expect("Rendering requires a surface").get_current_texture()?;
         let view = frame.texture.create_view(&wgpu::TextureViewDescriptor::default());
 
         let mut encoder = self.wgpu.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
@@ -226,4 +376,15 @@ impl App for MainApp {
     fn device_event(&mut self, event: &winit::event::DeviceEvent) {
         self.camera.device_event(event);
     }
-}
\ No newline at end of file
+}
+
+/// Returns the index of `path` in `paths`, appending it if necessary, or 0 if no path is given
+fn select_or_insert(paths: &mut Vec<PathBuf>, path: Option<&PathBuf>) -> usize {
+    match path {
+        Some(path) => paths.iter().position(|p| p == path).unwrap_or_else(|| {
+            paths.push(path.clone());
+            paths.len() - 1
+        }),
+        None => 0,
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::{HashMap, HashSet};
+
+use glam::Vec2;
+use rayon::prelude::*;
+
+use crate::cli::Args;
+use crate::common::camera::Camera;
+use crate::pathtracing::bvh::BVHBuilder;
+use crate::pathtracing::bvh::stats::BVHStats;
+use crate::pathtracing::raytracing_cpu::{CpuScene, NO_HIT};
+use crate::pathtracing::reference::generate_ray;
+use crate::pathtracing::scene::{BottomLevel, Scene, SceneData, SceneSettings};
+
+/// Builds the scene with each BLAS builder and some wide BLAS, then logs the SAH cost of the binary BLAS and the cost of
+/// tracing one primary ray per pixel on the CPU
+pub fn compare_bvh_builders(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    let scene_path = args.scene_path().ok_or("No scene found")?;
+    let size = (
+        args.width.unwrap_or(Args::DEFAULT_BATCH_SIZE.0),
+        args.height.unwrap_or(Args::DEFAULT_BATCH_SIZE.1),
+    );
+
+    // Note: The cache is bypassed to measure the build times
+    let mut scene = Scene::new(SceneSettings { cache_dir: None, ..args.scene_settings() });
+    scene.parse_gltf(&scene_path)?;
+
+    let camera = Camera { aspect_ratio: size.0 as f32 / size.1 as f32, ..Default::default() };
+    let clip_to_world = camera.buffer_data().clip_to_world;
+    let dim = Vec2::new(size.0 as f32, size.1 as f32);
+
+    let overlap_threshold = args.spatial_splits.unwrap_or(BVHBuilder::DEFAULT_OVERLAP_THRESHOLD);
+    let configs = [
+        (BVHBuilder::Sah, 2),
+        (BVHBuilder::Spatial { overlap_threshold }, 2),
+        (BVHBuilder::Linear, 2),
+        (BVHBuilder::Sah, 4),
+        (BVHBuilder::Sah, 8),
+    ];
+    for (builder, width) in configs {
+        scene.blas_builder = builder;
+        scene.blas_width = width;
+        let timer = std::time::Instant::now();
+        let cpu_scene = CpuScene::new(&scene);
+        let build_time = timer.elapsed();
+
+        let data = &cpu_scene.data;
+        let config = format!("{:?} BVH{}", builder, width);
+        match &data.blas {
+            BottomLevel::Binary(blas) => {
+                let roots: HashSet<_> = data.top_level.instances().map(|i| i.node).collect();
+                let sah_cost: f32 = roots.iter().map(|&root| blas.sah_cost(root)).sum();
+                log::info!("{}: {} BLAS nodes, {} triangle references, SAH cost {:.2}, built in {:?}",
+                    config, blas.nodes().len(), data.indices.len() / 3, sah_cost, build_time);
+            }
+            BottomLevel::Wide(blas) => {
+                log::info!("{}: {} BLAS nodes, {} triangle references, {} stack entries, built in {:?}",
+                    config, blas.nodes().len(), data.indices.len() / 3, blas.max_stack_size(), build_time);
+            }
+        }
+
+        let timer = std::time::Instant::now();
+        let (hits, n_aabb, n_tri) = (0..size.1).into_par_iter().map(|y| {
+            (0..size.0).fold((0u64, 0u64, 0u64), |(hits, n_aabb, n_tri), x| {
+                let ray = generate_ray(&clip_to_world, dim, Vec2::new(x as f32, y as f32), Vec2::splat(0.5));
+                let hit = cpu_scene.intersect_tlas(&ray);
+                (hits + (hit.dist != NO_HIT) as u64, n_aabb + hit.n_aabb as u64, n_tri + hit.n_tri as u64)
+            })
+        }).reduce(|| (0, 0, 0), |a, b| (a.0 + b.0, a.1 + b.1, a.2 + b.2));
+        let trace_time = timer.elapsed();
+
+        let rays = (size.0 * size.1) as f64;
+        log::info!("{}: Traced {} rays in {:?} ({:.2} Mrays/s), {:.1} box and {:.1} triangle tests per ray, {:.1}% hit",
+            config, rays, trace_time, rays / trace_time.as_secs_f64() * 1e-6,
+            n_aabb as f64 / rays, n_tri as f64 / rays, 100.0 * hits as f64 / rays);
+    }
+
+    Ok(())
+}
+
+/// Builds the scene with the binary BLAS, validates the BLAS and TLAS and logs their quality statistics.
+/// Returns an error on the first invalid tree.
+pub fn log_bvh_stats(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    let scene_path = args.scene_path().ok_or("No scene found")?;
+
+    let mut scene = Scene::new(SceneSettings { blas_width: 2, ..args.scene_settings() });
+    scene.parse_gltf(&scene_path)?;
+    let data = SceneData::load_or_build(&scene);
+    let BottomLevel::Binary(blas) = &data.blas else {
+        return Err("The BLAS is not binary".into());
+    };
+
+    // Maps BLAS root -> range of its triangle references and a primitive built into it,
+    // instances of the same mesh primitive share one BLAS
+    let top_level = &data.top_level;
+    let roots: HashMap<_, _> = data.index_ranges.iter().enumerate().map(|(primitive, range)| {
+        (top_level.primitive_instance(primitive).node, (range.start / 3..range.end / 3, primitive))
+    }).collect();
+
+    let mut blas_stats = BVHStats::default();
+    for (&root, (range, primitive)) in &roots {
+        blas.validate(root, range.clone())
+            .and_then(|_| blas.validate_triangles(root, &data.indices, scene.primitive_indices(*primitive)))
+            .map_err(|e| format!("BLAS at node {}: {}", root, e))?;
+        let stats = blas.stats(root);
+        log::debug!("BLAS at node {}: {}", root, stats);
+        blas_stats.merge(&stats);
+    }
+    log::info!("{} BLAS with {:?} builder: {}", roots.len(), scene.blas_builder, blas_stats);
+
+    let tlas = top_level.tlas();
+    tlas.validate(0, 0..top_level.instances().count() as u32).map_err(|e| format!("TLAS: {}", e))?;
+    log::info!("TLAS with {:?} builder: {}", scene.tlas_builder, tlas.stats(0));
+
+    Ok(())
+}
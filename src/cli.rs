@@ -0,0 +1,173 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+use crate::common::util::search_files;
+use crate::pathtracing::bvh::BVHBuilder;
+use crate::pathtracing::envmap::{self, EnvSource};
+use crate::pathtracing::normals::NormalGeneration;
+use crate::pathtracing::pathtracer::Pathtracer;
+use crate::pathtracing::scene::SceneSettings;
+use crate::pathtracing::sky::Sky;
+
+/// GPU path tracer for neural radiance caching experiments
+#[derive(Parser, Debug, Clone)]
+#[command(version, about)]
+pub struct Args {
+    /// glTF scene to load, defaults to the first .glb file in the assets folder
+    #[arg(short, long)]
+    pub scene: Option<PathBuf>,
+
+    /// DDS cubemap or equirectangular .hdr/.exr panorama to use as environment,
+    /// defaults to the first one in the assets folder or a procedural sky if there is none
+    #[arg(short, long)]
+    pub envmap: Option<PathBuf>,
+
+    /// Window width in interactive mode or image width in batch mode, at least the compute workgroup size
+    #[arg(long, value_parser = clap::value_parser!(u32).range(Pathtracer::COMPUTE_SIZE as i64..))]
+    pub width: Option<u32>,
+
+    /// Window height in interactive mode or image height in batch mode, at least the compute workgroup size
+    #[arg(long, value_parser = clap::value_parser!(u32).range(Pathtracer::COMPUTE_SIZE as i64..))]
+    pub height: Option<u32>,
+
+    /// Fraction of the window resolution to path trace at in interactive mode, needs to be greater than 0
+    #[arg(long, default_value_t = 0.3, value_parser = parse_resolution_factor)]
+    pub resolution_factor: f32,
+
+    /// Number of samples per pixel to accumulate, clamped to the size of the largest storage buffer of the device
+    #[arg(long, default_value_t = 1024, value_parser = clap::value_parser!(u32).range(1..))]
+    pub spp: u32,
+
+    /// Maximum number of bounces per path
+    #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u32).range(..=Pathtracer::MAX_BOUNCES as i64))]
+    pub bounces: u32,
+
+    /// Scales the throughput luminance for Russian Roulette, higher values terminate fewer paths
+    #[arg(long, default_value_t = 4.0)]
+    pub contribution_factor: f32,
+
+    /// Image to save the render to (.exr, .pfm, .hdr or .png)
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Render without a window, save the output and exit
+    #[arg(long, requires = "output")]
+    pub batch: bool,
+
+    /// Use a software adapter in batch mode for machines without a GPU
+    #[arg(long)]
+    pub software: bool,
+
+    /// Render with the CPU reference path tracer instead of the GPU in batch mode
+    #[arg(long, requires = "batch", conflicts_with = "software")]
+    pub cpu: bool,
+
+    /// Reference image to compare the batch render against, logs the relative mean squared error
+    #[arg(long, requires = "batch")]
+    pub compare: Option<PathBuf>,
+
+    /// Generate smooth normals for primitives without normals, keeping edges sharper than this angle in degrees.
+    /// Flat normals are generated if omitted
+    #[arg(long)]
+    pub crease_angle: Option<f32>,
+
+    /// Build the BLAS with spatial splits (SBVH) where the children of an object split overlap
+    /// by more than this fraction of the root surface area
+    #[arg(long, num_args = 0..=1, default_missing_value = "1e-5")]
+    pub spatial_splits: Option<f32>,
+
+    /// Build the BLAS from Morton codes (LBVH), which is much faster but slower to trace
+    #[arg(long, conflicts_with = "spatial_splits")]
+    pub linear_blas: bool,
+
+    /// Maximum number of children of the BLAS nodes, widths above 2 collapse the binary BLAS to compressed wide nodes
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u32).range(2..=8))]
+    pub blas_width: u32,
+
+    /// Build the TLAS with the SAH builder instead of from Morton codes (LBVH)
+    #[arg(long)]
+    pub sah_tlas: bool,
+
+    /// Directory to cache the geometry and acceleration structures of loaded scenes in,
+    /// keyed by a hash of the glTF files and the builder settings
+    #[arg(long, default_value = "cache")]
+    pub cache_dir: PathBuf,
+
+    /// Always build the acceleration structures instead of loading them from the cache
+    #[arg(long)]
+    pub no_cache: bool,
+
+    /// Compare the SAH cost and CPU traversal performance of the BLAS builders and widths on the scene and exit
+    #[arg(long)]
+    pub compare_bvh: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Tools that run on the scene instead of rendering it
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Log quality statistics of the binary BLAS and the TLAS built with the selected builders, validate them and exit
+    BvhStats,
+}
+
+impl Args {
+    pub const DEFAULT_BATCH_SIZE: (u32, u32) = (1280, 720);
+
+    /// Returns the scene given on the command line or the first scene in the assets folder
+    pub fn scene_path(&self) -> Option<PathBuf> {
+        self.scene.clone().or_else(|| search_files("assets", &["glb"]).ok()?.into_iter().next())
+    }
+
+    /// Returns the environment map given on the command line, the first environment map in the assets folder or the default sky
+    pub fn environment(&self) -> EnvSource {
+        self.envmap.clone()
+            .or_else(|| search_files("assets", envmap::EXTENSIONS).ok()?.into_iter().next())
+            .map_or_else(|| EnvSource::Sky(Sky::default()), EnvSource::File)
+    }
+
+    pub fn blas_builder(&self) -> BVHBuilder {
+        match self.spatial_splits {
+            Some(overlap_threshold) => BVHBuilder::Spatial { overlap_threshold },
+            None if self.linear_blas => BVHBuilder::Linear,
+            None => BVHBuilder::Sah,
+        }
+    }
+
+    pub fn tlas_builder(&self) -> BVHBuilder {
+        if self.sah_tlas { BVHBuilder::Sah } else { BVHBuilder::Linear }
+    }
+
+    pub fn cache_dir(&self) -> Option<PathBuf> {
+        (!self.no_cache).then(|| self.cache_dir.clone())
+    }
+
+    /// Settings of the loaded scenes, used by every renderer so that they process scenes the same way
+    pub fn scene_settings(&self) -> SceneSettings {
+        SceneSettings {
+            normal_generation: self.normal_generation(),
+            blas_builder: self.blas_builder(),
+            tlas_builder: self.tlas_builder(),
+            blas_width: self.blas_width as usize,
+            cache_dir: self.cache_dir(),
+        }
+    }
+
+    pub fn normal_generation(&self) -> NormalGeneration {
+        match self.crease_angle {
+            Some(degrees) => NormalGeneration::Smooth { crease_angle: degrees.to_radians() },
+            None => NormalGeneration::Flat,
+        }
+    }
+}
+
+fn parse_resolution_factor(value: &str) -> Result<f32, String> {
+    let factor: f32 = value.parse().map_err(|err| format!("{err}"))?;
+    if factor.is_finite() && factor > 0.0 {
+        Ok(factor)
+    } else {
+        Err(format!("{factor} is not a finite number greater than 0"))
+    }
+}
@@ -5,10 +5,12 @@ pub mod wgpu_context;
 pub mod camera;
 pub mod texture;
 pub mod util;
+pub mod hdr_image;
 
 pub use app_handler::{App, AppHandler};
 pub use imgui_context::ImGuiContext;
 pub use performance_metric::PerformanceMetrics;
 pub use wgpu_context::WGPUContext;
 pub use camera::CameraController;
-pub use texture::Texture;
\ No newline at end of file
+pub use texture::Texture;
+pub use hdr_image::HdrImage;
\ No newline at end of file
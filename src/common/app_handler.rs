@@ -1,9 +1,14 @@
 use std::sync::Arc;
 
-use winit::{application::ApplicationHandler, dpi::PhysicalSize, event::{DeviceEvent, DeviceId, ElementState, KeyEvent, WindowEvent}, event_loop::ActiveEventLoop, keyboard::{KeyCode, PhysicalKey}, window::{Window, WindowId}};
+use winit::{application::ApplicationHandler, dpi::PhysicalSize, event::{DeviceEvent, DeviceId, ElementState, KeyEvent, WindowEvent}, event_loop::ActiveEventLoop, keyboard::{KeyCode, PhysicalKey}, window::{Window, WindowAttributes, WindowId}};
 
 pub trait App {
-    async fn new(window: Arc<Window>) -> Self;
+    /// Startup configuration, e.g. parsed command line arguments
+    type Config;
+    fn window_attributes(_config: &Self::Config) -> WindowAttributes {
+        Window::default_attributes()
+    }
+    async fn new(window: Arc<Window>, config: &Self::Config) -> Self;
     fn window(&self) -> &Window;
     fn resize(&mut self, new_size: PhysicalSize<u32>);
     fn window_event(&mut self, event: &WindowEvent);
@@ -14,18 +19,19 @@ pub trait App {
 
 pub struct AppHandler<T: App> {
     app: Option<T>,
+    config: T::Config,
 }
 
-impl<T: App> Default for AppHandler<T> {
-    fn default() -> Self {
-        Self { app: None }
+impl<T: App> AppHandler<T> {
+    pub fn new(config: T::Config) -> Self {
+        Self { app: None, config }
     }
 }
 
 impl<T: App> ApplicationHandler for AppHandler<T> {
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
-        let window = Arc::new(event_loop.create_window(Window::default_attributes()).expect("Failed to create window"));
-        self.app = Some(pollster::block_on(T::new(window)));
+        let window = Arc::new(event_loop.create_window(T::window_attributes(&self.config)).expect("Failed to create window"));
+        self.app = Some(pollster::block_on(T::new(window, &self.config)));
     }
 
     fn window_event(&mut self, event_loop: &ActiveEventLoop, window_id: WindowId, event: WindowEvent) {
@@ -11,15 +11,58 @@ pub struct CameraBuffer {
     pub clip_to_world: Mat4,
 }
 
+/// Perspective camera looking at a target, independent of the GPU so that it can be used by the CPU renderer
+#[derive(Clone, Copy, Debug)]
+pub struct Camera {
+    pub world_position: Vec3,
+    pub target: Vec3,
+    pub up: Vec3,
+    /// Vertical field of view in radians
+    pub fov: f32,
+    pub aspect_ratio: f32,
+    pub near: f32,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self {
+            world_position: Vec3::new(5.0, 0.0, 0.0),
+            target: Vec3::new(0.0, 0.0, 0.0),
+            up: Vec3::new(0.0, 1.0, 0.0),
+            fov: PI / 3.0,
+            aspect_ratio: 1.0,
+            near: 0.1,
+        }
+    }
+}
+
+impl Camera {
+    pub fn view_matrix(&self) -> Mat4 {
+        Mat4::look_at_rh(self.world_position, self.target, self.up)
+    }
+
+    pub fn projection_matrix(&self) -> Mat4 {
+        Mat4::perspective_infinite_rh(self.fov, self.aspect_ratio, self.near)
+    }
+
+    #[allow(dead_code)]
+    fn focal_length(&self) -> f32 {
+        1.0 / (self.fov / 2.0).tan()
+    }
+
+    pub fn buffer_data(&self) -> CameraBuffer {
+        let world_to_clip = self.projection_matrix() * self.view_matrix();
+        CameraBuffer {
+            world_to_clip,
+            clip_to_world: world_to_clip.inverse(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct CameraController {
-    world_position: Vec3,
-    target: Vec3,
-    up: Vec3,
+    camera: Camera,
     min_dist: f32,
-    fov: f32,
-    aspect_ratio: f32,
-    near: f32,
     is_dirty: bool,
     data: CameraBuffer,
     buffer: wgpu::Buffer,
@@ -38,51 +81,52 @@ impl CameraController {
         });
 
         Self {
-            world_position: Vec3::new(5.0, 0.0, 0.0),
-            target: Vec3::new(0.0, 0.0, 0.0),
-            up: Vec3::new(0.0, 1.0, 0.0),
+            camera: Camera::default(),
             min_dist: 0.1,
-            fov: PI / 3.0,
-            aspect_ratio: 1.0,
-            near: 0.1,
             is_dirty: true,
             data: CameraBuffer::default(),
             buffer,
         }
     }
 
+    pub fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
     pub fn orbit(&mut self, delta: Vec2) {
-        let relative_pos = self.world_position - self.target;
+        let camera = &mut self.camera;
+        let relative_pos = camera.world_position - camera.target;
         let direction = relative_pos.normalize();
-        let right = direction.cross(self.up).normalize(); 
-        let max_up_delta = direction.dot(self.up).acos();
+        let right = direction.cross(camera.up).normalize(); 
+        let max_up_delta = direction.dot(camera.up).acos();
         let max_down_delta = -(PI - max_up_delta);
         let clamped_delta_y = delta.y.clamp(max_down_delta + Self::ALTITUDE_DELTA, max_up_delta - Self::ALTITUDE_DELTA);
-        let rotation = Quat::from_axis_angle(self.up, -delta.x)
+        let rotation = Quat::from_axis_angle(camera.up, -delta.x)
             * Quat::from_axis_angle(right, clamped_delta_y);
-        self.world_position = self.target + rotation.mul_vec3(relative_pos);
+        camera.world_position = camera.target + rotation.mul_vec3(relative_pos);
         self.invalidate();
     }
 
     pub fn zoom(&mut self, delta: f32) {
-        let direction = self.world_position - self.target;
+        let camera = &mut self.camera;
+        let direction = camera.world_position - camera.target;
         let distance = direction.length();
         let direction = direction / distance;
         let distance = (distance - delta).max(self.min_dist);
-        self.world_position = self.target + direction * distance;
+        camera.world_position = camera.target + direction * distance;
         self.invalidate();
     }
 
     pub fn move_in_eye_space(&mut self, delta: Vec3) {
-        let world_to_view = self.calc_view_matrix();
+        let world_to_view = self.camera.view_matrix();
         let cam_delta = world_to_view.transform_vector3(delta);
-        self.world_position += cam_delta;
-        self.target += cam_delta;
+        self.camera.world_position += cam_delta;
+        self.camera.target += cam_delta;
         self.invalidate();
     }
 
     pub fn resize(&mut self, aspect_ratio: f32) {
-        self.aspect_ratio = aspect_ratio;
+        self.camera.aspect_ratio = aspect_ratio;
         self.invalidate();
     }
 
@@ -118,32 +162,9 @@ impl CameraController {
         self.is_dirty = true;
     }
 
-    fn calc_view_matrix(&self) -> Mat4 {
-        Mat4::look_at_rh(self.world_position, self.target, self.up)
-    }
-
-    fn calc_projection_matrix(&self) -> Mat4 {
-        Mat4::perspective_infinite_rh(self.fov, self.aspect_ratio, self.near)
-    }
-
-    #[allow(dead_code)]
-    fn calc_focal_length(&self) -> f32 {
-        1.0 / (self.fov / 2.0).tan()
-    }
-
-    fn calc_camera_data(&self) -> CameraBuffer {
-        let world_to_view = self.calc_view_matrix();
-        let view_to_clip = self.calc_projection_matrix();
-        let world_to_clip = view_to_clip * world_to_view;
-        CameraBuffer {
-            world_to_clip,
-            clip_to_world: world_to_clip.inverse(),
-        }
-    }
-
     pub fn update(&mut self, wgpu: &WGPUContext) -> bool {
         if self.is_dirty {
-            self.data = self.calc_camera_data();
+            self.data = self.camera.buffer_data();
             wgpu.queue.write_buffer(&self.buffer, 0, bytemuck::bytes_of(&self.data));
             self.is_dirty = false;
             true
@@ -0,0 +1,158 @@
+use std::io::Write;
+use std::path::Path;
+
+use glam::{Vec3, Vec4, Vec4Swizzles};
+
+use super::{Texture, WGPUContext};
+
+/// Linear HDR image with 32-bit float RGBA pixels stored row by row from the top left
+pub struct HdrImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Vec4>,
+}
+
+#[derive(Debug)]
+pub enum ExportError {
+    Io(std::io::Error),
+    Image(image::ImageError),
+    UnsupportedFormat(String),
+}
+
+impl From<std::io::Error> for ExportError {
+    fn from(e: std::io::Error) -> Self {
+        ExportError::Io(e)
+    }
+}
+
+impl From<image::ImageError> for ExportError {
+    fn from(e: image::ImageError) -> Self {
+        ExportError::Image(e)
+    }
+}
+
+impl std::fmt::Display for ExportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ExportError::Io(e) => write!(f, "IO error: {}", e),
+            ExportError::Image(e) => write!(f, "Image error: {}", e),
+            ExportError::UnsupportedFormat(ext) => write!(f, "Unsupported image format {:?}, use exr, pfm, hdr or png", ext),
+        }
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+impl HdrImage {
+    /// Reads back an `Rgba32Float` texture from the GPU
+    pub fn from_texture(wgpu: &WGPUContext, texture: &Texture) -> Self {
+        assert_eq!(texture.format(), wgpu::TextureFormat::Rgba32Float, "Only Rgba32Float textures can be read back");
+        let size = texture.size();
+        let data = texture.download(wgpu);
+        Self {
+            width: size.x,
+            height: size.y,
+            pixels: bytemuck::pod_collect_to_vec(&data),
+        }
+    }
+
+    /// Loads any image format supported by `image`, for example a reference render
+    pub fn open(path: &Path) -> Result<Self, ExportError> {
+        let image = image::open(path)?.into_rgba32f();
+        Ok(Self {
+            width: image.width(),
+            height: image.height(),
+            pixels: bytemuck::pod_collect_to_vec(image.as_raw()),
+        })
+    }
+
+    /// Relative mean squared error of the rgb channels against a reference of the same size.
+    /// Note: The squared reference is offset by 1e-2 to keep dark pixels from dominating the error
+    pub fn relative_mse(&self, reference: &HdrImage) -> Option<f32> {
+        if (self.width, self.height) != (reference.width, reference.height) {
+            return None;
+        }
+        let sum: f32 = self.pixels.iter().zip(&reference.pixels)
+            .map(|(p, r)| ((p.xyz() - r.xyz()).powf(2.0) / (r.xyz() * r.xyz() + 1e-2)).element_sum())
+            .sum();
+        Some(sum / (3 * self.pixels.len().max(1)) as f32)
+    }
+
+    pub fn mean(&self) -> Vec4 {
+        self.pixels.iter().sum::<Vec4>() / self.pixels.len().max(1) as f32
+    }
+
+    /// Saves the image choosing the format from the file extension:
+    /// 32-bit float OpenEXR (.exr), PFM (.pfm), Radiance RGBE (.hdr) or tonemapped 8-bit sRGB PNG (.png)
+    pub fn save(&self, path: &Path) -> Result<(), ExportError> {
+        let timer = std::time::Instant::now();
+        let ext = path.extension().unwrap_or_default().to_string_lossy().to_lowercase();
+        match ext.as_str() {
+            "exr" => self.save_exr(path)?,
+            "pfm" => self.save_pfm(path)?,
+            "hdr" => self.save_hdr(path)?,
+            "png" => self.save_png(path)?,
+            _ => return Err(ExportError::UnsupportedFormat(ext)),
+        }
+        log::info!("Saved {:?} in {:?}", path, timer.elapsed());
+        Ok(())
+    }
+
+    pub fn save_exr(&self, path: &Path) -> Result<(), ExportError> {
+        let data = bytemuck::cast_slice(&self.pixels).to_vec();
+        let image = image::Rgba32FImage::from_raw(self.width, self.height, data).expect("Pixel count does not match size");
+        image.save_with_format(path, image::ImageFormat::OpenExr)?;
+        Ok(())
+    }
+
+    /// Portable float map, see https://www.pauldebevec.com/Research/HDR/PFM/
+    pub fn save_pfm(&self, path: &Path) -> Result<(), ExportError> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        // A negative scale marks little-endian data
+        write!(file, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+        // PFM stores rows from the bottom to the top
+        for row in self.rows().rev() {
+            for pixel in row {
+                for c in pixel.xyz().to_array() {
+                    file.write_all(&c.to_le_bytes())?;
+                }
+            }
+        }
+        file.flush()?;
+        Ok(())
+    }
+
+    pub fn save_hdr(&self, path: &Path) -> Result<(), ExportError> {
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        let pixels: Vec<_> = self.pixels.iter().map(|p| image::Rgb(p.xyz().to_array())).collect();
+        image::codecs::hdr::HdrEncoder::new(file).encode(&pixels, self.width as usize, self.height as usize)?;
+        Ok(())
+    }
+
+    /// Saves a tonemapped 8-bit sRGB preview, not suited for comparisons
+    pub fn save_png(&self, path: &Path) -> Result<(), ExportError> {
+        let data = self.pixels.iter()
+            .flat_map(|p| linear_to_srgb(aces_tonemap(p.xyz())).to_array())
+            .map(|c| (c * 255.0 + 0.5) as u8)
+            .collect();
+        let image = image::RgbImage::from_raw(self.width, self.height, data).expect("Pixel count does not match size");
+        image.save_with_format(path, image::ImageFormat::Png)?;
+        Ok(())
+    }
+
+    fn rows(&self) -> std::slice::ChunksExact<'_, Vec4> {
+        self.pixels.chunks_exact(self.width as usize)
+    }
+}
+
+/// ACES filmic curve fit by Krzysztof Narkowicz, see https://knarkowicz.wordpress.com/2016/01/06/aces-filmic-tone-mapping-curve/
+fn aces_tonemap(x: Vec3) -> Vec3 {
+    let x = x * 0.6;
+    ((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)).clamp(Vec3::ZERO, Vec3::ONE)
+}
+
+fn linear_to_srgb(linear: Vec3) -> Vec3 {
+    linear.to_array().map(|c| {
+        if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 }
+    }).into()
+}
@@ -2,6 +2,33 @@ use wgpu::util::DeviceExt;
 
 use super::WGPUContext;
 
+#[derive(Debug)]
+pub enum CubemapError {
+    Dds(ddsfile::Error),
+    UnsupportedFormat(String),
+    NotACubemap,
+    Truncated,
+}
+
+impl From<ddsfile::Error> for CubemapError {
+    fn from(e: ddsfile::Error) -> Self {
+        CubemapError::Dds(e)
+    }
+}
+
+impl std::fmt::Display for CubemapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CubemapError::Dds(e) => write!(f, "DDS error: {}", e),
+            CubemapError::UnsupportedFormat(format) => write!(f, "Unsupported cubemap format {}, use BC6H, RGBA16F, RGBA32F, R11G11B10F or RGB9E5", format),
+            CubemapError::NotACubemap => write!(f, "DDS file does not contain a square cubemap with all six faces"),
+            CubemapError::Truncated => write!(f, "DDS file is missing cubemap data"),
+        }
+    }
+}
+
+impl std::error::Error for CubemapError {}
+
 #[derive(Debug)]
 pub struct Texture {
     texture: wgpu::Texture,
@@ -53,7 +80,8 @@ impl Texture {
             format,
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT
                 | wgpu::TextureUsages::TEXTURE_BINDING
-                | wgpu::TextureUsages::STORAGE_BINDING,
+                | wgpu::TextureUsages::STORAGE_BINDING
+                | wgpu::TextureUsages::COPY_SRC,
             view_formats: &[],
         };
 
@@ -86,33 +114,18 @@ impl Texture {
         Self::create_texture(wgpu, size, format)
     }
 
-    pub fn create_cubemap(wgpu: &WGPUContext, bytes: &[u8]) -> Self {
-        let mut image = ddsfile::Dds::read(bytes).expect("Could not read DDS file");
-        let format = match image.get_dxgi_format() {
-            Some(ddsfile::DxgiFormat::BC6H_UF16) => wgpu::TextureFormat::Bc6hRgbUfloat,
-            _ => unimplemented!("Unsupported cubemap format"),
-        };
-        // Force the array size to 6, because the DDS loader doesn't set it correctly
-        image.header10.as_mut().unwrap().array_size = 6;
+    /// Loads a cubemap with all its mip levels from a DDS file with a DX10 or legacy header
+    pub fn create_cubemap(wgpu: &WGPUContext, bytes: &[u8]) -> Result<Self, CubemapError> {
+        let image = ddsfile::Dds::read(bytes)?;
         log::debug!("Cubemap Info: {:#?}", image);
-
-        let size = wgpu::Extent3d {
-            width: image.get_width(),
-            height: image.get_height(),
-            depth_or_array_layers: 6,
-        };
-
-        let mut data = Vec::new();
-        for i in 0..6 {
-            data.extend_from_slice(image.get_data(i).expect("Could not load cubemap layer"));
-        }
+        let DdsCubemap { format, size, mip_level_count, data } = DdsCubemap::new(&image)?;
 
         let texture = wgpu.device.create_texture_with_data(
             &wgpu.queue,
             &wgpu::TextureDescriptor {
                 label: Some("Cubemap Texture"),
                 size,
-                mip_level_count: image.get_num_mipmap_levels(),
+                mip_level_count,
                 sample_count: 1,
                 dimension: wgpu::TextureDimension::D2,
                 format,
@@ -120,9 +133,46 @@ impl Texture {
                 view_formats: &[format],
             },
             wgpu::util::TextureDataOrder::LayerMajor,
-            &data,
+            data,
+        );
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            ..Default::default()
+        });
+
+        let sampler = wgpu.device.create_sampler(
+            &wgpu::SamplerDescriptor {
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                mipmap_filter: wgpu::FilterMode::Linear,
+                ..Default::default()
+            }
         );
 
+        Ok(Self { texture, view, sampler })
+    }
+
+    /// Creates an empty cubemap with a full mip chain which can be written by compute shaders
+    pub fn create_storage_cubemap(wgpu: &WGPUContext, size: u32, format: wgpu::TextureFormat) -> Self {
+        let texture = wgpu.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Cubemap Texture"),
+            size: wgpu::Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: 6,
+            },
+            mip_level_count: size.ilog2() + 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::STORAGE_BINDING,
+            view_formats: &[],
+        });
+
         let view = texture.create_view(&wgpu::TextureViewDescriptor {
             dimension: Some(wgpu::TextureViewDimension::Cube),
             ..Default::default()
@@ -173,6 +223,108 @@ impl Texture {
         Self { texture, view, sampler }
     }
 
+    /// Creates a 2D texture array with `layers` layers of the same size and `levels` mip levels from tightly packed
+    /// mip-major data, with the layers of each level in order
+    pub fn create_array(wgpu: &WGPUContext, format: wgpu::TextureFormat, width: u32, height: u32, layers: u32, levels: u32, data: &[u8]) -> Self {
+        let texture = wgpu.device.create_texture_with_data(
+            &wgpu.queue,
+            &wgpu::TextureDescriptor {
+                label: Some("Texture Array"),
+                size: wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: layers,
+                },
+                mip_level_count: levels,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING,
+                view_formats: &[format],
+            },
+            wgpu::util::TextureDataOrder::MipMajor,
+            data,
+        );
+
+        // Note: The dimension has to be explicit, otherwise a single layer would be viewed as D2
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+
+        let sampler = wgpu.device.create_sampler(
+            &wgpu::SamplerDescriptor {
+                address_mode_u: wgpu::AddressMode::Repeat,
+                address_mode_v: wgpu::AddressMode::Repeat,
+                address_mode_w: wgpu::AddressMode::Repeat,
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                mipmap_filter: wgpu::FilterMode::Linear,
+                ..Default::default()
+            }
+        );
+
+        Self { texture, view, sampler }
+    }
+
+    /// Copies the first mip level of the texture back to the CPU and returns the tightly packed texel data.
+    /// Note: This blocks until the GPU has finished all submitted work.
+    pub fn download(&self, wgpu: &WGPUContext) -> Vec<u8> {
+        let size = self.texture.size();
+        let block_size = self.format().block_copy_size(None).expect("Unsupported texture format for download");
+        let unpadded_bytes_per_row = size.width * block_size;
+        let padded_bytes_per_row = unpadded_bytes_per_row.next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+
+        let buffer = wgpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Texture Download Buffer"),
+            size: (padded_bytes_per_row * size.height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = wgpu.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Texture Download Encoder"),
+        });
+        encoder.copy_texture_to_buffer(
+            self.texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_bytes_per_row),
+                    rows_per_image: Some(size.height),
+                },
+            },
+            wgpu::Extent3d { depth_or_array_layers: 1, ..size },
+        );
+        wgpu.queue.submit(Some(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |result| result.expect("Failed to map download buffer"));
+        wgpu.device.poll(wgpu::Maintain::Wait);
+
+        let mut data = Vec::with_capacity((unpadded_bytes_per_row * size.height) as usize);
+        for row in slice.get_mapped_range().chunks_exact(padded_bytes_per_row as usize) {
+            data.extend_from_slice(&row[..unpadded_bytes_per_row as usize]);
+        }
+        buffer.unmap();
+        data
+    }
+
+    /// Creates a view of all layers of a single mip level, e.g. to write the faces of a cubemap
+    pub fn create_mip_view(&self, level: u32) -> wgpu::TextureView {
+        self.texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            base_mip_level: level,
+            mip_level_count: Some(1),
+            ..Default::default()
+        })
+    }
+
+    pub fn mip_level_count(&self) -> u32 {
+        self.texture.mip_level_count()
+    }
+
     pub fn sampler(&self) -> &wgpu::Sampler {
         &self.sampler
     }
@@ -189,4 +341,171 @@ impl Texture {
         let size = self.texture.size();
         glam::uvec3(size.width, size.height, size.depth_or_array_layers)
     }
-}
\ No newline at end of file
+}
+
+/// Format, size and face data of a DDS cubemap, see `Texture::create_cubemap`
+struct DdsCubemap<'a> {
+    format: wgpu::TextureFormat,
+    size: wgpu::Extent3d,
+    mip_level_count: u32,
+    /// Faces one after another with all their mip levels
+    data: &'a [u8],
+}
+
+impl<'a> DdsCubemap<'a> {
+    fn new(image: &'a ddsfile::Dds) -> Result<Self, CubemapError> {
+        let format = dds_format(image)?;
+
+        // Note: The DX10 header counts whole cubes in its array size, legacy headers flag every face
+        let is_cubemap = match &image.header10 {
+            Some(header10) => header10.misc_flag.contains(ddsfile::MiscFlag::TEXTURECUBE),
+            None => image.header.caps2.contains(ddsfile::Caps2::CUBEMAP | ddsfile::Caps2::CUBEMAP_ALLFACES),
+        };
+        let (width, height) = (image.get_width(), image.get_height());
+        if !is_cubemap || width != height {
+            return Err(CubemapError::NotACubemap);
+        }
+
+        let size = wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 6,
+        };
+        let mip_level_count = image.get_num_mipmap_levels().clamp(1, size.max_mips(wgpu::TextureDimension::D2));
+
+        // Further cubes of an array are ignored
+        let (block_width, block_height) = format.block_dimensions();
+        let block_size = format.block_copy_size(None).expect("Cubemap formats have a block size");
+        let face_size: u32 = (0..mip_level_count).map(|level| {
+            let mip = size.mip_level_size(level, wgpu::TextureDimension::D2);
+            mip.width.div_ceil(block_width) * mip.height.div_ceil(block_height) * block_size
+        }).sum();
+        let data = image.data.get(..6 * face_size as usize).ok_or(CubemapError::Truncated)?;
+
+        Ok(Self { format, size, mip_level_count, data })
+    }
+}
+
+/// Maps the DXGI or legacy Direct3D format of a DDS file to a filterable float texture format
+fn dds_format(image: &ddsfile::Dds) -> Result<wgpu::TextureFormat, CubemapError> {
+    use ddsfile::{D3DFormat, DxgiFormat};
+    if let Some(format) = image.get_dxgi_format() {
+        match format {
+            DxgiFormat::BC6H_UF16 => Ok(wgpu::TextureFormat::Bc6hRgbUfloat),
+            DxgiFormat::BC6H_SF16 => Ok(wgpu::TextureFormat::Bc6hRgbFloat),
+            DxgiFormat::R16G16B16A16_Float => Ok(wgpu::TextureFormat::Rgba16Float),
+            DxgiFormat::R32G32B32A32_Float => Ok(wgpu::TextureFormat::Rgba32Float),
+            DxgiFormat::R11G11B10_Float => Ok(wgpu::TextureFormat::Rg11b10Float),
+            DxgiFormat::R9G9B9E5_SharedExp => Ok(wgpu::TextureFormat::Rgb9e5Ufloat),
+            _ => Err(CubemapError::UnsupportedFormat(format!("{:?}", format))),
+        }
+    } else {
+        match image.get_d3d_format() {
+            Some(D3DFormat::A16B16G16R16F) => Ok(wgpu::TextureFormat::Rgba16Float),
+            Some(D3DFormat::A32B32G32R32F) => Ok(wgpu::TextureFormat::Rgba32Float),
+            Some(format) => Err(CubemapError::UnsupportedFormat(format!("{:?}", format))),
+            None => Err(CubemapError::UnsupportedFormat(String::from("unknown"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ddsfile::{AlphaMode, Caps2, D3D10ResourceDimension, D3DFormat, DataFormat, Dds, DxgiFormat, NewD3dParams, NewDxgiParams, PixelFormatFlags};
+
+    use super::*;
+
+    /// Writes the DDS file and reads it back, as `Texture::create_cubemap` would load it
+    fn roundtrip(image: Dds) -> Dds {
+        let mut bytes = Vec::new();
+        image.write(&mut bytes).unwrap();
+        Dds::read(&bytes[..]).unwrap()
+    }
+
+    fn dx10(format: DxgiFormat, width: u32, height: u32, is_cubemap: bool) -> Dds {
+        roundtrip(Dds::new_dxgi(NewDxgiParams {
+            height,
+            width,
+            depth: None,
+            format,
+            mipmap_levels: Some(3),
+            array_layers: Some(6),
+            caps2: None,
+            is_cubemap,
+            resource_dimension: D3D10ResourceDimension::Texture2D,
+            alpha_mode: AlphaMode::Unknown,
+        }).unwrap())
+    }
+
+    /// Legacy Direct3D header, which only flags the faces in `caps2`
+    fn legacy(format: D3DFormat, faces: usize) -> Dds {
+        let mut image = Dds::new_d3d(NewD3dParams {
+            height: 8,
+            width: 8,
+            depth: None,
+            format,
+            mipmap_levels: Some(4),
+            caps2: Some(Caps2::CUBEMAP | Caps2::CUBEMAP_ALLFACES),
+        }).unwrap();
+        // Note: ddsfile only allocates the first face and writes float formats without the FourCC code that other tools use
+        image.data = image.data.repeat(faces);
+        if let Some(fourcc) = format.get_fourcc() {
+            image.header.spf.flags = PixelFormatFlags::FOURCC;
+            image.header.spf.fourcc = Some(fourcc);
+        }
+        roundtrip(image)
+    }
+
+    #[test]
+    fn dx10_headers_map_to_texture_formats() {
+        for (dxgi, format) in [
+            (DxgiFormat::BC6H_UF16, wgpu::TextureFormat::Bc6hRgbUfloat),
+            (DxgiFormat::BC6H_SF16, wgpu::TextureFormat::Bc6hRgbFloat),
+            (DxgiFormat::R16G16B16A16_Float, wgpu::TextureFormat::Rgba16Float),
+            (DxgiFormat::R32G32B32A32_Float, wgpu::TextureFormat::Rgba32Float),
+            (DxgiFormat::R11G11B10_Float, wgpu::TextureFormat::Rg11b10Float),
+            (DxgiFormat::R9G9B9E5_SharedExp, wgpu::TextureFormat::Rgb9e5Ufloat),
+        ] {
+            let image = dx10(dxgi, 16, 16, true);
+            let cubemap = DdsCubemap::new(&image).unwrap();
+            assert_eq!(cubemap.format, format);
+            assert_eq!(cubemap.size, wgpu::Extent3d { width: 16, height: 16, depth_or_array_layers: 6 });
+            assert_eq!(cubemap.mip_level_count, 3);
+            assert_eq!(cubemap.data.len(), image.data.len(), "{:?} faces have the wrong size", dxgi);
+        }
+    }
+
+    #[test]
+    fn legacy_headers_map_to_texture_formats() {
+        let image = legacy(D3DFormat::A16B16G16R16F, 6);
+        let cubemap = DdsCubemap::new(&image).unwrap();
+        assert_eq!(cubemap.format, wgpu::TextureFormat::Rgba16Float);
+        assert_eq!(cubemap.mip_level_count, 4);
+        assert_eq!(cubemap.data.len(), 6 * (64 + 16 + 4 + 1) * 8);
+
+        let image = legacy(D3DFormat::A32B32G32R32F, 6);
+        assert_eq!(DdsCubemap::new(&image).unwrap().format, wgpu::TextureFormat::Rgba32Float);
+    }
+
+    #[test]
+    fn truncated_cubemaps_are_rejected() {
+        let image = legacy(D3DFormat::A16B16G16R16F, 5);
+        assert!(matches!(DdsCubemap::new(&image), Err(CubemapError::Truncated)));
+    }
+
+    #[test]
+    fn textures_that_are_no_square_cubemaps_are_rejected() {
+        let image = dx10(DxgiFormat::R16G16B16A16_Float, 16, 16, false);
+        assert!(matches!(DdsCubemap::new(&image), Err(CubemapError::NotACubemap)));
+        let image = dx10(DxgiFormat::R16G16B16A16_Float, 16, 8, true);
+        assert!(matches!(DdsCubemap::new(&image), Err(CubemapError::NotACubemap)));
+    }
+
+    #[test]
+    fn unsupported_formats_are_rejected() {
+        let image = dx10(DxgiFormat::R8G8B8A8_UNorm, 16, 16, true);
+        assert!(matches!(DdsCubemap::new(&image), Err(CubemapError::UnsupportedFormat(format)) if format == "R8G8B8A8_UNorm"));
+        let image = legacy(D3DFormat::A8R8G8B8, 6);
+        assert!(matches!(DdsCubemap::new(&image), Err(CubemapError::UnsupportedFormat(format)) if format == "A8R8G8B8"));
+    }
+}
@@ -1,10 +1,11 @@
 use std::path::PathBuf;
 
-pub fn search_files(path: &str, ext: &str) -> Result<Vec<PathBuf>, std::io::Error> {
+/// Returns the sorted paths of all files in `path` with one of the extensions in `exts`
+pub fn search_files(path: &str, exts: &[&str]) -> Result<Vec<PathBuf>, std::io::Error> {
     let mut files = std::fs::read_dir(path)?
         .filter_map(|e| e.ok())
         .map(|e| e.path())
-        .filter(|f| f.extension().map_or(false, |x| x == ext))
+        .filter(|f| f.extension().is_some_and(|x| exts.iter().any(|ext| x == *ext)))
         .collect::<Vec<_>>();
     files.sort();
     Ok(files)
@@ -3,7 +3,8 @@ use std::sync::Arc;
 use winit::{dpi::PhysicalSize, window::Window};
 
 pub struct WGPUContext {
-    pub surface: wgpu::Surface<'static>, // TODO: Remove 'static lifetime
+    /// Is `None` for headless contexts which render into offscreen textures only
+    pub surface: Option<wgpu::Surface<'static>>, // TODO: Remove 'static lifetime
     pub device: wgpu::Device,
     pub queue: wgpu::Queue,
     pub config: wgpu::SurfaceConfiguration,
@@ -27,6 +28,52 @@ impl WGPUContext {
             .await
             .expect("Failed to find an appropriate adapter");
 
+        let (device, queue) = Self::request_device(&adapter).await;
+
+        let surface_caps = surface.get_capabilities(&adapter);
+        log::info!("Surface capabilities: {:#?}", surface_caps);
+
+        let size = window.inner_size().max(PhysicalSize::new(1, 1));
+        let config = Self::default_config(size);
+
+        surface.configure(&device, &config);
+
+        Self {
+            surface: Some(surface),
+            device,
+            queue,
+            config,
+        }
+    }
+
+    /// Creates a context without a window or surface.
+    /// The configuration only describes the size and format of offscreen render targets.
+    /// Set `force_fallback_adapter` to run on a software adapter on machines without a GPU.
+    pub async fn new_headless(size: PhysicalSize<u32>, force_fallback_adapter: bool) -> Self {
+        let instance = wgpu::Instance::default();
+
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                force_fallback_adapter,
+                compatible_surface: None,
+            })
+            .await
+            .expect("Failed to find an appropriate adapter");
+
+        let (device, queue) = Self::request_device(&adapter).await;
+
+        let config = Self::default_config(size.max(PhysicalSize::new(1, 1)));
+
+        Self {
+            surface: None,
+            device,
+            queue,
+            config,
+        }
+    }
+
+    async fn request_device(adapter: &wgpu::Adapter) -> (wgpu::Device, wgpu::Queue) {
         log::info!("Adapter: {:#?}", adapter.get_info());
         log::info!("Supported features: {:#?}", adapter.features());
         log::info!("Supported limits: {:#?}", adapter.limits());
@@ -52,12 +99,11 @@ impl WGPUContext {
             .expect("Failed to create device");
         log::info!("Requested limits: {:#?}", device.limits());
 
-        let surface_caps = surface.get_capabilities(&adapter);
-        log::info!("Surface capabilities: {:#?}", surface_caps);
-
-        let size = window.inner_size().max(PhysicalSize::new(1, 1));
+        (device, queue)
+    }
 
-        let config = wgpu::SurfaceConfiguration {
+    fn default_config(size: PhysicalSize<u32>) -> wgpu::SurfaceConfiguration {
+        wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
             format: wgpu::TextureFormat::Rgba16Float,
             width: size.width,
@@ -66,15 +112,6 @@ impl WGPUContext {
             alpha_mode: wgpu::CompositeAlphaMode::Auto,
             view_formats: vec![],
             desired_maximum_frame_latency: 2,
-        };
-
-        surface.configure(&device, &config);
-
-        Self {
-            surface,
-            device,
-            queue,
-            config,
         }
     }
 
@@ -82,7 +119,9 @@ impl WGPUContext {
         if new_size.width > 0 && new_size.height > 0 {
             self.config.width = new_size.width;
             self.config.height = new_size.height;
-            self.surface.configure(&self.device, &self.config);
+            if let Some(surface) = &self.surface {
+                surface.configure(&self.device, &self.config);
+            }
         }
     }
-}
\ No newline at end of file
+}
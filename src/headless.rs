@@ -0,0 +1,113 @@
+use winit::dpi::PhysicalSize;
+
+use crate::cli::Args;
+use crate::common::camera::Camera;
+use crate::common::{CameraController, HdrImage, WGPUContext};
+
+use crate::pathtracing::envmap::{EnvMap, EnvSettings};
+use crate::pathtracing::reference::{CpuEnvironment, ReferencePathtracer};
+use crate::pathtracing::scene::{Scene, SceneBuffers};
+use crate::pathtracing::pathtracer::Pathtracer;
+
+/// Offline renderer without a window, used for render jobs and regression tests
+pub struct HeadlessRenderer {
+    wgpu: WGPUContext,
+    scene: SceneBuffers,
+    // Note: Only kept to own the environment map and camera buffer that the path tracer binds
+    _envmap: EnvMap,
+    _camera: CameraController,
+    pathtracer: Pathtracer,
+}
+
+impl HeadlessRenderer {
+    /// Note: The output resolution is rounded down to a multiple of the compute workgroup size
+    pub async fn new(args: &Args) -> Result<Self, Box<dyn std::error::Error>> {
+        let scene_path = args.scene_path().ok_or("No scene found")?;
+        let size = PhysicalSize::new(
+            args.width.unwrap_or(Args::DEFAULT_BATCH_SIZE.0),
+            args.height.unwrap_or(Args::DEFAULT_BATCH_SIZE.1),
+        );
+
+        let wgpu = WGPUContext::new_headless(size, args.software).await;
+
+        let mut scene_data = Scene::new(args.scene_settings());
+        scene_data.parse_gltf(&scene_path)?;
+        let scene = SceneBuffers::from_scene(&wgpu, &scene_data);
+
+        let envmap = EnvMap::create(&wgpu, &args.environment())?;
+
+        let mut camera = CameraController::new(&wgpu);
+        camera.resize(size.width as f32 / size.height as f32);
+        camera.update(&wgpu);
+
+        let mut pathtracer = Pathtracer::new(&wgpu, &scene, &camera, &envmap, args.spp);
+        pathtracer.resolution_factor = 1.0;
+        pathtracer.globals.bounces = args.bounces;
+        pathtracer.globals.contribution_factor = args.contribution_factor;
+        pathtracer.resize(&wgpu);
+        pathtracer.update(&wgpu, &camera, &envmap);
+
+        let output_size = pathtracer.output_texture().size();
+        if output_size.x != size.width || output_size.y != size.height {
+            log::warn!("Rendering at {}x{} instead of {}x{}", output_size.x, output_size.y, size.width, size.height);
+        }
+
+        Ok(Self {
+            wgpu,
+            scene,
+            _envmap: envmap,
+            _camera: camera,
+            pathtracer,
+        })
+    }
+
+    /// Restarts accumulation and renders `samples` samples per pixel
+    pub fn render(&mut self, samples: u32) {
+        if samples > self.pathtracer.max_sample_count {
+            log::warn!("Clamping {} samples to the maximum of {}", samples, self.pathtracer.max_sample_count);
+        }
+
+        let timer = std::time::Instant::now();
+        self.pathtracer.invalidate();
+        for _ in 0..samples.min(self.pathtracer.max_sample_count) {
+            let mut encoder = self.wgpu.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Headless Encoder"),
+            });
+            self.pathtracer.dispatch(&mut encoder, &self.scene);
+            self.wgpu.queue.submit(Some(encoder.finish()));
+        }
+        self.wgpu.device.poll(wgpu::Maintain::Wait);
+        log::info!("Rendered {} samples in {:?}", self.pathtracer.sample_count(), timer.elapsed());
+    }
+
+    /// Reads the accumulated image back to the CPU
+    pub fn read_output(&self) -> HdrImage {
+        HdrImage::from_texture(&self.wgpu, self.pathtracer.output_texture())
+    }
+}
+
+/// Renders `args.spp` samples per pixel with the CPU reference path tracer, using the same defaults as `HeadlessRenderer`
+pub fn render_reference(args: &Args) -> Result<HdrImage, Box<dyn std::error::Error>> {
+    let scene_path = args.scene_path().ok_or("No scene found")?;
+    let size = (
+        args.width.unwrap_or(Args::DEFAULT_BATCH_SIZE.0),
+        args.height.unwrap_or(Args::DEFAULT_BATCH_SIZE.1),
+    );
+
+    let mut scene = Scene::new(args.scene_settings());
+    scene.parse_gltf(&scene_path)?;
+
+    let environment = CpuEnvironment::new(&args.environment(), &EnvSettings::default())?;
+    let camera = Camera { aspect_ratio: size.0 as f32 / size.1 as f32, ..Default::default() };
+
+    let mut pathtracer = ReferencePathtracer::new(&scene, environment, &camera, size);
+    pathtracer.bounces = args.bounces;
+    pathtracer.contribution_factor = args.contribution_factor;
+
+    let output_size = pathtracer.size();
+    if output_size != size {
+        log::warn!("Rendering at {}x{} instead of {}x{}", output_size.0, output_size.1, size.0, size.1);
+    }
+
+    Ok(pathtracer.render(args.spp))
+}
@@ -1,14 +1,75 @@
 mod app;
+mod benchmark;
+mod cli;
 mod common;
+mod headless;
 mod pathtracing;
 
 use app::MainApp;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+use cli::{Args, Command};
+use common::HdrImage;
+use headless::HeadlessRenderer;
 use winit::event_loop::{ControlFlow, EventLoop};
 
 fn main() {
     pretty_env_logger::init();
+    let args = Args::parse();
+    if args.compare_bvh && args.command.is_some() {
+        Args::command().error(ErrorKind::ArgumentConflict, "--compare-bvh cannot be used with a subcommand").exit();
+    }
+
+    if args.compare_bvh {
+        if let Err(e) = benchmark::compare_bvh_builders(&args) {
+            log::error!("Failed to compare BVH builders: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::BvhStats) = args.command {
+        if let Err(e) = benchmark::log_bvh_stats(&args) {
+            log::error!("Failed to validate BVH: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if args.batch {
+        if let Err(e) = render_batch(&args) {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let event_loop = EventLoop::new().expect("Failed to create event loop");
     event_loop.set_control_flow(ControlFlow::Poll);
-    let mut app_handler = common::AppHandler::<MainApp>::default();
+    let mut app_handler = common::AppHandler::<MainApp>::new(args);
     event_loop.run_app(&mut app_handler).expect("Failed to run app");
-}
\ No newline at end of file
+}
+
+/// Renders the scene without a window, compares it to the reference and saves it
+fn render_batch(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    let image = if args.cpu {
+        headless::render_reference(args).map_err(|e| format!("Failed to render on the CPU: {}", e))?
+    } else {
+        let mut renderer = pollster::block_on(HeadlessRenderer::new(args))
+            .map_err(|e| format!("Failed to create headless renderer: {}", e))?;
+        renderer.render(args.spp);
+        renderer.read_output()
+    };
+    log::info!("Mean radiance: {}", image.mean());
+    if let Some(reference) = &args.compare {
+        let reference = HdrImage::open(reference).map_err(|e| format!("Failed to open {:?}: {}", reference, e))?;
+        match image.relative_mse(&reference) {
+            Some(error) => log::info!("Relative MSE: {}", error),
+            None => log::error!("Reference is {}x{} but the render is {}x{}", reference.width, reference.height, image.width, image.height),
+        }
+    }
+    if let Some(output) = &args.output {
+        image.save(output).map_err(|e| format!("Failed to save {:?}: {}", output, e))?;
+    }
+    Ok(())
+}
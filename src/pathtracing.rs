@@ -3,4 +3,11 @@ pub mod mesh_renderer;
 pub mod pathtracer;
 pub mod bvh;
 pub mod scene;
-pub mod envmap;
\ No newline at end of file
+pub mod atlas;
+pub mod envmap;
+pub mod tangents;
+pub mod normals;
+pub mod lights;
+pub mod sky;
+pub mod raytracing_cpu;
+pub mod reference;
@@ -0,0 +1,309 @@
+use glam::{Vec2, Vec4};
+use image::{imageops, RgbaImage};
+
+/// Maximum width and height of a texture in the atlas
+const MAX_TEXTURE_SIZE: u32 = 2048;
+/// All textures are halved until the atlas with its mip levels fits into this budget
+const TEXTURE_MEMORY_BUDGET: u64 = 1 << 30;
+/// Footprint that selects the full resolution of every texture, matches POINT_FOOTPRINT in raytracing_sw.wgsl
+pub const POINT_FOOTPRINT: f32 = -64.0;
+
+/// Region of one texture in the atlas, needs to match TextureEntry in raytracing_sw.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct AtlasEntry {
+    /// Position of the full resolution texture in its page, a multiple of `1 << max_level`
+    pub offset: [u32; 2],
+    /// Power of two size of the full resolution texture
+    pub size: [u32; 2],
+    pub page: u32,
+    /// Coarsest mip level, at which the shorter side is a single texel
+    pub max_level: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AtlasLimits {
+    /// Maximum width and height of the pages
+    pub max_size: u32,
+    pub max_pages: u32,
+    /// Maximum size of all pages and their mip levels in bytes
+    pub memory_budget: u64,
+}
+
+impl AtlasLimits {
+    pub fn new(limits: &wgpu::Limits) -> Self {
+        Self {
+            max_size: MAX_TEXTURE_SIZE.min(limits.max_texture_dimension_2d),
+            max_pages: limits.max_texture_array_layers,
+            memory_budget: TEXTURE_MEMORY_BUDGET,
+        }
+    }
+}
+
+/// RGBA8 textures packed into the square pages of a texture array.
+/// Every texture keeps its aspect ratio rounded to powers of two and has its own mip chain down to a width or height of
+/// one texel, which is aligned so that its mip levels never share texels with other textures.
+/// Note: Sampling is done manually as the hardware sampler can neither repeat nor stop mipmapping inside a region.
+pub struct TextureAtlas {
+    pub page_size: u32,
+    pub pages: u32,
+    pub levels: u32,
+    /// Regions of the textures, textures which did not fit are left out at the end
+    pub entries: Vec<AtlasEntry>,
+    /// Mip-major and layer-major texel data ready to be uploaded
+    pub data: Vec<u8>,
+}
+
+impl TextureAtlas {
+    pub fn new(textures: &[RgbaImage], limits: &AtlasLimits) -> Self {
+        let sizes: Vec<_> = textures.iter().map(|t| [t.width(), t.height()]).collect();
+        let (page_size, pages, entries) = layout(&sizes, limits);
+        if entries.len() < textures.len() {
+            log::warn!("Dropping {} textures that do not fit into {} pages of {}x{}", textures.len() - entries.len(), limits.max_pages, page_size, page_size);
+        }
+
+        let levels = entries.iter().map(|e| e.max_level + 1).max().unwrap_or(1);
+        let mut atlas = Self { page_size, pages, levels, data: vec![0; level_offset(page_size, pages, levels)], entries };
+        for (texture, index) in textures.iter().zip(0..atlas.entries.len()) {
+            let entry = atlas.entries[index];
+            let [width, height] = entry.size;
+            let mut image = if texture.dimensions() == (width, height) {
+                texture.clone()
+            } else {
+                imageops::resize(texture, width, height, imageops::FilterType::Triangle)
+            };
+            for level in 0..=entry.max_level {
+                if level > 0 {
+                    image = imageops::resize(&image, width >> level, height >> level, imageops::FilterType::Triangle);
+                }
+                atlas.write(&entry, level, &image);
+            }
+        }
+        atlas
+    }
+
+    /// Number of textures in the atlas
+    pub fn texture_count(&self) -> u32 {
+        self.entries.len() as u32
+    }
+
+    fn write(&mut self, entry: &AtlasEntry, level: u32, image: &RgbaImage) {
+        let row_bytes = image.width() as usize * 4;
+        for (y, row) in image.as_raw().chunks_exact(row_bytes).enumerate() {
+            let start = self.texel_index(entry.page, level, entry.offset[0] >> level, (entry.offset[1] >> level) + y as u32);
+            self.data[start..start + row_bytes].copy_from_slice(row);
+        }
+    }
+
+    fn texel_index(&self, page: u32, level: u32, x: u32, y: u32) -> usize {
+        let size = (self.page_size >> level) as usize;
+        level_offset(self.page_size, self.pages, level) + ((page as usize * size + y as usize) * size + x as usize) * 4
+    }
+
+    /// Trilinearly filtered lookup with repeating texture coordinates, matches `sample_texture` in raytracing_sw.wgsl.
+    /// `footprint` is the base 2 logarithm of the filter width in texture coordinates.
+    pub fn sample(&self, index: u32, texcoord: Vec2, footprint: f32) -> Vec4 {
+        let entry = &self.entries[index as usize];
+        let level = (footprint + 0.5 * ((entry.size[0] * entry.size[1]) as f32).log2()).clamp(0.0, entry.max_level as f32);
+        let l0 = level as u32;
+        let l1 = (l0 + 1).min(entry.max_level);
+        self.sample_level(entry, texcoord, l0).lerp(self.sample_level(entry, texcoord, l1), level.fract())
+    }
+
+    /// Bilinearly filtered lookup in one mip level, matches `sample_texture_level` in raytracing_sw.wgsl
+    fn sample_level(&self, entry: &AtlasEntry, texcoord: Vec2, level: u32) -> Vec4 {
+        let size = Vec2::new((entry.size[0] >> level) as f32, (entry.size[1] >> level) as f32);
+        let p = texcoord * size - 0.5;
+        let p0 = p.floor();
+        let f = p - p0;
+        // Note: Wrapping with floor instead of % keeps negative coordinates repeating
+        let p0 = p0 - (p0 / size).floor() * size;
+        let texel = |dx: f32, dy: f32| {
+            let x = (p0.x + dx) % size.x;
+            let y = (p0.y + dy) % size.y;
+            let i = self.texel_index(entry.page, level, (entry.offset[0] >> level) + x as u32, (entry.offset[1] >> level) + y as u32);
+            Vec4::from_array([0, 1, 2, 3].map(|c| self.data[i + c] as f32 / 255.0))
+        };
+        let top = texel(0.0, 0.0).lerp(texel(1.0, 0.0), f.x);
+        let bottom = texel(0.0, 1.0).lerp(texel(1.0, 1.0), f.x);
+        top.lerp(bottom, f.y)
+    }
+}
+
+/// Byte offset of mip level `level` in the data of `pages` pages
+fn level_offset(page_size: u32, pages: u32, level: u32) -> usize {
+    (0..level).map(|l| (page_size >> l) as usize * (page_size >> l) as usize * pages as usize * 4).sum()
+}
+
+/// Rounds both sides to the nearest power of two and halves them until they fit into `max_size`
+fn fit_size([width, height]: [u32; 2], max_size: u32) -> [u32; 2] {
+    let round = |x: u32| {
+        let x = x.max(1);
+        let up = x.next_power_of_two();
+        if up - x > x - up / 2 { up / 2 } else { up }
+    };
+    let [mut width, mut height] = [round(width), round(height)];
+    while width.max(height) > max_size {
+        width = (width / 2).max(1);
+        height = (height / 2).max(1);
+    }
+    [width, height]
+}
+
+/// Places textures of the given sizes into as few pages as possible, halving all of them until the atlas fits the limits.
+/// Returns the page size, the number of pages and the entries in the order of `sizes`.
+fn layout(sizes: &[[u32; 2]], limits: &AtlasLimits) -> (u32, u32, Vec<AtlasEntry>) {
+    let mut sizes: Vec<_> = sizes.iter().map(|&size| fit_size(size, limits.max_size)).collect();
+    loop {
+        // Larger pages waste less space than halving the textures
+        let mut page_size = sizes.iter().flatten().copied().max().unwrap_or(1);
+        let (mut pages, mut entries) = pack(&sizes, page_size);
+        while pages > limits.max_pages && page_size < limits.max_size {
+            page_size *= 2;
+            (pages, entries) = pack(&sizes, page_size);
+        }
+
+        let levels = entries.iter().map(|e| e.max_level + 1).max().unwrap_or(1);
+        if pages <= limits.max_pages && level_offset(page_size, pages, levels) as u64 <= limits.memory_budget {
+            return (page_size, pages, entries);
+        }
+
+        if sizes.iter().all(|&size| size == [1, 1]) {
+            sizes.pop();
+        } else {
+            for size in &mut sizes {
+                *size = size.map(|s| (s / 2).max(1));
+            }
+        }
+    }
+}
+
+/// Packs power of two sized textures into shelves, tallest first, so that every texture is aligned to its shorter side
+fn pack(sizes: &[[u32; 2]], page_size: u32) -> (u32, Vec<AtlasEntry>) {
+    let mut order: Vec<_> = (0..sizes.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse((sizes[i][1], sizes[i][0])));
+
+    let mut entries = vec![bytemuck::Zeroable::zeroed(); sizes.len()];
+    let (mut page, mut x, mut shelf_y, mut shelf_height) = (0, 0, 0, 0);
+    for i in order {
+        let [width, height] = sizes[i];
+        let align = width.min(height);
+        x = u32::next_multiple_of(x, align);
+        if x + width > page_size {
+            shelf_y += shelf_height;
+            x = 0;
+            shelf_height = 0;
+        }
+        // Note: The shelves get lower, so their positions are multiples of all following heights
+        if shelf_y + height > page_size {
+            page += 1;
+            shelf_y = 0;
+            x = 0;
+            shelf_height = 0;
+        }
+        shelf_height = shelf_height.max(height);
+        entries[i] = AtlasEntry { offset: [x, shelf_y], size: [width, height], page, max_level: align.ilog2() };
+        x += width;
+    }
+    // Note: Without textures there is a single empty page, as the texture array can not be empty
+    (page + 1, entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: AtlasLimits = AtlasLimits { max_size: 2048, max_pages: 256, memory_budget: 1 << 30 };
+
+    fn overlaps(a: &AtlasEntry, b: &AtlasEntry) -> bool {
+        a.page == b.page && (0..2).all(|d| a.offset[d] < b.offset[d] + b.size[d] && b.offset[d] < a.offset[d] + a.size[d])
+    }
+
+    fn assert_valid(page_size: u32, pages: u32, entries: &[AtlasEntry]) {
+        for (i, a) in entries.iter().enumerate() {
+            assert!(a.page < pages);
+            assert!(a.offset[0] + a.size[0] <= page_size && a.offset[1] + a.size[1] <= page_size, "{a:?} leaves the page");
+            assert_eq!(a.size[0].min(a.size[1]), 1 << a.max_level);
+            assert!(a.offset.iter().all(|o| o % (1 << a.max_level) == 0), "{a:?} is not aligned to its coarsest mip level");
+            for b in &entries[i + 1..] {
+                assert!(!overlaps(a, b), "{a:?} overlaps {b:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn sizes_keep_their_aspect_ratio() {
+        assert_eq!(fit_size([1920, 1080], 2048), [2048, 1024]);
+        assert_eq!(fit_size([4096, 512], 2048), [2048, 256]);
+        assert_eq!(fit_size([300, 3], 2048), [256, 4]);
+        assert_eq!(fit_size([8192, 1], 2048), [2048, 1]);
+    }
+
+    #[test]
+    fn entries_keep_the_texture_order() {
+        let sizes = [[16, 16], [64, 32], [8, 128], [64, 64], [1, 1], [32, 8]];
+        let (page_size, pages, entries) = layout(&sizes, &LIMITS);
+        assert_eq!(entries.len(), sizes.len());
+        for (size, entry) in sizes.iter().zip(&entries) {
+            assert_eq!(&entry.size, size);
+        }
+        assert_eq!(page_size, 128);
+        assert_valid(page_size, pages, &entries);
+    }
+
+    #[test]
+    fn many_textures_use_larger_pages() {
+        let sizes = vec![[64, 64]; 100];
+        let limits = AtlasLimits { max_pages: 8, ..LIMITS };
+        let (page_size, pages, entries) = layout(&sizes, &limits);
+        assert_eq!(entries.len(), 100);
+        assert!(entries.iter().all(|e| e.size == [64, 64]));
+        assert!(pages <= 8);
+        assert_valid(page_size, pages, &entries);
+    }
+
+    #[test]
+    fn textures_are_halved_to_fit_the_limits() {
+        let sizes = vec![[256, 128]; 10];
+        let limits = AtlasLimits { max_size: 256, max_pages: 2, memory_budget: 1 << 20 };
+        let (page_size, pages, entries) = layout(&sizes, &limits);
+        assert!(entries.iter().all(|e| e.size == [128, 64]));
+        assert!(pages <= 2);
+        assert!(level_offset(page_size, pages, 8) as u64 <= limits.memory_budget);
+        assert_valid(page_size, pages, &entries);
+    }
+
+    #[test]
+    fn textures_beyond_the_limits_are_dropped() {
+        let sizes = vec![[4, 4]; 10];
+        let limits = AtlasLimits { max_size: 2, max_pages: 2, memory_budget: 1 << 20 };
+        let (page_size, pages, entries) = layout(&sizes, &limits);
+        assert_eq!(page_size, 2);
+        assert_eq!(pages, 2);
+        assert_eq!(entries.len(), 8);
+        assert_valid(page_size, pages, &entries);
+    }
+
+    #[test]
+    fn sampling_repeats_inside_the_texture_and_filters_mip_levels() {
+        // A 4x2 texture with a white and a black half next to a gray texture
+        let striped = RgbaImage::from_fn(4, 2, |x, _| image::Rgba(if x < 2 { [255; 4] } else { [0, 0, 0, 255] }));
+        let gray = RgbaImage::from_pixel(4, 4, image::Rgba([128; 4]));
+        let atlas = TextureAtlas::new(&[striped, gray], &LIMITS);
+        assert_eq!(atlas.texture_count(), 2);
+        assert_eq!(atlas.levels, 3);
+
+        let white = Vec4::ONE;
+        let black = Vec4::new(0.0, 0.0, 0.0, 1.0);
+        assert_eq!(atlas.sample(0, Vec2::new(0.25, 0.5), POINT_FOOTPRINT), white);
+        assert_eq!(atlas.sample(0, Vec2::new(-0.25, 0.5), POINT_FOOTPRINT), black);
+        assert_eq!(atlas.sample(0, Vec2::new(2.25, -3.5), POINT_FOOTPRINT), white);
+        // The texel centers on the edge blend the first and last column instead of the gray texture
+        let edge = atlas.sample(0, Vec2::new(0.0, 0.5), POINT_FOOTPRINT);
+        assert!((edge - white.lerp(black, 0.5)).abs().max_element() < 1e-6, "{edge}");
+        // A footprint of the whole texture selects the coarsest level, which blurs the stripes
+        let average = atlas.sample(0, Vec2::new(0.5, 0.5), 0.0);
+        assert!((average - white.lerp(black, 0.5)).abs().max_element() < 0.01, "{average}");
+        assert!(atlas.sample(0, Vec2::new(0.25, 0.5), 0.0).x < 1.0);
+    }
+}
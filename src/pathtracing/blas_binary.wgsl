@@ -0,0 +1,65 @@
+// Traversal of the binary BLAS, see blas_wide.wgsl for the wide BLAS
+const BLAS_STACK_SIZE = 32u;
+
+@group(1) @binding(0) var<storage, read> blas: array<BVHNode>;
+
+fn intersect_BLAS(ray: Ray, instance_index: u32) -> RawHit {
+    var stack: array<StackEntry, BLAS_STACK_SIZE>;
+
+    let instance = instances[instance_index];
+    let index_top = instance.node;
+
+    var hit = no_raw_hit();
+
+    // Init stack with top node
+    var i = 0u;
+    let top = blas[index_top];
+    let dist_top = intersect_AABB(ray, AABB(top.min, top.max));
+    hit.n_aabb += 1u;
+    if dist_top < hit.dist {
+        stack[i] = StackEntry(index_top, dist_top);
+        i += 1u;
+    }
+
+    while i > 0u {
+        // Pop next node from stack
+        i -= 1u;
+        var stack_entry = stack[i];
+        if stack_entry.dist >= hit.dist { continue; } // Skip if node is farther than current hit
+        var node = blas[stack_entry.index];
+        let is_leaf = node.end > 0u;
+        if is_leaf { // Leaf node
+            intersect_triangles(ray, instance, node.start, node.end, &hit);
+        } else {
+            let index_left = node.start;
+            let left_node = blas[index_left];
+            let left = StackEntry(index_left, intersect_AABB(ray, AABB(left_node.min, left_node.max)));
+
+            let index_right = index_left + 1u;
+            let right_node = blas[index_right];
+            let right = StackEntry(index_right, intersect_AABB(ray, AABB(right_node.min, right_node.max)));
+
+            hit.n_aabb += 2u;
+
+            var far = left;
+            var near = right;
+            if left.dist < right.dist {
+                far = right;
+                near = left;
+            }
+
+            // Look at far node last
+            if far.dist < hit.dist {
+                stack[i] = far;
+                i += 1u;
+            }
+
+            // Look at near node first
+            if near.dist < hit.dist {
+                stack[i] = near;
+                i += 1u;
+            }
+        }
+    }
+    return hit;
+}
@@ -0,0 +1,102 @@
+// Traversal of the wide BLAS with quantized child bounds, see blas_binary.wgsl for the binary BLAS
+// Note: Needs to match MAX_WIDTH and STACK_SIZE in bvh/wide.rs
+const BLAS_WIDTH = 8u;
+const BLAS_STACK_SIZE = 64u;
+
+// Note: Needs to match WideNode in bvh/wide.rs, each vec2u holds one byte per child
+struct WideNode {
+    origin: vec3f,
+    exponents_imask: u32,
+    child_base: u32,
+    triangle_base: u32,
+    counts: vec2u,
+    lo_x: vec2u,
+    lo_y: vec2u,
+    lo_z: vec2u,
+    hi_x: vec2u,
+    hi_y: vec2u,
+    hi_z: vec2u,
+};
+
+@group(1) @binding(0) var<storage, read> blas: array<WideNode>;
+
+// Inner children are referenced by their node and have no triangles, leaves by their first triangle
+struct WideStackEntry {
+    index: u32,
+    count: u32,
+    dist: f32,
+};
+
+fn child_byte(bytes: vec2u, slot: u32) -> u32 {
+    return extractBits(bytes[slot / 4u], (slot % 4u) * 8u, 8u);
+}
+
+fn intersect_BLAS(ray: Ray, instance_index: u32) -> RawHit {
+    var stack: array<WideStackEntry, BLAS_STACK_SIZE>;
+    // Children hit by the ray, sorted with the farthest first
+    var children: array<WideStackEntry, BLAS_WIDTH>;
+
+    let instance = instances[instance_index];
+
+    var hit = no_raw_hit();
+
+    // Note: The root has no bounds of its own, the TLAS already tested the bounds of the instance
+    var i = 0u;
+    stack[i] = WideStackEntry(instance.node, 0u, 0.0);
+    i += 1u;
+
+    while i > 0u {
+        // Pop next node from stack
+        i -= 1u;
+        let stack_entry = stack[i];
+        if stack_entry.dist >= hit.dist { continue; } // Skip if node is farther than current hit
+        if stack_entry.count > 0u { // Leaf
+            intersect_triangles(ray, instance, stack_entry.index, stack_entry.index + stack_entry.count, &hit);
+            continue;
+        }
+        let node = blas[stack_entry.index];
+        // The grid spacing is a power of two, so the biased exponents are the float bits
+        let exponents = vec3u(node.exponents_imask, node.exponents_imask >> 8u, node.exponents_imask >> 16u) & vec3u(0xffu);
+        let scale = bitcast<vec3f>(exponents << vec3u(23u));
+        let inner_mask = node.exponents_imask >> 24u;
+
+        var n_children = 0u;
+        var child = node.child_base;
+        var triangle = node.triangle_base;
+        for (var slot = 0u; slot < BLAS_WIDTH; slot += 1u) {
+            let count = child_byte(node.counts, slot);
+            let is_inner = extractBits(inner_mask, slot, 1u) != 0u;
+            if count == 0u && !is_inner { continue; } // Empty slot
+
+            let lo = vec3f(f32(child_byte(node.lo_x, slot)), f32(child_byte(node.lo_y, slot)), f32(child_byte(node.lo_z, slot)));
+            let hi = vec3f(f32(child_byte(node.hi_x, slot)), f32(child_byte(node.hi_y, slot)), f32(child_byte(node.hi_z, slot)));
+            let dist = intersect_AABB(ray, AABB(node.origin + lo * scale, node.origin + hi * scale));
+            hit.n_aabb += 1u;
+
+            // Note: Leaves are visited in order with the inner children, intersecting them right away tests far more triangles
+            if dist < hit.dist {
+                var k = n_children;
+                while k > 0u && children[k - 1u].dist < dist {
+                    children[k] = children[k - 1u];
+                    k -= 1u;
+                }
+                children[k] = WideStackEntry(select(triangle, child, is_inner), count, dist);
+                n_children += 1u;
+            }
+            if is_inner {
+                child += 1u;
+            } else {
+                triangle += count;
+            }
+        }
+
+        // Push the farthest child first so that the nearest one is visited next
+        for (var c = 0u; c < n_children; c += 1u) {
+            if children[c].dist < hit.dist {
+                stack[i] = children[c];
+                i += 1u;
+            }
+        }
+    }
+    return hit;
+}
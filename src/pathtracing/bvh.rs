@@ -1,10 +1,35 @@
 use std::ops::Range;
 
 use glam::{UVec3, Vec3};
+use rayon::prelude::*;
 
 use super::scene::Vertex;
 
-pub trait BVHPrimitive {
+mod lbvh;
+mod sbvh;
+pub mod stats;
+pub mod wide;
+
+/// Construction algorithm of a BVH
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BVHBuilder {
+    /// Binned surface area heuristic with object splits only
+    #[default]
+    Sah,
+    /// Spatial splits that clip triangle references against the split plane (SBVH), tried where the children of
+    /// the best object split overlap by more than `overlap_threshold` times the surface area of the root
+    Spatial { overlap_threshold: f32 },
+    /// Primitives sorted by the Morton codes of their centers (LBVH), fast enough to rebuild trees every frame
+    /// but slower to traverse
+    Linear,
+}
+
+impl BVHBuilder {
+    /// Overlap threshold suggested by Stich et al. 2009
+    pub const DEFAULT_OVERLAP_THRESHOLD: f32 = 1e-5;
+}
+
+pub trait BVHPrimitive: Send + Sync {
     fn min(&self) -> Vec3;
     fn max(&self) -> Vec3;
     fn center(&self) -> Vec3 {
@@ -13,7 +38,7 @@ pub trait BVHPrimitive {
 }
 
 #[repr(C)]
-#[derive(Clone, Copy, Debug, bytemuck::NoUninit)]
+#[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct BVHNode {
     pub min: Vec3,
     /// If this is a leaf node, this is the index of the first triangle index.
@@ -52,8 +77,7 @@ impl BVHNode {
         Self { min: bin.min, start, max: bin.max, end: start + bin.count, }
     }
 
-    #[allow(dead_code)]
-    fn is_leaf(&self) -> bool {
+    pub fn is_leaf(&self) -> bool {
         self.end > 0
     }
 
@@ -62,6 +86,23 @@ impl BVHNode {
         self.start = left_child;
     }
 
+    /// Offsets the child index of inner nodes or the primitive range of leaves
+    fn rebase(mut self, node_offset: u32, primitive_offset: u32) -> Self {
+        if self.is_leaf() {
+            self.start += primitive_offset;
+            self.end += primitive_offset;
+        } else {
+            self.start += node_offset;
+        }
+        self
+    }
+
+    /// Half of the surface area, which is proportional to the probability of a random ray hitting the box
+    fn area(&self) -> f32 {
+        let extent = self.max - self.min;
+        extent.x * extent.y + extent.x * extent.z + extent.y * extent.z
+    }
+
     fn cost(&self) -> f32 {
         debug_assert!(self.end > self.start, "No leaf: {:#?}", self);
         let extent = self.max - self.min;
@@ -74,6 +115,7 @@ impl BVHNode {
     }
 }
 
+#[derive(Clone, Copy)]
 pub struct Triangle {
     center: Vec3,
     min: Vec3,
@@ -109,6 +151,23 @@ pub fn flatten_triangle_list(triangles: &[Triangle], indices: &mut[u32]) {
     }
 }
 
+/// Random triangles in the box at `origin` with the given extent, whose corners lie up to `size` times the extent
+/// around their center. Each triangle has its own vertices, so the first index of a triangle identifies it
+#[cfg(test)]
+pub fn random_triangles(count: u32, origin: Vec3, extent: Vec3, size: f32) -> (Vec<Vertex>, Vec<Triangle>) {
+    use glam::UVec4;
+    use crate::pathtracing::raytracing_cpu::hash4f;
+
+    let vertices: Vec<_> = (0..count * 3).map(|i| {
+        let center = hash4f(UVec4::new(i / 3, 0, 0, 0)).truncate();
+        let offset = hash4f(UVec4::new(i, 1, 0, 0)).truncate() - 0.5;
+        Vertex { position: origin + (center + offset * size) * extent, ..Vertex::default() }
+    }).collect();
+    let indices: Vec<_> = (0..count * 3).collect();
+    let triangles = build_triangle_cache(&vertices, &indices);
+    (vertices, triangles)
+}
+
 #[derive(Clone, Copy)]
 struct Bin {
     min: Vec3,
@@ -152,46 +211,166 @@ impl Bin {
 
 const MAX_DEPTH: u32 = 32;
 const N_BINS: usize = 16;
+/// Smaller subtrees are built serially as spawning tasks would cost more than they gain
+const PARALLEL_BUILD_THRESHOLD: u32 = 4096;
+/// Larger nodes are binned in parallel chunks
+const PARALLEL_BINNING_THRESHOLD: u32 = 65536;
+const BINNING_CHUNK_SIZE: usize = 16384;
 
 #[derive(Default)]
 pub struct BVHTree {
     nodes: Vec<BVHNode>,
 }
 
+/// Subtree built independently of its final position in the node list
+enum Subtree {
+    /// The root followed by its descendants in the order of `build_serial`, indices are relative to the subtree
+    Serial(Vec<BVHNode>),
+    /// The root was split and both children were built in parallel
+    Split {
+        root: BVHNode,
+        left: Box<Subtree>,
+        right: Box<Subtree>,
+        /// Number of primitives in the left subtree, the right subtree follows them
+        left_count: u32,
+    },
+}
+
 impl BVHTree {
+    /// Builds a BVH over the primitives in `range` and returns the index of its root node.
+    /// Independent subtrees are built in parallel, the result is identical to a serial build.
     pub fn append(&mut self, primitives: &mut[impl BVHPrimitive], range: Range<u32>) -> u32 {
         let timer = std::time::Instant::now();
-        let mut stack = Vec::new();
 
-        let parent_index = self.nodes.len() as u32;
-        let parent = BVHNode::new_leaf(primitives, range);
-        self.nodes.push(parent);
-        stack.push((0u32, parent_index));
+        let root_index = self.nodes.len() as u32;
+        let primitive_offset = range.start;
+        let primitives = &mut primitives[range.start as usize..range.end as usize];
+        let root = BVHNode::new_leaf(primitives, 0..range.end - range.start);
+        let subtree = build_subtree(primitives, root, 0);
+        // Note: Placeholder until the subtree is flattened
+        self.nodes.push(root);
+        self.flatten(subtree, root_index, primitive_offset);
 
-        // TODO: Make parallel (maybe using rayon?)
-        while let Some((depth, node_index)) = stack.pop() {
-            if depth >= MAX_DEPTH {
-                continue;
+        log::info!("Built BVH in {:?}", timer.elapsed());
+        root_index
+    }
+
+    /// Writes the subtree to the node list in the same order as `build_serial`:
+    /// Children are stored next to each other, followed by the descendants of the right child and then those of the left child
+    fn flatten(&mut self, subtree: Subtree, index: u32, primitive_offset: u32) {
+        match subtree {
+            Subtree::Serial(nodes) => {
+                // Local index 0 is the root at `index`, local index 1 is the next free node
+                let node_offset = self.nodes.len() as u32 - 1;
+                self.nodes[index as usize] = nodes[0].rebase(node_offset, primitive_offset);
+                self.nodes.extend(nodes[1..].iter().map(|node| node.rebase(node_offset, primitive_offset)));
             }
-            let node = &self.nodes[node_index as usize];
-            if let Some((left, right)) = split_node(primitives, node) {
+            Subtree::Split { mut root, left, right, left_count } => {
                 let left_index = self.nodes.len() as u32;
-                let right_index = left_index + 1;
-                self.nodes[node_index as usize].make_inner(left_index);
-                self.nodes.push(left);
-                self.nodes.push(right);
-                stack.push((depth + 1, left_index));
-                stack.push((depth + 1, right_index));
+                root.make_inner(left_index);
+                self.nodes[index as usize] = root;
+                // Note: Placeholders until the children are flattened
+                self.nodes.extend([root, root]);
+                self.flatten(*right, left_index + 1, primitive_offset + left_count);
+                self.flatten(*left, left_index, primitive_offset);
             }
         }
-
-        log::info!("Built BVH in {:?}", timer.elapsed());
-        parent_index
     }
 
     pub fn nodes(&self) -> &[BVHNode] {
         &self.nodes
     }
+
+    /// Restores a tree from the nodes of `nodes()`
+    pub fn from_nodes(nodes: Vec<BVHNode>) -> Self {
+        Self { nodes }
+    }
+
+    /// Recomputes the bounds of all nodes after the primitives moved, keeping the topology.
+    /// Returns the indices of the nodes whose bounds changed in ascending order.
+    pub fn refit(&mut self, primitives: &[impl BVHPrimitive]) -> Vec<u32> {
+        let mut changed = Vec::new();
+        // Note: Children are always stored after their parent, so a reverse pass updates them first
+        for index in (0..self.nodes.len()).rev() {
+            let node = self.nodes[index];
+            let refitted = if node.is_leaf() {
+                BVHNode::new_leaf(primitives, node.range())
+            } else {
+                let (left, right) = (&self.nodes[node.start as usize], &self.nodes[node.start as usize + 1]);
+                BVHNode { min: left.min.min(right.min), max: left.max.max(right.max), ..node }
+            };
+            if refitted.min != node.min || refitted.max != node.max {
+                self.nodes[index] = refitted;
+                changed.push(index as u32);
+            }
+        }
+        changed.reverse();
+        changed
+    }
+
+    /// Expected cost of tracing a random ray through the tree below `root` using the surface area heuristic,
+    /// with unit costs for each traversal step and triangle test
+    pub fn sah_cost(&self, root: u32) -> f32 {
+        let Some(root_node) = self.nodes.get(root as usize) else {
+            return 0.0; // Empty tree
+        };
+        let root_area = root_node.area();
+        let mut cost = 0.0;
+        let mut stack = vec![root];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index as usize];
+            if node.is_leaf() {
+                cost += node.count() as f32 * node.area() / root_area;
+            } else {
+                cost += node.area() / root_area;
+                stack.extend([node.start, node.start + 1]);
+            }
+        }
+        cost
+    }
+}
+
+/// Builds the subtree below `root`, whose range covers all of `primitives`
+fn build_subtree(primitives: &mut[impl BVHPrimitive], root: BVHNode, depth: u32) -> Subtree {
+    if root.count() < PARALLEL_BUILD_THRESHOLD || depth >= MAX_DEPTH {
+        return Subtree::Serial(build_serial(primitives, root, depth));
+    }
+    let Some((left, right)) = split_node(primitives, &root) else {
+        return Subtree::Serial(vec![root]);
+    };
+
+    let left_count = left.count();
+    let (left_primitives, right_primitives) = primitives.split_at_mut(left_count as usize);
+    let right = BVHNode { start: right.start - left_count, end: right.end - left_count, ..right };
+    let (left, right) = rayon::join(
+        || build_subtree(left_primitives, left, depth + 1),
+        || build_subtree(right_primitives, right, depth + 1),
+    );
+    Subtree::Split { root, left: Box::new(left), right: Box::new(right), left_count }
+}
+
+/// Builds the subtree below `root` from an explicit stack, returns the root followed by its descendants
+fn build_serial(primitives: &mut[impl BVHPrimitive], root: BVHNode, depth: u32) -> Vec<BVHNode> {
+    let mut nodes = vec![root];
+    let mut stack = vec![(depth, 0u32)];
+
+    while let Some((depth, node_index)) = stack.pop() {
+        if depth >= MAX_DEPTH {
+            continue;
+        }
+        let node = &nodes[node_index as usize];
+        if let Some((left, right)) = split_node(primitives, node) {
+            let left_index = nodes.len() as u32;
+            let right_index = left_index + 1;
+            nodes[node_index as usize].make_inner(left_index);
+            nodes.push(left);
+            nodes.push(right);
+            stack.push((depth + 1, left_index));
+            stack.push((depth + 1, right_index));
+        }
+    }
+
+    nodes
 }
 
 pub fn build_bvh(primitives: &mut[impl BVHPrimitive], range: Range<u32>) -> BVHTree {
@@ -200,6 +379,20 @@ pub fn build_bvh(primitives: &mut[impl BVHPrimitive], range: Range<u32>) -> BVHT
     tree
 }
 
+/// Note: Spatial splits need the triangle geometry, see `BVHTree::append_spatial`, so they fall back to object splits here
+pub fn build_bvh_with(builder: BVHBuilder, primitives: &mut[impl BVHPrimitive], range: Range<u32>) -> BVHTree {
+    let mut tree = BVHTree::default();
+    // Note: The TLAS of a scene without instances has no nodes
+    if range.is_empty() {
+        return tree;
+    }
+    match builder {
+        BVHBuilder::Sah | BVHBuilder::Spatial { .. } => tree.append(primitives, range),
+        BVHBuilder::Linear => tree.append_linear(primitives, range),
+    };
+    tree
+}
+
 struct Split {
     axis: usize,
     mid: f32,
@@ -259,18 +452,19 @@ fn find_best_split(primitives: &[impl BVHPrimitive], parent: &BVHNode) -> Option
 
 fn approximate_best_split(primitives: &[impl BVHPrimitive], parent: &BVHNode) -> Option<Split> {
     // Build N_BINS bins per axis
-    let mut bins = [Bin::default(); N_BINS * 3];
     let step = (parent.max - parent.min) / N_BINS as f32;
-
-    for i in parent.range() {
-        let primitive = &primitives[i as usize];
-
-        let bin_indices = Vec3::floor((primitive.center() - parent.min) / step).as_uvec3().min(UVec3::splat(N_BINS as u32 - 1));
-
-        bins[bin_indices.x as usize].include(primitive);
-        bins[N_BINS + bin_indices.y as usize].include(primitive);
-        bins[N_BINS * 2 + bin_indices.z as usize].include(primitive);
-    }
+    let range = &primitives[parent.start as usize..parent.end as usize];
+    // Note: Merging bins is exact, so the chunking does not affect the result
+    let bins = if parent.count() >= PARALLEL_BINNING_THRESHOLD {
+        range.par_chunks(BINNING_CHUNK_SIZE)
+            .map(|chunk| bin_primitives(chunk, parent.min, step))
+            .reduce(|| [Bin::default(); N_BINS * 3], |mut a, b| {
+                a.iter_mut().zip(&b).for_each(|(a, b)| a.include_bin(b));
+                a
+            })
+    } else {
+        bin_primitives(range, parent.min, step)
+    };
 
     let mut best_cost = parent.cost();
     let mut result = None;
@@ -295,6 +489,20 @@ fn approximate_best_split(primitives: &[impl BVHPrimitive], parent: &BVHNode) ->
     result
 }
 
+fn bin_primitives(primitives: &[impl BVHPrimitive], min: Vec3, step: Vec3) -> [Bin; N_BINS * 3] {
+    let mut bins = [Bin::default(); N_BINS * 3];
+
+    for primitive in primitives {
+        let bin_indices = Vec3::floor((primitive.center() - min) / step).as_uvec3().min(UVec3::splat(N_BINS as u32 - 1));
+
+        bins[bin_indices.x as usize].include(primitive);
+        bins[N_BINS + bin_indices.y as usize].include(primitive);
+        bins[N_BINS * 2 + bin_indices.z as usize].include(primitive);
+    }
+
+    bins
+}
+
 #[allow(dead_code)]
 fn longest_split(parent: &BVHNode) -> Split {
     let extent = parent.max - parent.min;
@@ -327,4 +535,55 @@ fn split(primitives: &mut[impl BVHPrimitive], parent: &BVHNode, split: Split) ->
     let left = BVHNode::from_bin(&left, parent.start);
     let right = BVHNode::from_bin(&right, parent.start + left.count());
     Some((left, right))
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_primitives(count: u32) -> Vec<Triangle> {
+        random_triangles(count, Vec3::ZERO, Vec3::new(100.0, 10.0, 1.0), 0.01).1
+    }
+
+    fn ids(primitives: &[Triangle]) -> Vec<u32> {
+        primitives.iter().map(|t| t.indices[0]).collect()
+    }
+
+    #[test]
+    fn parallel_build_matches_serial_build() {
+        let count = 8 * PARALLEL_BUILD_THRESHOLD + 123;
+        let mut serial_primitives = random_primitives(count);
+        let root = BVHNode::new_leaf(&serial_primitives, 0..count);
+        let serial_nodes = build_serial(&mut serial_primitives, root, 0);
+
+        // Note: A dedicated pool makes sure that subtrees are built on several threads even on machines with a single core
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        for _ in 0..3 {
+            let mut primitives = random_primitives(count);
+            let tree = pool.install(|| build_bvh(&mut primitives, 0..count));
+            assert!(bytemuck::cast_slice::<_, u8>(tree.nodes()) == bytemuck::cast_slice::<_, u8>(&serial_nodes), "Nodes differ from the serial build");
+            assert!(ids(&primitives) == ids(&serial_primitives), "Primitive order differs from the serial build");
+        }
+    }
+
+    #[test]
+    fn appended_parallel_build_matches_serial_build() {
+        // Appending behind an existing tree offsets the node and primitive indices
+        let (offset, count) = (1000, 4 * PARALLEL_BUILD_THRESHOLD);
+        let mut serial_primitives = random_primitives(offset + count);
+        let root = BVHNode::new_leaf(&serial_primitives[offset as usize..], 0..count);
+        let serial_nodes = build_serial(&mut serial_primitives[offset as usize..], root, 0);
+
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let mut primitives = random_primitives(offset + count);
+        let mut tree = BVHTree::default();
+        let (first_root, second_root) = pool.install(|| {
+            (tree.append(&mut primitives, 0..offset), tree.append(&mut primitives, offset..offset + count))
+        });
+        assert_eq!(first_root, 0);
+        // Note: Local node indices of the serial build are relative to the root
+        let expected: Vec<_> = serial_nodes.iter().map(|node| node.rebase(second_root, offset)).collect();
+        assert!(bytemuck::cast_slice::<_, u8>(&tree.nodes()[second_root as usize..]) == bytemuck::cast_slice::<_, u8>(&expected));
+        assert!(ids(&primitives[offset as usize..]) == ids(&serial_primitives[offset as usize..]));
+    }
+}
@@ -0,0 +1,272 @@
+use glam::Vec3;
+
+use super::{approximate_best_split, find_best_split, split, BVHNode, BVHPrimitive, BVHTree, Bin, Triangle, MAX_DEPTH};
+use crate::pathtracing::scene::Vertex;
+
+const N_SPATIAL_BINS: usize = 32;
+
+/// Part of a triangle, the bounds are clipped when the triangle straddles a spatial split
+#[derive(Clone, Copy)]
+struct Reference {
+    triangle: u32,
+    min: Vec3,
+    max: Vec3,
+}
+
+impl BVHPrimitive for Reference {
+    fn min(&self) -> Vec3 {self.min}
+    fn max(&self) -> Vec3 {self.max}
+}
+
+impl Reference {
+    /// Clips the triangle to the slab between `lo` and `hi` along `axis`, returns None if nothing remains
+    fn clip(&self, corners: &[Vec3; 3], axis: usize, lo: f32, hi: f32) -> Option<Self> {
+        let mut min = Vec3::INFINITY;
+        let mut max = Vec3::NEG_INFINITY;
+        for i in 0..3 {
+            let (a, b) = (corners[i], corners[(i + 1) % 3]);
+            if (lo..=hi).contains(&a[axis]) {
+                min = min.min(a);
+                max = max.max(a);
+            }
+            // Add the intersections of the edge with both planes
+            for plane in [lo, hi] {
+                if (a[axis] - plane) * (b[axis] - plane) < 0.0 {
+                    let mut p = a.lerp(b, (plane - a[axis]) / (b[axis] - a[axis]));
+                    p[axis] = plane;
+                    min = min.min(p);
+                    max = max.max(p);
+                }
+            }
+        }
+        // Note: Earlier splits may have clipped the bounds already
+        let (min, max) = (min.max(self.min), max.min(self.max));
+        min.cmple(max).all().then_some(Self { triangle: self.triangle, min, max })
+    }
+}
+
+struct SpatialSplit {
+    axis: usize,
+    position: f32,
+    cost: f32,
+}
+
+impl BVHTree {
+    /// Builds a BVH with spatial splits after "Spatial Splits in Bounding Volume Hierarchies" by Stich et al. 2009.
+    /// Leaves reference the triangles appended to `references`, triangles straddling a spatial split are referenced multiple times.
+    /// Returns the index of the root node.
+    pub fn append_spatial(&mut self, triangles: &[Triangle], vertices: &[Vertex], overlap_threshold: f32, references: &mut Vec<Triangle>) -> u32 {
+        let timer = std::time::Instant::now();
+        let first_reference = references.len();
+
+        let corners: Vec<_> = triangles.iter().map(|t| t.indices.map(|i| vertices[i as usize].position)).collect();
+        let refs: Vec<_> = triangles.iter().enumerate()
+            .map(|(i, t)| Reference { triangle: i as u32, min: t.min, max: t.max })
+            .collect();
+
+        let root_index = self.nodes.len() as u32;
+        let root = BVHNode::new_leaf(&refs, 0..refs.len() as u32);
+        let min_overlap = overlap_threshold * root.area();
+        self.nodes.push(root);
+        let mut stack = vec![(0u32, root_index, refs)];
+
+        while let Some((depth, node_index, mut refs)) = stack.pop() {
+            let split = if depth < MAX_DEPTH {
+                split_references(&mut refs, &self.nodes[node_index as usize], &corners, min_overlap)
+            } else {
+                None
+            };
+            if let Some((left, right, right_refs)) = split {
+                let left_index = self.nodes.len() as u32;
+                let right_index = left_index + 1;
+                self.nodes[node_index as usize].make_inner(left_index);
+                self.nodes.push(left);
+                self.nodes.push(right);
+                stack.push((depth + 1, left_index, refs));
+                stack.push((depth + 1, right_index, right_refs));
+            } else {
+                let node = &mut self.nodes[node_index as usize];
+                node.start = references.len() as u32;
+                references.extend(refs.iter().map(|r| triangles[r.triangle as usize]));
+                node.end = references.len() as u32;
+            }
+        }
+
+        log::info!("Built SBVH with {} references to {} triangles in {:?}", references.len() - first_reference, triangles.len(), timer.elapsed());
+        root_index
+    }
+}
+
+/// Splits the references of a node using the best object or spatial split.
+/// Returns the children and the references of the right child while `refs` keeps those of the left child.
+fn split_references(refs: &mut Vec<Reference>, node: &BVHNode, corners: &[[Vec3; 3]], min_overlap: f32) -> Option<(BVHNode, BVHNode, Vec<Reference>)> {
+    let parent = BVHNode { start: 0, end: refs.len() as u32, ..*node };
+    if parent.count() < 2 {
+        return None;
+    }
+
+    let object_split = match parent.count() {
+        2..=11 => find_best_split(refs, &parent),
+        _ => approximate_best_split(refs, &parent),
+    };
+    let (object_cost, overlap) = match &object_split {
+        Some(s) => {
+            let mut left = Bin::default();
+            let mut right = Bin::default();
+            for r in refs.iter() {
+                if r.center()[s.axis] < s.mid { left.include(r) } else { right.include(r) }
+            }
+            let overlap = Bin { min: left.min.max(right.min), max: left.max.min(right.max), count: 1 };
+            let overlap_area = if overlap.min.cmple(overlap.max).all() { overlap.cost() } else { 0.0 };
+            (left.cost() + right.cost(), overlap_area)
+        }
+        None => (f32::INFINITY, f32::INFINITY),
+    };
+
+    // Spatial splits only pay off where the children of the object split overlap
+    if overlap > min_overlap {
+        if let Some(s) = find_spatial_split(refs, &parent, corners) {
+            if s.cost < object_cost && s.cost < parent.cost() {
+                if let Some(result) = spatial_split(refs, corners, &s) {
+                    return Some(result);
+                }
+            }
+        }
+    }
+
+    let (left, right) = split(refs, &parent, object_split?)?;
+    let right_refs = refs.split_off(left.count() as usize);
+    Some((left, right, right_refs))
+}
+
+/// Bins the clipped references into N_SPATIAL_BINS slabs per axis and finds the split plane with the lowest SAH cost
+fn find_spatial_split(refs: &[Reference], parent: &BVHNode, corners: &[[Vec3; 3]]) -> Option<SpatialSplit> {
+    let mut result: Option<SpatialSplit> = None;
+
+    for axis in 0..3 {
+        let lo = parent.min[axis];
+        let step = (parent.max[axis] - lo) / N_SPATIAL_BINS as f32;
+        if step <= 0.0 {
+            continue;
+        }
+        let plane = |i: usize| if i == N_SPATIAL_BINS { parent.max[axis] } else { lo + step * i as f32 };
+        let bin_index = |x: f32| (((x - lo) / step).floor().max(0.0) as usize).min(N_SPATIAL_BINS - 1);
+
+        // Bins only track the clipped bounds, references are counted where they enter and exit
+        let mut bins = [Bin::default(); N_SPATIAL_BINS];
+        let mut entries = [0u32; N_SPATIAL_BINS];
+        let mut exits = [0u32; N_SPATIAL_BINS];
+        for r in refs {
+            let (first, last) = (bin_index(r.min[axis]), bin_index(r.max[axis]));
+            entries[first] += 1;
+            exits[last] += 1;
+            for (i, bin) in bins.iter_mut().enumerate().take(last + 1).skip(first) {
+                if let Some(clipped) = r.clip(&corners[r.triangle as usize], axis, plane(i), plane(i + 1)) {
+                    bin.min = bin.min.min(clipped.min);
+                    bin.max = bin.max.max(clipped.max);
+                }
+            }
+        }
+
+        let mut right_bins = [Bin::default(); N_SPATIAL_BINS];
+        let mut right = Bin::default();
+        for i in (1..N_SPATIAL_BINS).rev() {
+            right.include_bin(&bins[i]);
+            right.count += exits[i];
+            right_bins[i] = right;
+        }
+
+        let mut left = Bin::default();
+        for i in 1..N_SPATIAL_BINS {
+            left.include_bin(&bins[i - 1]);
+            left.count += entries[i - 1];
+            let right = &right_bins[i];
+            if left.count == 0 || right.count == 0 {
+                continue;
+            }
+            let cost = left.cost() + right.cost();
+            if cost < result.as_ref().map_or(f32::INFINITY, |s| s.cost) {
+                result = Some(SpatialSplit { axis, position: plane(i), cost });
+            }
+        }
+    }
+
+    result
+}
+
+/// Distributes the references to both sides of the plane, references straddling it are clipped and end up on both sides
+fn spatial_split(refs: &mut Vec<Reference>, corners: &[[Vec3; 3]], s: &SpatialSplit) -> Option<(BVHNode, BVHNode, Vec<Reference>)> {
+    let mut left_refs = Vec::with_capacity(refs.len());
+    let mut right_refs = Vec::with_capacity(refs.len());
+    for r in refs.iter() {
+        if r.max[s.axis] <= s.position {
+            left_refs.push(*r);
+        } else if r.min[s.axis] >= s.position {
+            right_refs.push(*r);
+        } else {
+            let triangle = &corners[r.triangle as usize];
+            let left = r.clip(triangle, s.axis, r.min[s.axis], s.position);
+            let right = r.clip(triangle, s.axis, s.position, r.max[s.axis]);
+            if left.is_none() && right.is_none() {
+                // Note: Rounding can clip a reference away on both sides, keep it unclipped on the side of its center
+                if r.center()[s.axis] <= s.position { left_refs.push(*r) } else { right_refs.push(*r) }
+            }
+            left_refs.extend(left);
+            right_refs.extend(right);
+        }
+    }
+
+    if left_refs.is_empty() || right_refs.is_empty() {
+        log::debug!("Failed to split node spatially");
+        return None;
+    }
+
+    let left = BVHNode::new_leaf(&left_refs, 0..left_refs.len() as u32);
+    let right = BVHNode::new_leaf(&right_refs, 0..right_refs.len() as u32);
+    *refs = left_refs;
+    Some((left, right, right_refs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pathtracing::bvh::{build_triangle_cache, flatten_triangle_list, random_triangles, BVHBuilder};
+
+    #[test]
+    fn references_clipped_away_on_both_sides_are_kept() {
+        // The bounds of the first reference miss its triangle in y, as if rounding had clipped it away
+        let corners = [
+            [Vec3::ZERO, Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)],
+            [Vec3::ZERO, Vec3::new(0.25, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)],
+        ];
+        let mut refs = vec![
+            Reference { triangle: 0, min: Vec3::new(0.0, 2.0, 0.0), max: Vec3::new(2.0, 3.0, 0.0) },
+            Reference { triangle: 1, min: Vec3::ZERO, max: Vec3::new(0.25, 1.0, 0.0) },
+        ];
+        let (_, _, right_refs) = spatial_split(&mut refs, &corners, &SpatialSplit { axis: 0, position: 0.5, cost: 0.0 })
+            .expect("The lost reference must end up on the right");
+        assert_eq!(refs.iter().map(|r| r.triangle).collect::<Vec<_>>(), [1]);
+        assert_eq!(right_refs.iter().map(|r| r.triangle).collect::<Vec<_>>(), [0]);
+    }
+
+    #[test]
+    fn thin_overlapping_triangles_are_covered() {
+        // Long slivers through the unit cube, which overlap a lot and are clipped by many spatial splits
+        let (mut vertices, _) = random_triangles(1000, Vec3::ZERO, Vec3::ONE, 2.0);
+        for corners in vertices.chunks_exact_mut(3) {
+            corners[2].position = corners[0].position.lerp(corners[2].position, 1e-3);
+        }
+        let indices: Vec<_> = (0..vertices.len() as u32).collect();
+        let triangles = build_triangle_cache(&vertices, &indices);
+
+        let mut tree = BVHTree::default();
+        let mut references = Vec::new();
+        let root = tree.append_spatial(&triangles, &vertices, BVHBuilder::DEFAULT_OVERLAP_THRESHOLD, &mut references);
+        assert!(references.len() > triangles.len(), "No triangle was split");
+        tree.validate(root, 0..references.len() as u32).unwrap();
+
+        let mut reference_indices = vec![0; references.len() * 3];
+        flatten_triangle_list(&references, &mut reference_indices);
+        tree.validate_triangles(root, &reference_indices, &indices).unwrap();
+    }
+}
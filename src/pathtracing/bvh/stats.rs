@@ -0,0 +1,358 @@
+use std::collections::HashSet;
+use std::ops::Range;
+
+use glam::Vec3;
+
+use super::{BVHTree, MAX_DEPTH};
+
+/// Quality statistics of the tree below a root node, see `BVHTree::stats`
+#[derive(Clone, Debug, Default)]
+pub struct BVHStats {
+    pub node_count: u32,
+    pub leaf_count: u32,
+    /// Expected cost of tracing a random ray, see `BVHTree::sah_cost`
+    pub sah_cost: f32,
+    /// Surface area of the root, which the SAH cost is relative to
+    pub root_area: f32,
+    /// Number of leaves at each depth, the root is at depth 0
+    pub leaf_depths: Vec<u32>,
+    /// Number of leaves with each primitive count
+    pub leaf_sizes: Vec<u32>,
+    /// Leaves without primitives and nodes with inverted bounds
+    pub empty_nodes: u32,
+    /// Nodes whose bounds have no surface area or are not finite
+    pub degenerate_nodes: u32,
+    /// Leaves with more than one primitive at `MAX_DEPTH`, where the builders stop splitting regardless of the cost
+    pub truncated_leaves: u32,
+}
+
+impl BVHStats {
+    /// Whether `MAX_DEPTH` may have kept the builder from splitting a leaf
+    pub fn is_truncated(&self) -> bool {
+        self.truncated_leaves > 0
+    }
+
+    /// Adds the statistics of another tree, e.g. to summarize all BLAS of a scene.
+    /// The SAH costs are averaged weighted by the root areas, as a random ray hits each root proportionally to its area
+    pub fn merge(&mut self, other: &Self) {
+        self.node_count += other.node_count;
+        self.leaf_count += other.leaf_count;
+        let root_area = self.root_area + other.root_area;
+        if root_area > 0.0 {
+            self.sah_cost = (self.sah_cost * self.root_area + other.sah_cost * other.root_area) / root_area;
+        }
+        self.root_area = root_area;
+        merge_histogram(&mut self.leaf_depths, &other.leaf_depths);
+        merge_histogram(&mut self.leaf_sizes, &other.leaf_sizes);
+        self.empty_nodes += other.empty_nodes;
+        self.degenerate_nodes += other.degenerate_nodes;
+        self.truncated_leaves += other.truncated_leaves;
+    }
+}
+
+impl std::fmt::Display for BVHStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{} nodes, {} leaves, SAH cost {:.2}, {} empty and {} degenerate nodes",
+            self.node_count, self.leaf_count, self.sah_cost, self.empty_nodes, self.degenerate_nodes)?;
+        writeln!(f, "Leaf depths: {}", format_histogram(&self.leaf_depths))?;
+        write!(f, "Leaf sizes: {}", format_histogram(&self.leaf_sizes))?;
+        if self.is_truncated() {
+            write!(f, "\n{} leaves were truncated at depth {}", self.truncated_leaves, MAX_DEPTH)?;
+        }
+        Ok(())
+    }
+}
+
+fn add_to_histogram(histogram: &mut Vec<u32>, value: u32) {
+    let value = value as usize;
+    if histogram.len() <= value {
+        histogram.resize(value + 1, 0);
+    }
+    histogram[value] += 1;
+}
+
+fn merge_histogram(histogram: &mut Vec<u32>, other: &[u32]) {
+    if histogram.len() < other.len() {
+        histogram.resize(other.len(), 0);
+    }
+    histogram.iter_mut().zip(other).for_each(|(a, b)| *a += b);
+}
+
+/// Lists the non-empty buckets as "value: count"
+fn format_histogram(histogram: &[u32]) -> String {
+    histogram.iter().enumerate()
+        .filter(|(_, &count)| count > 0)
+        .map(|(value, count)| format!("{}: {}", value, count))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[derive(Debug)]
+pub enum ValidationError {
+    /// The child of an inner node is out of bounds or not stored after the node
+    InvalidChild { node: u32, child: u32 },
+    ChildNotContained { node: u32, child: u32 },
+    /// The primitive range of a leaf is empty or outside the range of the tree
+    InvalidLeafRange { node: u32, range: Range<u32> },
+    PrimitiveNotCovered(u32),
+    PrimitiveCoveredTwice(u32),
+    /// A triangle, given by its vertex indices, is not referenced by any leaf
+    TriangleNotReferenced([u32; 3]),
+    /// A leaf references a triangle that does not belong to the tree
+    UnknownTriangle([u32; 3]),
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ValidationError::InvalidChild { node, child } => write!(f, "Node {} has an invalid child {}", node, child),
+            ValidationError::ChildNotContained { node, child } => write!(f, "Child {} is not contained in its parent {}", child, node),
+            ValidationError::InvalidLeafRange { node, range } => write!(f, "Leaf {} has an invalid primitive range {:?}", node, range),
+            ValidationError::PrimitiveNotCovered(primitive) => write!(f, "Primitive {} is not covered by any leaf", primitive),
+            ValidationError::PrimitiveCoveredTwice(primitive) => write!(f, "Primitive {} is covered by more than one leaf", primitive),
+            ValidationError::TriangleNotReferenced(triangle) => write!(f, "Triangle {:?} is not referenced by any leaf", triangle),
+            ValidationError::UnknownTriangle(triangle) => write!(f, "Triangle {:?} is referenced but does not belong to the tree", triangle),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl BVHTree {
+    /// Collects quality statistics of the tree below `root`.
+    /// Note: Assumes a valid topology, see `validate`
+    pub fn stats(&self, root: u32) -> BVHStats {
+        let Some(root_node) = self.nodes.get(root as usize) else {
+            return BVHStats::default(); // Empty tree
+        };
+        let root_area = root_node.area();
+        let mut stats = BVHStats { root_area, ..Default::default() };
+        let mut stack = vec![(root, 0)];
+        while let Some((index, depth)) = stack.pop() {
+            let node = &self.nodes[index as usize];
+            stats.node_count += 1;
+            let extent = node.max - node.min;
+            let is_inverted = extent.cmplt(Vec3::ZERO).any();
+            if !is_inverted && (!extent.is_finite() || node.area() == 0.0) {
+                stats.degenerate_nodes += 1;
+            }
+
+            if node.is_leaf() {
+                // Note: Does not use `count` as it asserts non-empty leaves
+                let count = node.end.saturating_sub(node.start);
+                stats.leaf_count += 1;
+                stats.sah_cost += count as f32 * node.area() / root_area;
+                add_to_histogram(&mut stats.leaf_depths, depth);
+                add_to_histogram(&mut stats.leaf_sizes, count);
+                if is_inverted || count == 0 {
+                    stats.empty_nodes += 1;
+                }
+                if depth >= MAX_DEPTH && count > 1 {
+                    stats.truncated_leaves += 1;
+                }
+            } else {
+                stats.sah_cost += node.area() / root_area;
+                if is_inverted {
+                    stats.empty_nodes += 1;
+                }
+                stack.extend([(node.start, depth + 1), (node.start + 1, depth + 1)]);
+            }
+        }
+        stats
+    }
+
+    /// Checks that the children of each node below `root` are contained in their parent
+    /// and that the leaves cover each primitive in `primitives` exactly once.
+    /// Note: With spatial splits, the primitives are the triangle references
+    pub fn validate(&self, root: u32, primitives: Range<u32>) -> Result<(), ValidationError> {
+        if root as usize >= self.nodes.len() {
+            // Note: An empty tree covers no primitives
+            return if primitives.is_empty() { Ok(()) } else { Err(ValidationError::PrimitiveNotCovered(primitives.start)) };
+        }
+        let mut covered = vec![false; primitives.len()];
+        let mut stack = vec![root];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index as usize];
+            if node.is_leaf() {
+                if node.start >= node.end || node.start < primitives.start || node.end > primitives.end {
+                    return Err(ValidationError::InvalidLeafRange { node: index, range: node.start..node.end });
+                }
+                for primitive in node.start..node.end {
+                    let covered = &mut covered[(primitive - primitives.start) as usize];
+                    if *covered {
+                        return Err(ValidationError::PrimitiveCoveredTwice(primitive));
+                    }
+                    *covered = true;
+                }
+            } else {
+                for child in [node.start, node.start + 1] {
+                    // Note: Children are always stored after their parent, which also rules out cycles
+                    if child <= index || child as usize >= self.nodes.len() {
+                        return Err(ValidationError::InvalidChild { node: index, child });
+                    }
+                    let child_node = &self.nodes[child as usize];
+                    if child_node.min.cmplt(node.min).any() || child_node.max.cmpgt(node.max).any() {
+                        return Err(ValidationError::ChildNotContained { node: index, child });
+                    }
+                    stack.push(child);
+                }
+            }
+        }
+
+        match covered.iter().position(|&covered| !covered) {
+            Some(i) => Err(ValidationError::PrimitiveNotCovered(primitives.start + i as u32)),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that the leaves below `root` reference each triangle of `triangles` and no other triangle,
+    /// where `indices` are the vertex indices of the triangles in the order of the leaves.
+    /// Triangles are identified by their vertex indices, as spatial splits reference them from multiple leaves.
+    /// Note: Assumes a valid topology, see `validate`
+    pub fn validate_triangles(&self, root: u32, indices: &[u32], triangles: &[u32]) -> Result<(), ValidationError> {
+        let expected: HashSet<_> = triangles.chunks_exact(3).map(|t| [t[0], t[1], t[2]]).collect();
+        let mut referenced = HashSet::with_capacity(expected.len());
+        let mut stack = if (root as usize) < self.nodes.len() { vec![root] } else { Vec::new() };
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index as usize];
+            if node.is_leaf() {
+                for t in indices[node.start as usize * 3..node.end as usize * 3].chunks_exact(3) {
+                    let triangle = [t[0], t[1], t[2]];
+                    if !expected.contains(&triangle) {
+                        return Err(ValidationError::UnknownTriangle(triangle));
+                    }
+                    referenced.insert(triangle);
+                }
+            } else {
+                stack.extend([node.start, node.start + 1]);
+            }
+        }
+
+        match triangles.chunks_exact(3).map(|t| [t[0], t[1], t[2]]).find(|t| !referenced.contains(t)) {
+            Some(triangle) => Err(ValidationError::TriangleNotReferenced(triangle)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pathtracing::bvh::{self, BVHBuilder, BVHNode, Triangle};
+    use crate::pathtracing::scene::Vertex;
+
+    fn random_triangles(count: u32) -> (Vec<Vertex>, Vec<Triangle>) {
+        bvh::random_triangles(count, Vec3::ZERO, Vec3::ONE, 0.3)
+    }
+
+    fn assert_consistent(tree: &BVHTree, root: u32, primitive_count: u32) {
+        tree.validate(root, 0..primitive_count).unwrap();
+        let stats = tree.stats(root);
+        assert_eq!(stats.node_count, 2 * stats.leaf_count - 1);
+        assert_eq!(stats.node_count as usize, tree.nodes().len() - root as usize);
+        assert_eq!(stats.leaf_depths.iter().sum::<u32>(), stats.leaf_count);
+        let covered: u32 = stats.leaf_sizes.iter().enumerate().map(|(size, &count)| size as u32 * count).sum();
+        assert_eq!(covered, primitive_count);
+        assert_eq!(stats.empty_nodes, 0);
+        assert!((stats.sah_cost - tree.sah_cost(root)).abs() <= 1e-4 * stats.sah_cost);
+    }
+
+    #[test]
+    fn object_split_builds_are_valid() {
+        for count in [1, 2, 3, 100, 1000] {
+            let (_, mut triangles) = random_triangles(count);
+            assert_consistent(&bvh::build_bvh(&mut triangles, 0..count), 0, count);
+            for builder in [BVHBuilder::Sah, BVHBuilder::Linear] {
+                assert_consistent(&bvh::build_bvh_with(builder, &mut triangles, 0..count), 0, count);
+            }
+        }
+    }
+
+    #[test]
+    fn spatial_split_builds_are_valid() {
+        for count in [1, 2, 3, 100, 1000] {
+            let (vertices, triangles) = random_triangles(count);
+            let mut tree = BVHTree::default();
+            let mut references = Vec::new();
+            let root = tree.append_spatial(&triangles, &vertices, BVHBuilder::DEFAULT_OVERLAP_THRESHOLD, &mut references);
+            assert!(references.len() >= triangles.len());
+            assert_consistent(&tree, root, references.len() as u32);
+        }
+    }
+
+    #[test]
+    fn spatial_splits_reference_every_triangle() {
+        let (vertices, triangles) = random_triangles(1000);
+        let mut tree = BVHTree::default();
+        let mut references = Vec::new();
+        let root = tree.append_spatial(&triangles, &vertices, BVHBuilder::DEFAULT_OVERLAP_THRESHOLD, &mut references);
+        let mut indices = vec![0; references.len() * 3];
+        bvh::flatten_triangle_list(&references, &mut indices);
+        let triangle_indices: Vec<_> = (0..3000).collect();
+        assert!(tree.validate_triangles(root, &indices, &triangle_indices).is_ok());
+
+        assert!(matches!(tree.validate_triangles(root, &indices, &triangle_indices[3..]),
+            Err(ValidationError::UnknownTriangle([0, 1, 2]))));
+        let mut missing = triangle_indices.clone();
+        missing.extend([0, 2, 1]);
+        assert!(matches!(tree.validate_triangles(root, &indices, &missing),
+            Err(ValidationError::TriangleNotReferenced([0, 2, 1]))));
+    }
+
+    #[test]
+    fn merged_sah_costs_are_weighted_by_the_root_areas() {
+        let small = BVHStats { sah_cost: 4.0, root_area: 1.0, ..Default::default() };
+        let large = BVHStats { sah_cost: 1.0, root_area: 3.0, ..Default::default() };
+        let mut merged = BVHStats::default();
+        merged.merge(&small);
+        assert_eq!(merged.sah_cost, 4.0);
+        merged.merge(&large);
+        assert_eq!(merged.sah_cost, 1.75);
+        assert_eq!(merged.root_area, 4.0);
+    }
+
+    fn leaf(range: Range<u32>) -> BVHNode {
+        BVHNode { min: Vec3::ZERO, start: range.start, max: Vec3::ONE, end: range.end }
+    }
+
+    fn inner(left_child: u32) -> BVHNode {
+        BVHNode { min: Vec3::ZERO, start: left_child, max: Vec3::ONE, end: 0 }
+    }
+
+    fn validate(nodes: Vec<BVHNode>, primitives: Range<u32>) -> Result<(), ValidationError> {
+        BVHTree::from_nodes(nodes).validate(0, primitives)
+    }
+
+    #[test]
+    fn corrupted_trees_are_rejected() {
+        assert!(validate(vec![inner(1), leaf(0..1), leaf(1..2)], 0..2).is_ok());
+
+        assert!(matches!(validate(vec![inner(0), leaf(0..1), leaf(1..2)], 0..2),
+            Err(ValidationError::InvalidChild { node: 0, child: 0 })));
+        assert!(matches!(validate(vec![inner(2), leaf(0..1), leaf(1..2)], 0..2),
+            Err(ValidationError::InvalidChild { node: 0, child: 3 })));
+
+        let mut outside = leaf(1..2);
+        outside.max.y = 2.0;
+        assert!(matches!(validate(vec![inner(1), leaf(0..1), outside], 0..2),
+            Err(ValidationError::ChildNotContained { node: 0, child: 2 })));
+
+        assert!(matches!(validate(vec![inner(1), leaf(0..1), BVHNode { start: 1, ..leaf(0..1) }], 0..2),
+            Err(ValidationError::InvalidLeafRange { node: 2, .. })));
+        assert!(matches!(validate(vec![inner(1), leaf(0..1), leaf(1..3)], 0..2),
+            Err(ValidationError::InvalidLeafRange { node: 2, .. })));
+
+        assert!(matches!(validate(vec![inner(1), leaf(0..1), leaf(1..2)], 0..3),
+            Err(ValidationError::PrimitiveNotCovered(2))));
+        assert!(matches!(validate(vec![inner(1), leaf(0..2), leaf(1..2)], 0..2),
+            Err(ValidationError::PrimitiveCoveredTwice(1))));
+    }
+
+    #[test]
+    fn empty_trees_cover_no_primitives() {
+        let tree = BVHTree::default();
+        assert!(tree.validate(0, 0..0).is_ok());
+        assert!(matches!(tree.validate(0, 0..1), Err(ValidationError::PrimitiveNotCovered(0))));
+        assert_eq!(tree.stats(0).node_count, 0);
+    }
+}
@@ -0,0 +1,501 @@
+use std::ops::Range;
+
+use glam::Vec3;
+
+use super::{BVHNode, BVHTree};
+
+/// Maximum number of children of a wide node, needs to match BLAS_WIDTH in blas_wide.wgsl
+pub const MAX_WIDTH: usize = 8;
+/// Number of stack entries available to the traversal, needs to match BLAS_STACK_SIZE in blas_wide.wgsl
+pub const STACK_SIZE: u32 = 64;
+/// Leaves store their triangle count in a byte, larger leaves are split
+const MAX_LEAF_SIZE: u32 = u8::MAX as u32;
+
+/// Node of a wide BVH with up to eight children, whose bounds are quantized to 8 bits per axis relative to the node
+/// after "Efficient Incoherent Ray Traversal on GPUs Through Compressed Wide BVHs" by Ylitie et al. 2017.
+/// Note: Needs to match WideNode in blas_wide.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct WideNode {
+    /// Minimum corner of the quantization grid
+    pub origin: Vec3,
+    /// Biased exponents of the grid spacing per axis in the lower three bytes, the mask of inner children in the upper byte
+    pub exponents_imask: u32,
+    /// Index of the first inner child, the inner children are stored next to each other
+    pub child_base: u32,
+    /// Index of the first triangle, the triangles of the leaf children are stored next to each other
+    pub triangle_base: u32,
+    /// Number of triangles of each leaf child, zero for inner children and empty slots
+    pub counts: [u8; MAX_WIDTH],
+    pub lo_x: [u8; MAX_WIDTH],
+    pub lo_y: [u8; MAX_WIDTH],
+    pub lo_z: [u8; MAX_WIDTH],
+    pub hi_x: [u8; MAX_WIDTH],
+    pub hi_y: [u8; MAX_WIDTH],
+    pub hi_z: [u8; MAX_WIDTH],
+}
+
+impl WideNode {
+    pub fn inner_mask(&self) -> u32 {
+        self.exponents_imask >> 24
+    }
+
+    pub fn is_inner(&self, slot: usize) -> bool {
+        self.inner_mask() & (1 << slot) != 0
+    }
+
+    pub fn is_empty(&self, slot: usize) -> bool {
+        self.counts[slot] == 0 && !self.is_inner(slot)
+    }
+
+    /// Index of the inner child in `slot`
+    pub fn child(&self, slot: usize) -> u32 {
+        self.child_base + (self.inner_mask() & ((1 << slot) - 1)).count_ones()
+    }
+
+    /// Spacing of the quantization grid
+    pub fn scale(&self) -> Vec3 {
+        let exponent = |axis: u32| f32::from_bits(((self.exponents_imask >> (axis * 8)) & 0xff) << 23);
+        Vec3::new(exponent(0), exponent(1), exponent(2))
+    }
+
+    /// Dequantized bounds of the child in `slot`, which contain the exact bounds
+    pub fn child_bounds(&self, slot: usize) -> (Vec3, Vec3) {
+        let lo = Vec3::new(self.lo_x[slot] as f32, self.lo_y[slot] as f32, self.lo_z[slot] as f32);
+        let hi = Vec3::new(self.hi_x[slot] as f32, self.hi_y[slot] as f32, self.hi_z[slot] as f32);
+        (self.origin + lo * self.scale(), self.origin + hi * self.scale())
+    }
+}
+
+/// Part of the binary BVH that becomes a child of a wide node
+enum Child {
+    /// Inner node of the binary BVH
+    Inner(u32),
+    /// Triangles of a binary leaf or a part of it
+    Leaf(Range<u32>),
+}
+
+struct Item {
+    child: Child,
+    min: Vec3,
+    max: Vec3,
+}
+
+impl Item {
+    fn new(tree: &BVHTree, index: u32) -> Self {
+        let node = &tree.nodes[index as usize];
+        let child = if node.is_leaf() { Child::Leaf(node.range()) } else { Child::Inner(index) };
+        Self { child, min: node.min, max: node.max }
+    }
+
+    /// Items that need a wide node of their own
+    fn is_inner(&self) -> bool {
+        match &self.child {
+            Child::Inner(_) => true,
+            Child::Leaf(range) => range.len() as u32 > MAX_LEAF_SIZE,
+        }
+    }
+
+    fn area(&self) -> f32 {
+        BVHNode { min: self.min, start: 0, max: self.max, end: 0 }.area()
+    }
+
+    /// Replaces the item by its children, leaves are split in half and keep their bounds
+    fn open(self, tree: &BVHTree) -> [Self; 2] {
+        match self.child {
+            Child::Inner(index) => {
+                let left = tree.nodes[index as usize].start;
+                [Self::new(tree, left), Self::new(tree, left + 1)]
+            }
+            Child::Leaf(range) => {
+                let mid = range.start + range.len() as u32 / 2;
+                [
+                    Self { child: Child::Leaf(range.start..mid), ..self },
+                    Self { child: Child::Leaf(mid..range.end), ..self },
+                ]
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct WideBVH {
+    nodes: Vec<WideNode>,
+    /// Number of stack entries needed to traverse the deepest tree
+    max_stack_size: u32,
+}
+
+impl WideBVH {
+    /// Collapses the binary BVH below `root` to wide nodes with at most `width` children and returns the index of the wide root.
+    /// Inner nodes with the largest surface area are opened first. The triangles of the binary leaves are reordered
+    /// within `indices` so that the triangles of all leaf children of a wide node are next to each other.
+    pub fn append(&mut self, tree: &BVHTree, root: u32, width: usize, indices: &mut [u32]) -> u32 {
+        debug_assert!((2..=MAX_WIDTH).contains(&width), "Invalid width: {}", width);
+
+        // Note: The triangles below a binary node are always next to each other
+        let triangles = subtree_range(tree, root);
+        let source = indices[triangles.start as usize * 3..triangles.end as usize * 3].to_vec();
+        let mut next_triangle = triangles.start;
+
+        let root_index = self.nodes.len() as u32;
+        self.nodes.push(WideNode::default());
+        let mut stack = vec![(root_index, Item::new(tree, root))];
+
+        while let Some((index, item)) = stack.pop() {
+            let mut items = vec![item];
+            while items.len() < width {
+                let Some((largest, _)) = items.iter().enumerate()
+                    .filter(|(_, item)| item.is_inner())
+                    .max_by(|(_, a), (_, b)| a.area().total_cmp(&b.area())) else { break };
+                let [left, right] = items.swap_remove(largest).open(tree);
+                items.extend([left, right]);
+            }
+
+            let mut node = quantize(&items);
+            node.child_base = self.nodes.len() as u32;
+            node.triangle_base = next_triangle;
+            let mut inner_mask = 0;
+            for (slot, item) in items.into_iter().enumerate() {
+                if item.is_inner() {
+                    inner_mask |= 1 << slot;
+                    stack.push((self.nodes.len() as u32, item));
+                    self.nodes.push(WideNode::default());
+                } else if let Child::Leaf(range) = item.child {
+                    node.counts[slot] = range.len() as u8;
+                    let source_range = (range.start - triangles.start) as usize * 3..(range.end - triangles.start) as usize * 3;
+                    let target = next_triangle as usize * 3;
+                    indices[target..target + source_range.len()].copy_from_slice(&source[source_range]);
+                    next_triangle += range.len() as u32;
+                }
+            }
+            node.exponents_imask |= inner_mask << 24;
+            self.nodes[index as usize] = node;
+        }
+        debug_assert_eq!(next_triangle, triangles.end);
+
+        self.max_stack_size = self.max_stack_size.max(self.stack_size(root_index));
+        root_index
+    }
+
+    pub fn nodes(&self) -> &[WideNode] {
+        &self.nodes
+    }
+
+    /// Restores a tree from the nodes of `nodes()` and its `max_stack_size()`
+    pub fn from_nodes(nodes: Vec<WideNode>, max_stack_size: u32) -> Self {
+        Self { nodes, max_stack_size }
+    }
+
+    /// Number of stack entries needed to traverse any of the trees, when all children are pushed at once
+    pub fn max_stack_size(&self) -> u32 {
+        self.max_stack_size
+    }
+
+    fn stack_size(&self, root: u32) -> u32 {
+        // Note: Children are always stored after their parent, so a reverse pass visits them first
+        let mut stack_sizes = vec![0; self.nodes.len() - root as usize];
+        for index in (root as usize..self.nodes.len()).rev() {
+            let node = &self.nodes[index];
+            let n_children = (0..MAX_WIDTH).filter(|&slot| !node.is_empty(slot)).count() as u32;
+            // A child is popped before it pushes its own children, while its siblings stay on the stack
+            stack_sizes[index - root as usize] = (0..MAX_WIDTH).filter(|&slot| node.is_inner(slot))
+                .map(|slot| n_children - 1 + stack_sizes[(node.child(slot) - root) as usize])
+                .fold(n_children, u32::max);
+        }
+        stack_sizes[0].max(1)
+    }
+}
+
+/// Range of the triangles referenced by the leaves below `root`
+fn subtree_range(tree: &BVHTree, root: u32) -> Range<u32> {
+    let (mut start, mut end) = (u32::MAX, 0);
+    let mut stack = vec![root];
+    while let Some(index) = stack.pop() {
+        let node = &tree.nodes[index as usize];
+        if node.is_leaf() {
+            start = start.min(node.start);
+            end = end.max(node.end);
+        } else {
+            stack.extend([node.start, node.start + 1]);
+        }
+    }
+    start..end
+}
+
+/// Creates a node with the quantized bounds of the items, the bounds of each item are rounded outwards
+fn quantize(items: &[Item]) -> WideNode {
+    let min = items.iter().fold(Vec3::INFINITY, |min, item| min.min(item.min));
+    let max = items.iter().fold(Vec3::NEG_INFINITY, |max, item| max.max(item.max));
+    let mut node = WideNode { origin: min, ..Default::default() };
+
+    for axis in 0..3 {
+        // Find the smallest power of two spacing that covers the extent in 255 steps after rounding
+        let extent = max[axis] - min[axis];
+        let mut exponent = if extent > 0.0 { (extent / 255.0).log2().ceil() as i32 } else { -126 };
+        exponent = exponent.clamp(-126, 127);
+        while exponent < 127 && min[axis] + 255.0 * 2f32.powi(exponent) < max[axis] {
+            exponent += 1;
+        }
+        node.exponents_imask |= ((exponent + 127) as u32) << (axis * 8);
+        let scale = 2f32.powi(exponent);
+        let decode = |q: u8| min[axis] + q as f32 * scale;
+
+        let (lo, hi) = match axis {
+            0 => (&mut node.lo_x, &mut node.hi_x),
+            1 => (&mut node.lo_y, &mut node.hi_y),
+            _ => (&mut node.lo_z, &mut node.hi_z),
+        };
+        for (slot, item) in items.iter().enumerate() {
+            // Note: The offsets are rounded, so the quantized bounds are corrected until they contain the item
+            let mut q_lo = ((item.min[axis] - min[axis]) / scale).floor().clamp(0.0, 255.0) as u8;
+            while q_lo > 0 && decode(q_lo) > item.min[axis] {
+                q_lo -= 1;
+            }
+            let mut q_hi = ((item.max[axis] - min[axis]) / scale).ceil().clamp(0.0, 255.0) as u8;
+            while q_hi < 255 && decode(q_hi) < item.max[axis] {
+                q_hi += 1;
+            }
+            lo[slot] = q_lo;
+            hi[slot] = q_hi;
+        }
+    }
+    node
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use glam::UVec4;
+    use itertools::iproduct;
+
+    use super::*;
+    use crate::pathtracing::bvh::{self, Triangle};
+    use crate::pathtracing::raytracing_cpu::{hash4f, CpuScene, Ray, NO_HIT};
+    use crate::pathtracing::scene::{BottomLevel, Scene};
+
+    fn random_triangles(count: u32, origin: Vec3, extent: Vec3) -> Vec<Triangle> {
+        bvh::random_triangles(count, origin, extent, 0.3).1
+    }
+
+    /// Unit cube, far from the origin, flat in z and all triangles at the same point, which forces leaves to be split
+    fn test_inputs() -> Vec<Vec<Triangle>> {
+        vec![
+            random_triangles(1000, Vec3::ZERO, Vec3::ONE),
+            random_triangles(1000, Vec3::splat(1e4), Vec3::splat(1e-2)),
+            random_triangles(1000, Vec3::new(-3.0, 2.0, 0.5), Vec3::new(5.0, 1.0, 0.0)),
+            random_triangles(600, Vec3::ONE, Vec3::ZERO),
+        ]
+    }
+
+    struct Collapsed {
+        tree: BVHTree,
+        /// Triangle indices in the order of the binary leaves
+        binary_indices: Vec<u32>,
+        wide: WideBVH,
+        /// Triangle indices in the order of the wide leaves
+        wide_indices: Vec<u32>,
+        /// Wide root and triangle range of each BLAS
+        roots: Vec<(u32, Range<u32>)>,
+    }
+
+    /// Builds a binary BLAS over each half of the triangles, like two meshes of a scene, and collapses both to `width`
+    fn collapse(mut triangles: Vec<Triangle>, width: usize) -> Collapsed {
+        let count = triangles.len() as u32;
+        let ranges = [0..count / 2, count / 2..count];
+        let mut tree = BVHTree::default();
+        let binary_roots: Vec<_> = ranges.iter().map(|range| tree.append(&mut triangles, range.clone())).collect();
+        let mut binary_indices = vec![0; triangles.len() * 3];
+        bvh::flatten_triangle_list(&triangles, &mut binary_indices);
+
+        let mut wide = WideBVH::default();
+        let mut wide_indices = binary_indices.clone();
+        let roots = binary_roots.into_iter().zip(ranges)
+            .map(|(root, range)| (wide.append(&tree, root, width, &mut wide_indices), range))
+            .collect();
+        Collapsed { tree, binary_indices, wide, wide_indices, roots }
+    }
+
+    /// Wide nodes below `root` including itself
+    fn subtree(wide: &WideBVH, root: u32) -> Vec<u32> {
+        let mut nodes = vec![root];
+        let mut i = 0;
+        while let Some(&index) = nodes.get(i) {
+            let node = &wide.nodes()[index as usize];
+            nodes.extend((0..MAX_WIDTH).filter(|&slot| node.is_inner(slot)).map(|slot| node.child(slot)));
+            i += 1;
+        }
+        nodes
+    }
+
+    /// Triangle ranges of the leaf child in `slot` or of all leaves below the inner child in `slot`
+    fn triangles_below(wide: &WideBVH, node: &WideNode, slot: usize) -> Vec<Range<u32>> {
+        if node.is_inner(slot) {
+            let child = &wide.nodes()[node.child(slot) as usize];
+            (0..MAX_WIDTH).flat_map(|slot| triangles_below(wide, child, slot)).collect()
+        } else if node.is_empty(slot) {
+            Vec::new()
+        } else {
+            let start = node.triangle_base + node.counts[..slot].iter().map(|&count| count as u32).sum::<u32>();
+            std::iter::once(start..start + node.counts[slot] as u32).collect()
+        }
+    }
+
+    fn sorted_triangles(indices: &[u32], range: Range<u32>) -> Vec<&[u32]> {
+        let mut triangles: Vec<_> = indices[range.start as usize * 3..range.end as usize * 3].chunks_exact(3).collect();
+        triangles.sort();
+        triangles
+    }
+
+    #[test]
+    fn leaves_cover_each_triangle_once() {
+        for width in [4, 8] {
+            for triangles in test_inputs() {
+                let collapsed = collapse(triangles, width);
+                for (root, range) in &collapsed.roots {
+                    let mut covered = vec![0; range.len()];
+                    let node = &collapsed.wide.nodes()[*root as usize];
+                    for leaf in (0..MAX_WIDTH).flat_map(|slot| triangles_below(&collapsed.wide, node, slot)) {
+                        assert!(leaf.start >= range.start && leaf.end <= range.end, "Leaf {:?} is outside of {:?}", leaf, range);
+                        for triangle in leaf {
+                            covered[(triangle - range.start) as usize] += 1;
+                        }
+                    }
+                    assert!(covered.iter().all(|&count| count == 1), "Width {}: Triangles are not covered exactly once", width);
+                    // The triangles are only reordered within the range of their BLAS
+                    assert_eq!(sorted_triangles(&collapsed.wide_indices, range.clone()), sorted_triangles(&collapsed.binary_indices, range.clone()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn quantized_bounds_contain_the_binary_nodes() {
+        for width in [4, 8] {
+            for triangles in test_inputs() {
+                let collapsed = collapse(triangles, width);
+                let nodes = collapsed.tree.nodes();
+                // Binary leaf of each triangle, identified by its first vertex index
+                let mut binary_leaf = HashMap::new();
+                for (index, node) in nodes.iter().enumerate().filter(|(_, node)| node.is_leaf()) {
+                    for triangle in node.start..node.end {
+                        binary_leaf.insert(collapsed.binary_indices[triangle as usize * 3], index);
+                    }
+                }
+
+                for (root, _) in &collapsed.roots {
+                    for index in subtree(&collapsed.wide, *root) {
+                        let node = &collapsed.wide.nodes()[index as usize];
+                        for slot in (0..MAX_WIDTH).filter(|&slot| !node.is_empty(slot)) {
+                            let leaves: Vec<_> = triangles_below(&collapsed.wide, node, slot).into_iter().flatten()
+                                .map(|triangle| binary_leaf[&collapsed.wide_indices[triangle as usize * 3]])
+                                .collect();
+                            if !node.is_inner(slot) {
+                                assert!(leaves.iter().all(|&leaf| leaf == leaves[0]), "Leaf child mixes triangles of several binary leaves");
+                            }
+                            // Note: The binary builders compute tight bounds, so the union of the leaves is the bounds of the binary subtree
+                            let min = leaves.iter().fold(Vec3::INFINITY, |min, &leaf| min.min(nodes[leaf].min));
+                            let max = leaves.iter().fold(Vec3::NEG_INFINITY, |max, &leaf| max.max(nodes[leaf].max));
+                            let (lo, hi) = node.child_bounds(slot);
+                            assert!(lo.cmple(min).all() && hi.cmpge(max).all(),
+                                "Width {}: Quantized bounds {} {} of node {} slot {} do not contain {} {}", width, lo, hi, index, slot, min, max);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Largest stack of a traversal that pushes all children of a node and visits the path to `target` first
+    fn traversal_stack_size(wide: &WideBVH, root: u32, parents: &HashMap<u32, u32>, target: u32) -> usize {
+        let mut path = vec![target];
+        while let Some(&parent) = parents.get(path.last().unwrap()) {
+            path.push(parent);
+        }
+
+        // Inner children are pushed with their node, leaves without
+        let mut stack = vec![Some(root)];
+        let mut max_size = stack.len();
+        while let Some(entry) = stack.pop() {
+            let Some(index) = entry else { continue };
+            let node = &wide.nodes()[index as usize];
+            let mut children: Vec<_> = (0..MAX_WIDTH).filter(|&slot| !node.is_empty(slot))
+                .map(|slot| node.is_inner(slot).then(|| node.child(slot)))
+                .collect();
+            // The child on the path is pushed last, so that it is popped while its siblings stay on the stack
+            children.sort_by_key(|child| child.is_some_and(|child| path.contains(&child)));
+            stack.extend(children);
+            max_size = max_size.max(stack.len());
+        }
+        max_size
+    }
+
+    #[test]
+    fn stack_size_matches_the_deepest_traversal() {
+        for width in [4, 8] {
+            for triangles in test_inputs() {
+                let collapsed = collapse(triangles, width);
+                let wide = &collapsed.wide;
+                let mut max_size = 0;
+                for (root, _) in &collapsed.roots {
+                    let subtree = subtree(wide, *root);
+                    let parents: HashMap<_, _> = subtree.iter().flat_map(|&index| {
+                        let node = &wide.nodes()[index as usize];
+                        (0..MAX_WIDTH).filter(|&slot| node.is_inner(slot)).map(move |slot| (node.child(slot), index))
+                    }).collect();
+                    for &target in &subtree {
+                        max_size = max_size.max(traversal_stack_size(wide, *root, &parents, target));
+                    }
+                }
+                assert_eq!(wide.max_stack_size() as usize, max_size, "Width {}", width);
+            }
+        }
+    }
+
+    fn load_spheres(blas_width: usize) -> CpuScene {
+        let mut scene = Scene::default();
+        scene.blas_width = blas_width;
+        scene.parse_gltf(&Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/spheres.glb")).unwrap();
+        CpuScene::new(&scene)
+    }
+
+    #[test]
+    fn wide_blas_hits_match_the_binary_blas() {
+        let binary = load_spheres(2);
+        let BottomLevel::Binary(tree) = &binary.data.blas else { panic!("Expected a binary BLAS") };
+        let instance_count = binary.data.top_level.instances().count() as u32;
+
+        for width in [4, 8] {
+            let wide = load_spheres(width);
+            assert!(matches!(wide.data.blas, BottomLevel::Wide(_)), "Width {}: The wide BLAS was not used", width);
+
+            let (mut n_rays, mut n_hits) = (0, 0);
+            for instance in 0..instance_count {
+                let root = &tree.nodes()[binary.instance(instance).node as usize];
+                let extent = root.max - root.min;
+                // Grid of slightly tilted rays towards each side of the BLAS bounds, starting outside of them
+                for (axis, i, j) in iproduct!(0..3, 0..16, 0..16) {
+                    let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+                    let mut origin = root.min - extent;
+                    origin[u] = root.min[u] + extent[u] * (i as f32 / 15.0 * 1.2 - 0.1);
+                    origin[v] = root.min[v] + extent[v] * (j as f32 / 15.0 * 1.2 - 0.1);
+                    let mut direction = (hash4f(UVec4::new(instance, axis as u32, i, j)).truncate() - 0.5) * 0.2;
+                    direction[axis] = 1.0;
+                    let ray = Ray::new(origin, direction.normalize());
+
+                    let (expected, hit) = (binary.intersect_blas(&ray, instance), wide.intersect_blas(&ray, instance));
+                    assert_eq!(hit.dist == NO_HIT, expected.dist == NO_HIT,
+                        "Width {}: Ray {:?} hits at {} instead of {}", width, ray, hit.dist, expected.dist);
+                    if expected.dist != NO_HIT {
+                        assert!((hit.dist - expected.dist).abs() <= 1e-6 * expected.dist,
+                            "Width {}: Ray {:?} hits at {} instead of {}", width, ray, hit.dist, expected.dist);
+                        n_hits += 1;
+                    }
+                    n_rays += 1;
+                }
+            }
+            assert!(n_hits > n_rays / 4, "Only {} of {} rays hit the spheres", n_hits, n_rays);
+        }
+    }
+}
@@ -45,6 +45,11 @@ fn luminance(linear_rgb: vec3f) -> f32 {
     return dot(vec3f(0.2126, 0.7152, 0.0722), linear_rgb);
 }
 
+/// Decodes an sRGB encoded color to linear (https://en.wikipedia.org/wiki/SRGB)
+fn srgb_to_linear(srgb: vec3f) -> vec3f {
+    return select(pow((srgb + 0.055) / 1.055, vec3f(2.4)), srgb / 12.92, srgb <= vec3f(0.04045));
+}
+
 /// Returns the upper left 3x3 submatrix of a 4x4 matrix
 fn mat3(m: mat4x4f) -> mat3x3f {
     return mat3x3f(m[0].xyz, m[1].xyz, m[2].xyz);
@@ -68,4 +73,18 @@ fn build_tbn_from_normal(n: vec3f) -> mat3x3f {
     }
     let b = cross(n, t);
     return mat3x3f(t, b, n);
-}
\ No newline at end of file
+}
+
+/// Maps equirectangular coordinates in [0,1]^2 to a direction with the y-axis pointing up
+fn equirect_to_direction(uv: vec2f) -> vec3f {
+    let phi = TWO_PI * uv.x;
+    let theta = PI * uv.y;
+    return vec3f(sin(theta) * cos(phi), cos(theta), sin(theta) * sin(phi));
+}
+
+/// Inverse of `equirect_to_direction`, the direction needs to be normalized
+fn direction_to_equirect(dir: vec3f) -> vec2f {
+    let phi = atan2(dir.z, dir.x);
+    let theta = acos(clamp(dir.y, -1.0, 1.0));
+    return vec2f(fract(phi * INV_TWO_PI), theta * INV_PI);
+}
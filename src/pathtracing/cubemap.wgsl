@@ -0,0 +1,93 @@
+const COMPUTE_SIZE: u32 = 8u;
+
+@group(0) @binding(0) var equirect: texture_2d<f32>;
+@group(0) @binding(1) var equirect_sampler: sampler;
+@group(0) @binding(2) var output: texture_storage_2d_array<rgba16float, write>;
+@group(0) @binding(3) var source: texture_2d_array<f32>;
+
+// Note: Needs to match SunUniforms in envmap.rs
+struct Sun {
+    direction: vec3f,
+    // Radiance integrated over the sun disc, zero without a sun
+    irradiance: vec3f,
+};
+
+@group(0) @binding(4) var<uniform> sun: Sun;
+
+/// Returns the direction through the center of a cubemap texel,
+/// see "Cube Map Face Selection" in the Vulkan specification for the face orientations
+fn cube_texel_direction(id: vec3u, size: u32) -> vec3f {
+    let st = 2.0 * (vec2f(id.xy) + 0.5) / f32(size) - 1.0;
+    let s = st.x;
+    let t = st.y;
+    switch id.z {
+        case 0u: { return normalize(vec3f(1.0, -t, -s)); }
+        case 1u: { return normalize(vec3f(-1.0, -t, s)); }
+        case 2u: { return normalize(vec3f(s, 1.0, t)); }
+        case 3u: { return normalize(vec3f(s, -1.0, -t)); }
+        case 4u: { return normalize(vec3f(s, -t, 1.0)); }
+        default: { return normalize(vec3f(-s, -t, -1.0)); }
+    }
+}
+
+/// Inverse of `cube_texel_direction`, returns the texel that contains the direction
+fn direction_to_cube_texel(dir: vec3f, size: u32) -> vec3u {
+    let a = abs(dir);
+    var face: u32;
+    var st: vec2f;
+    if a.x >= a.y && a.x >= a.z {
+        face = select(1u, 0u, dir.x > 0.0);
+        st = vec2f(select(dir.z, -dir.z, dir.x > 0.0), -dir.y) / a.x;
+    } else if a.y >= a.z {
+        face = select(3u, 2u, dir.y > 0.0);
+        st = vec2f(dir.x, select(-dir.z, dir.z, dir.y > 0.0)) / a.y;
+    } else {
+        face = select(5u, 4u, dir.z > 0.0);
+        st = vec2f(select(-dir.x, dir.x, dir.z > 0.0), -dir.y) / a.z;
+    }
+    let texel = min(vec2u((st * 0.5 + 0.5) * f32(size)), vec2u(size - 1u));
+    return vec3u(texel, face);
+}
+
+/// Solid angle of a cubemap texel from "Cube Map Texel Solid Angle" by Rory Driscoll
+fn cube_texel_solid_angle(texel: vec2u, size: u32) -> f32 {
+    let st0 = 2.0 * vec2f(texel) / f32(size) - 1.0;
+    let st1 = 2.0 * vec2f(texel + 1u) / f32(size) - 1.0;
+    return area_element(st0.x, st0.y) - area_element(st0.x, st1.y) - area_element(st1.x, st0.y) + area_element(st1.x, st1.y);
+}
+
+fn area_element(x: f32, y: f32) -> f32 {
+    return atan2(x * y, sqrt(x * x + y * y + 1.0));
+}
+
+/// Resamples an equirectangular panorama into the first mip level of a cubemap
+@compute
+@workgroup_size(COMPUTE_SIZE, COMPUTE_SIZE)
+fn from_equirect(@builtin(global_invocation_id) id: vec3u) {
+    let size = textureDimensions(output).x;
+    if any(id.xy >= vec2u(size)) { return; }
+
+    let uv = direction_to_equirect(cube_texel_direction(id, size));
+    var color = textureSampleLevel(equirect, equirect_sampler, uv, 0.0).rgb;
+    // The sun is smaller than a texel and would be missed by the lookups, so its energy goes into the texel it falls into
+    if any(sun.irradiance > vec3f(0.0)) && all(direction_to_cube_texel(sun.direction, size) == id) {
+        color += sun.irradiance / cube_texel_solid_angle(id.xy, size);
+    }
+    textureStore(output, id.xy, id.z, vec4f(color, 1.0));
+}
+
+/// Averages 2x2 texels of the previous mip level
+@compute
+@workgroup_size(COMPUTE_SIZE, COMPUTE_SIZE)
+fn downsample(@builtin(global_invocation_id) id: vec3u) {
+    let size = textureDimensions(output).x;
+    if any(id.xy >= vec2u(size)) { return; }
+
+    let p = vec2i(id.xy) * 2;
+    let layer = i32(id.z);
+    let color = textureLoad(source, p, layer, 0)
+        + textureLoad(source, p + vec2i(1, 0), layer, 0)
+        + textureLoad(source, p + vec2i(0, 1), layer, 0)
+        + textureLoad(source, p + vec2i(1, 1), layer, 0);
+    textureStore(output, id.xy, id.z, color * 0.25);
+}
@@ -0,0 +1,31 @@
+const COMPUTE_SIZE: u32 = 8u;
+// Note: Each grid cell averages SUBSAMPLES x SUBSAMPLES lookups
+const SUBSAMPLES: u32 = 4u;
+
+@group(0) @binding(0) var environment: texture_cube<f32>;
+@group(0) @binding(1) var environment_sampler: sampler;
+@group(0) @binding(2) var output: texture_storage_2d<r32float, write>;
+
+/// Projects the environment onto an equirectangular grid of luminance weighted by sin(theta),
+/// which is proportional to the radiance times the solid angle of each cell
+@compute
+@workgroup_size(COMPUTE_SIZE, COMPUTE_SIZE)
+fn main(@builtin(global_invocation_id) id: vec3u) {
+    let dim = textureDimensions(output);
+    if any(id.xy >= dim) { return; }
+
+    // Pick the mip level whose texels roughly match the distance between two subsamples
+    let texels_per_cell = 4.0 * f32(textureDimensions(environment).x) / f32(dim.x);
+    let level = max(log2(texels_per_cell / f32(SUBSAMPLES)), 0.0);
+
+    var sum = 0.0;
+    for (var y = 0u; y < SUBSAMPLES; y += 1u) {
+        for (var x = 0u; x < SUBSAMPLES; x += 1u) {
+            let uv = (vec2f(id.xy) + (vec2f(f32(x), f32(y)) + 0.5) / f32(SUBSAMPLES)) / vec2f(dim);
+            let radiance = textureSampleLevel(environment, environment_sampler, equirect_to_direction(uv), level).rgb;
+            sum += luminance(radiance) * sin(PI * uv.y);
+        }
+    }
+
+    textureStore(output, id.xy, vec4f(sum / f32(SUBSAMPLES * SUBSAMPLES)));
+}
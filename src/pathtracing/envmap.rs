@@ -1,19 +1,275 @@
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
+use glam::{Mat3, Mat4, Vec3};
+
+use wgpu::util::DeviceExt;
+
+use crate::common::util::{create_shader_module, include_shaders};
+use crate::common::texture::CubemapError;
 use crate::common::{Texture, WGPUContext};
+use super::sky::{Sky, SunDisc};
 
 pub struct EnvMap {
     texture: Texture,
+    distribution: wgpu::Buffer,
+    pub settings: EnvSettings,
+    /// Settings currently stored in the uniform buffer
+    uploaded: EnvSettings,
+    uniforms: wgpu::Buffer,
+}
+
+/// What camera rays that miss the scene show, the scene is always lit by the environment itself
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Background {
+    #[default]
+    Environment,
+    /// Environment sampled from a coarser mip level, from 0 (sharp) to 1 (smallest mip level)
+    Blurred { blur: f32 },
+    /// Constant radiance, not affected by the exposure
+    Solid(Vec3),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EnvSettings {
+    /// Rotation around the y-axis in radians
+    pub yaw: f32,
+    /// Rotation around the x-axis in radians, applied before the yaw
+    pub pitch: f32,
+    /// Linear multiplier of the environment radiance
+    pub exposure: f32,
+    pub background: Background,
+}
+
+impl EnvSettings {
+    /// Rotation from world space into the space of the environment map
+    pub fn world_to_env(&self) -> Mat3 {
+        let env_to_world = Mat3::from_rotation_y(self.yaw) * Mat3::from_rotation_x(self.pitch);
+        env_to_world.transpose()
+    }
+}
+
+impl Default for EnvSettings {
+    fn default() -> Self {
+        Self {
+            yaw: 0.0,
+            pitch: 0.0,
+            exposure: 1.0,
+            background: Background::default(),
+        }
+    }
+}
+
+/// Note: Needs to match EnvUniforms in pathtracing.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, Debug, bytemuck::NoUninit)]
+struct EnvUniforms {
+    world_to_env: Mat4,
+    background_color: Vec3,
+    exposure: f32,
+    background_lod: f32,
+    background: u32,
+    _padding: [u32; 2],
+}
+
+impl EnvUniforms {
+    fn new(settings: &EnvSettings, mip_level_count: u32) -> Self {
+        let (background, background_color, background_lod) = match settings.background {
+            Background::Environment => (0, Vec3::ZERO, 0.0),
+            Background::Blurred { blur } => (1, Vec3::ZERO, blur.clamp(0.0, 1.0) * (mip_level_count - 1) as f32),
+            Background::Solid(color) => (2, color, 0.0),
+        };
+        Self {
+            world_to_env: Mat4::from_mat3(settings.world_to_env()),
+            background_color,
+            exposure: settings.exposure,
+            background_lod,
+            background,
+            _padding: [0; 2],
+        }
+    }
+}
+
+/// Note: Needs to match Sun in cubemap.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, Debug, bytemuck::NoUninit)]
+struct SunUniforms {
+    direction: Vec3,
+    _padding: u32,
+    irradiance: Vec3,
+    _padding2: u32,
 }
 
 // TODO: Get skyboxes from git repo
 
+/// Describes what an environment map is created from
+#[derive(Clone, Debug, PartialEq)]
+pub enum EnvSource {
+    File(PathBuf),
+    /// Procedural sky that is baked when the environment map is created
+    Sky(Sky),
+    /// Constant radiance from all directions, works without any assets
+    Uniform(Vec3),
+}
+
+impl EnvSource {
+    pub fn name(&self) -> Cow<'_, str> {
+        match self {
+            EnvSource::File(path) => path.to_string_lossy(),
+            EnvSource::Sky(_) => Cow::Borrowed("Sky"),
+            EnvSource::Uniform(_) => Cow::Borrowed("Uniform"),
+        }
+    }
+
+    /// Returns the environment as an equirectangular panorama in linear RGB, which is not possible for DDS cubemaps
+    pub fn to_equirect(&self) -> Result<image::Rgba32FImage, EnvMapError> {
+        match self {
+            EnvSource::File(path) => {
+                let ext = path.extension().unwrap_or_default().to_string_lossy().to_lowercase();
+                match ext.as_str() {
+                    "hdr" | "exr" => Ok(image::open(path)?.into_rgba32f()),
+                    "dds" => Err(EnvMapError::NotEquirect),
+                    _ => Err(EnvMapError::UnsupportedFormat(ext)),
+                }
+            },
+            EnvSource::Sky(sky) => {
+                let mut image = sky.bake();
+                sky.sun().splat(&mut image);
+                Ok(image)
+            },
+            EnvSource::Uniform(color) => {
+                // Note: A 4x2 panorama results in a cubemap with a single texel per face
+                let pixel = image::Rgba(color.extend(1.0).to_array());
+                Ok(image::Rgba32FImage::from_pixel(4, 2, pixel))
+            },
+        }
+    }
+}
+
+/// File extensions of supported environment maps
+pub const EXTENSIONS: &[&str] = &["dds", "hdr", "exr"];
+/// Maximum face size of cubemaps converted from equirectangular panoramas
+const MAX_CUBEMAP_SIZE: u32 = 2048;
+
+#[derive(Debug)]
+pub enum EnvMapError {
+    Io(std::io::Error),
+    Image(image::ImageError),
+    Cubemap(CubemapError),
+    UnsupportedFormat(String),
+    /// DDS cubemaps can only be decoded by the GPU
+    NotEquirect,
+}
+
+impl From<std::io::Error> for EnvMapError {
+    fn from(e: std::io::Error) -> Self {
+        EnvMapError::Io(e)
+    }
+}
+
+impl From<image::ImageError> for EnvMapError {
+    fn from(e: image::ImageError) -> Self {
+        EnvMapError::Image(e)
+    }
+}
+
+impl From<CubemapError> for EnvMapError {
+    fn from(e: CubemapError) -> Self {
+        EnvMapError::Cubemap(e)
+    }
+}
+
+impl std::fmt::Display for EnvMapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            EnvMapError::Io(e) => write!(f, "IO error: {}", e),
+            EnvMapError::Image(e) => write!(f, "Image error: {}", e),
+            EnvMapError::Cubemap(e) => write!(f, "Cubemap error: {}", e),
+            EnvMapError::UnsupportedFormat(ext) => write!(f, "Unsupported environment map format {:?}, use dds, hdr or exr", ext),
+            EnvMapError::NotEquirect => write!(f, "DDS cubemaps are only supported on the GPU, use hdr or exr"),
+        }
+    }
+}
+
+impl std::error::Error for EnvMapError {}
+
+/// Resolution of the equirectangular luminance grid used for importance sampling
+pub const DISTRIBUTION_SIZE: (u32, u32) = (512, 256);
+const COMPUTE_SIZE: u32 = 8;
+
+/// Header of the environment distribution buffer.
+/// It is followed by the marginal CDF over the rows (height + 1 values)
+/// and the conditional CDF over the columns of each row (width + 1 values per row).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, bytemuck::NoUninit)]
+struct DistributionHeader {
+    width: u32,
+    height: u32,
+    /// Zero if the environment is black and can not be sampled
+    integral: f32,
+    _padding: u32,
+}
+
 impl EnvMap {
-    pub fn load(wgpu: &WGPUContext, path: &PathBuf) -> Result<Self, std::io::Error> {
-        let bytes = std::fs::read(path)?;
-        let texture = Texture::create_cubemap(wgpu, bytes.as_slice());
+    pub fn create(wgpu: &WGPUContext, source: &EnvSource) -> Result<Self, EnvMapError> {
+        match source {
+            EnvSource::File(path) => Self::load(wgpu, path),
+            // Note: The sun is added after resampling the sky into the cubemap, where it still falls into a single texel
+            EnvSource::Sky(sky) => Ok(Self::from_cubemap(wgpu, Self::equirect_to_cubemap(wgpu, sky.bake(), Some(sky.sun())))),
+            _ => Ok(Self::from_equirect(wgpu, source.to_equirect()?)),
+        }
+    }
 
-        Ok(Self { texture })
+    /// Loads a DDS cubemap or an equirectangular Radiance HDR or OpenEXR panorama
+    pub fn load(wgpu: &WGPUContext, path: &Path) -> Result<Self, EnvMapError> {
+        let timer = std::time::Instant::now();
+        let ext = path.extension().unwrap_or_default().to_string_lossy().to_lowercase();
+        let texture = match ext.as_str() {
+            "dds" => {
+                let bytes = std::fs::read(path)?;
+                Texture::create_cubemap(wgpu, bytes.as_slice())?
+            },
+            "hdr" | "exr" => {
+                let image = image::open(path)?.into_rgba32f();
+                Self::equirect_to_cubemap(wgpu, image, None)
+            },
+            _ => return Err(EnvMapError::UnsupportedFormat(ext)),
+        };
+        log::info!("Loaded {:?} in {:?}", path, timer.elapsed());
+
+        Ok(Self::from_cubemap(wgpu, texture))
+    }
+
+    /// Creates an environment map from an equirectangular panorama in linear RGB
+    pub fn from_equirect(wgpu: &WGPUContext, image: image::Rgba32FImage) -> Self {
+        Self::from_cubemap(wgpu, Self::equirect_to_cubemap(wgpu, image, None))
+    }
+
+    fn from_cubemap(wgpu: &WGPUContext, texture: Texture) -> Self {
+        let distribution = Self::create_distribution(wgpu, &texture);
+        let settings = EnvSettings::default();
+        let uniforms = wgpu.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Environment Uniforms"),
+            contents: bytemuck::bytes_of(&EnvUniforms::new(&settings, texture.mip_level_count())),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        Self { texture, distribution, settings, uploaded: settings, uniforms }
+    }
+
+    /// Uploads changed settings and returns true if the accumulated image needs to be reset
+    pub fn update(&mut self, wgpu: &WGPUContext) -> bool {
+        if self.settings == self.uploaded {
+            return false;
+        }
+        let uniforms = EnvUniforms::new(&self.settings, self.texture.mip_level_count());
+        wgpu.queue.write_buffer(&self.uniforms, 0, bytemuck::bytes_of(&uniforms));
+        self.uploaded = self.settings;
+        true
+    }
+
+    pub fn uniform_binding(&self) -> wgpu::BindingResource<'_> {
+        self.uniforms.as_entire_binding()
     }
 
     pub fn view(&self) -> &wgpu::TextureView {
@@ -23,4 +279,355 @@ impl EnvMap {
     pub fn sampler(&self) -> &wgpu::Sampler {
         self.texture.sampler()
     }
-}
\ No newline at end of file
+
+    /// Buffer with the piecewise constant 2D distribution for importance sampling, see `sample_environment` in pathtracing.wgsl
+    pub fn distribution(&self) -> &wgpu::Buffer {
+        &self.distribution
+    }
+
+    /// Resamples an equirectangular panorama into a cubemap, adds the sun and generates its mip chain on the GPU
+    fn equirect_to_cubemap(wgpu: &WGPUContext, mut image: image::Rgba32FImage, sun: Option<SunDisc>) -> Texture {
+        let max_size = wgpu.device.limits().max_texture_dimension_2d;
+        if image.width() > max_size || image.height() > max_size {
+            let scale = max_size as f32 / image.width().max(image.height()) as f32;
+            let (width, height) = ((image.width() as f32 * scale) as u32, (image.height() as f32 * scale) as u32);
+            log::warn!("Downscaling {}x{} environment map to {}x{}", image.width(), image.height(), width, height);
+            image = image::imageops::resize(&image, width, height, image::imageops::FilterType::Triangle);
+        }
+
+        // A quarter of the panorama width keeps the texel density at the horizon
+        let size = (image.width() / 4).max(1).next_power_of_two().min(MAX_CUBEMAP_SIZE);
+        let cubemap = Texture::create_storage_cubemap(wgpu, size, wgpu::TextureFormat::Rgba16Float);
+
+        let equirect = Texture::from_data(wgpu, wgpu::TextureFormat::Rgba32Float, image.width(), image.height(), bytemuck::cast_slice(image.as_raw()));
+        // Wrap around horizontally to avoid a seam
+        let equirect_sampler = wgpu.device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let sun = sun.unwrap_or(SunDisc { direction: Vec3::Y, irradiance: Vec3::ZERO });
+        let sun_uniforms = wgpu.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Sun Uniforms"),
+            contents: bytemuck::bytes_of(&SunUniforms { direction: sun.direction, _padding: 0, irradiance: sun.irradiance, _padding2: 0 }),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+
+        let output_entry = wgpu::BindGroupLayoutEntry {
+            binding: 2,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::StorageTexture {
+                access: wgpu::StorageTextureAccess::WriteOnly,
+                format: wgpu::TextureFormat::Rgba16Float,
+                view_dimension: wgpu::TextureViewDimension::D2Array,
+            },
+            count: None,
+        };
+
+        let equirect_layout = wgpu.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Equirect Layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                output_entry,
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let downsample_layout = wgpu.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Cubemap Downsample Layout"),
+            entries: &[
+                output_entry,
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let module = create_shader_module!(wgpu.device, "Cubemap", "cubemap.wgsl", "common.wgsl");
+        let create_pipeline = |layout: &wgpu::BindGroupLayout, entry_point: &str| {
+            let pipeline_layout = wgpu.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Cubemap Pipeline Layout"),
+                bind_group_layouts: &[layout],
+                push_constant_ranges: &[],
+            });
+            wgpu.device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some("Cubemap Compute"),
+                layout: Some(&pipeline_layout),
+                module: &module,
+                entry_point,
+                compilation_options: wgpu::PipelineCompilationOptions {
+                    constants: &HashMap::new(),
+                    zero_initialize_workgroup_memory: false,
+                    vertex_pulling_transform: false,
+                },
+                cache: None,
+            })
+        };
+        let equirect_pipeline = create_pipeline(&equirect_layout, "from_equirect");
+        let downsample_pipeline = create_pipeline(&downsample_layout, "downsample");
+
+        let mip_views: Vec<_> = (0..cubemap.mip_level_count()).map(|level| cubemap.create_mip_view(level)).collect();
+
+        let mut encoder = wgpu.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Cubemap Encoder"),
+        });
+        for (level, view) in mip_views.iter().enumerate() {
+            let group = if level == 0 {
+                wgpu.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some("Equirect Bind Group"),
+                    layout: &equirect_layout,
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: wgpu::BindingResource::TextureView(equirect.view()),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: wgpu::BindingResource::Sampler(&equirect_sampler),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 2,
+                            resource: wgpu::BindingResource::TextureView(view),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 4,
+                            resource: sun_uniforms.as_entire_binding(),
+                        },
+                    ],
+                })
+            } else {
+                wgpu.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some("Cubemap Downsample Bind Group"),
+                    layout: &downsample_layout,
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 2,
+                            resource: wgpu::BindingResource::TextureView(view),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 3,
+                            resource: wgpu::BindingResource::TextureView(&mip_views[level - 1]),
+                        },
+                    ],
+                })
+            };
+
+            // Note: Each level gets its own pass, as it reads the level written by the previous pass
+            let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Cubemap Pass"),
+                timestamp_writes: None,
+            });
+            cpass.set_pipeline(if level == 0 { &equirect_pipeline } else { &downsample_pipeline });
+            cpass.set_bind_group(0, &group, &[]);
+            let n_workgroups = (size >> level).div_ceil(COMPUTE_SIZE);
+            cpass.dispatch_workgroups(n_workgroups, n_workgroups, 6);
+        }
+        wgpu.queue.submit(Some(encoder.finish()));
+
+        cubemap
+    }
+
+    /// Projects the cubemap onto an equirectangular grid of luminance times the solid angle on the GPU
+    /// and builds the sampling distribution from it on the CPU
+    fn create_distribution(wgpu: &WGPUContext, cubemap: &Texture) -> wgpu::Buffer {
+        let timer = std::time::Instant::now();
+        let (width, height) = DISTRIBUTION_SIZE;
+        let grid = Texture::create_texture(wgpu, wgpu::Extent3d { width, height, depth_or_array_layers: 1 }, wgpu::TextureFormat::R32Float);
+
+        let layout = wgpu.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Environment Distribution Layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::Cube,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::StorageTexture {
+                        access: wgpu::StorageTextureAccess::WriteOnly,
+                        format: wgpu::TextureFormat::R32Float,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let group = wgpu.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Environment Distribution Bind Group"),
+            layout: &layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(cubemap.view()),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(cubemap.sampler()),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(grid.view()),
+                },
+            ],
+        });
+
+        let pipeline_layout = wgpu.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Environment Distribution Pipeline Layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+
+        let module = create_shader_module!(wgpu.device, "Environment Distribution", "env_distribution.wgsl", "common.wgsl");
+
+        let pipeline = wgpu.device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Environment Distribution Compute"),
+            layout: Some(&pipeline_layout),
+            module: &module,
+            entry_point: "main",
+            compilation_options: wgpu::PipelineCompilationOptions {
+                constants: &HashMap::new(),
+                zero_initialize_workgroup_memory: false,
+                vertex_pulling_transform: false,
+            },
+            cache: None,
+        });
+
+        let mut encoder = wgpu.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Environment Distribution Encoder"),
+        });
+        {
+            let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Environment Distribution Pass"),
+                timestamp_writes: None,
+            });
+            cpass.set_pipeline(&pipeline);
+            cpass.set_bind_group(0, &group, &[]);
+            cpass.dispatch_workgroups(width / COMPUTE_SIZE, height / COMPUTE_SIZE, 1);
+        }
+        wgpu.queue.submit(Some(encoder.finish()));
+
+        let weights: Vec<f32> = bytemuck::pod_collect_to_vec(&grid.download(wgpu));
+        let contents = EnvDistribution::new(&weights, width, height).to_bytes();
+
+        log::info!("Built environment distribution in {:?}", timer.elapsed());
+
+        wgpu.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Environment Distribution"),
+            contents: &contents,
+            usage: wgpu::BufferUsages::STORAGE,
+        })
+    }
+}
+
+/// Piecewise constant 2D distribution over the equirectangular projection for importance sampling the environment
+pub struct EnvDistribution {
+    pub width: u32,
+    pub height: u32,
+    /// Mean of the weights, zero if the environment is black and can not be sampled
+    pub integral: f32,
+    /// Marginal CDF over the rows followed by the conditional CDF over the columns of each row
+    pub cdf: Vec<f32>,
+}
+
+impl EnvDistribution {
+    /// Builds the distribution proportional to `weights` laid out row by row
+    pub fn new(weights: &[f32], width: u32, height: u32) -> Self {
+        // Accumulate in f64 to keep the CDFs monotonic for large grids
+        let row_cdf = |row: &[f32]| -> (Vec<f64>, f64) {
+            let mut cdf = Vec::with_capacity(row.len() + 1);
+            let mut sum = 0.0;
+            cdf.push(0.0);
+            for &w in row {
+                sum += if w.is_finite() { w.max(0.0) as f64 } else { 0.0 };
+                cdf.push(sum);
+            }
+            (cdf, sum)
+        };
+        let normalize = |cdf: &mut Vec<f64>, sum: f64| {
+            let n = cdf.len() - 1;
+            for (i, c) in cdf.iter_mut().enumerate() {
+                // Fall back to a uniform distribution if everything is zero
+                *c = if sum > 0.0 { *c / sum } else { i as f64 / n as f64 };
+            }
+        };
+
+        let mut row_sums = Vec::with_capacity(height as usize);
+        let mut conditional = Vec::with_capacity((height * (width + 1)) as usize);
+        for row in weights.chunks_exact(width as usize) {
+            let (mut cdf, sum) = row_cdf(row);
+            normalize(&mut cdf, sum);
+            conditional.extend(cdf);
+            row_sums.push(sum as f32);
+        }
+
+        let (mut marginal, total) = row_cdf(&row_sums);
+        normalize(&mut marginal, total);
+
+        Self {
+            width,
+            height,
+            integral: (total / (width * height) as f64) as f32,
+            cdf: marginal.into_iter().chain(conditional).map(|c| c as f32).collect(),
+        }
+    }
+
+    /// Returns the header followed by the marginal and conditional CDFs as bytes
+    fn to_bytes(&self) -> Vec<u8> {
+        let header = DistributionHeader {
+            width: self.width,
+            height: self.height,
+            integral: self.integral,
+            _padding: 0,
+        };
+        let mut bytes = bytemuck::bytes_of(&header).to_vec();
+        bytes.extend_from_slice(bytemuck::cast_slice(&self.cdf));
+        bytes
+    }
+}
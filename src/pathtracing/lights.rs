@@ -0,0 +1,68 @@
+use glam::Vec3;
+
+/// Emissive triangle in world space for next event estimation
+#[repr(C)]
+#[derive(Clone, Copy, Debug, bytemuck::NoUninit)]
+pub struct LightTriangle {
+    pub p0: Vec3,
+    /// Probability of sampling this or any previous triangle
+    pub cdf: f32,
+    pub p1: Vec3,
+    /// Index of the instance in the TLAS for looking up the emission
+    pub instance: u32,
+    pub p2: Vec3,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, bytemuck::NoUninit)]
+pub struct LightInfo {
+    pub count: u32,
+    /// Sum of the emitted luminance times the area of all emissive triangles
+    pub total_power: f32,
+    _padding: [u32; 2],
+}
+
+/// Perceived luminance of a linear color, matches `luminance` in common.wgsl
+pub fn luminance(linear_rgb: Vec3) -> f32 {
+    linear_rgb.dot(Vec3::new(0.2126, 0.7152, 0.0722))
+}
+
+/// Builds a list of emissive triangles with a CDF proportional to their power for light sampling.
+/// Takes the world space corners, the emitted luminance and the instance index of each triangle.
+/// Note: The list contains a single unused triangle if there are no lights as GPU buffers must not be empty.
+pub fn build_light_list(triangles: impl IntoIterator<Item = ([Vec3; 3], f32, u32)>) -> (Vec<LightTriangle>, LightInfo) {
+    let timer = std::time::Instant::now();
+
+    let mut total_power = 0.0;
+    let mut lights: Vec<_> = triangles.into_iter().filter_map(|([p0, p1, p2], luminance, instance)| {
+        let area = 0.5 * (p1 - p0).cross(p2 - p0).length();
+        let power = luminance * area;
+        if power <= 0.0 || !power.is_finite() {
+            return None;
+        }
+        total_power += power;
+        Some(LightTriangle { p0, cdf: total_power, p1, instance, p2, _padding: 0 })
+    }).collect();
+
+    for light in &mut lights {
+        light.cdf /= total_power;
+    }
+    // Prevent sampling past the end due to rounding errors
+    if let Some(last) = lights.last_mut() {
+        last.cdf = 1.0;
+    }
+
+    let info = LightInfo {
+        count: lights.len() as u32,
+        total_power,
+        _padding: [0; 2],
+    };
+
+    if lights.is_empty() {
+        lights.push(LightTriangle { p0: Vec3::ZERO, cdf: 1.0, p1: Vec3::ZERO, instance: 0, p2: Vec3::ZERO, _padding: 0 });
+    }
+
+    log::info!("Built light list with {} emissive triangles in {:?}", info.count, timer.elapsed());
+    (lights, info)
+}
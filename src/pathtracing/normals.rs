@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+
+use glam::Vec3;
+
+use super::scene::{split_vertices, Vertex};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NormalGeneration {
+    /// Face normals as required by the glTF specification for primitives without normals
+    #[default]
+    Flat,
+    /// Angle-weighted vertex normals, faces meeting at more than `crease_angle` radians keep a hard edge
+    Smooth { crease_angle: f32 },
+}
+
+/// Generates normals for an indexed triangle list, splitting vertices along hard edges
+pub fn generate_normals(vertices: &mut Vec<Vertex>, indices: &mut [u32], mode: NormalGeneration) {
+    let timer = std::time::Instant::now();
+
+    let face_normals: Vec<_> = indices.chunks_exact(3).map(|triangle| {
+        let [p0, p1, p2] = [0, 1, 2].map(|i| vertices[triangle[i] as usize].position);
+        (p1 - p0).cross(p2 - p0).normalize_or_zero()
+    }).collect();
+
+    match mode {
+        NormalGeneration::Flat => {
+            split_vertices(vertices, indices, |corner, vertex| Vertex {
+                normal: face_normals[corner / 3],
+                ..vertex
+            });
+        }
+        NormalGeneration::Smooth { crease_angle } => {
+            let corner_normals = smooth_corner_normals(vertices, indices, &face_normals, crease_angle);
+            split_vertices(vertices, indices, |corner, vertex| Vertex {
+                normal: corner_normals[corner],
+                ..vertex
+            });
+        }
+    }
+
+    log::info!("Generated {:?} normals for {} triangles in {:?}", mode, indices.len() / 3, timer.elapsed());
+}
+
+/// Averages the normals of all faces touching a corner position weighted by their angle at that corner,
+/// see "Computing Vertex Normals from Polygonal Facets" by Thürmer and Wüthrich 1998.
+/// Faces are matched by position so that vertices duplicated at UV seams are smoothed as well.
+fn smooth_corner_normals(vertices: &[Vertex], indices: &[u32], face_normals: &[Vec3], crease_angle: f32) -> Vec<Vec3> {
+    let corner_angles: Vec<_> = indices.chunks_exact(3).flat_map(|triangle| {
+        let p = [0, 1, 2].map(|i| vertices[triangle[i] as usize].position);
+        [0, 1, 2].map(|i| {
+            let e1 = (p[(i + 1) % 3] - p[i]).normalize_or_zero();
+            let e2 = (p[(i + 2) % 3] - p[i]).normalize_or_zero();
+            e1.dot(e2).clamp(-1.0, 1.0).acos()
+        })
+    }).collect();
+
+    // Maps position bits -> corners at that position
+    let mut corners_at = HashMap::<_, Vec<usize>>::new();
+    for (corner, &index) in indices.iter().enumerate() {
+        let key = vertices[index as usize].position.to_array().map(f32::to_bits);
+        corners_at.entry(key).or_default().push(corner);
+    }
+
+    let cos_crease = crease_angle.cos();
+    let mut corner_normals = vec![Vec3::ZERO; indices.len()];
+    for corners in corners_at.values() {
+        for &corner in corners {
+            let face_normal = face_normals[corner / 3];
+            let sum: Vec3 = corners.iter()
+                .filter(|&&other| face_normal.dot(face_normals[other / 3]) >= cos_crease)
+                .map(|&other| corner_angles[other] * face_normals[other / 3])
+                .sum();
+            corner_normals[corner] = sum.normalize_or(face_normal);
+        }
+    }
+    corner_normals
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec4;
+
+    use super::*;
+
+    fn vertex(position: Vec3, u: f32) -> Vertex {
+        Vertex { position, u, normal: Vec3::ZERO, v: 0.0, tangent: Vec4::ZERO }
+    }
+
+    /// Unit cube whose faces share the 8 corner vertices
+    fn cube() -> (Vec<Vertex>, Vec<u32>) {
+        let vertices = (0..8).map(|i| vertex(Vec3::new((i & 1) as f32, ((i >> 1) & 1) as f32, ((i >> 2) & 1) as f32), 0.0)).collect();
+        let quads = [[0, 2, 3, 1], [4, 5, 7, 6], [0, 1, 5, 4], [2, 6, 7, 3], [0, 4, 6, 2], [1, 3, 7, 5]];
+        let indices = quads.iter().flat_map(|q| [q[0], q[1], q[2], q[0], q[2], q[3]]).collect();
+        (vertices, indices)
+    }
+
+    /// Two triangles folded by 90 degrees along the z-axis, whose vertices on the fold are duplicated with different u
+    /// like at a UV seam. The face normals are (1, 1, 0) and (-1, 1, 0) normalized.
+    fn folded_seam() -> (Vec<Vertex>, Vec<u32>) {
+        let vertices = vec![
+            vertex(Vec3::ZERO, 0.0), vertex(Vec3::Z, 0.0), vertex(Vec3::new(1.0, -1.0, 0.0), 0.0),
+            vertex(Vec3::Z, 1.0), vertex(Vec3::ZERO, 1.0), vertex(Vec3::new(-1.0, -1.0, 0.0), 1.0),
+        ];
+        (vertices, vec![0, 1, 2, 3, 4, 5])
+    }
+
+    fn face_normal(vertices: &[Vertex], triangle: &[u32]) -> Vec3 {
+        let [p0, p1, p2] = [0, 1, 2].map(|i| vertices[triangle[i] as usize].position);
+        (p1 - p0).cross(p2 - p0).normalize()
+    }
+
+    fn assert_flat(vertices: &[Vertex], indices: &[u32]) {
+        for triangle in indices.chunks_exact(3) {
+            let expected = face_normal(vertices, triangle);
+            for &index in triangle {
+                let normal = vertices[index as usize].normal;
+                assert!(normal.abs_diff_eq(expected, 1e-6), "Normal {} of vertex {} is not {}", normal, index, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn flat_normals_split_shared_vertices_per_face() {
+        let (mut vertices, mut indices) = cube();
+        generate_normals(&mut vertices, &mut indices, NormalGeneration::Flat);
+        assert_eq!(vertices.len(), 24, "Each face needs its own 4 vertices");
+        assert_flat(&vertices, &indices);
+    }
+
+    #[test]
+    fn smooth_normals_keep_edges_sharper_than_the_crease_angle() {
+        let (mut vertices, mut indices) = cube();
+        generate_normals(&mut vertices, &mut indices, NormalGeneration::Smooth { crease_angle: 30f32.to_radians() });
+        assert_eq!(vertices.len(), 24);
+        assert_flat(&vertices, &indices);
+
+        let (mut vertices, mut indices) = cube();
+        generate_normals(&mut vertices, &mut indices, NormalGeneration::Smooth { crease_angle: 100f32.to_radians() });
+        assert_eq!(vertices.len(), 8, "No vertex needs to be split");
+        for vertex in &vertices {
+            let expected = (vertex.position - 0.5).normalize();
+            assert!(vertex.normal.abs_diff_eq(expected, 1e-6), "Normal {} at {} is not {}", vertex.normal, vertex.position, expected);
+        }
+    }
+
+    #[test]
+    fn smooth_normals_are_welded_across_uv_seams() {
+        let (mut vertices, mut indices) = folded_seam();
+        generate_normals(&mut vertices, &mut indices, NormalGeneration::Smooth { crease_angle: 100f32.to_radians() });
+        assert_eq!(vertices.len(), 6, "The seam must keep its texture coordinates");
+        for index in [0, 1, 3, 4] {
+            let normal = vertices[index].normal;
+            assert!(normal.abs_diff_eq(Vec3::Y, 1e-6), "Normal {} of seam vertex {} is not {}", normal, index, Vec3::Y);
+        }
+        assert!(vertices[2].normal.abs_diff_eq(Vec3::new(1.0, 1.0, 0.0).normalize(), 1e-6));
+        assert!(vertices[5].normal.abs_diff_eq(Vec3::new(-1.0, 1.0, 0.0).normalize(), 1e-6));
+
+        let (mut vertices, mut indices) = folded_seam();
+        generate_normals(&mut vertices, &mut indices, NormalGeneration::Smooth { crease_angle: 30f32.to_radians() });
+        assert_flat(&vertices, &indices);
+    }
+}
@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 use std::time::Instant;
 
-use glam::{uvec2, Vec3Swizzles, Vec4};
+use glam::{uvec2, UVec2, Vec3Swizzles, Vec4};
 use itertools::iproduct;
 use sobol_burley::sample_4d;
 use wgpu::util::DeviceExt;
@@ -45,16 +45,26 @@ impl Default for Globals {
 
 // TODO: Cleanup
 impl Pathtracer {
-    const COMPUTE_SIZE: u32 = 8;
-    const LDS_PER_BOUNCE: u32 = 2;
+    pub const COMPUTE_SIZE: u32 = 8;
+    pub const LDS_PER_BOUNCE: u32 = 4;
+    /// Note: Needs to match MAX_BOUNCES in pathtracing.wgsl
+    pub const MAX_BOUNCES: u32 = 32;
 
-    pub fn new(wgpu: &WGPUContext, scene: &SceneBuffers, camera: &CameraController, envmap: &EnvMap) -> Self {
+    pub fn new(wgpu: &WGPUContext, scene: &SceneBuffers, camera: &CameraController, envmap: &EnvMap, max_sample_count: u32) -> Self {
         let resolution_factor = 0.3;
         let output = Self::create_output_texture(wgpu, resolution_factor);
 
         let globals = Globals::default();
-        let max_sample_count = 1024;
-        let dims = globals.bounces * Self::LDS_PER_BOUNCE + 1;
+        // Generate enough dimensions for the maximum number of bounces so that the bounce count can change at runtime
+        // Note: The last bounce is inclusive
+        let dims = (Self::MAX_BOUNCES + 1) * Self::LDS_PER_BOUNCE + 1;
+        // Note: The LDS buffer needs to fit into a single storage binding
+        let sample_size = dims as u64 * std::mem::size_of::<Vec4>() as u64;
+        let max_samples = (wgpu.device.limits().max_storage_buffer_binding_size as u64 / sample_size) as u32;
+        if max_sample_count > max_samples {
+            log::warn!("Clamping {} samples per pixel to the maximum of {} supported by the device", max_sample_count, max_samples);
+        }
+        let max_sample_count = max_sample_count.min(max_samples);
         let n = max_sample_count;
 
         // TODO: maybe dynamically generate LDS per frame
@@ -62,7 +72,7 @@ impl Pathtracer {
         let lds: Vec<_> = iproduct!(0..n, 0..dims).map(|(sample_index, dimension_set)| {
             Vec4::from(sample_4d(sample_index, dimension_set, 0))
         }).collect();
-        log::info!("Generated Sobol-Burley-Sequence in {:?} using {} KiB", timer.elapsed(), n * dims * 32 / 1024);
+        log::info!("Generated Sobol-Burley-Sequence in {:?} using {} KiB", timer.elapsed(), n as u64 * sample_size / 1024);
 
         let lds_buffer = wgpu.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Pathtracer LDS"),
@@ -119,23 +129,68 @@ impl Pathtracer {
                     ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 6,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
             ]
         });
 
         let global_group = Self::create_global_group(wgpu, &global_layout, &output, camera, &lds_buffer, envmap);
 
+        let pipeline = Self::create_pipeline(wgpu, &global_layout, scene);
+
+        Self { 
+            pipeline,
+            global_layout,
+            global_group,
+            lds_buffer,
+            output,
+            globals,
+            resolution_factor,
+            max_sample_count,
+        }
+    }
+
+    /// Recreates the pipeline for a new scene, whose BLAS layout may differ from the previous one
+    pub fn set_scene(&mut self, wgpu: &WGPUContext, scene: &SceneBuffers) {
+        self.pipeline = Self::create_pipeline(wgpu, &self.global_layout, scene);
+    }
+
+    fn create_pipeline(wgpu: &WGPUContext, global_layout: &wgpu::BindGroupLayout, scene: &SceneBuffers) -> wgpu::ComputePipeline {
         let layout = wgpu.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("Raytracer Pipeline Layout"),
-            bind_group_layouts: &[&global_layout, scene.layout()],
+            bind_group_layouts: &[global_layout, scene.layout()],
             push_constant_ranges: &[PushConstantRange {
                 stages: wgpu::ShaderStages::COMPUTE,
                 range: 0..std::mem::size_of::<Globals>() as u32,
             }],
         });
 
-        let module = create_shader_module!(wgpu.device, "Pathtracer", "pathtracing.wgsl", "raytracing_sw.wgsl", "common.wgsl");
+        // Note: The BLAS traversal depends on the node layout of the scene
+        let module = if scene.has_wide_blas() {
+            create_shader_module!(wgpu.device, "Pathtracer", "pathtracing.wgsl", "raytracing_sw.wgsl", "blas_wide.wgsl", "common.wgsl")
+        } else {
+            create_shader_module!(wgpu.device, "Pathtracer", "pathtracing.wgsl", "raytracing_sw.wgsl", "blas_binary.wgsl", "common.wgsl")
+        };
 
-        let pipeline = wgpu.device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        wgpu.device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
             label: Some("Raytracer Compute"),
             layout: Some(&layout),
             module: &module,
@@ -146,18 +201,7 @@ impl Pathtracer {
                 vertex_pulling_transform: false,
             },
             cache: None,
-        });
-
-        Self { 
-            pipeline,
-            global_layout,
-            global_group,
-            lds_buffer,
-            output,
-            globals,
-            resolution_factor,
-            max_sample_count,
-        }
+        })
     }
 
     fn create_global_group(wgpu: &WGPUContext, global_layout: &wgpu::BindGroupLayout, output: &Texture, camera: &CameraController, lds_buffer: &wgpu::Buffer, envmap: &EnvMap) -> wgpu::BindGroup {
@@ -185,13 +229,21 @@ impl Pathtracer {
                     binding: 4,
                     resource: wgpu::BindingResource::Sampler(envmap.sampler()),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: envmap.distribution().as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: envmap.uniform_binding(),
+                },
             ]
         })
     }
 
     fn create_output_texture(wgpu: &WGPUContext, resolution_factor: f32) -> Texture {
         let dim = uvec2(wgpu.config.width, wgpu.config.height).as_vec2() * resolution_factor;
-        let dim = dim.as_uvec2() / Self::COMPUTE_SIZE * Self::COMPUTE_SIZE;
+        let dim = (dim.as_uvec2() / Self::COMPUTE_SIZE * Self::COMPUTE_SIZE).max(UVec2::splat(Self::COMPUTE_SIZE));
 
         let size = wgpu::Extent3d {
             width: dim.x,
@@ -1,7 +1,8 @@
 const COMPUTE_SIZE: u32 = 8u;
-const LDS_PER_BOUNCE: u32 = 2u;
-// TODO: Move to a push constant
-const LDS_STRIDE = 8 * LDS_PER_BOUNCE + 1u;
+const LDS_PER_BOUNCE: u32 = 4u;
+// Note: Needs to match Pathtracer::MAX_BOUNCES
+const MAX_BOUNCES: u32 = 32u;
+const LDS_STRIDE = (MAX_BOUNCES + 1u) * LDS_PER_BOUNCE + 1u;
 
 struct CameraData {
     world_to_clip: mat4x4f,
@@ -14,6 +15,52 @@ struct CameraData {
 @group(0) @binding(3) var environment: texture_cube<f32>;
 @group(0) @binding(4) var environment_sampler: sampler;
 
+// Note: Needs to match DistributionHeader in envmap.rs
+struct EnvDistribution {
+    width: u32,
+    height: u32,
+    integral: f32,
+    _padding: u32,
+    // Marginal CDF over rows followed by the conditional CDF over columns of each row
+    cdf: array<f32>,
+};
+
+@group(0) @binding(5) var<storage, read> env_distribution: EnvDistribution;
+
+const BACKGROUND_ENVIRONMENT = 0u;
+const BACKGROUND_BLURRED = 1u;
+const BACKGROUND_SOLID = 2u;
+
+// Note: Needs to match EnvUniforms in envmap.rs
+struct EnvUniforms {
+    world_to_env: mat4x4f,
+    background_color: vec3f,
+    exposure: f32,
+    background_lod: f32,
+    background: u32,
+};
+
+@group(0) @binding(6) var<uniform> env_uniforms: EnvUniforms;
+
+// Note: Needs to match LightTriangle in lights.rs
+struct LightTriangle {
+    p0: vec3f,
+    cdf: f32,
+    p1: vec3f,
+    instance: u32,
+    p2: vec3f,
+    _padding: u32,
+};
+
+// Note: Needs to match LightInfo in lights.rs
+struct LightInfo {
+    count: u32,
+    total_power: f32,
+};
+
+@group(1) @binding(7) var<storage, read> lights: array<LightTriangle>;
+@group(1) @binding(8) var<uniform> light_info: LightInfo;
+
 struct PushConstants {
     sample: u32,
     weight: f32,
@@ -40,6 +87,17 @@ fn generate_ray(id: vec3u, rand: vec2f) -> Ray {
     return Ray(pos, dir, 1.0 / dir);
 }
 
+/// Angle between the camera rays through neighboring pixels in the center of the image, the spread of the ray cones
+fn pixel_spread_angle() -> f32 {
+    let dim = vec2f(textureDimensions(output));
+    let world_pos = camera.clip_to_world[3];
+    let pos = world_pos.xyz / world_pos.w;
+    let center = camera.clip_to_world * vec4f(0.0, 0.0, -1.0, 1.0);
+    let neighbor = camera.clip_to_world * vec4f(0.0, 2.0 / dim.y, -1.0, 1.0);
+    // Note: The chord between the unit directions is accurate for small angles
+    return distance(normalize(pos - center.xyz / center.w), normalize(pos - neighbor.xyz / neighbor.w));
+}
+
 /// Sample visible normal distribution function using the algorithm
 /// from "Sampling Visible GGX Normals with Spherical Caps" by Dupuy et al. 2023.
 /// https://cdrdv2-public.intel.com/782052/sampling-visible-ggx-normals.pdf
@@ -114,6 +172,17 @@ fn F_SchlickApprox(HdotV: f32, R0: vec3f) -> vec3f {
     return R0 + (1.0 - R0) * pow(1.0 - HdotV, 5.0);
 }
 
+/// Unpolarized Fresnel reflectance of a dielectric interface (see https://pbr-book.org/4ed/Reflection_Models/Specular_Reflection_and_Transmission#FresnelReflectance).
+/// eta is the relative index of refraction η_i / η_t, returns 1.0 for total internal reflection.
+fn F_Dielectric(cosThetaI: f32, eta: f32) -> f32 {
+    let sin2ThetaT = eta * eta * (1.0 - cosThetaI * cosThetaI);
+    if sin2ThetaT >= 1.0 { return 1.0; }
+    let cosThetaT = sqrt(1.0 - sin2ThetaT);
+    let rs = (eta * cosThetaI - cosThetaT) / (eta * cosThetaI + cosThetaT);
+    let rp = (cosThetaI - eta * cosThetaT) / (cosThetaI + eta * cosThetaT);
+    return 0.5 * (rs * rs + rp * rp);
+}
+
 /// Lambda for the Trowbridge-Reitz NDF
 /// Measures invisible masked microfacet area per visible microfacet area.
 fn Lambda_TrowbridgeReitz(NdotV: f32, alpha2: f32) -> f32 {
@@ -131,6 +200,12 @@ fn G2_TrowbridgeReitz(NdotL: f32, NdotV: f32, alpha2: f32) -> f32 {
     return 1.0 / (1.0 + lambdaL + lambdaV);
 }
 
+/// Trowbridge-Reitz (GGX) normal distribution function
+fn D_TrowbridgeReitz(NdotH: f32, alpha2: f32) -> f32 {
+    let d = NdotH * NdotH * (alpha2 - 1.0) + 1.0;
+    return alpha2 / (PI * d * d);
+}
+
 /// Smith's shadowing-masking function for the Trowbridge-Reitz NDF.
 fn G1_TrowbridgeReitz(NdotV: f32, alpha2: f32) -> f32 {
     let lambdaV = Lambda_TrowbridgeReitz(NdotV, alpha2);
@@ -145,9 +220,208 @@ fn mikktspace(hit: HitInfo) -> mat3x3f {
     return mat3x3f(t, b, n);
 }
 
+// Note: Perfect mirrors can not be evaluated for light samples, so alpha2 is clamped to keep the NDF finite
+const MIN_ALPHA2: f32 = 1e-7;
+
+struct BrdfEval {
+    // BRDF times the cosine term
+    value: vec3f,
+    // Solid angle density of sampling the direction with the lobe probabilities of `sample_rendering_eq`
+    pdf: f32,
+};
+
+/// Evaluates the specular Trowbridge-Reitz and the diffuse Brent-Burley lobes for a given incident direction.
+/// Matches the weights used for BRDF sampling in `sample_rendering_eq`.
+/// Note: The dielectric transmission lobe is left out and only reached by sampling it, see `sample_transmission`
+fn eval_brdf(wo: vec3f, wi: vec3f, n: vec3f, albedo: vec3f, metallic: f32, alpha: f32, p_specular: f32, transmission: f32) -> BrdfEval {
+    let cosThetaO = dot(wo, n);
+    let cosThetaI = dot(wi, n);
+    if cosThetaO <= 0.0 || cosThetaI <= 0.0 {
+        return BrdfEval(vec3f(0.0), 0.0);
+    }
+    let alpha2 = max(alpha * alpha, MIN_ALPHA2);
+    let wm = normalize(wi + wo);
+    let cosThetaD = dot(wi, wm);
+    let D = D_TrowbridgeReitz(dot(wm, n), alpha2);
+
+    let F0 = mix(vec3f(0.04), albedo, metallic);
+    let F = F_SchlickApprox(cosThetaD, F0);
+    let specular = F * D * G2_TrowbridgeReitz(cosThetaI, cosThetaO, alpha2) / (4.0 * cosThetaO);
+    // VNDF sampling density D_wo(wm) = G1(wo) * D(wm) * dot(wo, wm) / cosThetaO with the reflection Jacobian 1 / (4 * dot(wo, wm))
+    let pdf_specular = G1_TrowbridgeReitz(cosThetaO, alpha2) * D / (4.0 * cosThetaO);
+
+    let FD90 = 0.5 + 2 * alpha * pow(cosThetaD, 2.0);
+    let response = (1 + (FD90 - 1) * pow(1 - cosThetaI, 5.0)) * (1 + (FD90 - 1) * pow(1 - cosThetaO, 5.0));
+    // Note: Transmission replaces the diffuse base below the specular layer
+    let diffuse = (1 - metallic) * (1 - transmission) * albedo * response * INV_PI * cosThetaI;
+    let pdf_diffuse = (1 - transmission) * cosThetaI * INV_PI;
+
+    return BrdfEval(specular + diffuse, mix(pdf_diffuse, pdf_specular, p_specular));
+}
+
+struct DielectricSample {
+    wi: vec3f,
+    // Sampled BSDF times the cosine term divided by the density
+    weight: vec3f,
+};
+
+/// Samples the transmission through a rough dielectric from "Microfacet Models for Refraction through Rough Surfaces" by Walter et al. 2007.
+/// It replaces the diffuse base below the specular layer as in KHR_materials_transmission, so only the light that is not
+/// reflected by the dielectric interface is transmitted and light that can not leave the medium is reflected instead.
+/// eta is the relative index of refraction η_i / η_t, thin-walled surfaces transmit without bending the ray.
+fn sample_transmission(rand: vec2f, wo: vec3f, n: vec3f, tint: vec3f, F0: vec3f, alpha: f32, eta: f32, thin_walled: bool) -> DielectricSample {
+    let wm = sample_vndf_iso(rand, wo, alpha, n);
+    let F = F_Dielectric(dot(wo, wm), eta);
+    var wi: vec3f;
+    var weight: vec3f;
+    if F >= 1.0 {
+        // Total internal reflection of everything the specular layer does not reflect
+        wi = reflect(-wo, wm);
+        weight = 1.0 - F_SchlickApprox(dot(wo, wm), F0);
+        if dot(wi, n) <= 0.0 { return DielectricSample(wi, vec3f(0.0)); }
+    } else {
+        if thin_walled {
+            // Mirror the reflection through the surface
+            wi = reflect(-wo, wm);
+            wi -= 2.0 * dot(wi, n) * n;
+            weight = tint * (1.0 - F);
+        } else {
+            wi = refract(-wo, wm, eta);
+            // Radiance is compressed into a smaller solid angle when entering a denser medium
+            weight = tint * (1.0 - F) * eta * eta;
+        }
+        if dot(wi, n) >= 0.0 { return DielectricSample(wi, vec3f(0.0)); }
+    }
+    // The microfacet is sampled from the VNDF, leaving G2 / G1
+    let alpha2 = alpha * alpha;
+    let LambdaL = Lambda_TrowbridgeReitz(dot(wi, n), alpha2);
+    let LambdaV = Lambda_TrowbridgeReitz(dot(wo, n), alpha2);
+    return DielectricSample(wi, weight * (1.0 + LambdaV) / (1.0 + LambdaL + LambdaV));
+}
+
+/// Power heuristic with beta = 2 for multiple importance sampling, see "Optimally Combining Sampling Techniques for Monte Carlo Rendering" by Veach and Guibas 1995
+fn mis_weight(pdf: f32, other_pdf: f32) -> f32 {
+    let pdf2 = pdf * pdf;
+    return pdf2 / (pdf2 + other_pdf * other_pdf);
+}
+
+/// Selects an emissive triangle proportional to its power using a binary search over the CDF
+fn sample_light_index(rand: f32) -> u32 {
+    var low = 0u;
+    var high = light_info.count - 1u;
+    while low < high {
+        let mid = (low + high) / 2u;
+        if lights[mid].cdf <= rand {
+            low = mid + 1u;
+        } else {
+            high = mid;
+        }
+    }
+    return low;
+}
+
+/// Uniformly samples a point on a triangle, see https://pbr-book.org/4ed/Shapes/Triangle_Meshes#Sampling
+fn sample_triangle(rand: vec2f, p0: vec3f, p1: vec3f, p2: vec3f) -> vec3f {
+    let su = sqrt(rand.x);
+    let b0 = 1.0 - su;
+    let b1 = rand.y * su;
+    return b0 * p0 + b1 * p1 + (1.0 - b0 - b1) * p2;
+}
+
+/// Solid angle density of sampling a point on an emissive triangle of `instance` seen from distance `dist`.
+/// Triangles are chosen proportional to their power and then sampled uniformly by area,
+/// so the area density is the emitted luminance divided by the total power.
+fn light_pdf(instance: u32, light_normal: vec3f, wi: vec3f, dist: f32) -> f32 {
+    let pdf_area = luminance(instances[instance].color.xyz) / light_info.total_power;
+    let cos_light = abs(dot(normalize(light_normal), wi));
+    return pdf_area * dist * dist / cos_light;
+}
+
+/// Radiance arriving from the environment along a world space direction
+fn environment_radiance(dir: vec3f, lod: f32) -> vec3f {
+    let env_dir = mat3(env_uniforms.world_to_env) * dir;
+    return env_uniforms.exposure * textureSampleLevel(environment, environment_sampler, env_dir, lod).xyz;
+}
+
+/// Radiance seen by camera rays that miss the scene
+fn background_radiance(dir: vec3f) -> vec3f {
+    switch env_uniforms.background {
+        case BACKGROUND_BLURRED: {
+            return environment_radiance(dir, env_uniforms.background_lod);
+        }
+        case BACKGROUND_SOLID: {
+            return env_uniforms.background_color;
+        }
+        default: {
+            return environment_radiance(dir, 0.0);
+        }
+    }
+}
+
+/// Returns the bin i with cdf[offset + i] <= rand < cdf[offset + i + 1] using a binary search
+fn sample_env_cdf(offset: u32, count: u32, rand: f32) -> u32 {
+    var low = 0u;
+    var high = count - 1u;
+    while low < high {
+        let mid = (low + high + 1u) / 2u;
+        if env_distribution.cdf[offset + mid] <= rand {
+            low = mid;
+        } else {
+            high = mid - 1u;
+        }
+    }
+    return low;
+}
+
+struct EnvSample {
+    direction: vec3f,
+    pdf: f32,
+};
+
+/// Samples a world space direction proportional to the environment luminance using the piecewise constant 2D distribution
+/// over the equirectangular projection, see https://pbr-book.org/4ed/Sampling_Algorithms/Sampling_Multidimensional_Functions
+fn sample_environment(rand: vec2f) -> EnvSample {
+    let width = env_distribution.width;
+    let height = env_distribution.height;
+
+    let row = sample_env_cdf(0u, height, rand.y);
+    let m0 = env_distribution.cdf[row];
+    let m1 = env_distribution.cdf[row + 1u];
+    let v = (f32(row) + (rand.y - m0) / (m1 - m0)) / f32(height);
+
+    let offset = height + 1u + row * (width + 1u);
+    let col = sample_env_cdf(offset, width, rand.x);
+    let c0 = env_distribution.cdf[offset + col];
+    let c1 = env_distribution.cdf[offset + col + 1u];
+    let u = (f32(col) + (rand.x - c0) / (c1 - c0)) / f32(width);
+
+    let pdf_uv = (m1 - m0) * f32(height) * (c1 - c0) * f32(width);
+    let sin_theta = sin(PI * v);
+    // Jacobian of the equirectangular mapping: dω = 2π² sin(θ) du dv
+    let pdf = select(0.0, pdf_uv / (2.0 * PI * PI * sin_theta), sin_theta > 0.0);
+    // Note: The inverse of the rotation is its transpose and it does not change the density
+    let direction = transpose(mat3(env_uniforms.world_to_env)) * equirect_to_direction(vec2f(u, v));
+    return EnvSample(direction, pdf);
+}
+
+/// Solid angle density of sampling the world space direction `dir` with `sample_environment`
+fn environment_pdf(dir: vec3f) -> f32 {
+    let width = env_distribution.width;
+    let height = env_distribution.height;
+    let uv = direction_to_equirect(normalize(mat3(env_uniforms.world_to_env) * dir));
+    let row = min(u32(uv.y * f32(height)), height - 1u);
+    let col = min(u32(uv.x * f32(width)), width - 1u);
+
+    let offset = height + 1u + row * (width + 1u);
+    let pdf_row = (env_distribution.cdf[row + 1u] - env_distribution.cdf[row]) * f32(height);
+    let pdf_col = (env_distribution.cdf[offset + col + 1u] - env_distribution.cdf[offset + col]) * f32(width);
+    let sin_theta = sin(PI * uv.y);
+    return select(0.0, pdf_row * pdf_col / (2.0 * PI * PI * sin_theta), sin_theta > 0.0);
+}
+
 /// Takes a precomputed Sobol-Burley sample and performs a Cranly-Patterson-Rotation with a per pixel shift.
 /// For each sample the precomputed Sobol-Burley array contains first one vec4f for lens and pixel sampling 
-/// and then two vec4f for each bounce.
+/// and then four vec4f for each bounce.
 fn sample_sobol_burley_bounce(i: u32, bounce: u32, shift: vec4f, dim: u32) -> vec4f {
     let sample = sobol_burley[i * LDS_STRIDE + 1u + bounce * LDS_PER_BOUNCE + dim];
     return fract(sample + shift);
@@ -159,28 +433,57 @@ fn sample_sobol_burley_extra(i: u32, shift: vec4f) -> vec4f {
 }
 
 fn sample_rendering_eq(sample: u32, shift: vec4f, dir: Ray) -> vec3f {
+    var radiance = vec3f(0.0);
     var throughput = vec3f(1.0);
     var ray = dir;
+    var cone = RayCone(0.0, pixel_spread_angle());
+    // Density of the last BRDF sample for MIS, zero if the direction can not be sampled by next event estimation
+    var brdf_pdf = 0.0;
     for (var bounce = 0u; bounce <= c.bounces; bounce += 1u) {
-        let hit = intersect_scene(ray);
+        let hit = intersect_scene(ray, cone);
 
         if hit.dist == NO_HIT {
-            let env_color = textureSampleLevel(environment, environment_sampler, ray.direction, 0.0).xyz;
-            return throughput * env_color;
+            if bounce == 0u {
+                return background_radiance(ray.direction);
+            }
+            let env_color = environment_radiance(ray.direction, 0.0);
+            var weight = 1.0;
+            if brdf_pdf > 0.0 && env_distribution.integral > 0.0 {
+                weight = mis_weight(brdf_pdf, environment_pdf(ray.direction));
+            }
+            return radiance + throughput * weight * env_color;
         }
 
         if (hit.flags & EMISSIVE) != 0u {
-            return throughput * hit.color.xyz;
+            var weight = 1.0;
+            if brdf_pdf > 0.0 && light_info.count > 0u {
+                weight = mis_weight(brdf_pdf, light_pdf(hit.instance, hit.geometric_normal, ray.direction, hit.dist));
+            }
+            return radiance + throughput * weight * hit.color.xyz;
+        }
+
+        cone.width += cone.spread * hit.dist * length(ray.direction);
+
+        let instance = instances[hit.instance];
+        let back_face = (hit.flags & BACK_FACE) != 0u;
+        let thin_walled = instance.thickness == 0.0;
+        // Beer-Lambert absorption inside the volume the ray is leaving, the attenuation distance is given in the space of the mesh
+        if back_face && !thin_walled {
+            let local_dist = length(mat3(instance.world_to_local) * ray.direction) * hit.dist;
+            throughput *= exp(-instance.absorption * local_dist);
         }
 
         // Collect hit info
         let alpha = hit.roughness * hit.roughness;
         let alpha2 = alpha * alpha;
-        let n = normalize(hit.normal);
+        // Note: Shading happens on the side of the incoming ray
+        let n = select(1.0, -1.0, back_face) * normalize(hit.normal);
 
         // Collect bounce info
         let sobol_0 = sample_sobol_burley_bounce(sample, bounce, shift, 0u);
         let sobol_1 = sample_sobol_burley_bounce(sample, bounce, shift, 1u);
+        let sobol_2 = sample_sobol_burley_bounce(sample, bounce, shift, 2u);
+        let sobol_3 = sample_sobol_burley_bounce(sample, bounce, shift, 3u);
         let wo = normalize(-ray.direction);
         let cosThetaO = dot(wo, n);
         var wi: vec3f;
@@ -188,8 +491,6 @@ fn sample_rendering_eq(sample: u32, shift: vec4f, dir: Ray) -> vec3f {
         let metallic = hit.metallic;
         let albedo = hit.color.xyz;
 
-        // TODO: Importance Sample environment map
-
         // TODO: Importance Sample using the complete BRDF
         let F0 = mix(vec3f(0.04), albedo, metallic);
         let specular_weight = luminance(F_SchlickApprox(dot(wo, n), F0));
@@ -198,7 +499,43 @@ fn sample_rendering_eq(sample: u32, shift: vec4f, dir: Ray) -> vec3f {
         let p_specular = specular_weight / (specular_weight + diffuse_weight);
         let p_diffuse = 1.0 - p_specular;
 
+        // Next event estimation: Sample a point on an emissive triangle and trace a shadow ray towards it
+        // Note: The emission found by the next bounce is only counted if the path can continue
+        if light_info.count > 0u && bounce < c.bounces {
+            let light = lights[sample_light_index(sobol_2.x)];
+            let light_position = sample_triangle(sobol_2.yz, light.p0, light.p1, light.p2);
+            let light_normal = cross(light.p1 - light.p0, light.p2 - light.p0);
+            let dist = length(light_position - hit.position);
+            let wi_light = (light_position - hit.position) / dist;
+            let brdf = eval_brdf(wo, wi_light, n, albedo, metallic, alpha, p_specular, hit.transmission);
+            // Back faces do not emit unless they are double-sided
+            let double_sided = (instances[light.instance].flags & DOUBLE_SIDED) != 0u;
+            if brdf.pdf > 0.0 && (dot(light_normal, wi_light) < 0.0 || double_sided) {
+                let shadow = intersect_scene(spawn_ray(hit, wi_light), cone);
+                // The light is visible if the sampled point is the first hit
+                if (shadow.flags & EMISSIVE) != 0u && abs(shadow.dist - dist) <= 1e-3 * dist {
+                    let pdf = light_pdf(light.instance, light_normal, wi_light, dist);
+                    radiance += throughput * brdf.value * shadow.color.xyz * mis_weight(pdf, brdf.pdf) / pdf;
+                }
+            }
+        }
+
+        // Next event estimation: Sample the environment and trace a shadow ray towards it
+        if env_distribution.integral > 0.0 && bounce < c.bounces {
+            let env = sample_environment(sobol_3.xy);
+            let brdf = eval_brdf(wo, env.direction, n, albedo, metallic, alpha, p_specular, hit.transmission);
+            if brdf.pdf > 0.0 && env.pdf > 0.0 {
+                let shadow = intersect_TLAS(spawn_ray(hit, env.direction));
+                if shadow.dist == NO_HIT {
+                    let env_color = environment_radiance(env.direction, 0.0);
+                    radiance += throughput * brdf.value * env_color * mis_weight(env.pdf, brdf.pdf) / env.pdf;
+                }
+            }
+        }
+
         // Precomputed texture for BRDF mean for importance sampling
+        // Note: The diffuse lobe stands for the whole base, which is partly replaced by transmission
+        let transmitted = sobol_0.x >= p_specular && sobol_1.x < hit.transmission;
         if sobol_0.x < p_specular { // Trowbridge-Reitz-Specular
             let wm = sample_vndf_iso(sobol_0.yz, wo, alpha, n); // Sample microfacet normal after Trowbridge-Reitz VNDF
             wi = reflect(-wo, wm);
@@ -209,6 +546,12 @@ fn sample_rendering_eq(sample: u32, shift: vec4f, dir: Ray) -> vec3f {
             let LambdaV = Lambda_TrowbridgeReitz(cosThetaO, alpha2);
             let specular = F * (1 + LambdaV) / (1 + LambdaL + LambdaV); // = F * (G2 / G1)
             throughput *= specular / p_specular;
+        } else if transmitted { // Rough dielectric transmission
+            // Note: Thin-walled surfaces are entered and left from air
+            let eta = select(1.0 / instance.ior, instance.ior, back_face && !thin_walled);
+            let transmission = sample_transmission(sobol_0.yz, wo, n, albedo, F0, alpha, eta, thin_walled);
+            wi = transmission.wi;
+            throughput *= (1 - metallic) * transmission.weight / p_diffuse;
         } else { // Brent-Burley-Diffuse
             let tangent_to_world = build_tbn(n, hit.tangent.xyz);
             wi = tangent_to_world * sample_cosine_hemisphere(sobol_1.yz);
@@ -222,6 +565,13 @@ fn sample_rendering_eq(sample: u32, shift: vec4f, dir: Ray) -> vec3f {
             throughput *= diffuse / p_diffuse;
         }
 
+        // Note: Transmitted samples can not be generated by next event estimation
+        if transmitted {
+            brdf_pdf = 0.0;
+        } else {
+            brdf_pdf = eval_brdf(wo, wi, n, albedo, metallic, alpha, p_specular, hit.transmission).pdf;
+        }
+
         // Unbiased Russian Roulette path termination
         // Start with 1.0 then gradually decrease to 0.0
         var p_continue = min(1.0 - pow(f32(bounce) / f32(c.bounces), 8.0), 1.0);
@@ -232,12 +582,12 @@ fn sample_rendering_eq(sample: u32, shift: vec4f, dir: Ray) -> vec3f {
         if sobol_0.z < p_continue {
             throughput /= p_continue;
         } else {
-            return vec3f(0.0);
+            return radiance;
         }
 
-        ray = Ray(hit.position, wi, 1.0 / wi);
+        ray = spawn_ray(hit, wi);
     }
-    return vec3f(0.0);
+    return radiance;
 }
 
 @compute
@@ -0,0 +1,674 @@
+use std::ops::Range;
+
+use glam::{Mat2, Mat3, Mat4, UVec4, Vec2, Vec3, Vec4, Vec4Swizzles};
+
+use super::atlas::{AtlasLimits, TextureAtlas, POINT_FOOTPRINT};
+use super::bvh::wide::{self, WideBVH};
+use super::bvh::{BVHNode, BVHTree};
+use super::scene::{BottomLevel, Instance, Scene, SceneData, Vertex, NO_TEXTURE};
+
+// Note: This is a port of raytracing_sw.wgsl, blas_binary.wgsl and blas_wide.wgsl, changes to either need to be mirrored in the other
+
+pub const NO_HIT: f32 = f32::MAX;
+/// Half the distance between 1.0 and the next float, the relative error bound of a single float operation
+const MACHINE_EPSILON: f32 = f32::EPSILON * 0.5;
+const STACK_SIZE: usize = 32;
+
+/// The hit triangle belongs to an emissive instance
+pub const EMISSIVE: u32 = 1;
+/// The ray hit the back side of the triangle, only possible for instances without backface culling
+pub const BACK_FACE: u32 = 2;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Ray {
+    pub origin: Vec3,
+    pub direction: Vec3,
+    pub inv_direction: Vec3,
+}
+
+impl Ray {
+    pub fn new(origin: Vec3, direction: Vec3) -> Self {
+        Self { origin, direction, inv_direction: direction.recip() }
+    }
+}
+
+/// Conservative bound of the relative error accumulated by n float operations, see pbrt section 6.8
+fn gamma(n: f32) -> f32 {
+    (n * MACHINE_EPSILON) / (1.0 - n * MACHINE_EPSILON)
+}
+
+fn permute(v: Vec3, kx: usize, ky: usize, kz: usize) -> Vec3 {
+    Vec3::new(v[kx], v[ky], v[kz])
+}
+
+/// Watertight ray-triangle intersection from "Watertight Ray/Triangle Intersection" by Woop et al.
+/// Returns the distance and the barycentric coordinates of `v1` and `v2`.
+pub fn intersect_triangle(ray: &Ray, v0: Vec3, v1: Vec3, v2: Vec3, cull_backfaces: bool) -> Option<Vec3> {
+    // Transform the triangle into a space where the ray starts at the origin and points along +z
+    let abs_direction = ray.direction.abs();
+    let kz = if abs_direction.x > abs_direction.y && abs_direction.x > abs_direction.z {
+        0
+    } else if abs_direction.y > abs_direction.z {
+        1
+    } else {
+        2
+    };
+    let kx = (kz + 1) % 3;
+    let ky = (kx + 1) % 3;
+    let d = permute(ray.direction, kx, ky, kz);
+    let shear = Vec3::new(-d.x / d.z, -d.y / d.z, 1.0 / d.z);
+    let [p0, p1, p2] = [v0, v1, v2].map(|v| {
+        let p = permute(v - ray.origin, kx, ky, kz);
+        Vec3::new(p.x + shear.x * p.z, p.y + shear.y * p.z, p.z)
+    });
+
+    // Edge functions, the ray is inside if they all have the same sign
+    let e0 = p1.x * p2.y - p1.y * p2.x;
+    let e1 = p2.x * p0.y - p2.y * p0.x;
+    let e2 = p0.x * p1.y - p0.y * p1.x;
+    if (e0 < 0.0 || e1 < 0.0 || e2 < 0.0) && (e0 > 0.0 || e1 > 0.0 || e2 > 0.0) {
+        return None; // Outside
+    }
+    let det = e0 + e1 + e2;
+    // Note: The sign of the determinant depends on the facing and the sign of the permuted direction
+    if det == 0.0 || (cull_backfaces && det * d.z >= 0.0) {
+        return None; // Parallel or culled
+    }
+
+    // Compute the scaled distance and reject hits behind the origin
+    let z = Vec3::new(p0.z, p1.z, p2.z) * shear.z;
+    let t_scaled = e0 * z.x + e1 * z.y + e2 * z.z;
+    if (det < 0.0 && t_scaled >= 0.0) || (det > 0.0 && t_scaled <= 0.0) {
+        return None; // Behind
+    }
+    let inv_det = 1.0 / det;
+    let t = t_scaled * inv_det;
+
+    // Reject hits whose distance can not be distinguished from zero given the rounding error
+    let max_z = z.abs().max_element();
+    let max_x = Vec3::new(p0.x, p1.x, p2.x).abs().max_element();
+    let max_y = Vec3::new(p0.y, p1.y, p2.y).abs().max_element();
+    let max_e = Vec3::new(e0, e1, e2).abs().max_element();
+    let delta_z = gamma(3.0) * max_z;
+    let delta_x = gamma(5.0) * (max_x + max_z);
+    let delta_y = gamma(5.0) * (max_y + max_z);
+    let delta_e = 2.0 * (gamma(2.0) * max_x * max_y + delta_y * max_x + delta_x * max_y);
+    let delta_t = 3.0 * (gamma(3.0) * max_e * max_z + delta_e * max_z + delta_z * max_e) * inv_det.abs();
+    if t <= delta_t {
+        return None; // Behind
+    }
+    Some(Vec3::new(t, e1 * inv_det, e2 * inv_det))
+}
+
+/// From https://tavianator.com/2022/ray_box_boundary.html
+pub fn intersect_aabb(ray: &Ray, min: Vec3, max: Vec3) -> f32 {
+    let t_min = (min - ray.origin) * ray.inv_direction;
+    let t_max = (max - ray.origin) * ray.inv_direction;
+    let t_near = t_min.min(t_max).max_element();
+    // Grow the far distance by the rounding error so that rays through the boundary do not miss
+    let t_far = t_min.max(t_max).min_element() * (1.0 + 2.0 * gamma(3.0));
+    if t_near <= t_far && t_far >= 0.0 { t_near } else { NO_HIT }
+}
+
+/// Bounds the absolute error of transforming the point `p` with the affine `transform`, where `p` has the error `p_error`
+fn transform_error(transform: &Mat4, p: Vec3, p_error: Vec3) -> Vec3 {
+    let m = Mat3::from_cols(transform.x_axis.xyz().abs(), transform.y_axis.xyz().abs(), transform.z_axis.xyz().abs());
+    (1.0 + gamma(3.0)) * (m * p_error) + gamma(3.0) * (m * p.abs() + transform.w_axis.xyz().abs())
+}
+
+/// Transforms a ray into the local space of an instance, returns the local ray and the distance its origin was advanced
+fn to_local_ray(ray: &Ray, world_to_local: &Mat4) -> (Ray, f32) {
+    let local_direction = world_to_local.transform_vector3(ray.direction);
+    // Advance the origin past the transformation error so that spawned rays stay off their surface
+    let origin_error = transform_error(world_to_local, ray.origin, Vec3::ZERO);
+    let advance = local_direction.abs().dot(origin_error) / local_direction.length_squared();
+    let local_origin = world_to_local.transform_point3(ray.origin) + advance * local_direction;
+    (Ray::new(local_origin, local_direction), advance)
+}
+
+/// Returns the world space position at the barycentric coordinates of a triangle and the bound of its absolute error.
+/// Note: Interpolating the vertices is more accurate than following the ray and has a known error bound
+fn interpolate_position(local_to_world: &Mat4, [p0, p1, p2]: [Vec3; 3], b: Vec3) -> (Vec3, Vec3) {
+    let local_position = b.x * p0 + b.y * p1 + b.z * p2;
+    let local_error = gamma(7.0) * ((b.x * p0).abs() + (b.y * p1).abs() + (b.z * p2).abs());
+    (local_to_world.transform_point3(local_position), transform_error(local_to_world, local_position, local_error))
+}
+
+/// Ray cone for texture filtering from "Improved Shader and Texture Level of Detail Using Ray Cones" by Akenine-Möller et al. 2021
+#[derive(Clone, Copy, Debug)]
+pub struct RayCone {
+    /// Width of the cone at the ray origin
+    pub width: f32,
+    /// Angle by which the cone widens, surface curvature is ignored so it stays the same after every bounce
+    pub spread: f32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct HitInfo {
+    pub position: Vec3,
+    /// Conservative bound of the absolute rounding error of the position
+    pub position_error: Vec3,
+    pub dist: f32,
+    /// Note: this is unnormalized to enable MikkTSpace
+    pub normal: Vec3,
+    /// Note: this is unnormalized and faces the front side of the triangle
+    pub geometric_normal: Vec3,
+    pub instance: u32,
+    pub texcoord: Vec2,
+    pub roughness: f32,
+    pub color: Vec4,
+    /// Note: this is unnormalized to enable MikkTSpace
+    pub tangent: Vec4,
+    pub metallic: f32,
+    pub flags: u32,
+    pub transmission: f32,
+    /// Number of ray-box and ray-triangle tests during traversal, matches the debug output of pathtracing.wgsl
+    #[allow(dead_code)]
+    pub n_aabb: u32,
+    #[allow(dead_code)]
+    pub n_tri: u32,
+}
+
+impl Default for HitInfo {
+    fn default() -> Self {
+        Self {
+            position: Vec3::ZERO,
+            position_error: Vec3::ZERO,
+            dist: NO_HIT,
+            normal: Vec3::ZERO,
+            geometric_normal: Vec3::ZERO,
+            instance: 0,
+            texcoord: Vec2::ZERO,
+            roughness: 0.0,
+            color: Vec4::ZERO,
+            tangent: Vec4::ZERO,
+            metallic: 0.0,
+            flags: 0,
+            transmission: 0.0,
+            n_aabb: 0,
+            n_tri: 0,
+        }
+    }
+}
+
+impl HitInfo {
+    /// Returns a ray from the hit position that can not intersect the hit surface again.
+    /// The origin is moved along the geometric normal just beyond the error bounds of the position.
+    pub fn spawn_ray(&self, direction: Vec3) -> Ray {
+        let n = self.geometric_normal.normalize();
+        let mut offset = n.abs().dot(self.position_error) * n;
+        if direction.dot(n) < 0.0 {
+            offset = -offset;
+        }
+        let mut origin = self.position + offset;
+        // Round away from the surface so that the offset is not lost to rounding
+        for i in 0..3 {
+            if offset[i] > 0.0 {
+                origin[i] = origin[i].next_up();
+            } else if offset[i] < 0.0 {
+                origin[i] = origin[i].next_down();
+            }
+        }
+        Ray::new(origin, direction)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RawHit {
+    pub instance: u32,
+    pub indices: [u32; 3],
+    pub barycentrics: Vec3,
+    pub dist: f32,
+    pub n_aabb: u32,
+    pub n_tri: u32,
+}
+
+impl Default for RawHit {
+    fn default() -> Self {
+        Self { instance: 0, indices: [0; 3], barycentrics: Vec3::ZERO, dist: NO_HIT, n_aabb: 0, n_tri: 0 }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct StackEntry {
+    index: u32,
+    dist: f32,
+}
+
+/// Inner children are referenced by their node and have no triangles, leaves by their first triangle
+#[derive(Clone, Copy)]
+struct WideStackEntry {
+    index: u32,
+    count: u32,
+    dist: f32,
+}
+
+/// Scene data traversed by the CPU, together with the same texture atlas that the GPU samples
+pub struct CpuScene {
+    pub data: SceneData,
+    pub textures: TextureAtlas,
+}
+
+impl CpuScene {
+    pub fn new(scene: &Scene) -> Self {
+        Self {
+            data: SceneData::load_or_build(scene),
+            textures: TextureAtlas::new(scene.textures(), &AtlasLimits::new(&wgpu::Limits::default())),
+        }
+    }
+
+    pub fn instance(&self, index: u32) -> &Instance {
+        self.data.top_level.instance(index)
+    }
+
+    fn vertex(&self, index: u32) -> &Vertex {
+        &self.data.vertices[index as usize]
+    }
+
+    pub fn intersect_scene(&self, ray: &Ray, cone: RayCone) -> HitInfo {
+        let hit = self.intersect_tlas(ray);
+
+        let mut info = HitInfo { dist: hit.dist, n_aabb: hit.n_aabb, n_tri: hit.n_tri, ..Default::default() };
+        if info.dist == NO_HIT { return info; }
+
+        let instance = self.instance(hit.instance);
+        let [v0, v1, v2] = hit.indices.map(|i| self.vertex(i));
+
+        let b = hit.barycentrics;
+        (info.position, info.position_error) = interpolate_position(&instance.local_to_world, [v0.position, v1.position, v2.position], b);
+
+        let local_normal = b.x * v0.normal + b.y * v1.normal + b.z * v2.normal;
+        let local_tangent = b.x * v0.tangent + b.y * v1.tangent + b.z * v2.tangent;
+        let local_geometric_normal = (v1.position - v0.position).cross(v2.position - v0.position);
+
+        let normal_matrix = Mat3::from_mat4(instance.world_to_local).transpose();
+        info.instance = hit.instance;
+        info.normal = normal_matrix * local_normal;
+        info.geometric_normal = normal_matrix * local_geometric_normal;
+        info.tangent = (Mat3::from_mat4(instance.local_to_world) * local_tangent.xyz()).extend(local_tangent.w);
+        info.texcoord = b.x * Vec2::new(v0.u, v0.v) + b.y * Vec2::new(v1.u, v1.v) + b.z * Vec2::new(v2.u, v2.v);
+
+        // Footprint of the ray cone in texture coordinates from the ratio of the texture and world space areas of the triangle
+        let to_world = Mat3::from_mat4(instance.local_to_world);
+        let world_area = (to_world * (v1.position - v0.position)).cross(to_world * (v2.position - v0.position)).length();
+        let uv_area = Mat2::from_cols(Vec2::new(v1.u - v0.u, v1.v - v0.v), Vec2::new(v2.u - v0.u, v2.v - v0.v)).determinant().abs();
+        let width = cone.width + cone.spread * hit.dist * ray.direction.length();
+        let cos_theta = ray.direction.normalize().dot(info.geometric_normal.normalize()).abs();
+        let footprint = 0.5 * (uv_area / world_area).log2() + (width / cos_theta).log2();
+
+        info.color = instance.color;
+        info.roughness = instance.roughness;
+        info.metallic = instance.metallic;
+        info.transmission = instance.transmission;
+        if info.geometric_normal.dot(ray.direction) > 0.0 {
+            info.flags |= BACK_FACE;
+        }
+        if instance.emissive > 0.0 {
+            info.flags |= EMISSIVE;
+            if instance.emissive_texture != NO_TEXTURE {
+                info.color *= srgb_to_linear(self.textures.sample(instance.emissive_texture, info.texcoord, footprint).xyz()).extend(1.0);
+            }
+            return info;
+        }
+
+        if instance.base_color_texture != NO_TEXTURE {
+            let base_color = self.textures.sample(instance.base_color_texture, info.texcoord, footprint);
+            info.color *= srgb_to_linear(base_color.xyz()).extend(base_color.w);
+        }
+
+        if instance.metallic_roughness_texture != NO_TEXTURE {
+            // Roughness is stored in the green channel, metalness in the blue channel
+            let metallic_roughness = self.textures.sample(instance.metallic_roughness_texture, info.texcoord, footprint);
+            info.roughness *= metallic_roughness.y;
+            info.metallic *= metallic_roughness.z;
+        }
+
+        if instance.transmission_texture != NO_TEXTURE {
+            info.transmission *= self.textures.sample(instance.transmission_texture, info.texcoord, footprint).x;
+        }
+
+        if instance.normal_texture != NO_TEXTURE {
+            let tangent_normal = (self.textures.sample(instance.normal_texture, info.texcoord, footprint).xyz() * 2.0 - 1.0) * Vec3::new(instance.normal_scale, instance.normal_scale, 1.0);
+            info.normal = mikktspace(&info) * tangent_normal;
+        }
+
+        info
+    }
+
+    pub fn intersect_tlas(&self, ray: &Ray) -> RawHit {
+        let tlas = self.data.top_level.tlas().nodes();
+        let mut stack = Vec::with_capacity(STACK_SIZE);
+        let mut hit = RawHit::default();
+
+        let Some(top) = tlas.first() else { return hit; };
+        let dist_top = intersect_aabb(ray, top.min, top.max);
+        hit.n_aabb += 1;
+        if dist_top < hit.dist {
+            stack.push(StackEntry { index: 0, dist: dist_top });
+        }
+
+        while let Some(stack_entry) = stack.pop() {
+            if stack_entry.dist >= hit.dist { continue; } // Skip if node is farther than current hit
+            let node = &tlas[stack_entry.index as usize];
+            if node.is_leaf() {
+                for j in node.start..node.end {
+                    let (local_ray, advance) = to_local_ray(ray, &self.instance(j).world_to_local);
+                    let hit_local = self.intersect_blas(&local_ray, j);
+                    hit.n_aabb += hit_local.n_aabb;
+                    hit.n_tri += hit_local.n_tri;
+                    if hit_local.dist + advance < hit.dist {
+                        hit.dist = hit_local.dist + advance;
+                        hit.instance = j;
+                        hit.indices = hit_local.indices;
+                        hit.barycentrics = hit_local.barycentrics;
+                    }
+                }
+            } else {
+                hit.n_aabb += 2;
+                push_children(ray, tlas, node, hit.dist, &mut stack);
+            }
+        }
+        hit
+    }
+
+    /// Returns true if the surface of the instance is opaque at the given point.
+    /// Blended surfaces are opaque with a probability of their alpha, which stays the same for a ray and a triangle.
+    fn alpha_test(&self, instance: &Instance, ray: &Ray, triangle: u32, vertices: [&Vertex; 3], barycentrics: Vec3) -> bool {
+        let mut alpha = instance.color.w;
+        if instance.base_color_texture != NO_TEXTURE {
+            let [v0, v1, v2] = vertices;
+            let texcoord = barycentrics.x * Vec2::new(v0.u, v0.v) + barycentrics.y * Vec2::new(v1.u, v1.v) + barycentrics.z * Vec2::new(v2.u, v2.v);
+            alpha *= self.textures.sample(instance.base_color_texture, texcoord, POINT_FOOTPRINT).w;
+        }
+        if let Some(cutoff) = instance.alpha_cutoff() {
+            return alpha >= cutoff;
+        }
+        let direction = ray.direction.to_array().map(f32::to_bits);
+        let origin = ray.origin.to_array().map(f32::to_bits);
+        let seed = UVec4::new(direction[0] ^ origin[1], direction[1] ^ origin[2], direction[2] ^ origin[0], triangle);
+        alpha > hash4f(seed).x
+    }
+
+    pub fn intersect_blas(&self, ray: &Ray, instance_index: u32) -> RawHit {
+        match &self.data.blas {
+            BottomLevel::Binary(blas) => self.intersect_binary_blas(ray, instance_index, blas),
+            BottomLevel::Wide(blas) => self.intersect_wide_blas(ray, instance_index, blas),
+        }
+    }
+
+    fn intersect_binary_blas(&self, ray: &Ray, instance_index: u32, blas: &BVHTree) -> RawHit {
+        let blas = blas.nodes();
+        let mut stack = Vec::with_capacity(STACK_SIZE);
+
+        let instance = self.instance(instance_index);
+        let index_top = instance.node;
+
+        let mut hit = RawHit::default();
+
+        let top = &blas[index_top as usize];
+        let dist_top = intersect_aabb(ray, top.min, top.max);
+        hit.n_aabb += 1;
+        if dist_top < hit.dist {
+            stack.push(StackEntry { index: index_top, dist: dist_top });
+        }
+
+        while let Some(stack_entry) = stack.pop() {
+            if stack_entry.dist >= hit.dist { continue; } // Skip if node is farther than current hit
+            let node = &blas[stack_entry.index as usize];
+            if node.is_leaf() {
+                self.intersect_triangles(ray, instance, node.start..node.end, &mut hit);
+            } else {
+                hit.n_aabb += 2;
+                push_children(ray, blas, node, hit.dist, &mut stack);
+            }
+        }
+        hit
+    }
+
+    fn intersect_wide_blas(&self, ray: &Ray, instance_index: u32, blas: &WideBVH) -> RawHit {
+        let blas = blas.nodes();
+        let mut stack = Vec::with_capacity(wide::STACK_SIZE as usize);
+        // Children hit by the ray, sorted with the farthest first
+        let mut children = Vec::with_capacity(wide::MAX_WIDTH);
+
+        let instance = self.instance(instance_index);
+
+        let mut hit = RawHit::default();
+
+        // Note: The root has no bounds of its own, the TLAS already tested the bounds of the instance
+        stack.push(WideStackEntry { index: instance.node, count: 0, dist: 0.0 });
+
+        while let Some(stack_entry) = stack.pop() {
+            if stack_entry.dist >= hit.dist { continue; } // Skip if node is farther than current hit
+            if stack_entry.count > 0 { // Leaf
+                self.intersect_triangles(ray, instance, stack_entry.index..stack_entry.index + stack_entry.count, &mut hit);
+                continue;
+            }
+            let node = &blas[stack_entry.index as usize];
+
+            let mut child = node.child_base;
+            let mut triangle = node.triangle_base;
+            for slot in 0..wide::MAX_WIDTH {
+                if node.is_empty(slot) { continue; }
+                let (min, max) = node.child_bounds(slot);
+                let dist = intersect_aabb(ray, min, max);
+                hit.n_aabb += 1;
+
+                let count = node.counts[slot] as u32;
+                let is_inner = node.is_inner(slot);
+                // Note: Leaves are visited in order with the inner children, intersecting them right away tests far more triangles
+                if dist < hit.dist {
+                    let k = children.partition_point(|c: &WideStackEntry| c.dist >= dist);
+                    children.insert(k, WideStackEntry { index: if is_inner { child } else { triangle }, count, dist });
+                }
+                if is_inner {
+                    child += 1;
+                } else {
+                    triangle += count;
+                }
+            }
+
+            // Push the farthest child first so that the nearest one is visited next
+            stack.extend(children.drain(..).filter(|c| c.dist < hit.dist));
+            // Note: The stack of blas_wide.wgsl has a fixed size, wider trees fall back to the binary BLAS
+            debug_assert!(stack.len() <= wide::STACK_SIZE as usize, "Wide BLAS stack overflow");
+        }
+        hit
+    }
+
+    /// Intersects the triangles of the instance in `triangles` and updates `hit` if one of them is closer
+    fn intersect_triangles(&self, ray: &Ray, instance: &Instance, triangles: Range<u32>, hit: &mut RawHit) {
+        let indices = &self.data.indices;
+        // Note: Rays need to leave transmissive instances through their back faces
+        let cull_backfaces = !instance.is_double_sided() && instance.transmission == 0.0;
+        let alpha_tested = instance.is_alpha_tested();
+
+        for triangle in triangles {
+            let j = triangle as usize * 3;
+            let triangle_indices = [indices[j], indices[j + 1], indices[j + 2]];
+            let vertices = triangle_indices.map(|i| self.vertex(i));
+            let t = intersect_triangle(ray, vertices[0].position, vertices[1].position, vertices[2].position, cull_backfaces);
+            hit.n_tri += 1;
+            let Some(t) = t else { continue; };
+            if t.x < hit.dist {
+                let barycentrics = Vec3::new(1.0 - t.y - t.z, t.y, t.z);
+                if alpha_tested && !self.alpha_test(instance, ray, triangle, vertices, barycentrics) {
+                    continue;
+                }
+                hit.dist = t.x;
+                hit.barycentrics = barycentrics;
+                hit.indices = triangle_indices;
+            }
+        }
+    }
+}
+
+/// Pushes the children of an inner node which are closer than `max_dist`, the near child is visited first
+fn push_children(ray: &Ray, nodes: &[BVHNode], node: &BVHNode, max_dist: f32, stack: &mut Vec<StackEntry>) {
+    let index_left = node.start;
+    let left_node = &nodes[index_left as usize];
+    let left = StackEntry { index: index_left, dist: intersect_aabb(ray, left_node.min, left_node.max) };
+
+    let index_right = index_left + 1;
+    let right_node = &nodes[index_right as usize];
+    let right = StackEntry { index: index_right, dist: intersect_aabb(ray, right_node.min, right_node.max) };
+
+    let (near, far) = if left.dist < right.dist { (left, right) } else { (right, left) };
+
+    // Look at far node last
+    if far.dist < max_dist {
+        stack.push(far);
+    }
+
+    // Look at near node first
+    if near.dist < max_dist {
+        stack.push(near);
+    }
+}
+
+/// From http://mikktspace.com to apply normal maps
+pub fn mikktspace(hit: &HitInfo) -> Mat3 {
+    let n = hit.normal;
+    let t = hit.tangent.xyz();
+    let b = hit.tangent.w * n.cross(t);
+    Mat3::from_cols(t, b, n)
+}
+
+/// Decodes an sRGB encoded color to linear (https://en.wikipedia.org/wiki/SRGB)
+pub fn srgb_to_linear(srgb: Vec3) -> Vec3 {
+    srgb.to_array().map(|c| if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }).into()
+}
+
+/// PCG4D from http://jcgt.org/published/0009/03/02/, matches `hash4u` in common.wgsl
+pub fn hash4u(s: UVec4) -> UVec4 {
+    let mut v = s.to_array().map(|x| x.wrapping_mul(1664525).wrapping_add(1013904223));
+    for _ in 0..2 {
+        v[0] = v[0].wrapping_add(v[1].wrapping_mul(v[3]));
+        v[1] = v[1].wrapping_add(v[2].wrapping_mul(v[0]));
+        v[2] = v[2].wrapping_add(v[0].wrapping_mul(v[1]));
+        v[3] = v[3].wrapping_add(v[1].wrapping_mul(v[2]));
+        v = v.map(|x| x ^ (x >> 16));
+    }
+    UVec4::from_array(v)
+}
+
+/// Maps a random u32 to a random float in [0,1), matches `map4f` in common.wgsl
+pub fn hash4f(s: UVec4) -> Vec4 {
+    Vec4::from_array(hash4u(s).to_array().map(|x| f32::from_bits((x >> 9) | 0x3F800000) - 1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+
+    use glam::Quat;
+
+    use super::*;
+
+    fn random(i: u32, seed: u32) -> Vec4 {
+        hash4f(UVec4::new(i, seed, 0, 0))
+    }
+
+    /// Intersects a ray through `target` with each triangle and returns the hits
+    fn hits(triangles: &[[Vec3; 3]], target: Vec3, direction: Vec3) -> Vec<Vec3> {
+        let ray = Ray::new(target - 2.0 * direction, direction);
+        triangles.iter().filter_map(|&[v0, v1, v2]| intersect_triangle(&ray, v0, v1, v2, true)).collect()
+    }
+
+    /// Asserts that the ray hit at least one triangle and that all hits are at the same distance.
+    /// Note: Without a tie-breaking rule, rays exactly through a shared edge or vertex hit all adjacent triangles,
+    /// the traversal records only the first of them as it only keeps strictly closer hits
+    fn assert_single_hit(hits: &[Vec3], target: Vec3) {
+        assert!(!hits.is_empty(), "Ray through {} falls through a gap", target);
+        for hit in hits {
+            assert!((hit.x - hits[0].x).abs() <= 1e-6 * hits[0].x, "Ray through {} hits {:?} at different distances", target, hits);
+        }
+    }
+
+    #[test]
+    fn rays_through_a_shared_edge_hit_exactly_one_triangle() {
+        let [a, b, c, d] = [Vec3::ZERO, Vec3::X, Vec3::new(1.0, 1.0, 0.0), Vec3::Y];
+        let quad = [[a, b, c], [a, c, d]];
+        let oblique = Vec3::new(0.3, -0.2, -1.0).normalize();
+        for i in 0..=1000 {
+            let s = i as f32 / 1000.0;
+            // Exactly through the diagonal, including the shared vertices a and c
+            let target = Vec3::new(s, s, 0.0);
+            assert_single_hit(&hits(&quad, target, Vec3::NEG_Z), target);
+            if i == 0 || i == 1000 {
+                continue;
+            }
+            assert_single_hit(&hits(&quad, target, oblique), target);
+            // Next to the diagonal only the triangle on that side is hit
+            for offset in [-1e-6, 1e-6] {
+                let target = Vec3::new(s + offset, s, 0.0);
+                assert_eq!(hits(&quad, target, Vec3::NEG_Z).len(), 1, "Ray through {} does not hit exactly one triangle", target);
+            }
+        }
+        // No gaps anywhere in the quad
+        for i in 0..4096 {
+            let r = random(i, 0);
+            let target = Vec3::new(r.x, r.y, 0.0);
+            assert_single_hit(&hits(&quad, target, Vec3::new(r.z - 0.5, r.w - 0.5, -1.0).normalize()), target);
+        }
+    }
+
+    #[test]
+    fn rays_through_a_shared_vertex_hit_the_fan() {
+        let n = 7;
+        let rim: Vec<_> = (0..n).map(|i| {
+            let angle = i as f32 * TAU / n as f32;
+            Vec3::new(angle.cos(), angle.sin(), 0.0)
+        }).collect();
+        let fan: Vec<_> = (0..n).map(|i| [Vec3::ZERO, rim[i], rim[(i + 1) % n]]).collect();
+
+        for i in 0..1024 {
+            let r = random(i, 1);
+            let direction = Vec3::new(2.0 * r.x - 1.0, 2.0 * r.y - 1.0, -1.0).normalize();
+            assert_single_hit(&hits(&fan, Vec3::ZERO, direction), Vec3::ZERO);
+        }
+        // Along the spokes shared by two triangles
+        for &spoke in &rim {
+            for k in 1..100 {
+                let target = spoke * (k as f32 / 100.0);
+                assert_single_hit(&hits(&fan, target, Vec3::NEG_Z), target);
+            }
+        }
+    }
+
+    #[test]
+    fn spawned_rays_do_not_hit_their_own_triangle() {
+        let triangle = [Vec3::new(-1.0, -1.0, 0.0), Vec3::new(2.0, -0.5, 0.1), Vec3::new(0.0, 1.5, -0.2)];
+        let [v0, v1, v2] = triangle;
+        let rotation = Quat::from_rotation_arc(Vec3::Z, Vec3::new(1.0, 2.0, 3.0).normalize());
+        let local_to_world = Mat4::from_scale_rotation_translation(Vec3::new(3.0, 0.5, 2.0), rotation, Vec3::new(1000.0, -500.0, 250.0));
+        let world_to_local = local_to_world.inverse();
+        let normal_matrix = Mat3::from_mat4(world_to_local).transpose();
+        let intersect = |ray: &Ray| {
+            let (local_ray, _) = to_local_ray(ray, &world_to_local);
+            intersect_triangle(&local_ray, v0, v1, v2, false)
+        };
+
+        let mut n_hits = 0;
+        for i in 0..1024 {
+            let (r0, r1) = (random(i, 2), random(i, 3));
+            // Uniform point on the triangle, hit from a random point on either side
+            let (u, v) = if r0.x + r0.y > 1.0 { (1.0 - r0.x, 1.0 - r0.y) } else { (r0.x, r0.y) };
+            let target = local_to_world.transform_point3((1.0 - u - v) * v0 + u * v1 + v * v2);
+            let origin = target + 10.0 * (Vec3::new(r0.z, r0.w, r1.x) * 2.0 - 1.0);
+            let Some(t) = intersect(&Ray::new(origin, target - origin)) else { continue; };
+            n_hits += 1;
+
+            let b = Vec3::new(1.0 - t.y - t.z, t.y, t.z);
+            let (position, position_error) = interpolate_position(&local_to_world, triangle, b);
+            let hit = HitInfo { position, position_error, geometric_normal: normal_matrix * (v1 - v0).cross(v2 - v0), ..Default::default() };
+            for j in 0..16 {
+                let r = random(i * 16 + j, 4);
+                let direction = (Vec3::new(r.x, r.y, r.z) * 2.0 - 1.0).normalize();
+                let ray = hit.spawn_ray(direction);
+                assert!(intersect(&ray).is_none(), "Ray spawned at {} towards {} hits its own triangle", position, direction);
+            }
+        }
+        assert!(n_hits > 900, "Only {} of the rays hit the triangle", n_hits);
+    }
+}
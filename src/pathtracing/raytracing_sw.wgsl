@@ -1,9 +1,16 @@
 const MAX_FLOAT: f32 = 0x1.fffffep+127f;
 const NO_HIT: f32 = MAX_FLOAT;
-const EPS: f32 = 0.00000001;
-const BIAS: f32 = 0.1;
+// Half the distance between 1.0 and the next float, the relative error bound of a single float operation
+const MACHINE_EPSILON: f32 = 0x1p-24f;
 const TLAS_STACK_SIZE = 32u;
-const BLAS_STACK_SIZE = 32u;
+// Note: Needs to match NO_TEXTURE in scene.rs
+const NO_TEXTURE = 0xffffffffu;
+// Note: Instance flags need to match scene.rs
+const DOUBLE_SIDED = 1u;
+const ALPHA_MASK = 2u;
+const ALPHA_BLEND = 4u;
+// The alpha cutoff is stored in 1/255 steps in 8 bits of the flags
+const ALPHA_CUTOFF_OFFSET = 8u;
 
 struct BVHNode {
     min: vec3f,
@@ -20,6 +27,18 @@ struct Instance {
     metallic: f32,
     emissive: f32,
     node: u32,
+    base_color_texture: u32,
+    metallic_roughness_texture: u32,
+    normal_texture: u32,
+    emissive_texture: u32,
+    absorption: vec3f,
+    transmission: f32,
+    ior: f32,
+    thickness: f32,
+    transmission_texture: u32,
+    flags: u32,
+    normal_scale: f32,
+    _padding: array<u32, 3>,
 };
 
 struct Vertex {
@@ -30,11 +49,32 @@ struct Vertex {
     tangent: vec4f,
 };
 
-@group(1) @binding(0) var<storage, read> blas: array<BVHNode>;
+// Note: The BLAS at binding 0 and `intersect_BLAS` are defined in blas_binary.wgsl or blas_wide.wgsl
 @group(1) @binding(1) var<storage, read> tlas: array<BVHNode>;
 @group(1) @binding(2) var<storage, read> instances: array<Instance>;
 @group(1) @binding(3) var<storage, read> vertices: array<Vertex>;
 @group(1) @binding(4) var<storage, read> indices: array<u32>;
+@group(1) @binding(5) var textures: texture_2d_array<f32>;
+@group(1) @binding(6) var<storage, read> texture_entries: array<TextureEntry>;
+
+// Region of one texture in the atlas, see atlas.rs
+struct TextureEntry {
+    offset: vec2u,
+    size: vec2u,
+    page: u32,
+    max_level: u32,
+};
+
+// Footprint that selects the full resolution of every texture
+const POINT_FOOTPRINT = -64.0;
+
+/// Ray cone for texture filtering from "Improved Shader and Texture Level of Detail Using Ray Cones" by Akenine-Möller et al. 2021
+struct RayCone {
+    // Width of the cone at the ray origin
+    width: f32,
+    // Angle by which the cone widens, surface curvature is ignored so it stays the same after every bounce
+    spread: f32,
+};
 
 struct Ray {
     origin: vec3f,
@@ -47,34 +87,77 @@ struct AABB {
     max: vec3f,
 };
 
-// Möller–Trumbore intersection algorithm
-fn intersect_triangle(ray: Ray, v0: vec3f, v1: vec3f, v2: vec3f) -> vec3f {
-    let edge1 = v1 - v0;
-    let edge2 = v2 - v0;
-    let h = cross(ray.direction, edge2);
-    let det = dot(edge1, h);
-    // TODO: Do det > -EPS for inner refraction rays
-    if det < EPS { // Backface culling: det < EPS
-        return vec3f(NO_HIT, NO_HIT, NO_HIT); // Parallel or culled
+/// Conservative bound of the relative error accumulated by n float operations, see pbrt section 6.8
+fn gamma(n: f32) -> f32 {
+    return (n * MACHINE_EPSILON) / (1.0 - n * MACHINE_EPSILON);
+}
+
+fn permute(v: vec3f, kx: u32, ky: u32, kz: u32) -> vec3f {
+    return vec3f(v[kx], v[ky], v[kz]);
+}
+
+// Watertight ray-triangle intersection from "Watertight Ray/Triangle Intersection" by Woop et al.
+// Rays through shared edges and vertices hit at least one of the adjacent triangles
+fn intersect_triangle(ray: Ray, v0: vec3f, v1: vec3f, v2: vec3f, cull_backfaces: bool) -> vec3f {
+    // Transform the triangle into a space where the ray starts at the origin and points along +z
+    let abs_direction = abs(ray.direction);
+    var kz = 2u;
+    if abs_direction.x > abs_direction.y && abs_direction.x > abs_direction.z {
+        kz = 0u;
+    } else if abs_direction.y > abs_direction.z {
+        kz = 1u;
     }
-    var inv_det = 1.0 / det;
-    var s = ray.origin - v0;
-    var u = inv_det * dot(s, h);
-    if u < 0.0 || u > 1.0 {
+    let kx = (kz + 1u) % 3u;
+    let ky = (kx + 1u) % 3u;
+    let d = permute(ray.direction, kx, ky, kz);
+    var p0 = permute(v0 - ray.origin, kx, ky, kz);
+    var p1 = permute(v1 - ray.origin, kx, ky, kz);
+    var p2 = permute(v2 - ray.origin, kx, ky, kz);
+    let shear = vec3f(-d.x / d.z, -d.y / d.z, 1.0 / d.z);
+    p0 = vec3f(p0.xy + shear.xy * p0.z, p0.z);
+    p1 = vec3f(p1.xy + shear.xy * p1.z, p1.z);
+    p2 = vec3f(p2.xy + shear.xy * p2.z, p2.z);
+
+    // Edge functions, the ray is inside if they all have the same sign
+    let e0 = p1.x * p2.y - p1.y * p2.x;
+    let e1 = p2.x * p0.y - p2.y * p0.x;
+    let e2 = p0.x * p1.y - p0.y * p1.x;
+    if (e0 < 0.0 || e1 < 0.0 || e2 < 0.0) && (e0 > 0.0 || e1 > 0.0 || e2 > 0.0) {
         return vec3f(NO_HIT, NO_HIT, NO_HIT); // Outside
     }
-    var q = cross(s, edge1);
-    var v = inv_det * dot(ray.direction, q);
-    if v < 0.0 || u + v > 1.0 {
-        return vec3f(NO_HIT, NO_HIT, NO_HIT); // Outside
+    let det = e0 + e1 + e2;
+    // Note: The sign of the determinant depends on the facing and the sign of the permuted direction
+    if det == 0.0 || (cull_backfaces && det * d.z >= 0.0) {
+        return vec3f(NO_HIT, NO_HIT, NO_HIT); // Parallel or culled
     }
-    var t = inv_det * dot(edge2, q);
-    // TODO: Fix near plane for reflections, setting BIAS to 0.0 results in artifacts
-    // See https://www.pbr-book.org/4ed/Shapes/Managing_Rounding_Error for better solution
-    if t < BIAS {
+
+    // Compute the scaled distance and reject hits behind the origin
+    let z = vec3f(p0.z, p1.z, p2.z) * shear.z;
+    let t_scaled = e0 * z.x + e1 * z.y + e2 * z.z;
+    if (det < 0.0 && t_scaled >= 0.0) || (det > 0.0 && t_scaled <= 0.0) {
+        return vec3f(NO_HIT, NO_HIT, NO_HIT); // Behind
+    }
+    let inv_det = 1.0 / det;
+    let t = t_scaled * inv_det;
+
+    // Reject hits whose distance can not be distinguished from zero given the rounding error
+    let max_z = max_component(abs(z));
+    let max_x = max_component(abs(vec3f(p0.x, p1.x, p2.x)));
+    let max_y = max_component(abs(vec3f(p0.y, p1.y, p2.y)));
+    let max_e = max_component(abs(vec3f(e0, e1, e2)));
+    let delta_z = gamma(3.0) * max_z;
+    let delta_x = gamma(5.0) * (max_x + max_z);
+    let delta_y = gamma(5.0) * (max_y + max_z);
+    let delta_e = 2.0 * (gamma(2.0) * max_x * max_y + delta_y * max_x + delta_x * max_y);
+    let delta_t = 3.0 * (gamma(3.0) * max_e * max_z + delta_e * max_z + delta_z * max_e) * abs(inv_det);
+    if t <= delta_t {
         return vec3f(NO_HIT, NO_HIT, NO_HIT); // Behind
     }
-    return vec3f(t, u, v);
+    return vec3f(t, e1 * inv_det, e2 * inv_det);
+}
+
+fn max_component(v: vec3f) -> f32 {
+    return max(v.x, max(v.y, v.z));
 }
 
 // From https://tavianator.com/2022/ray_box_boundary.html
@@ -84,11 +167,14 @@ fn intersect_AABB(ray: Ray, aabb: AABB) -> f32 {
     var t_1 = min(t_min, t_max);
     var t_2 = max(t_min, t_max);
     var t_near = max(t_1.x, max(t_1.y, t_1.z));
-    var t_far = min(t_2.x, min(t_2.y, t_2.z));
+    // Note: Grow the far distance by the rounding error so that rays through the boundary do not miss
+    var t_far = min(t_2.x, min(t_2.y, t_2.z)) * (1.0 + 2.0 * gamma(3.0));
     return select(NO_HIT, t_near, t_near <= t_far && t_far >= 0.0);
 }
 
 const EMISSIVE = 1u;
+// The ray hit the back side of the triangle, only possible for instances without backface culling
+const BACK_FACE = 2u;
 
 // Note: The fields are ordered to be aligned to 16 bytes
 struct HitInfo {
@@ -97,6 +183,9 @@ struct HitInfo {
     // Note: this is unnormalized to enable MikkTSpace
     normal: vec3f,
     n_aabb: u32,
+    // Note: this is unnormalized and faces the front side of the triangle
+    geometric_normal: vec3f,
+    instance: u32,
     texcoord: vec2f,
     n_tri: u32,
     roughness: f32,
@@ -105,10 +194,13 @@ struct HitInfo {
     tangent: vec4f,
     metallic: f32,
     flags: u32,
+    transmission: f32,
+    // Note: Conservative bound of the absolute rounding error of the position
+    position_error: vec3f,
 };
 
 fn no_hit_info() -> HitInfo {
-    return HitInfo(vec3f(0.0), NO_HIT, vec3f(0.0), 0u, vec2f(0.0), 0u, 0.0, vec4f(0.0), vec4f(0.0), 0.0, 0u);
+    return HitInfo(vec3f(0.0), NO_HIT, vec3f(0.0), 0u, vec3f(0.0), 0u, vec2f(0.0), 0u, 0.0, vec4f(0.0), vec4f(0.0), 0.0, 0u, 0.0, vec3f(0.0));
 }
 
 struct RawHit {
@@ -135,7 +227,7 @@ struct StackEntry {
     dist: f32,
 };
 
-fn intersect_scene(ray: Ray) -> HitInfo {
+fn intersect_scene(ray: Ray, cone: RayCone) -> HitInfo {
     let hit = intersect_TLAS(ray);
 
     var info: HitInfo;
@@ -144,34 +236,143 @@ fn intersect_scene(ray: Ray) -> HitInfo {
     if info.dist == NO_HIT { return info; }
 
     let instance = instances[hit.instance];
-    
-    info.position = ray.origin + hit.dist * ray.direction;
 
     let v0 = vertices[hit.i0];
     let v1 = vertices[hit.i1];
     let v2 = vertices[hit.i2];
+
+    // Note: Interpolating the vertices is more accurate than following the ray and has a known error bound
+    let b = hit.barycentrics;
+    let local_position = mat3x3f(v0.position, v1.position, v2.position) * b;
+    let local_error = gamma(7.0) * (abs(b.x * v0.position) + abs(b.y * v1.position) + abs(b.z * v2.position));
+    info.position = (instance.local_to_world * vec4f(local_position, 1.0)).xyz;
+    info.position_error = transform_error(instance.local_to_world, local_position, local_error);
                     
     let local_normal = mat3x3f(v0.normal, v1.normal, v2.normal) * hit.barycentrics;
     let local_tangent = mat3x4f(v0.tangent, v1.tangent, v2.tangent) * hit.barycentrics;
 
+    let local_geometric_normal = cross(v1.position - v0.position, v2.position - v0.position);
+
+    info.instance = hit.instance;
     info.normal = transpose(mat3(instance.world_to_local)) * local_normal;
+    info.geometric_normal = transpose(mat3(instance.world_to_local)) * local_geometric_normal;
     info.tangent = vec4f(mat3(instance.local_to_world) * local_tangent.xyz, local_tangent.w);
     
     // TODO: Benchmark?
     // info.texcoord = hit.barycentrics * mat2x3f(vec3f(v0.u, v1.u, v2.u), vec3f(v0.v, v1.v, v2.v)); // 16.7 44.7
     info.texcoord = mat3x2f(vec2f(v0.u, v0.v), vec2f(v1.u, v1.v), vec2f(v2.u, v2.v)) * hit.barycentrics; // 16.4 44.3
 
+    // Footprint of the ray cone in texture coordinates from the ratio of the texture and world space areas of the triangle
+    let world_area = length(cross(mat3(instance.local_to_world) * (v1.position - v0.position), mat3(instance.local_to_world) * (v2.position - v0.position)));
+    let uv_area = abs(determinant(mat2x2f(vec2f(v1.u - v0.u, v1.v - v0.v), vec2f(v2.u - v0.u, v2.v - v0.v))));
+    let width = cone.width + cone.spread * hit.dist * length(ray.direction);
+    let cos_theta = abs(dot(normalize(ray.direction), normalize(info.geometric_normal)));
+    let footprint = 0.5 * log2(uv_area / world_area) + log2(width / cos_theta);
+
     info.color = instance.color;
     info.roughness = instance.roughness;
     info.metallic = instance.metallic;
+    info.transmission = instance.transmission;
     info.flags = 0u;
+    if dot(info.geometric_normal, ray.direction) > 0.0 {
+        info.flags |= BACK_FACE;
+    }
     if instance.emissive > 0.0 {
         info.flags |= EMISSIVE;
+        if instance.emissive_texture != NO_TEXTURE {
+            info.color *= vec4f(srgb_to_linear(sample_texture(instance.emissive_texture, info.texcoord, footprint).rgb), 1.0);
+        }
+        return info;
+    }
+
+    if instance.base_color_texture != NO_TEXTURE {
+        let base_color = sample_texture(instance.base_color_texture, info.texcoord, footprint);
+        info.color *= vec4f(srgb_to_linear(base_color.rgb), base_color.a);
+    }
+
+    if instance.metallic_roughness_texture != NO_TEXTURE {
+        // Roughness is stored in the green channel, metalness in the blue channel
+        let metallic_roughness = sample_texture(instance.metallic_roughness_texture, info.texcoord, footprint);
+        info.roughness *= metallic_roughness.g;
+        info.metallic *= metallic_roughness.b;
+    }
+
+    if instance.transmission_texture != NO_TEXTURE {
+        info.transmission *= sample_texture(instance.transmission_texture, info.texcoord, footprint).r;
+    }
+
+    if instance.normal_texture != NO_TEXTURE {
+        let tangent_normal = (sample_texture(instance.normal_texture, info.texcoord, footprint).xyz * 2.0 - 1.0) * vec3f(instance.normal_scale, instance.normal_scale, 1.0);
+        info.normal = mikktspace(info) * tangent_normal;
     }
 
     return info;
 };
 
+/// Bounds the absolute error of transforming the point `p` with the affine `transform`, where `p` has the error `p_error`
+fn transform_error(transform: mat4x4f, p: vec3f, p_error: vec3f) -> vec3f {
+    let m = mat3x3f(abs(transform[0].xyz), abs(transform[1].xyz), abs(transform[2].xyz));
+    return (1.0 + gamma(3.0)) * (m * p_error) + gamma(3.0) * (m * abs(p) + abs(transform[3].xyz));
+}
+
+/// Returns a ray from the hit position that can not intersect the hit surface again.
+/// The origin is moved along the geometric normal just beyond the error bounds of the position.
+fn spawn_ray(hit: HitInfo, direction: vec3f) -> Ray {
+    let n = normalize(hit.geometric_normal);
+    var offset = dot(abs(n), hit.position_error) * n;
+    if dot(direction, n) < 0.0 {
+        offset = -offset;
+    }
+    var origin = hit.position + offset;
+    // Round away from the surface so that the offset is not lost to rounding
+    for (var i = 0u; i < 3u; i += 1u) {
+        if offset[i] > 0.0 {
+            origin[i] = next_float_up(origin[i]);
+        } else if offset[i] < 0.0 {
+            origin[i] = next_float_down(origin[i]);
+        }
+    }
+    return Ray(origin, direction, 1.0 / direction);
+}
+
+fn next_float_up(v: f32) -> f32 {
+    // Note: -0.0 and 0.0 need to step to the smallest positive float
+    let bits = bitcast<u32>(select(v, 0.0, v == 0.0));
+    return bitcast<f32>(select(bits - 1u, bits + 1u, v >= 0.0));
+}
+
+fn next_float_down(v: f32) -> f32 {
+    let bits = bitcast<u32>(select(v, -0.0, v == 0.0));
+    return bitcast<f32>(select(bits - 1u, bits + 1u, v <= 0.0));
+}
+
+/// Trilinearly filtered lookup with repeating texture coordinates in the atlas region of the texture.
+/// `footprint` is the base 2 logarithm of the filter width in texture coordinates.
+fn sample_texture(index: u32, texcoord: vec2f, footprint: f32) -> vec4f {
+    let entry = texture_entries[index];
+    let level = clamp(footprint + 0.5 * log2(f32(entry.size.x * entry.size.y)), 0.0, f32(entry.max_level));
+    let l0 = u32(level);
+    let l1 = min(l0 + 1u, entry.max_level);
+    return mix(sample_texture_level(entry, texcoord, l0), sample_texture_level(entry, texcoord, l1), fract(level));
+}
+
+/// Bilinearly filtered lookup in one mip level of an atlas region
+fn sample_texture_level(entry: TextureEntry, texcoord: vec2f, level: u32) -> vec4f {
+    let size = vec2f(entry.size >> vec2u(level));
+    let p = texcoord * size - 0.5;
+    var p0 = floor(p);
+    let f = p - p0;
+    // Note: Wrapping with floor instead of % keeps negative coordinates repeating
+    p0 -= floor(p0 / size) * size;
+    let offset = entry.offset >> vec2u(level);
+    // Note: naga rejects u32 mip levels next to an array index
+    let t00 = textureLoad(textures, offset + vec2u(p0), entry.page, i32(level));
+    let t10 = textureLoad(textures, offset + vec2u((p0 + vec2f(1.0, 0.0)) % size), entry.page, i32(level));
+    let t01 = textureLoad(textures, offset + vec2u((p0 + vec2f(0.0, 1.0)) % size), entry.page, i32(level));
+    let t11 = textureLoad(textures, offset + vec2u((p0 + 1.0) % size), entry.page, i32(level));
+    return mix(mix(t00, t10, f.x), mix(t01, t11, f.x), f.y);
+}
+
 // TODO: Implement HW raytracing
 fn intersect_TLAS(ray: Ray) -> RawHit {
     var stack: array<StackEntry, TLAS_STACK_SIZE>;
@@ -200,14 +401,17 @@ fn intersect_TLAS(ray: Ray) -> RawHit {
         if is_leaf { // Leaf node
             for (var j = node.start; j < node.end; j += 1u) {
                 let instance = instances[j];
-                let local_origin = instance.world_to_local * vec4f(ray.origin, 1.0);
                 let local_direction = mat3(instance.world_to_local) * ray.direction;
-                let local_ray = Ray(local_origin.xyz, local_direction, 1.0 / local_direction);
-                let hit_local = intersect_BLAS(local_ray, instance.node);
+                // Advance the origin past the transformation error so that spawned rays stay off their surface
+                let origin_error = transform_error(instance.world_to_local, ray.origin, vec3f(0.0));
+                let advance = dot(abs(local_direction), origin_error) / dot(local_direction, local_direction);
+                let local_origin = (instance.world_to_local * vec4f(ray.origin, 1.0)).xyz + advance * local_direction;
+                let local_ray = Ray(local_origin, local_direction, 1.0 / local_direction);
+                let hit_local = intersect_BLAS(local_ray, j);
                 hit.n_aabb += hit_local.n_aabb;
                 hit.n_tri += hit_local.n_tri;
-                if hit_local.dist < hit.dist {
-                    hit.dist = hit_local.dist;
+                if hit_local.dist + advance < hit.dist {
+                    hit.dist = hit_local.dist + advance;
                     hit.instance = j;
                     hit.i0 = hit_local.i0;
                     hit.i1 = hit_local.i1;
@@ -249,71 +453,41 @@ fn intersect_TLAS(ray: Ray) -> RawHit {
     return hit;
 }
 
-fn intersect_BLAS(ray: Ray, index_top: u32) -> RawHit {
-    var stack: array<StackEntry, BLAS_STACK_SIZE>;
-
-    var hit = no_raw_hit();
-
-    // Init stack with top node
-    var i = 0u;
-    let top = blas[index_top];
-    let dist_top = intersect_AABB(ray, AABB(top.min, top.max));
-    hit.n_aabb += 1u;
-    if dist_top < hit.dist {
-        stack[i] = StackEntry(index_top, dist_top);
-        i += 1u;
+/// Returns true if the surface of the instance is opaque at the given point.
+/// Blended surfaces are opaque with a probability of their alpha, which stays the same for a ray and a triangle.
+fn alpha_test(instance: Instance, ray: Ray, triangle: u32, v0: Vertex, v1: Vertex, v2: Vertex, barycentrics: vec3f) -> bool {
+    var alpha = instance.color.a;
+    if instance.base_color_texture != NO_TEXTURE {
+        let texcoord = mat3x2f(vec2f(v0.u, v0.v), vec2f(v1.u, v1.v), vec2f(v2.u, v2.v)) * barycentrics;
+        alpha *= sample_texture(instance.base_color_texture, texcoord, POINT_FOOTPRINT).a;
     }
+    if (instance.flags & ALPHA_MASK) != 0u {
+        return alpha >= f32(extractBits(instance.flags, ALPHA_CUTOFF_OFFSET, 8u)) / 255.0;
+    }
+    let seed = bitcast<vec3u>(ray.direction) ^ bitcast<vec3u>(ray.origin).yzx;
+    return alpha > hash4f(vec4u(seed, triangle)).x;
+}
 
-    while i > 0u {
-        // Pop next node from stack
-        i -= 1u;
-        var stack_entry = stack[i];
-        if stack_entry.dist >= hit.dist { continue; } // Skip if node is farther than current hit
-        var node = blas[stack_entry.index];
-        let is_leaf = node.end > 0u;
-        if is_leaf { // Leaf node
-            for (var j = node.start * 3u; j < node.end * 3u; j += 3u) {
-                let i0 = indices[j + 0u]; let v0 = vertices[i0];
-                let i1 = indices[j + 1u]; let v1 = vertices[i1];
-                let i2 = indices[j + 2u]; let v2 = vertices[i2];
-                let t = intersect_triangle(ray, v0.position, v1.position, v2.position);
-                hit.n_tri += 1u;
-                if t.x < hit.dist {
-                    hit.dist = t.x;
-                    hit.barycentrics = vec3f(1.0 - t.y - t.z, t.yz);
-                    hit.i0 = i0; hit.i1 = i1; hit.i2 = i2;
-                }
-            }
-        } else {
-            let index_left = node.start;
-            let left_node = blas[index_left];
-            let left = StackEntry(index_left, intersect_AABB(ray, AABB(left_node.min, left_node.max)));
-
-            let index_right = index_left + 1u;
-            let right_node = blas[index_right];
-            let right = StackEntry(index_right, intersect_AABB(ray, AABB(right_node.min, right_node.max)));
-            
-            hit.n_aabb += 2u;
-
-            var far = left;
-            var near = right;
-            if left.dist < right.dist {
-                far = right;
-                near = left;
-            }
-
-            // Look at far node last
-            if far.dist < hit.dist {
-                stack[i] = far;
-                i += 1u;
-            }
-
-            // Look at near node first
-            if near.dist < hit.dist {
-                stack[i] = near;
-                i += 1u;
+/// Intersects the triangles in [start, end) of the instance and updates `hit` if one of them is closer
+fn intersect_triangles(ray: Ray, instance: Instance, start: u32, end: u32, hit: ptr<function, RawHit>) {
+    // Note: Rays need to leave transmissive instances through their back faces
+    let cull_backfaces = (instance.flags & DOUBLE_SIDED) == 0u && instance.transmission == 0.0;
+    let alpha_tested = (instance.flags & (ALPHA_MASK | ALPHA_BLEND)) != 0u;
+
+    for (var j = start * 3u; j < end * 3u; j += 3u) {
+        let i0 = indices[j + 0u]; let v0 = vertices[i0];
+        let i1 = indices[j + 1u]; let v1 = vertices[i1];
+        let i2 = indices[j + 2u]; let v2 = vertices[i2];
+        let t = intersect_triangle(ray, v0.position, v1.position, v2.position, cull_backfaces);
+        (*hit).n_tri += 1u;
+        if t.x < (*hit).dist {
+            let barycentrics = vec3f(1.0 - t.y - t.z, t.yz);
+            if alpha_tested && !alpha_test(instance, ray, j / 3u, v0, v1, v2, barycentrics) {
+                continue;
             }
+            (*hit).dist = t.x;
+            (*hit).barycentrics = barycentrics;
+            (*hit).i0 = i0; (*hit).i1 = i1; (*hit).i2 = i2;
         }
     }
-    return hit;
 }
\ No newline at end of file
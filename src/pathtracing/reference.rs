@@ -0,0 +1,614 @@
+use std::f32::consts::PI;
+use std::sync::Mutex;
+
+use glam::{Mat3, Mat4, UVec4, Vec2, Vec3, Vec4, Vec4Swizzles};
+use image::Rgba32FImage;
+use sobol_burley::sample_4d;
+
+use crate::common::camera::Camera;
+use crate::common::HdrImage;
+use super::envmap::{Background, EnvDistribution, EnvMapError, EnvSettings, EnvSource, DISTRIBUTION_SIZE};
+use super::lights::luminance;
+use super::pathtracer::Pathtracer;
+use super::raytracing_cpu::{hash4f, CpuScene, Ray, RayCone, BACK_FACE, EMISSIVE, NO_HIT};
+use super::scene::Scene;
+use super::sky::{direction_to_equirect, equirect_to_direction};
+
+// Note: This is a port of pathtracing.wgsl, changes to either need to be mirrored in the other
+
+const INV_PI: f32 = 1.0 / PI;
+/// Perfect mirrors can not be evaluated for light samples, so alpha2 is clamped to keep the NDF finite
+const MIN_ALPHA2: f32 = 1e-7;
+/// Each cell of the environment distribution averages SUBSAMPLES x SUBSAMPLES lookups, matches env_distribution.wgsl
+const SUBSAMPLES: u32 = 4;
+
+/// Trilinear lookup in an equirectangular mip chain, wrapping around horizontally
+fn sample_mip_chain(levels: &[Rgba32FImage], uv: Vec2, lod: f32) -> Vec3 {
+    let lod = lod.clamp(0.0, (levels.len() - 1) as f32);
+    let level = lod.floor() as usize;
+    let sample = |image: &Rgba32FImage| {
+        let size = Vec2::new(image.width() as f32, image.height() as f32);
+        let p = uv * size - 0.5;
+        let p0 = p.floor();
+        let f = p - p0;
+        let texel = |dx: f32, dy: f32| {
+            let x = (p0.x + dx).rem_euclid(size.x) as u32;
+            let y = (p0.y + dy).clamp(0.0, size.y - 1.0) as u32;
+            Vec4::from(image.get_pixel(x.min(image.width() - 1), y).0).xyz()
+        };
+        let top = texel(0.0, 0.0).lerp(texel(1.0, 0.0), f.x);
+        let bottom = texel(0.0, 1.0).lerp(texel(1.0, 1.0), f.x);
+        top.lerp(bottom, f.y)
+    };
+    let color = sample(&levels[level]);
+    match levels.get(level + 1) {
+        Some(next) if lod > level as f32 => color.lerp(sample(next), lod - level as f32),
+        _ => color,
+    }
+}
+
+/// Environment map kept as an equirectangular mip chain instead of a cubemap
+pub struct CpuEnvironment {
+    levels: Vec<Rgba32FImage>,
+    distribution: EnvDistribution,
+    world_to_env: Mat3,
+    exposure: f32,
+    background: Background,
+}
+
+impl CpuEnvironment {
+    pub fn new(source: &EnvSource, settings: &EnvSettings) -> Result<Self, EnvMapError> {
+        let timer = std::time::Instant::now();
+        let mut levels = vec![source.to_equirect()?];
+        while let Some(last) = levels.last().filter(|l| l.width() > 1 && l.height() > 1) {
+            levels.push(image::imageops::resize(last, last.width() / 2, last.height() / 2, image::imageops::FilterType::Triangle));
+        }
+
+        // Project onto an equirectangular grid of luminance weighted by sin(theta) like env_distribution.wgsl
+        let (width, height) = DISTRIBUTION_SIZE;
+        let texels_per_cell = levels[0].width() as f32 / width as f32;
+        let level = (texels_per_cell / SUBSAMPLES as f32).log2().max(0.0);
+        let mut weights = Vec::with_capacity((width * height) as usize);
+        for (y, x) in itertools::iproduct!(0..height, 0..width) {
+            let mut sum = 0.0;
+            for (sy, sx) in itertools::iproduct!(0..SUBSAMPLES, 0..SUBSAMPLES) {
+                let offset = (Vec2::new(sx as f32, sy as f32) + 0.5) / SUBSAMPLES as f32;
+                let uv = (Vec2::new(x as f32, y as f32) + offset) / Vec2::new(width as f32, height as f32);
+                sum += luminance(sample_mip_chain(&levels, uv, level)) * (PI * uv.y).sin();
+            }
+            weights.push(sum / (SUBSAMPLES * SUBSAMPLES) as f32);
+        }
+
+        log::info!("Built CPU environment in {:?}", timer.elapsed());
+        Ok(Self {
+            levels,
+            distribution: EnvDistribution::new(&weights, width, height),
+            world_to_env: settings.world_to_env(),
+            exposure: settings.exposure,
+            background: settings.background,
+        })
+    }
+
+    /// Radiance arriving from the environment along a world space direction
+    pub fn radiance(&self, dir: Vec3, lod: f32) -> Vec3 {
+        let env_dir = (self.world_to_env * dir).normalize();
+        self.exposure * sample_mip_chain(&self.levels, direction_to_equirect(env_dir), lod)
+    }
+
+    /// Radiance seen by camera rays that miss the scene
+    pub fn background_radiance(&self, dir: Vec3) -> Vec3 {
+        match self.background {
+            Background::Environment => self.radiance(dir, 0.0),
+            Background::Blurred { blur } => self.radiance(dir, blur.clamp(0.0, 1.0) * (self.levels.len() - 1) as f32),
+            Background::Solid(color) => color,
+        }
+    }
+
+    /// Returns the bin i with cdf[offset + i] <= rand < cdf[offset + i + 1] using a binary search
+    fn sample_cdf(&self, offset: usize, count: usize, rand: f32) -> usize {
+        let cdf = &self.distribution.cdf[offset + 1..offset + count];
+        cdf.partition_point(|&c| c <= rand)
+    }
+
+    /// Samples a world space direction proportional to the environment luminance, returns the direction and its density
+    pub fn sample(&self, rand: Vec2) -> (Vec3, f32) {
+        let (width, height) = (self.distribution.width as usize, self.distribution.height as usize);
+        let cdf = &self.distribution.cdf;
+
+        let row = self.sample_cdf(0, height, rand.y);
+        let (m0, m1) = (cdf[row], cdf[row + 1]);
+        let v = (row as f32 + (rand.y - m0) / (m1 - m0)) / height as f32;
+
+        let offset = height + 1 + row * (width + 1);
+        let col = self.sample_cdf(offset, width, rand.x);
+        let (c0, c1) = (cdf[offset + col], cdf[offset + col + 1]);
+        let u = (col as f32 + (rand.x - c0) / (c1 - c0)) / width as f32;
+
+        let pdf_uv = (m1 - m0) * height as f32 * (c1 - c0) * width as f32;
+        let sin_theta = (PI * v).sin();
+        // Jacobian of the equirectangular mapping: dω = 2π² sin(θ) du dv
+        let pdf = if sin_theta > 0.0 { pdf_uv / (2.0 * PI * PI * sin_theta) } else { 0.0 };
+        // Note: The inverse of the rotation is its transpose and it does not change the density
+        (self.world_to_env.transpose() * equirect_to_direction(u, v), pdf)
+    }
+
+    /// Solid angle density of sampling the world space direction `dir` with `sample`
+    pub fn pdf(&self, dir: Vec3) -> f32 {
+        let (width, height) = (self.distribution.width as usize, self.distribution.height as usize);
+        let cdf = &self.distribution.cdf;
+        let uv = direction_to_equirect((self.world_to_env * dir).normalize());
+        let row = ((uv.y * height as f32) as usize).min(height - 1);
+        let col = ((uv.x * width as f32) as usize).min(width - 1);
+
+        let offset = height + 1 + row * (width + 1);
+        let pdf_row = (cdf[row + 1] - cdf[row]) * height as f32;
+        let pdf_col = (cdf[offset + col + 1] - cdf[offset + col]) * width as f32;
+        let sin_theta = (PI * uv.y).sin();
+        if sin_theta > 0.0 { pdf_row * pdf_col / (2.0 * PI * PI * sin_theta) } else { 0.0 }
+    }
+
+    fn integral(&self) -> f32 {
+        self.distribution.integral
+    }
+}
+
+/// Sample visible normal distribution function using the algorithm
+/// from "Sampling Visible GGX Normals with Spherical Caps" by Dupuy et al. 2023.
+fn sample_vndf_iso(rand: Vec2, wi: Vec3, alpha: f32, n: Vec3) -> Vec3 {
+    // Note: Else produces NaN for alpha = 0
+    if alpha == 0.0 { return n; }
+    // Decompose the vector in parallel and perpendicular components
+    let wi_z = n * wi.dot(n);
+    let wi_xy = wi - wi_z;
+    // Warp to the hemisphere configuration
+    let wi_std = (wi_z - alpha * wi_xy).normalize();
+    // Sample a spherical cap in (-wi_std.z, 1]
+    let wi_std_z = wi_std.dot(n);
+    let phi = (2.0 * rand.x - 1.0) * PI;
+    let z = (1.0 - rand.y) * (1.0 + wi_std_z) - wi_std_z;
+    let sin_theta = (1.0 - z * z).clamp(0.0, 1.0).sqrt();
+    let c_std = Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), z);
+    // Reflect sample to align with normal
+    let wr = n + Vec3::Z;
+    // Prevent division by zero
+    let safe_wrz = wr.z.max(1e-6);
+    let c = wr.dot(c_std) * wr / safe_wrz - c_std;
+    // Compute halfway direction as standard normal
+    let wm_std = c + wi_std;
+    let wm_std_z = n * n.dot(wm_std);
+    let wm_std_xy = wm_std_z - wm_std;
+    // Warp back to the ellipsoid configuration
+    (wm_std_z + alpha * wm_std_xy).normalize()
+}
+
+fn sample_cosine_hemisphere(rand: Vec2) -> Vec3 {
+    let phi = 2.0 * PI * rand.x;
+    let sin_theta = (1.0 - rand.y).sqrt();
+    let cos_theta = rand.y.sqrt();
+    Vec3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, cos_theta)
+}
+
+/// Schlick's approximation for the Fresnel term
+fn f_schlick_approx(h_dot_v: f32, r0: Vec3) -> Vec3 {
+    r0 + (1.0 - r0) * (1.0 - h_dot_v).powf(5.0)
+}
+
+/// Unpolarized Fresnel reflectance of a dielectric interface, eta is the relative index of refraction η_i / η_t
+fn f_dielectric(cos_theta_i: f32, eta: f32) -> f32 {
+    let sin2_theta_t = eta * eta * (1.0 - cos_theta_i * cos_theta_i);
+    if sin2_theta_t >= 1.0 { return 1.0; }
+    let cos_theta_t = (1.0 - sin2_theta_t).sqrt();
+    let rs = (eta * cos_theta_i - cos_theta_t) / (eta * cos_theta_i + cos_theta_t);
+    let rp = (cos_theta_i - eta * cos_theta_t) / (cos_theta_i + eta * cos_theta_t);
+    0.5 * (rs * rs + rp * rp)
+}
+
+/// Lambda for the Trowbridge-Reitz NDF
+fn lambda_trowbridge_reitz(n_dot_v: f32, alpha2: f32) -> f32 {
+    let cos2_theta = n_dot_v * n_dot_v;
+    let tan2_theta = (1.0 - cos2_theta) / cos2_theta;
+    (-1.0 + (1.0 + alpha2 * tan2_theta).sqrt()) / 2.0
+}
+
+/// Smith's shadowing-masking function for the Trowbridge-Reitz NDF
+fn g2_trowbridge_reitz(n_dot_l: f32, n_dot_v: f32, alpha2: f32) -> f32 {
+    1.0 / (1.0 + lambda_trowbridge_reitz(n_dot_l, alpha2) + lambda_trowbridge_reitz(n_dot_v, alpha2))
+}
+
+/// Trowbridge-Reitz (GGX) normal distribution function
+fn d_trowbridge_reitz(n_dot_h: f32, alpha2: f32) -> f32 {
+    let d = n_dot_h * n_dot_h * (alpha2 - 1.0) + 1.0;
+    alpha2 / (PI * d * d)
+}
+
+/// Smith's masking function for the Trowbridge-Reitz NDF
+fn g1_trowbridge_reitz(n_dot_v: f32, alpha2: f32) -> f32 {
+    1.0 / (1.0 + lambda_trowbridge_reitz(n_dot_v, alpha2))
+}
+
+/// Reorthogonalizes a tangent space using the Gram-Schmidt process and returns an orthonormal tangent space matrix
+fn build_tbn(n: Vec3, t: Vec3) -> Mat3 {
+    let nt = (t - t.dot(n) * n).normalize();
+    Mat3::from_cols(nt, n.cross(nt), n)
+}
+
+fn reflect(i: Vec3, n: Vec3) -> Vec3 {
+    i - 2.0 * n.dot(i) * n
+}
+
+/// Same as the WGSL builtin, returns zero for total internal reflection
+fn refract(i: Vec3, n: Vec3, eta: f32) -> Vec3 {
+    let k = 1.0 - eta * eta * (1.0 - n.dot(i) * n.dot(i));
+    if k < 0.0 { Vec3::ZERO } else { eta * i - (eta * n.dot(i) + k.sqrt()) * n }
+}
+
+struct BrdfEval {
+    /// BRDF times the cosine term
+    value: Vec3,
+    /// Solid angle density of sampling the direction with the lobe probabilities of `sample_rendering_eq`
+    pdf: f32,
+}
+
+/// Evaluates the specular Trowbridge-Reitz and the diffuse Brent-Burley lobes for a given incident direction
+#[allow(clippy::too_many_arguments)]
+fn eval_brdf(wo: Vec3, wi: Vec3, n: Vec3, albedo: Vec3, metallic: f32, alpha: f32, p_specular: f32, transmission: f32) -> BrdfEval {
+    let cos_theta_o = wo.dot(n);
+    let cos_theta_i = wi.dot(n);
+    if cos_theta_o <= 0.0 || cos_theta_i <= 0.0 {
+        return BrdfEval { value: Vec3::ZERO, pdf: 0.0 };
+    }
+    let alpha2 = (alpha * alpha).max(MIN_ALPHA2);
+    let wm = (wi + wo).normalize();
+    let cos_theta_d = wi.dot(wm);
+    let d = d_trowbridge_reitz(wm.dot(n), alpha2);
+
+    let f0 = Vec3::splat(0.04).lerp(albedo, metallic);
+    let f = f_schlick_approx(cos_theta_d, f0);
+    let specular = f * d * g2_trowbridge_reitz(cos_theta_i, cos_theta_o, alpha2) / (4.0 * cos_theta_o);
+    let pdf_specular = g1_trowbridge_reitz(cos_theta_o, alpha2) * d / (4.0 * cos_theta_o);
+
+    let fd90 = 0.5 + 2.0 * alpha * cos_theta_d.powf(2.0);
+    let response = (1.0 + (fd90 - 1.0) * (1.0 - cos_theta_i).powf(5.0)) * (1.0 + (fd90 - 1.0) * (1.0 - cos_theta_o).powf(5.0));
+    // Note: Transmission replaces the diffuse base below the specular layer
+    let diffuse = (1.0 - metallic) * (1.0 - transmission) * albedo * response * INV_PI * cos_theta_i;
+    let pdf_diffuse = (1.0 - transmission) * cos_theta_i * INV_PI;
+
+    BrdfEval {
+        value: specular + diffuse,
+        pdf: pdf_diffuse + (pdf_specular - pdf_diffuse) * p_specular,
+    }
+}
+
+/// Samples the transmission through a rough dielectric from "Microfacet Models for Refraction through Rough Surfaces" by Walter et al. 2007,
+/// which replaces the diffuse base below the specular layer, matches `sample_transmission` in pathtracing.wgsl.
+/// Returns the incident direction and the sampled BSDF times the cosine term divided by the density.
+#[allow(clippy::too_many_arguments)]
+fn sample_transmission(rand: Vec2, wo: Vec3, n: Vec3, tint: Vec3, f0: Vec3, alpha: f32, eta: f32, thin_walled: bool) -> (Vec3, Vec3) {
+    let wm = sample_vndf_iso(rand, wo, alpha, n);
+    let f = f_dielectric(wo.dot(wm), eta);
+    let (wi, weight) = if f >= 1.0 {
+        // Total internal reflection of everything the specular layer does not reflect
+        let wi = reflect(-wo, wm);
+        if wi.dot(n) <= 0.0 { return (wi, Vec3::ZERO); }
+        (wi, 1.0 - f_schlick_approx(wo.dot(wm), f0))
+    } else {
+        let (wi, weight) = if thin_walled {
+            // Mirror the reflection through the surface
+            let wi = reflect(-wo, wm);
+            (wi - 2.0 * wi.dot(n) * n, tint * (1.0 - f))
+        } else {
+            // Radiance is compressed into a smaller solid angle when entering a denser medium
+            (refract(-wo, wm, eta), tint * (1.0 - f) * eta * eta)
+        };
+        if wi.dot(n) >= 0.0 { return (wi, Vec3::ZERO); }
+        (wi, weight)
+    };
+    // The microfacet is sampled from the VNDF, leaving G2 / G1
+    let alpha2 = alpha * alpha;
+    let lambda_l = lambda_trowbridge_reitz(wi.dot(n), alpha2);
+    let lambda_v = lambda_trowbridge_reitz(wo.dot(n), alpha2);
+    (wi, weight * (1.0 + lambda_v) / (1.0 + lambda_l + lambda_v))
+}
+
+/// Power heuristic with beta = 2 for multiple importance sampling
+fn mis_weight(pdf: f32, other_pdf: f32) -> f32 {
+    let pdf2 = pdf * pdf;
+    pdf2 / (pdf2 + other_pdf * other_pdf)
+}
+
+/// Uniformly samples a point on a triangle
+fn sample_triangle(rand: Vec2, p0: Vec3, p1: Vec3, p2: Vec3) -> Vec3 {
+    let su = rand.x.sqrt();
+    let b0 = 1.0 - su;
+    let b1 = rand.y * su;
+    b0 * p0 + b1 * p1 + (1.0 - b0 - b1) * p2
+}
+
+/// CPU path tracer producing the same estimate as the GPU for comparisons and machines without a GPU
+pub struct ReferencePathtracer {
+    scene: CpuScene,
+    environment: CpuEnvironment,
+    clip_to_world: Mat4,
+    width: u32,
+    height: u32,
+    pub bounces: u32,
+    pub contribution_factor: f32,
+}
+
+impl ReferencePathtracer {
+    /// Note: The resolution is rounded down to a multiple of the compute workgroup size like on the GPU,
+    /// so that both images can be compared pixel by pixel, and is at least one workgroup
+    pub fn new(scene: &Scene, environment: CpuEnvironment, camera: &Camera, size: (u32, u32)) -> Self {
+        Self {
+            scene: CpuScene::new(scene),
+            environment,
+            clip_to_world: camera.buffer_data().clip_to_world,
+            width: (size.0 / Pathtracer::COMPUTE_SIZE * Pathtracer::COMPUTE_SIZE).max(Pathtracer::COMPUTE_SIZE),
+            height: (size.1 / Pathtracer::COMPUTE_SIZE * Pathtracer::COMPUTE_SIZE).max(Pathtracer::COMPUTE_SIZE),
+            bounces: 8,
+            contribution_factor: 4.0,
+        }
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Renders `samples` samples per pixel using all available cores
+    pub fn render(&self, samples: u32) -> HdrImage {
+        let timer = std::time::Instant::now();
+        let mut pixels = vec![Vec4::ZERO; (self.width * self.height) as usize];
+        let rows = Mutex::new(pixels.chunks_mut(self.width as usize).enumerate());
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+
+        std::thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(|| loop {
+                    let Some((y, row)) = rows.lock().unwrap().next() else { break; };
+                    for (x, pixel) in row.iter_mut().enumerate() {
+                        let id = UVec4::new(x as u32, y as u32, x as u32, y as u32);
+                        let mut sum = Vec3::ZERO;
+                        for sample in 0..samples {
+                            sum += self.render_sample(sample, id);
+                        }
+                        *pixel = (sum / samples.max(1) as f32).extend(1.0);
+                    }
+                });
+            }
+        });
+
+        log::info!("Rendered {} samples on the CPU in {:?}", samples, timer.elapsed());
+        HdrImage { width: self.width, height: self.height, pixels }
+    }
+
+    /// Matches `main` in pathtracing.wgsl for the pixel `id.xy`
+    fn render_sample(&self, sample: u32, id: UVec4) -> Vec3 {
+        let shift = hash4f(id);
+        let jitter = sobol_burley(sample, 0, shift);
+        let dim = Vec2::new(self.width as f32, self.height as f32);
+        let ray = generate_ray(&self.clip_to_world, dim, id.xy().as_vec2(), jitter.xy());
+        self.sample_rendering_eq(sample, shift, ray)
+    }
+
+    /// Selects an emissive triangle proportional to its power
+    fn sample_light_index(&self, rand: f32) -> usize {
+        let top_level = &self.scene.data.top_level;
+        let lights = &top_level.lights[..top_level.light_info.count as usize];
+        lights.partition_point(|l| l.cdf <= rand).min(lights.len() - 1)
+    }
+
+    /// Solid angle density of sampling a point on an emissive triangle of `instance` seen from distance `dist`
+    fn light_pdf(&self, instance: u32, light_normal: Vec3, wi: Vec3, dist: f32) -> f32 {
+        let pdf_area = luminance(self.scene.instance(instance).color.xyz()) / self.scene.data.top_level.light_info.total_power;
+        let cos_light = light_normal.normalize().dot(wi).abs();
+        pdf_area * dist * dist / cos_light
+    }
+
+    fn sample_rendering_eq(&self, sample: u32, shift: Vec4, camera_ray: Ray) -> Vec3 {
+        let light_count = self.scene.data.top_level.light_info.count;
+        let env_integral = self.environment.integral();
+        let mut radiance = Vec3::ZERO;
+        let mut throughput = Vec3::ONE;
+        let mut ray = camera_ray;
+        let dim = Vec2::new(self.width as f32, self.height as f32);
+        let mut cone = RayCone { width: 0.0, spread: pixel_spread_angle(&self.clip_to_world, dim) };
+        // Density of the last BRDF sample for MIS, zero if the direction can not be sampled by next event estimation
+        let mut brdf_pdf = 0.0;
+        for bounce in 0..=self.bounces {
+            let hit = self.scene.intersect_scene(&ray, cone);
+
+            if hit.dist == NO_HIT {
+                if bounce == 0 {
+                    return self.environment.background_radiance(ray.direction);
+                }
+                let env_color = self.environment.radiance(ray.direction, 0.0);
+                let mut weight = 1.0;
+                if brdf_pdf > 0.0 && env_integral > 0.0 {
+                    weight = mis_weight(brdf_pdf, self.environment.pdf(ray.direction));
+                }
+                return radiance + throughput * weight * env_color;
+            }
+
+            if hit.flags & EMISSIVE != 0 {
+                let mut weight = 1.0;
+                if brdf_pdf > 0.0 && light_count > 0 {
+                    weight = mis_weight(brdf_pdf, self.light_pdf(hit.instance, hit.geometric_normal, ray.direction, hit.dist));
+                }
+                return radiance + throughput * weight * hit.color.xyz();
+            }
+
+            cone.width += cone.spread * hit.dist * ray.direction.length();
+
+            let instance = self.scene.instance(hit.instance);
+            let back_face = hit.flags & BACK_FACE != 0;
+            let thin_walled = instance.thickness == 0.0;
+            // Beer-Lambert absorption inside the volume the ray is leaving, the attenuation distance is given in the space of the mesh
+            if back_face && !thin_walled {
+                let local_dist = (Mat3::from_mat4(instance.world_to_local) * ray.direction).length() * hit.dist;
+                throughput *= (-instance.absorption * local_dist).exp();
+            }
+
+            // Collect hit info
+            let alpha = hit.roughness * hit.roughness;
+            let alpha2 = alpha * alpha;
+            // Note: Shading happens on the side of the incoming ray
+            let n = if back_face { -1.0 } else { 1.0 } * hit.normal.normalize();
+
+            // Collect bounce info
+            let [sobol_0, sobol_1, sobol_2, sobol_3] = [0, 1, 2, 3].map(|dim| {
+                sobol_burley(sample, 1 + bounce * Pathtracer::LDS_PER_BOUNCE + dim, shift)
+            });
+            let wo = (-ray.direction).normalize();
+            let cos_theta_o = wo.dot(n);
+
+            let metallic = hit.metallic;
+            let albedo = hit.color.xyz();
+
+            let f0 = Vec3::splat(0.04).lerp(albedo, metallic);
+            let specular_weight = luminance(f_schlick_approx(wo.dot(n), f0));
+            let diffuse_weight = (1.0 - metallic) * luminance(albedo);
+
+            let p_specular = specular_weight / (specular_weight + diffuse_weight);
+            let p_diffuse = 1.0 - p_specular;
+
+            // Next event estimation: Sample a point on an emissive triangle and trace a shadow ray towards it
+            if light_count > 0 && bounce < self.bounces {
+                let light = &self.scene.data.top_level.lights[self.sample_light_index(sobol_2.x)];
+                let light_position = sample_triangle(sobol_2.yz(), light.p0, light.p1, light.p2);
+                let light_normal = (light.p1 - light.p0).cross(light.p2 - light.p0);
+                let dist = (light_position - hit.position).length();
+                let wi_light = (light_position - hit.position) / dist;
+                let brdf = eval_brdf(wo, wi_light, n, albedo, metallic, alpha, p_specular, hit.transmission);
+                // Back faces do not emit unless they are double-sided
+                let double_sided = self.scene.instance(light.instance).is_double_sided();
+                if brdf.pdf > 0.0 && (light_normal.dot(wi_light) < 0.0 || double_sided) {
+                    let shadow = self.scene.intersect_scene(&hit.spawn_ray(wi_light), cone);
+                    // The light is visible if the sampled point is the first hit
+                    if shadow.flags & EMISSIVE != 0 && (shadow.dist - dist).abs() <= 1e-3 * dist {
+                        let pdf = self.light_pdf(light.instance, light_normal, wi_light, dist);
+                        radiance += throughput * brdf.value * shadow.color.xyz() * mis_weight(pdf, brdf.pdf) / pdf;
+                    }
+                }
+            }
+
+            // Next event estimation: Sample the environment and trace a shadow ray towards it
+            if env_integral > 0.0 && bounce < self.bounces {
+                let (env_direction, env_pdf) = self.environment.sample(sobol_3.xy());
+                let brdf = eval_brdf(wo, env_direction, n, albedo, metallic, alpha, p_specular, hit.transmission);
+                if brdf.pdf > 0.0 && env_pdf > 0.0 {
+                    let shadow = self.scene.intersect_tlas(&hit.spawn_ray(env_direction));
+                    if shadow.dist == NO_HIT {
+                        let env_color = self.environment.radiance(env_direction, 0.0);
+                        radiance += throughput * brdf.value * env_color * mis_weight(env_pdf, brdf.pdf) / env_pdf;
+                    }
+                }
+            }
+
+            // Note: The diffuse lobe stands for the whole base, which is partly replaced by transmission
+            let transmitted = sobol_0.x >= p_specular && sobol_1.x < hit.transmission;
+            let wi = if sobol_0.x < p_specular { // Trowbridge-Reitz-Specular
+                let wm = sample_vndf_iso(sobol_0.yz(), wo, alpha, n);
+                let wi = reflect(-wo, wm);
+                let cos_theta_d = wo.dot(wm);
+                let cos_theta_i = wi.dot(n);
+                let f = f_schlick_approx(cos_theta_d, f0);
+                let lambda_l = lambda_trowbridge_reitz(cos_theta_i, alpha2);
+                let lambda_v = lambda_trowbridge_reitz(cos_theta_o, alpha2);
+                throughput *= f * (1.0 + lambda_v) / (1.0 + lambda_l + lambda_v) / p_specular;
+                wi
+            } else if transmitted { // Rough dielectric transmission
+                // Note: Thin-walled surfaces are entered and left from air
+                let eta = if back_face && !thin_walled { instance.ior } else { 1.0 / instance.ior };
+                let (wi, weight) = sample_transmission(sobol_0.yz(), wo, n, albedo, f0, alpha, eta, thin_walled);
+                throughput *= (1.0 - metallic) * weight / p_diffuse;
+                wi
+            } else { // Brent-Burley-Diffuse
+                let tangent_to_world = build_tbn(n, hit.tangent.xyz());
+                let wi = tangent_to_world * sample_cosine_hemisphere(sobol_1.yz());
+                let wm = (wi + wo).normalize();
+                let cos_theta_d = wi.dot(wm);
+                let cos_theta_i = wi.dot(n);
+                let fd90 = 0.5 + 2.0 * alpha * cos_theta_d.powf(2.0);
+                let response = (1.0 + (fd90 - 1.0) * (1.0 - cos_theta_i).powf(5.0)) * (1.0 + (fd90 - 1.0) * (1.0 - cos_theta_o).powf(5.0));
+                // Note: We drop the 1.0 / PI prefactor
+                throughput *= (1.0 - metallic) * albedo * response / p_diffuse;
+                wi
+            };
+
+            // Note: Transmitted samples can not be generated by next event estimation
+            brdf_pdf = if transmitted {
+                0.0
+            } else {
+                eval_brdf(wo, wi, n, albedo, metallic, alpha, p_specular, hit.transmission).pdf
+            };
+
+            // Unbiased Russian Roulette path termination
+            let mut p_continue = (1.0 - (bounce as f32 / self.bounces as f32).powf(8.0)).min(1.0);
+            p_continue *= (luminance(throughput) * self.contribution_factor).min(1.0);
+
+            if sobol_0.z < p_continue {
+                throughput /= p_continue;
+            } else {
+                return radiance;
+            }
+
+            ray = hit.spawn_ray(wi);
+        }
+        radiance
+    }
+}
+
+/// Camera ray through the pixel `id` of an image of size `dim`, `rand` is the position inside the pixel
+pub fn generate_ray(clip_to_world: &Mat4, dim: Vec2, id: Vec2, rand: Vec2) -> Ray {
+    let uv = 2.0 * (id + rand) / dim - 1.0;
+
+    let world_pos = clip_to_world.w_axis;
+    let pos = world_pos.xyz() / world_pos.w;
+
+    let clip_dir = Vec4::new(-uv.x, -uv.y, -1.0, 1.0);
+    let world_dir = *clip_to_world * clip_dir;
+    let dir = pos - world_dir.xyz() / world_dir.w;
+
+    Ray::new(pos, dir)
+}
+
+/// Angle between the camera rays through neighboring pixels in the center of the image, the spread of the ray cones
+pub fn pixel_spread_angle(clip_to_world: &Mat4, dim: Vec2) -> f32 {
+    let world_pos = clip_to_world.w_axis;
+    let pos = world_pos.xyz() / world_pos.w;
+    let center = *clip_to_world * Vec4::new(0.0, 0.0, -1.0, 1.0);
+    let neighbor = *clip_to_world * Vec4::new(0.0, 2.0 / dim.y, -1.0, 1.0);
+    // Note: The chord between the unit directions is accurate for small angles
+    (pos - center.xyz() / center.w).normalize().distance((pos - neighbor.xyz() / neighbor.w).normalize())
+}
+
+/// Sobol-Burley sample with a Cranley-Patterson rotation, matches `sample_sobol_burley_bounce` in pathtracing.wgsl
+fn sobol_burley(sample: u32, dimension_set: u32, shift: Vec4) -> Vec4 {
+    let value = Vec4::from(sample_4d(sample, dimension_set, 0)) + shift;
+    value - value.floor()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transmission_takes_what_the_specular_layer_does_not_reflect() {
+        let n = Vec3::Z;
+        let f0 = Vec3::splat(0.04);
+        let rand = Vec2::splat(0.5);
+
+        // Smooth thin glass seen head-on reflects F0 and transmits the rest
+        let (wi, weight) = sample_transmission(rand, n, n, Vec3::ONE, f0, 1e-4, 1.0 / 1.5, true);
+        assert!(wi.dot(n) < 0.0);
+        let total = weight + f_schlick_approx(1.0, f0);
+        assert!(total.abs_diff_eq(Vec3::ONE, 1e-3), "{total}");
+
+        // Beyond the critical angle inside the medium the rest is reflected as well
+        let wo = Vec3::new(0.9, 0.0, 0.1).normalize();
+        let (wi, weight) = sample_transmission(rand, wo, n, Vec3::ONE, f0, 1e-4, 1.5, false);
+        assert!(wi.dot(n) > 0.0);
+        let total = weight + f_schlick_approx(wo.dot(n), f0);
+        assert!(total.abs_diff_eq(Vec3::ONE, 1e-3), "{total}");
+    }
+}
@@ -1,17 +1,36 @@
-use std::collections::HashMap;
-use std::{mem, ops::Range, path::Path};
+use std::collections::{HashMap, HashSet};
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+use std::{mem, ops::Range};
 
-use glam::{Mat4, Vec3, Vec4};
-use itertools::izip;
+use glam::{Mat4, Quat, Vec3, Vec4, Vec4Swizzles};
+use image::RgbaImage;
+use itertools::{iproduct, izip};
 use wgpu::util::DeviceExt;
 
-use super::bvh::{self, BVHPrimitive, BVHTree};
+use super::atlas::{AtlasLimits, TextureAtlas};
+use super::bvh::{self, BVHBuilder, BVHNode, BVHPrimitive, BVHTree};
+use super::bvh::wide::{self, WideBVH};
+use super::lights::{self, LightInfo, LightTriangle};
+use super::normals::{self, NormalGeneration};
+use super::tangents;
 
-use crate::common::WGPUContext;
+use crate::common::{Texture, WGPUContext};
+
+mod cache;
+
+/// Marks a material without a texture, needs to match NO_TEXTURE in raytracing_sw.wgsl
+pub const NO_TEXTURE: u32 = u32::MAX;
+/// Instance flags, need to match raytracing_sw.wgsl
+const DOUBLE_SIDED: u32 = 1;
+const ALPHA_MASK: u32 = 2;
+const ALPHA_BLEND: u32 = 4;
+/// The alpha cutoff of masked materials is stored in 1/255 steps in 8 bits of the flags
+const ALPHA_CUTOFF_OFFSET: u32 = 8;
 
 // TODO: Benchmark best layout
 #[repr(C)]
-#[derive(Clone, Copy, Debug, Default, bytemuck::NoUninit)]
+#[derive(Clone, Copy, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Vertex {
     pub position: Vec3,
     pub u: f32,
@@ -56,6 +75,30 @@ impl Vertex {
     }
 }
 
+/// Assigns per-corner vertex attributes to an indexed triangle list.
+/// `corner_vertex` maps a corner index and its current vertex to the desired vertex.
+/// Vertices are split where corners sharing them disagree, corners which agree keep sharing a vertex.
+pub fn split_vertices(vertices: &mut Vec<Vertex>, indices: &mut [u32], corner_vertex: impl Fn(usize, Vertex) -> Vertex) {
+    // Maps (original vertex, new vertex bytes) -> final vertex
+    let mut welded = HashMap::new();
+    let mut used = vec![false; vertices.len()];
+    for (corner, index) in indices.iter_mut().enumerate() {
+        let original = *index as usize;
+        let vertex = corner_vertex(corner, vertices[original]);
+        let key: (usize, [u8; mem::size_of::<Vertex>()]) = (original, bytemuck::bytes_of(&vertex).try_into().unwrap());
+        *index = *welded.entry(key).or_insert_with(|| {
+            if used[original] {
+                vertices.push(vertex);
+                vertices.len() as u32 - 1
+            } else {
+                used[original] = true;
+                vertices[original] = vertex;
+                original as u32
+            }
+        });
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Primitive {
     local_to_world: Mat4,
@@ -63,6 +106,20 @@ pub struct Primitive {
     roughness: f32,
     metallic: f32,
     emissive: f32,
+    base_color_texture: u32,
+    metallic_roughness_texture: u32,
+    normal_texture: u32,
+    /// Scales the x and y components of the tangent space normals sampled from `normal_texture`
+    normal_scale: f32,
+    emissive_texture: u32,
+    transmission: f32,
+    transmission_texture: u32,
+    ior: f32,
+    /// Zero for thin-walled surfaces, otherwise the surface encloses a volume
+    thickness: f32,
+    /// Beer-Lambert absorption coefficient of the enclosed volume per unit length in the space of the mesh
+    absorption: Vec3,
+    flags: u32,
     index_range: Range<u32>,
 }
 
@@ -70,10 +127,10 @@ pub struct Primitive {
 pub enum MeshError {
     Gltf(gltf::Error),
     MissingPositions,
-    MissingNormals,
     MissingIndices,
     MissingTangents,
     NotTriangleList,
+    InvalidInstances,
 }
 
 impl From<gltf::Error> for MeshError {
@@ -87,48 +144,134 @@ impl std::fmt::Display for MeshError {
         match self {
             MeshError::Gltf(e) => write!(f, "Gltf error: {}", e),
             MeshError::MissingPositions => write!(f, "Missing positions"),
-            MeshError::MissingNormals => write!(f, "Missing normals"),
             MeshError::MissingIndices => write!(f, "Missing indices"),
-            MeshError::MissingTangents => write!(f, "Tangent generation is not yet supported"),
+            MeshError::MissingTangents => write!(f, "Tangent generation failed"),
             MeshError::NotTriangleList => write!(f, "Not a triangle list"),
+            MeshError::InvalidInstances => write!(f, "Invalid EXT_mesh_gpu_instancing attributes"),
         }
     }
 }
 
 impl std::error::Error for MeshError {}
 
-#[derive(Default)]
 pub struct Scene {
+    /// How to generate normals for primitives without normals
+    pub normal_generation: NormalGeneration,
+    /// Construction algorithm of the BLAS
+    pub blas_builder: BVHBuilder,
+    /// Construction algorithm of the TLAS, spatial splits fall back to object splits
+    pub tlas_builder: BVHBuilder,
+    /// Maximum number of children of the BLAS nodes, wider BLAS are collapsed from the binary one
+    pub blas_width: usize,
+    /// Directory to cache the built scene data in, nothing is cached if `None`
+    pub cache_dir: Option<PathBuf>,
+    /// Hash of the parsed glTF files and the settings they were parsed with
+    source_hash: u64,
     vertices: Vec<Vertex>,
     indices: Vec<u32>,
     primitives: Vec<Primitive>,
+    textures: Vec<RgbaImage>,
+}
+
+/// Settings for processing a parsed scene, shared by the interactive, batch and reference renderers
+#[derive(Clone, Debug)]
+pub struct SceneSettings {
+    pub normal_generation: NormalGeneration,
+    pub blas_builder: BVHBuilder,
+    pub tlas_builder: BVHBuilder,
+    pub blas_width: usize,
+    pub cache_dir: Option<PathBuf>,
+}
+
+impl Default for SceneSettings {
+    fn default() -> Self {
+        Self {
+            normal_generation: NormalGeneration::default(),
+            blas_builder: BVHBuilder::default(),
+            // Note: The TLAS is small and rebuilt whenever instances move, so build time matters more than quality
+            tlas_builder: BVHBuilder::Linear,
+            blas_width: 2,
+            cache_dir: None,
+        }
+    }
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        Self::new(SceneSettings::default())
+    }
 }
 
 impl Scene {
+    /// Creates an empty scene that processes the glTF files parsed into it with the given settings
+    pub fn new(settings: SceneSettings) -> Self {
+        Self {
+            normal_generation: settings.normal_generation,
+            blas_builder: settings.blas_builder,
+            tlas_builder: settings.tlas_builder,
+            blas_width: settings.blas_width,
+            cache_dir: settings.cache_dir,
+            source_hash: 0,
+            vertices: Vec::new(),
+            indices: Vec::new(),
+            primitives: Vec::new(),
+            textures: Vec::new(),
+        }
+    }
+
+    /// Decoded glTF images, indexed by the texture indices of the instances
+    pub fn textures(&self) -> &[RgbaImage] {
+        &self.textures
+    }
+
+    /// Vertex indices of the triangles of a primitive in the order they were parsed
+    pub fn primitive_indices(&self, primitive: usize) -> &[u32] {
+        let range = &self.primitives[primitive].index_range;
+        &self.indices[range.start as usize..range.end as usize]
+    }
+
     pub fn parse_gltf(&mut self, path: &Path) -> Result<(), MeshError> {
         let time = std::time::Instant::now();
-        let (gltf, buffers, _images) = gltf::import(path)?;
+        let mut hasher = cache::Fnv1aHasher::default();
+        hasher.write_u64(self.source_hash);
+        let (gltf, buffers, images) = import_gltf(path, &mut hasher)?;
+        hasher.write(format!("{:?}", self.normal_generation).as_bytes());
+        self.source_hash = hasher.finish();
         log::info!("Loaded {:?} in {:?}", path, time.elapsed());
         //log::info!("GLTF: {:#?}", gltf);
 
         let time = std::time::Instant::now();
 
-        // let mut textures = Vec::new();
-        // 
-        // for texture in gltf.textures() {
-        //     let image = _images.get(texture.source().index()).unwrap();
-        //     let format = match image.format {
-        //         gltf::image::Format::R8 => wgpu::TextureFormat::R8Unorm,
-        //         gltf::image::Format::R8G8 => wgpu::TextureFormat::Rg8Unorm,
-        //         gltf::image::Format::R8G8B8A8 => wgpu::TextureFormat::Rgba8Unorm,
-        //         gltf::image::Format::R16 => wgpu::TextureFormat::R16Unorm,
-        //         gltf::image::Format::R16G16 => wgpu::TextureFormat::Rg16Unorm,
-        //         gltf::image::Format::R16G16B16A16 => wgpu::TextureFormat::Rgba16Unorm,
-        //         gltf::image::Format::R32G32B32A32FLOAT => wgpu::TextureFormat::Rgba32Float,
-        //         _ => unimplemented!(),
-        //     };
-        //     textures.push(Texture::from_data(&wgpu, format, image.width, image.height, &image.pixels))
-        // }
+        // Every glTF image becomes one region of the scene texture atlas
+        let texture_offset = self.textures.len() as u32;
+        self.textures.extend(images.iter().map(convert_image));
+        let texture_index = |info: Option<gltf::texture::Texture>| {
+            info.map_or(NO_TEXTURE, |t| texture_offset + t.source().index() as u32)
+        };
+
+        // Note: All textures are sampled with TEXCOORD_0, repeating and trilinearly filtered from the texture atlas
+        for texture in gltf.textures() {
+            let sampler = texture.sampler();
+            if sampler.wrap_s() != gltf::texture::WrappingMode::Repeat || sampler.wrap_t() != gltf::texture::WrappingMode::Repeat {
+                log::warn!("Texture {} uses the wrap modes {:?} and {:?}, using Repeat instead", texture.index(), sampler.wrap_s(), sampler.wrap_t());
+            }
+            if sampler.mag_filter() == Some(gltf::texture::MagFilter::Nearest) {
+                log::warn!("Texture {} uses nearest filtering, using linear filtering instead", texture.index());
+            }
+        }
+        for material in gltf.materials() {
+            let pbr = material.pbr_metallic_roughness();
+            let tex_coords = [
+                pbr.base_color_texture().map(|i| i.tex_coord()),
+                pbr.metallic_roughness_texture().map(|i| i.tex_coord()),
+                material.normal_texture().map(|i| i.tex_coord()),
+                material.emissive_texture().map(|i| i.tex_coord()),
+                material.transmission().and_then(|t| t.transmission_texture()).map(|i| i.tex_coord()),
+            ];
+            if let Some(tex_coord) = tex_coords.into_iter().flatten().find(|&t| t != 0) {
+                log::warn!("Material {:?} uses TEXCOORD_{}, using TEXCOORD_0 instead", material.name(), tex_coord);
+            }
+        }
 
         // Maps primitive index -> index range
         let mut geometry_map = HashMap::new();
@@ -136,10 +279,6 @@ impl Scene {
         for mesh in gltf.meshes() {
             log::debug!("Processing {:?} primitives in mesh {:?}", mesh.primitives().len(), mesh.name());
             for primitive in mesh.primitives() {
-                // if let texture = primitive.material().pbr_metallic_roughness().base_color_texture() {
-                //     let texture = Texture::from_gltf(image, &images, &WGPUContext::new());
-                // }
-                // log::debug!("{:#?}", primitive.material().pbr_metallic_roughness().base_color_texture().unwrap().texture().source().index());
                 if primitive.mode() != gltf::mesh::Mode::Triangles {
                     return Err(MeshError::NotTriangleList);
                 }
@@ -148,45 +287,71 @@ impl Scene {
                 let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
 
                 let positions = reader.read_positions().ok_or(MeshError::MissingPositions)?;
-                let normals = reader.read_normals().ok_or(MeshError::MissingNormals)?;
+                let normals: &mut dyn Iterator<Item = _> = match reader.read_normals() {
+                    Some(n) => &mut n.into_iter(),
+                    None => &mut std::iter::repeat([0.0, 0.0, 0.0]),
+                };
 
                 let texcoords: &mut dyn Iterator<Item = _> = match reader.read_tex_coords(0) {
                     Some(t) => &mut t.into_f32(),
                     None => &mut std::iter::repeat([0.0, 0.0]),
                 };
 
-                let start_vertex = self.vertices.len() as u32;
-                let start_index = self.indices.len() as u32;
+                let mut vertices: Vec<_> = izip!(positions, normals, texcoords).map(|(position, normal, texcoord)| Vertex {
+                    position: Vec3::from(position),
+                    u: texcoord[0],
+                    normal: Vec3::from(normal),
+                    v: texcoord[1],
+                    tangent: Vec4::ZERO,
+                }).collect();
+                let mut indices: Vec<_> = reader.read_indices().ok_or(MeshError::MissingIndices)?.into_u32().collect();
 
-                if let Some(tangents) = reader.read_tangents() {
-                    for (position, normal, texcoord, tangent) in izip!(positions, normals, texcoords, tangents) {
-                        self.vertices.push(Vertex {
-                            position: Vec3::from(position),
-                            u: texcoord[0],
-                            normal: Vec3::from(normal),
-                            v: texcoord[1],
-                            tangent: Vec4::from(tangent),
-                        });
-                    }
+                let has_normals = reader.read_normals().is_some();
+                if !has_normals {
+                    normals::generate_normals(&mut vertices, &mut indices, self.normal_generation);
+                }
 
-                    let indices = reader.read_indices().ok_or(MeshError::MissingIndices)?.into_u32();
-                    self.indices.extend(indices.map(|i| i + start_vertex));
-                } else {
-                    // TODO: Generate tangents using mikktspace
+                // Note: glTF requires ignoring the tangents of primitives without normals, whose vertices may have been split above
+                if let Some(tangents) = reader.read_tangents().filter(|_| has_normals) {
+                    for (vertex, tangent) in vertices.iter_mut().zip(tangents) {
+                        vertex.tangent = Vec4::from(tangent);
+                    }
+                } else if !tangents::generate_tangents(&mut vertices, &mut indices) {
                     return Err(MeshError::MissingTangents);
                 }
 
+                let start_vertex = self.vertices.len() as u32;
+                let start_index = self.indices.len() as u32;
+                self.vertices.extend(vertices);
+                self.indices.extend(indices.iter().map(|i| i + start_vertex));
+
                 geometry_map.insert((mesh.index(), primitive.index()) , start_index..self.indices.len() as u32);
             }
         }
 
         //log::debug!("Primitives: {:#?}", geometry_map);
         
-        for node in gltf.nodes() {
+        // Walk the node hierarchy of the default scene, children inherit the transforms of their parents
+        let roots: Vec<_> = match gltf.default_scene().or_else(|| gltf.scenes().next()) {
+            Some(scene) => scene.nodes().collect(),
+            None => {
+                log::warn!("No scene in {:?}, using all root nodes", path);
+                let children: HashSet<_> = gltf.nodes().flat_map(|n| n.children()).map(|n| n.index()).collect();
+                gltf.nodes().filter(|n| !children.contains(&n.index())).collect()
+            }
+        };
+        let mut stack: Vec<_> = roots.into_iter().rev().map(|node| (node, Mat4::IDENTITY)).collect();
+
+        while let Some((node, parent_to_world)) = stack.pop() {
+            let local_to_world = parent_to_world * Mat4::from_cols_array_2d(&node.transform().matrix());
+            let children: Vec<_> = node.children().collect();
+            stack.extend(children.into_iter().rev().map(|child| (child, local_to_world)));
+
             if let Some(mesh) = node.mesh() {
-                let local_to_world = Mat4::from_cols_array_2d(&node.transform().matrix());
+                let instance_transforms = read_gpu_instances(&gltf, &buffers, &node)?
+                    .unwrap_or_else(|| vec![Mat4::IDENTITY]);
 
-                for primitive in mesh.primitives() {
+                for (instance_to_local, primitive) in iproduct!(instance_transforms, mesh.primitives()) {
                     let material = primitive.material();
                     let emissive = Vec3::from(material.emissive_factor());
                     let is_emissive = emissive != Vec3::ZERO;
@@ -195,14 +360,43 @@ impl Scene {
                     } else {
                         Vec4::from_array(material.pbr_metallic_roughness().base_color_factor())
                     };
+                    let pbr = material.pbr_metallic_roughness();
+                    let transmission = material.transmission();
+                    let volume = material.volume();
+                    // The attenuation color is reached after the attenuation distance, which defaults to infinity
+                    let absorption = volume.as_ref().map_or(Vec3::ZERO, |v| {
+                        -Vec3::from(v.attenuation_color().map(|c| c.max(1e-6).ln())) / v.attenuation_distance()
+                    });
+                    let mut flags = match material.alpha_mode() {
+                        gltf::material::AlphaMode::Opaque => 0,
+                        gltf::material::AlphaMode::Mask => {
+                            let cutoff = material.alpha_cutoff().unwrap_or(0.5).clamp(0.0, 1.0);
+                            ALPHA_MASK | ((cutoff * 255.0).round() as u32) << ALPHA_CUTOFF_OFFSET
+                        },
+                        gltf::material::AlphaMode::Blend => ALPHA_BLEND,
+                    };
+                    if material.double_sided() {
+                        flags |= DOUBLE_SIDED;
+                    }
                     let index_range = geometry_map.get(&(mesh.index(), primitive.index())).unwrap().to_owned();
                     self.primitives.push(Primitive { 
                         index_range,
-                        local_to_world,
+                        local_to_world: local_to_world * instance_to_local,
                         color,
-                        roughness: material.pbr_metallic_roughness().roughness_factor(),
-                        metallic: material.pbr_metallic_roughness().metallic_factor(),
+                        roughness: pbr.roughness_factor(),
+                        metallic: pbr.metallic_factor(),
                         emissive: if is_emissive {1.0} else {0.0},
+                        base_color_texture: texture_index(pbr.base_color_texture().map(|i| i.texture())),
+                        metallic_roughness_texture: texture_index(pbr.metallic_roughness_texture().map(|i| i.texture())),
+                        normal_texture: texture_index(material.normal_texture().map(|i| i.texture())),
+                        normal_scale: material.normal_texture().map_or(1.0, |i| i.scale()),
+                        emissive_texture: texture_index(material.emissive_texture().map(|i| i.texture())),
+                        transmission: transmission.as_ref().map_or(0.0, |t| t.transmission_factor()),
+                        transmission_texture: texture_index(transmission.and_then(|t| t.transmission_texture()).map(|i| i.texture())),
+                        ior: material.ior().unwrap_or(1.5),
+                        thickness: volume.as_ref().map_or(0.0, |v| v.thickness_factor()),
+                        absorption,
+                        flags,
                     });
                 }
             } else {
@@ -217,44 +411,248 @@ impl Scene {
     }
 }
 
+/// Extensions handled by `parse_gltf` which are unknown to `gltf`
+const EXTRA_EXTENSIONS: &[&str] = &["EXT_mesh_gpu_instancing"];
+
+/// Same as `gltf::import`, but also accepts files requiring one of `EXTRA_EXTENSIONS`.
+/// The file and its external buffers are fed into `hasher`.
+fn import_gltf(path: &Path, hasher: &mut impl Hasher) -> Result<(gltf::Document, Vec<gltf::buffer::Data>, Vec<gltf::image::Data>), gltf::Error> {
+    let base = path.parent().unwrap_or_else(|| Path::new("./"));
+    let bytes = std::fs::read(path).map_err(gltf::Error::Io)?;
+    hasher.write(&bytes);
+    let gltf::Gltf { document, blob } = gltf::Gltf::from_slice_without_validation(&bytes)?;
+
+    let mut json = document.into_json();
+    json.extensions_required.retain(|ext| !EXTRA_EXTENSIONS.contains(&ext.as_str()));
+    let document = gltf::Document::from_json(json)?;
+
+    let buffers = gltf::import_buffers(&document, Some(base), blob)?;
+    for buffer in document.buffers() {
+        if let gltf::buffer::Source::Uri(_) = buffer.source() {
+            hasher.write(&buffers[buffer.index()]);
+        }
+    }
+    let images = gltf::import_images(&document, Some(base), &buffers)?;
+    Ok((document, buffers, images))
+}
+
+/// Reads the per-instance transforms of a node using `EXT_mesh_gpu_instancing`,
+/// see https://github.com/KhronosGroup/glTF/tree/main/extensions/2.0/Vendor/EXT_mesh_gpu_instancing
+fn read_gpu_instances(gltf: &gltf::Document, buffers: &[gltf::buffer::Data], node: &gltf::Node) -> Result<Option<Vec<Mat4>>, MeshError> {
+    use gltf::accessor::{DataType, Dimensions, Iter};
+    use gltf::animation::util::Rotations;
+
+    let Some(extension) = node.extension_value("EXT_mesh_gpu_instancing") else {
+        return Ok(None);
+    };
+    let accessor = |name: &str| -> Result<Option<gltf::Accessor>, MeshError> {
+        match extension["attributes"].get(name) {
+            Some(index) => {
+                let index = index.as_u64().ok_or(MeshError::InvalidInstances)?;
+                gltf.accessors().nth(index as usize).map(Some).ok_or(MeshError::InvalidInstances)
+            },
+            None => Ok(None),
+        }
+    };
+    let buffer_data = |buffer: gltf::Buffer| buffers.get(buffer.index()).map(|data| &data.0[..]);
+    let read_vec3 = |accessor: gltf::Accessor| -> Result<Vec<[f32; 3]>, MeshError> {
+        if accessor.data_type() != DataType::F32 || accessor.dimensions() != Dimensions::Vec3 {
+            return Err(MeshError::InvalidInstances);
+        }
+        Ok(Iter::new(accessor, buffer_data).ok_or(MeshError::InvalidInstances)?.collect())
+    };
+
+    let translation = accessor("TRANSLATION")?;
+    let rotation = accessor("ROTATION")?;
+    let scale = accessor("SCALE")?;
+    let Some(count) = [&translation, &rotation, &scale].iter().filter_map(|a| a.as_ref()).map(|a| a.count()).max() else {
+        return Err(MeshError::InvalidInstances);
+    };
+
+    let translations = match translation {
+        Some(a) => read_vec3(a)?,
+        None => vec![[0.0; 3]; count],
+    };
+    let rotations: Vec<[f32; 4]> = match rotation {
+        Some(a) if a.dimensions() != Dimensions::Vec4 => return Err(MeshError::InvalidInstances),
+        Some(a) => match a.data_type() {
+            DataType::F32 => Rotations::F32(Iter::new(a, buffer_data).ok_or(MeshError::InvalidInstances)?),
+            DataType::I8 => Rotations::I8(Iter::new(a, buffer_data).ok_or(MeshError::InvalidInstances)?),
+            DataType::I16 => Rotations::I16(Iter::new(a, buffer_data).ok_or(MeshError::InvalidInstances)?),
+            _ => return Err(MeshError::InvalidInstances),
+        }.into_f32().collect(),
+        None => vec![[0.0, 0.0, 0.0, 1.0]; count],
+    };
+    let scales = match scale {
+        Some(a) => read_vec3(a)?,
+        None => vec![[1.0; 3]; count],
+    };
+
+    if translations.len() != count || rotations.len() != count || scales.len() != count {
+        return Err(MeshError::InvalidInstances);
+    }
+
+    let transforms = izip!(translations, rotations, scales).enumerate().map(|(i, (t, r, s))| {
+        // Note: Zero or non-finite rotations would produce NaN transforms, glam 0.29 has no Quat::try_normalize
+        let rotation = Vec4::from_array(r).try_normalize().map_or_else(|| {
+            log::warn!("Instance {} of node {} has an invalid rotation {:?}, using the identity", i, node.index(), r);
+            Quat::IDENTITY
+        }, Quat::from_vec4);
+        Mat4::from_scale_rotation_translation(Vec3::from(s), rotation, Vec3::from(t))
+    }).collect();
+    Ok(Some(transforms))
+}
+
+/// Converts a decoded glTF image to 8-bit RGBA, missing color channels are zero and missing alpha is opaque
+fn convert_image(image: &gltf::image::Data) -> RgbaImage {
+    use gltf::image::Format;
+    let (channels, bytes_per_channel) = match image.format {
+        Format::R8 => (1, 1),
+        Format::R8G8 => (2, 1),
+        Format::R8G8B8 => (3, 1),
+        Format::R8G8B8A8 => (4, 1),
+        Format::R16 => (1, 2),
+        Format::R16G16 => (2, 2),
+        Format::R16G16B16 => (3, 2),
+        Format::R16G16B16A16 => (4, 2),
+        Format::R32G32B32FLOAT => (3, 4),
+        Format::R32G32B32A32FLOAT => (4, 4),
+    };
+    let to_u8 = |c: &[u8]| match bytes_per_channel {
+        1 => c[0],
+        2 => (u16::from_le_bytes([c[0], c[1]]) >> 8) as u8,
+        _ => (f32::from_le_bytes([c[0], c[1], c[2], c[3]]).clamp(0.0, 1.0) * 255.0 + 0.5) as u8,
+    };
+    let mut data = Vec::with_capacity((image.width * image.height * 4) as usize);
+    for pixel in image.pixels.chunks_exact(channels * bytes_per_channel) {
+        let mut rgba = [0, 0, 0, 255];
+        for (c, channel) in pixel.chunks_exact(bytes_per_channel).enumerate() {
+            rgba[c] = to_u8(channel);
+        }
+        if channels == 1 {
+            // Grayscale
+            rgba[1] = rgba[0];
+            rgba[2] = rgba[0];
+        }
+        data.extend_from_slice(&rgba);
+    }
+    RgbaImage::from_raw(image.width, image.height, data).expect("Invalid glTF image size")
+}
+
+/// Packs all textures into an atlas and uploads its pages as layers of one texture array with mip levels.
+/// Returns the array, the atlas regions of the textures and the number of textures that fit.
+fn create_texture_atlas(wgpu: &WGPUContext, textures: &[RgbaImage]) -> (Texture, wgpu::Buffer, u32) {
+    let timer = std::time::Instant::now();
+    let atlas = TextureAtlas::new(textures, &AtlasLimits::new(&wgpu.device.limits()));
+    let array = Texture::create_array(wgpu, wgpu::TextureFormat::Rgba8Unorm, atlas.page_size, atlas.page_size, atlas.pages, atlas.levels, &atlas.data);
+    // Note: Storage buffers can not be empty
+    let entries = if atlas.entries.is_empty() { vec![bytemuck::Zeroable::zeroed()] } else { atlas.entries.clone() };
+    let entry_buffer = wgpu.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Texture Atlas Entries"),
+        contents: bytemuck::cast_slice(&entries),
+        usage: wgpu::BufferUsages::STORAGE,
+    });
+    log::info!("Uploaded {} textures into {} pages of {}x{} in {:?}", atlas.texture_count(), atlas.pages, atlas.page_size, atlas.page_size, timer.elapsed());
+    (array, entry_buffer, atlas.texture_count())
+}
+
+/// Note: Needs to match Instance in raytracing_sw.wgsl
 #[repr(C)]
-#[derive(Clone, Copy, bytemuck::NoUninit)]
-struct Instance {
-    world_to_local: Mat4,
-    local_to_world: Mat4,
-    color: Vec4,
-    roughness: f32,
-    metallic: f32,
-    emissive: f32,
-    node: u32,
+#[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Instance {
+    pub world_to_local: Mat4,
+    pub local_to_world: Mat4,
+    pub color: Vec4,
+    pub roughness: f32,
+    pub metallic: f32,
+    pub emissive: f32,
+    /// Root node of the BLAS
+    pub node: u32,
+    pub base_color_texture: u32,
+    pub metallic_roughness_texture: u32,
+    pub normal_texture: u32,
+    pub emissive_texture: u32,
+    pub absorption: Vec3,
+    pub transmission: f32,
+    pub ior: f32,
+    pub thickness: f32,
+    pub transmission_texture: u32,
+    pub flags: u32,
+    pub normal_scale: f32,
+    _padding: [u32; 3],
+}
+
+impl Instance {
+    pub fn is_double_sided(&self) -> bool {
+        self.flags & DOUBLE_SIDED != 0
+    }
+
+    pub fn is_alpha_tested(&self) -> bool {
+        self.flags & (ALPHA_MASK | ALPHA_BLEND) != 0
+    }
+
+    /// Returns the alpha cutoff of masked materials or `None` if alpha is blended
+    pub fn alpha_cutoff(&self) -> Option<f32> {
+        (self.flags & ALPHA_MASK != 0).then(|| ((self.flags >> ALPHA_CUTOFF_OFFSET) & 0xff) as f32 / 255.0)
+    }
+
+    /// Replaces textures which are not available on the GPU with `NO_TEXTURE`
+    fn limit_textures(mut self, texture_count: u32) -> Self {
+        for index in [
+            &mut self.base_color_texture,
+            &mut self.metallic_roughness_texture,
+            &mut self.normal_texture,
+            &mut self.emissive_texture,
+            &mut self.transmission_texture,
+        ] {
+            if *index >= texture_count {
+                *index = NO_TEXTURE;
+            }
+        }
+        self
+    }
 }
 
 struct InstanceWithBounds {
     instance: Instance,
+    /// Bounds of the BLAS
+    local_min: Vec3,
+    local_max: Vec3,
     world_min: Vec3,
     world_max: Vec3,
+    /// Index of the scene primitive
+    primitive: u32,
 }
 
 impl InstanceWithBounds {
-    fn approximate_from_instance(instance: Instance, local_min: Vec3, local_max: Vec3) -> Self {
+    fn approximate_from_instance(instance: Instance, local_min: Vec3, local_max: Vec3, primitive: u32) -> Self {
+        let mut result = Self {
+            instance,
+            local_min,
+            local_max,
+            world_min: Vec3::ZERO,
+            world_max: Vec3::ZERO,
+            primitive,
+        };
+        result.set_transform(instance.local_to_world);
+        result
+    }
+
+    fn set_transform(&mut self, local_to_world: Mat4) {
+        self.instance.local_to_world = local_to_world;
+        self.instance.world_to_local = local_to_world.inverse();
         // Transform all 8 corners of the local bounds to world space and find the new bounds
-        let mut world_min = Vec3::splat(f32::INFINITY);
-        let mut world_max = Vec3::splat(f32::NEG_INFINITY);
-        let local_to_world = instance.local_to_world;
+        self.world_min = Vec3::splat(f32::INFINITY);
+        self.world_max = Vec3::splat(f32::NEG_INFINITY);
         for i in 0..8u8 {
             let local = Vec3::new(
-                if i & 1 == 0 { local_min.x } else { local_max.x },
-                if i & 2 == 0 { local_min.y } else { local_max.y },
-                if i & 4 == 0 { local_min.z } else { local_max.z },
+                if i & 1 == 0 { self.local_min.x } else { self.local_max.x },
+                if i & 2 == 0 { self.local_min.y } else { self.local_max.y },
+                if i & 4 == 0 { self.local_min.z } else { self.local_max.z },
             );
             let world = local_to_world.transform_point3(local);
-            world_min = world_min.min(world);
-            world_max = world_max.max(world);
-        }
-        Self {
-            instance,
-            world_min,
-            world_max,
+            self.world_min = self.world_min.min(world);
+            self.world_max = self.world_max.max(world);
         }
     }
 }
@@ -268,23 +666,229 @@ impl BVHPrimitive for InstanceWithBounds {
     }
 }
 
+/// Emissive triangle in the local space of its scene primitive
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct EmissiveTriangle {
+    corners: [Vec3; 3],
+    primitive: u32,
+}
+
+/// Refitting is cheaper than rebuilding the TLAS until the SAH cost grew by this factor
+const TLAS_REBUILD_THRESHOLD: f32 = 1.5;
+
+/// Instances, the TLAS over them and the lights, which can be updated when instances move
+pub struct TopLevel {
+    builder: BVHBuilder,
+    tlas: BVHTree,
+    /// SAH cost of the TLAS when it was last built
+    built_cost: f32,
+    /// Instances in the order of the TLAS leaves
+    instances: Vec<InstanceWithBounds>,
+    /// Index of each scene primitive in `instances`
+    indices: Vec<u32>,
+    emissive_triangles: Vec<EmissiveTriangle>,
+    pub lights: Vec<LightTriangle>,
+    pub light_info: LightInfo,
+}
+
+/// Parts of the top level that changed in `TopLevel::update`
+pub struct TopLevelUpdate {
+    /// Nodes whose bounds changed in ascending order, `None` if the TLAS was rebuilt
+    pub refitted_nodes: Option<Vec<u32>>,
+    pub lights_changed: bool,
+}
+
+impl TopLevel {
+    fn new(builder: BVHBuilder, instances: Vec<InstanceWithBounds>, emissive_triangles: Vec<EmissiveTriangle>) -> Self {
+        let mut result = Self {
+            builder,
+            tlas: BVHTree::default(),
+            built_cost: 0.0,
+            indices: vec![0; instances.len()],
+            instances,
+            emissive_triangles,
+            lights: Vec::new(),
+            light_info: LightInfo::default(),
+        };
+        result.rebuild();
+        result.update_lights();
+        result
+    }
+
+    /// Restores the top level from a TLAS that was built over `instances` in their current order
+    fn from_tlas(builder: BVHBuilder, tlas: BVHTree, instances: Vec<InstanceWithBounds>, emissive_triangles: Vec<EmissiveTriangle>) -> Self {
+        let mut result = Self {
+            builder,
+            built_cost: tlas.sah_cost(0),
+            tlas,
+            indices: vec![0; instances.len()],
+            instances,
+            emissive_triangles,
+            lights: Vec::new(),
+            light_info: LightInfo::default(),
+        };
+        result.update_indices();
+        result.update_lights();
+        result
+    }
+
+    pub fn tlas(&self) -> &BVHTree {
+        &self.tlas
+    }
+
+    /// Returns the instance at `index` in the order of the TLAS leaves
+    pub fn instance(&self, index: u32) -> &Instance {
+        &self.instances[index as usize].instance
+    }
+
+    /// Instances in the order of the TLAS leaves
+    pub fn instances(&self) -> impl Iterator<Item = &Instance> {
+        self.instances.iter().map(|i| &i.instance)
+    }
+
+    /// Returns the instance of a scene primitive
+    pub fn primitive_instance(&self, primitive: usize) -> &Instance {
+        self.instance(self.indices[primitive])
+    }
+
+    pub fn transform(&self, primitive: usize) -> Mat4 {
+        self.primitive_instance(primitive).local_to_world
+    }
+
+    /// Moves the instance of a scene primitive and returns its index in the order of the TLAS leaves.
+    /// Note: The TLAS and lights are only updated by `update`
+    pub fn set_transform(&mut self, primitive: usize, local_to_world: Mat4) -> u32 {
+        let index = self.indices[primitive];
+        self.instances[index as usize].set_transform(local_to_world);
+        index
+    }
+
+    /// Refits the TLAS to the `moved` instances or rebuilds it once refitting degraded its SAH cost too much.
+    /// The lights are updated if an emissive instance moved or the instance order changed.
+    pub fn update(&mut self, moved: &[u32]) -> TopLevelUpdate {
+        let refitted_nodes = self.tlas.refit(&self.instances);
+        let emissive_moved = moved.iter().any(|&i| self.instance(i).emissive > 0.0);
+        // Note: The cost of a root without surface area is NaN, which would never compare as degraded
+        let cost = self.tlas.sah_cost(0);
+        if !cost.is_finite() || !self.built_cost.is_finite() || cost > TLAS_REBUILD_THRESHOLD * self.built_cost {
+            self.rebuild();
+            // Note: The lights reference the instances by their index, which changes with the TLAS
+            let lights_changed = !self.emissive_triangles.is_empty();
+            if lights_changed {
+                self.update_lights();
+            }
+            TopLevelUpdate { refitted_nodes: None, lights_changed }
+        } else {
+            if emissive_moved {
+                self.update_lights();
+            }
+            TopLevelUpdate { refitted_nodes: Some(refitted_nodes), lights_changed: emissive_moved }
+        }
+    }
+
+    fn rebuild(&mut self) {
+        let range = 0..self.instances.len() as u32;
+        self.tlas = bvh::build_bvh_with(self.builder, &mut self.instances, range);
+        self.built_cost = self.tlas.sah_cost(0);
+        self.update_indices();
+    }
+
+    fn update_indices(&mut self) {
+        for (index, instance) in self.instances.iter().enumerate() {
+            self.indices[instance.primitive as usize] = index as u32;
+        }
+    }
+
+    /// Transforms the emissive triangles to world space and rebuilds the light list
+    fn update_lights(&mut self) {
+        let emissive_triangles = self.emissive_triangles.iter().map(|t| {
+            let index = self.indices[t.primitive as usize];
+            let instance = self.instance(index);
+            let local_to_world = instance.local_to_world;
+            let [p0, p1, p2] = t.corners.map(|p| local_to_world.transform_point3(p));
+            // Keep the winding of front faces under mirroring transforms
+            let corners = if local_to_world.determinant() < 0.0 { [p0, p2, p1] } else { [p0, p1, p2] };
+            (corners, lights::luminance(instance.color.xyz()), index)
+        });
+        (self.lights, self.light_info) = lights::build_light_list(emissive_triangles);
+    }
+}
+
 pub struct SceneBuffers {
-    primitives: Vec<Primitive>,
+    index_ranges: Vec<Range<u32>>,
+    top_level: TopLevel,
+    /// Instances moved since the last `update` in the order of the TLAS leaves
+    moved: Vec<u32>,
+    texture_count: u32,
+    wide_blas: bool,
+    tlas_buffer: wgpu::Buffer,
+    instance_buffer: wgpu::Buffer,
+    light_buffer: wgpu::Buffer,
+    light_info_buffer: wgpu::Buffer,
     vertex_buffer: wgpu::Buffer,
     index_buffer: wgpu::Buffer,
     group: wgpu::BindGroup,
     layout: wgpu::BindGroupLayout,
 }
 
-impl SceneBuffers {
-    pub fn from_scene(wgpu: &WGPUContext, scene: &mut Scene) -> Self {
-        let mut triangles = bvh::build_triangle_cache(&scene.vertices, &scene.indices);
+/// Node layout of the BLAS, which determines the traversal kernel
+pub enum BottomLevel {
+    Binary(BVHTree),
+    Wide(WideBVH),
+}
+
+impl BottomLevel {
+    fn bytes(&self) -> &[u8] {
+        match self {
+            BottomLevel::Binary(tree) => bytemuck::cast_slice(tree.nodes()),
+            BottomLevel::Wide(wide) => bytemuck::cast_slice(wide.nodes()),
+        }
+    }
+}
+
+/// Geometry, acceleration structures, instances and lights of a scene as they are laid out in the GPU buffers
+pub struct SceneData {
+    pub vertices: Vec<Vertex>,
+    /// Triangle indices permuted by the BLAS, each leaf references a contiguous range of triangles
+    pub indices: Vec<u32>,
+    /// Range of each scene primitive in `indices`
+    pub index_ranges: Vec<Range<u32>>,
+    pub blas: BottomLevel,
+    pub top_level: TopLevel,
+}
+
+impl SceneData {
+    pub fn build(scene: &Scene) -> Self {
+        let triangles = bvh::build_triangle_cache(&scene.vertices, &scene.indices);
         let mut instances = Vec::new();
 
         let mut blas = BVHTree::default();
-        for primitive in &scene.primitives {
-            let triangle_range = primitive.index_range.start / 3..primitive.index_range.end / 3;
-            let node = blas.append(&mut triangles, triangle_range);
+        // Triangles in the order of the BLAS leaves, spatial splits may reference a triangle multiple times
+        let mut references = Vec::with_capacity(triangles.len());
+        let mut index_ranges = Vec::with_capacity(scene.primitives.len());
+        // Maps index range -> BLAS root and range in the permuted indices, instances of the same mesh primitive share one BLAS
+        let mut blas_map = HashMap::new();
+        for (primitive_index, primitive) in scene.primitives.iter().enumerate() {
+            let (node, index_range) = blas_map.entry(primitive.index_range.clone()).or_insert_with(|| {
+                let triangles = &triangles[primitive.index_range.start as usize / 3..primitive.index_range.end as usize / 3];
+                let start = references.len() as u32;
+                let node = match scene.blas_builder {
+                    BVHBuilder::Sah => {
+                        references.extend_from_slice(triangles);
+                        blas.append(&mut references, start..start + triangles.len() as u32)
+                    }
+                    BVHBuilder::Spatial { overlap_threshold } => {
+                        blas.append_spatial(triangles, &scene.vertices, overlap_threshold, &mut references)
+                    }
+                    BVHBuilder::Linear => {
+                        references.extend_from_slice(triangles);
+                        blas.append_linear(&mut references, start..start + triangles.len() as u32)
+                    }
+                };
+                (node, start * 3..references.len() as u32 * 3)
+            }).clone();
+            index_ranges.push(index_range);
             let local_min = blas.nodes()[node as usize].min;
             let local_max = blas.nodes()[node as usize].max;
             instances.push(InstanceWithBounds::approximate_from_instance(Instance {
@@ -295,39 +899,129 @@ impl SceneBuffers {
                 metallic: primitive.metallic,
                 emissive: primitive.emissive,
                 node,
-            }, local_min, local_max));
+                base_color_texture: primitive.base_color_texture,
+                metallic_roughness_texture: primitive.metallic_roughness_texture,
+                normal_texture: primitive.normal_texture,
+                emissive_texture: primitive.emissive_texture,
+                absorption: primitive.absorption,
+                transmission: primitive.transmission,
+                ior: primitive.ior,
+                thickness: primitive.thickness,
+                transmission_texture: primitive.transmission_texture,
+                flags: primitive.flags,
+                normal_scale: primitive.normal_scale,
+                _padding: [0; 3],
+            }, local_min, local_max, primitive_index as u32));
         }
 
-        let range = 0..instances.len() as u32;
-        let tlas = bvh::build_bvh(&mut instances, range);
+        log::info!("Built {} BLAS for {} instances", blas_map.len(), instances.len());
+
+        // Collect emissive triangles in local space, they are transformed to world space whenever the instances move
+        let (vertices, indices) = (&scene.vertices, &scene.indices);
+        let emissive_triangles = scene.primitives.iter().enumerate()
+            .filter(|(_, p)| p.emissive > 0.0)
+            .flat_map(|(primitive, p)| {
+                indices[p.index_range.start as usize..p.index_range.end as usize].chunks_exact(3).map(move |t| EmissiveTriangle {
+                    corners: [t[0], t[1], t[2]].map(|j| vertices[j as usize].position),
+                    primitive: primitive as u32,
+                })
+            })
+            .collect();
 
         // Apply triangle permutation to indices
-        bvh::flatten_triangle_list(&triangles, &mut scene.indices);
+        let mut indices = vec![0; references.len() * 3];
+        bvh::flatten_triangle_list(&references, &mut indices);
 
-        let stripped_instances: Vec<_> = instances.into_iter().map(|i| i.instance).collect();
+        let blas = if scene.blas_width > 2 {
+            let timer = std::time::Instant::now();
+            let mut wide = WideBVH::default();
+            // Note: The triangles are reordered in a copy as the binary BLAS is kept if the wide one overflows the traversal stack
+            let mut wide_indices = indices.clone();
+            // Maps binary root -> wide root, the triangles of each BLAS are reordered within its own index range
+            let mut roots = HashMap::new();
+            for instance in &instances {
+                let root = instance.instance.node;
+                roots.entry(root).or_insert_with(|| wide.append(&blas, root, scene.blas_width, &mut wide_indices));
+            }
+            log::info!("Collapsed BLAS to {} nodes with up to {} children in {:?}", wide.nodes().len(), scene.blas_width, timer.elapsed());
+            if wide.max_stack_size() > wide::STACK_SIZE {
+                log::warn!("Wide BLAS traversal needs {} stack entries, but only {} are available, using the binary BLAS instead",
+                    wide.max_stack_size(), wide::STACK_SIZE);
+                BottomLevel::Binary(blas)
+            } else {
+                indices = wide_indices;
+                for instance in &mut instances {
+                    instance.instance.node = roots[&instance.instance.node];
+                }
+                BottomLevel::Wide(wide)
+            }
+        } else {
+            BottomLevel::Binary(blas)
+        };
+
+        let top_level = TopLevel::new(scene.tlas_builder, instances, emissive_triangles);
+
+        Self {
+            vertices: scene.vertices.clone(),
+            indices,
+            index_ranges,
+            blas,
+            top_level,
+        }
+    }
+}
+
+impl SceneBuffers {
+    pub fn from_scene(wgpu: &WGPUContext, scene: &Scene) -> Self {
+        let (textures, texture_entry_buffer, texture_count) = create_texture_atlas(wgpu, &scene.textures);
+        let data = SceneData::load_or_build(scene);
+        // Textures that did not fit into the array are ignored
+        let top_level = data.top_level;
+        let instances: Vec<_> = top_level.instances().map(|i| i.limit_textures(texture_count)).collect();
 
         let blas_buffer = wgpu.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("BLAS Nodes"),
-            contents: bytemuck::cast_slice(blas.nodes()),
+            contents: data.blas.bytes(),
             usage: wgpu::BufferUsages::STORAGE,
         });
 
-        let tlas_buffer = wgpu.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        // Note: Rebuilds may change the number of TLAS nodes, a binary tree never has more than 2n - 1.
+        // Storage buffers can not be empty, so scenes without instances get a single leaf without instances.
+        let tlas_buffer = wgpu.device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("TLAS Nodes"),
-            contents: bytemuck::cast_slice(tlas.nodes()),
-            usage: wgpu::BufferUsages::STORAGE,
+            size: ((2 * instances.len() as u64).max(2) - 1) * mem::size_of::<BVHNode>() as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
         });
+        let empty_leaf = [BVHNode { min: Vec3::ZERO, start: 1, max: Vec3::ZERO, end: 1 }];
+        let tlas_nodes = if instances.is_empty() { &empty_leaf[..] } else { top_level.tlas().nodes() };
+        wgpu.queue.write_buffer(&tlas_buffer, 0, bytemuck::cast_slice(tlas_nodes));
 
         let instance_buffer = wgpu.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Instances"),
-            contents: bytemuck::cast_slice(&stripped_instances),
-            usage: wgpu::BufferUsages::STORAGE,
+            contents: bytemuck::cast_slice(&instances),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        });
+
+        // Note: Triangles without power are left out of the light list, so it never grows beyond the emissive triangles
+        let light_buffer = wgpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Lights"),
+            size: (top_level.emissive_triangles.len().max(top_level.lights.len()) * mem::size_of::<LightTriangle>()) as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        wgpu.queue.write_buffer(&light_buffer, 0, bytemuck::cast_slice(&top_level.lights));
+
+        let light_info_buffer = wgpu.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Light Info"),
+            contents: bytemuck::bytes_of(&top_level.light_info),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
         let vertex_buffer = wgpu.device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
                 label: Some("Vertex Buffer"),
-                contents: bytemuck::cast_slice(&scene.vertices),
+                contents: bytemuck::cast_slice(&data.vertices),
                 usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::STORAGE,
             }
         );
@@ -335,7 +1029,7 @@ impl SceneBuffers {
         let index_buffer = wgpu.device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
                 label: Some("Index Buffer"),
-                contents: bytemuck::cast_slice(&scene.indices),
+                contents: bytemuck::cast_slice(&data.indices),
                 usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::STORAGE,
             }
         );
@@ -393,6 +1087,46 @@ impl SceneBuffers {
                     },
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 6,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 7,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 8,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
             ],
         });
 
@@ -440,11 +1174,47 @@ impl SceneBuffers {
                         size: None,
                     }),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::TextureView(textures.view()),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer: &texture_entry_buffer,
+                        offset: 0,
+                        size: None,
+                    }),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer: &light_buffer,
+                        offset: 0,
+                        size: None,
+                    }),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 8,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer: &light_info_buffer,
+                        offset: 0,
+                        size: None,
+                    }),
+                },
             ],
         });
 
         Self {
-            primitives: scene.primitives.clone(),
+            index_ranges: data.index_ranges,
+            top_level,
+            moved: Vec::new(),
+            texture_count,
+            wide_blas: matches!(data.blas, BottomLevel::Wide(_)),
+            tlas_buffer,
+            instance_buffer,
+            light_buffer,
+            light_info_buffer,
             vertex_buffer,
             index_buffer,
             group,
@@ -452,6 +1222,59 @@ impl SceneBuffers {
         }
     }
 
+    /// Whether the BLAS buffer holds wide nodes, which need the traversal in blas_wide.wgsl
+    pub fn has_wide_blas(&self) -> bool {
+        self.wide_blas
+    }
+
+    /// Number of scene primitives, each of them is an instance
+    pub fn instance_count(&self) -> usize {
+        self.index_ranges.len()
+    }
+
+    pub fn transform(&self, primitive: usize) -> Mat4 {
+        self.top_level.transform(primitive)
+    }
+
+    /// Moves the instance of a scene primitive, the GPU buffers are updated by `update`
+    pub fn set_transform(&mut self, primitive: usize, local_to_world: Mat4) {
+        self.moved.push(self.top_level.set_transform(primitive, local_to_world));
+    }
+
+    /// Updates the TLAS, instances and lights on the GPU after instances moved, only writing the parts that changed.
+    /// Returns true if anything changed and accumulation needs to restart
+    pub fn update(&mut self, wgpu: &WGPUContext) -> bool {
+        if self.moved.is_empty() {
+            return false;
+        }
+        let timer = std::time::Instant::now();
+        let mut moved = mem::take(&mut self.moved);
+        moved.sort_unstable();
+        moved.dedup();
+
+        let update = self.top_level.update(&moved);
+        let top_level = &self.top_level;
+        let instance = |i: u32| top_level.instance(i).limit_textures(self.texture_count);
+        match &update.refitted_nodes {
+            Some(nodes) => {
+                write_elements(wgpu, &self.tlas_buffer, nodes, |i| top_level.tlas().nodes()[i as usize]);
+                write_elements(wgpu, &self.instance_buffer, &moved, instance);
+            }
+            None => {
+                wgpu.queue.write_buffer(&self.tlas_buffer, 0, bytemuck::cast_slice(top_level.tlas().nodes()));
+                let instances: Vec<_> = (0..top_level.instances.len() as u32).map(instance).collect();
+                wgpu.queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&instances));
+            }
+        }
+        if update.lights_changed {
+            wgpu.queue.write_buffer(&self.light_buffer, 0, bytemuck::cast_slice(&top_level.lights));
+            wgpu.queue.write_buffer(&self.light_info_buffer, 0, bytemuck::bytes_of(&top_level.light_info));
+        }
+
+        log::debug!("Updated TLAS for {} moved instances in {:?}, rebuilt: {}", moved.len(), timer.elapsed(), update.refitted_nodes.is_none());
+        true
+    }
+
     pub fn bind_group(&self) -> &wgpu::BindGroup {
         &self.group
     }
@@ -463,8 +1286,273 @@ impl SceneBuffers {
     pub fn draw(&self, render_pass: &mut wgpu::RenderPass) {
         render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
         render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
-        for primitive in &self.primitives {
-            render_pass.draw_indexed(primitive.index_range.clone(), 0, 0..1);
+        for index_range in &self.index_ranges {
+            render_pass.draw_indexed(index_range.clone(), 0, 0..1);
+        }
+    }
+}
+
+/// Writes the elements at the sorted `indices` to `buffer`, merging consecutive indices into one write
+fn write_elements<T: bytemuck::NoUninit>(wgpu: &WGPUContext, buffer: &wgpu::Buffer, indices: &[u32], element: impl Fn(u32) -> T) {
+    for run in indices.chunk_by(|&a, &b| b == a + 1) {
+        let elements: Vec<_> = run.iter().map(|&i| element(i)).collect();
+        let offset = run[0] as u64 * mem::size_of::<T>() as u64;
+        wgpu.queue.write_buffer(buffer, offset, bytemuck::cast_slice(&elements));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Single triangle as a data URI: three positions followed by three u16 indices
+    const TRIANGLE_BUFFER: &str = "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAAAAABAAIA";
+
+    /// Parses a glTF file with one triangle mesh and the given nodes and scenes, e.g. `"nodes": [...], "scenes": [...]`
+    fn parse(name: &str, nodes_and_scenes: &str) -> Scene {
+        let json = format!(r#"{{
+            "asset": {{ "version": "2.0" }},
+            {nodes_and_scenes},
+            "meshes": [{{ "primitives": [{{ "attributes": {{ "POSITION": 0 }}, "indices": 1 }}] }}],
+            "accessors": [
+                {{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3", "min": [0, 0, 0], "max": [1, 1, 0] }},
+                {{ "bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR" }}
+            ],
+            "bufferViews": [
+                {{ "buffer": 0, "byteOffset": 0, "byteLength": 36 }},
+                {{ "buffer": 0, "byteOffset": 36, "byteLength": 6 }}
+            ],
+            "buffers": [{{ "byteLength": 42, "uri": "{TRIANGLE_BUFFER}" }}]
+        }}"#);
+        let path = std::env::temp_dir().join(format!("nbounce_{}_{}.gltf", name, std::process::id()));
+        std::fs::write(&path, json).unwrap();
+        let mut scene = Scene::default();
+        let result = scene.parse_gltf(&path);
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+        scene
+    }
+
+    fn assert_transforms(scene: &Scene, expected: &[Mat4]) {
+        let transforms: Vec<_> = scene.primitives.iter().map(|p| p.local_to_world).collect();
+        assert_eq!(transforms.len(), expected.len(), "Wrong number of primitives");
+        for (i, (transform, expected)) in transforms.iter().zip(expected).enumerate() {
+            assert!(transform.abs_diff_eq(*expected, 1e-6), "Primitive {}: {} is not {}", i, transform, expected);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn children_inherit_parent_transforms() {
+        // Parent with TRS, child with a matrix and grandchild with TRS, each with the mesh
+        let scene = parse("hierarchy", r#"
+            "scene": 0,
+            "scenes": [{ "nodes": [0] }],
+            "nodes": [
+                { "mesh": 0, "children": [1], "translation": [1, 0, 0] },
+                { "mesh": 0, "children": [2], "matrix": [2, 0, 0, 0, 0, 2, 0, 0, 0, 0, 2, 0, 0, 3, 0, 1] },
+                { "mesh": 0, "rotation": [0, 0, 0.70710678, 0.70710678], "scale": [1, 1, 4] }
+            ]"#);
+        let parent = Mat4::from_translation(Vec3::X);
+        let child = parent * Mat4::from_cols_array(&[2.0, 0.0, 0.0, 0.0, 0.0, 2.0, 0.0, 0.0, 0.0, 0.0, 2.0, 0.0, 0.0, 3.0, 0.0, 1.0]);
+        let grandchild = child * Mat4::from_scale_rotation_translation(Vec3::new(1.0, 1.0, 4.0), Quat::from_rotation_z(std::f32::consts::FRAC_PI_2), Vec3::ZERO);
+        assert_transforms(&scene, &[parent, child, grandchild]);
+        assert!(grandchild.transform_point3(Vec3::X).abs_diff_eq(Vec3::new(1.0, 5.0, 0.0), 1e-5));
+    }
+
+    #[test]
+    fn only_the_default_scene_is_loaded() {
+        let scene = parse("default_scene", r#"
+            "scene": 1,
+            "scenes": [{ "nodes": [0] }, { "nodes": [1] }],
+            "nodes": [
+                { "mesh": 0, "translation": [5, 0, 0] },
+                { "children": [2], "translation": [0, 3, 0] },
+                { "mesh": 0, "translation": [0, 0, 1] }
+            ]"#);
+        assert_transforms(&scene, &[Mat4::from_translation(Vec3::new(0.0, 3.0, 1.0))]);
+    }
+
+    #[test]
+    fn meshes_referenced_twice_share_their_geometry() {
+        let scene = parse("shared_mesh", r#"
+            "scenes": [{ "nodes": [0, 1] }],
+            "nodes": [
+                { "mesh": 0, "translation": [1, 0, 0] },
+                { "children": [2], "scale": [2, 2, 2] },
+                { "mesh": 0, "translation": [0, 1, 0] }
+            ]"#);
+        assert_transforms(&scene, &[
+            Mat4::from_translation(Vec3::X),
+            Mat4::from_scale(Vec3::splat(2.0)) * Mat4::from_translation(Vec3::Y),
+        ]);
+        assert_eq!(scene.primitives[0].index_range, scene.primitives[1].index_range);
+        assert_eq!(scene.indices.len(), 3, "The mesh must only be stored once");
+    }
+
+    #[test]
+    fn gpu_instances_share_one_blas() {
+        // Three instances of the triangle with normalized i16 rotations, below a node with its own transform
+        let translations = [Vec3::ZERO, Vec3::X, Vec3::new(0.0, 2.0, 3.0)];
+        let rotations: [[i16; 4]; 3] = [[0, 0, 0, 32767], [0, 0, 23170, 23170], [0, 0, 32767, 0]];
+        let buffer = [bytemuck::cast_slice(&translations), bytemuck::cast_slice(&rotations)].concat();
+
+        let name = format!("nbounce_gpu_instances_{}", std::process::id());
+        let buffer_path = std::env::temp_dir().join(format!("{}.bin", name));
+        let path = std::env::temp_dir().join(format!("{}.gltf", name));
+        std::fs::write(&buffer_path, &buffer).unwrap();
+        std::fs::write(&path, format!(r#"{{
+            "asset": {{ "version": "2.0" }},
+            "extensionsUsed": ["EXT_mesh_gpu_instancing"],
+            "scenes": [{{ "nodes": [0] }}],
+            "nodes": [{{
+                "mesh": 0,
+                "scale": [2, 2, 2],
+                "extensions": {{ "EXT_mesh_gpu_instancing": {{ "attributes": {{ "TRANSLATION": 2, "ROTATION": 3 }} }} }}
+            }}],
+            "meshes": [{{ "primitives": [{{ "attributes": {{ "POSITION": 0 }}, "indices": 1 }}] }}],
+            "accessors": [
+                {{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3", "min": [0, 0, 0], "max": [1, 1, 0] }},
+                {{ "bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR" }},
+                {{ "bufferView": 2, "componentType": 5126, "count": 3, "type": "VEC3" }},
+                {{ "bufferView": 3, "componentType": 5122, "normalized": true, "count": 3, "type": "VEC4" }}
+            ],
+            "bufferViews": [
+                {{ "buffer": 0, "byteOffset": 0, "byteLength": 36 }},
+                {{ "buffer": 0, "byteOffset": 36, "byteLength": 6 }},
+                {{ "buffer": 1, "byteOffset": 0, "byteLength": 36 }},
+                {{ "buffer": 1, "byteOffset": 36, "byteLength": 24 }}
+            ],
+            "buffers": [{{ "byteLength": 42, "uri": "{TRIANGLE_BUFFER}" }}, {{ "byteLength": {}, "uri": "{}.bin" }}]
+        }}"#, buffer.len(), name)).unwrap();
+        let mut scene = Scene::default();
+        let result = scene.parse_gltf(&path);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&buffer_path).unwrap();
+        result.unwrap();
+
+        let node = Mat4::from_scale(Vec3::splat(2.0));
+        let quarter_turn = Quat::from_rotation_z(std::f32::consts::FRAC_PI_2);
+        let half_turn = Quat::from_rotation_z(std::f32::consts::PI);
+        assert_transforms(&scene, &[
+            node,
+            node * Mat4::from_rotation_translation(quarter_turn, Vec3::X),
+            node * Mat4::from_rotation_translation(half_turn, Vec3::new(0.0, 2.0, 3.0)),
+        ]);
+        assert_eq!(scene.indices.len(), 3, "The mesh must only be stored once");
+        let data = SceneData::build(&scene);
+        let roots: Vec<_> = (0..3).map(|primitive| data.top_level.primitive_instance(primitive).node).collect();
+        assert_eq!(roots, [roots[0]; 3], "The instances must share one BLAS");
+    }
+
+    #[test]
+    fn tlas_without_surface_area_is_rebuilt() {
+        // Instances collapsed to a point leave the TLAS root without surface area
+        let instance = Instance { world_to_local: Mat4::IDENTITY, local_to_world: Mat4::IDENTITY, ..bytemuck::Zeroable::zeroed() };
+        let instances = (0..4).map(|i| InstanceWithBounds::approximate_from_instance(instance, Vec3::ONE, Vec3::ONE, i)).collect();
+        let mut top_level = TopLevel::new(BVHBuilder::Sah, instances, Vec::new());
+        assert!(top_level.built_cost.is_nan());
+        assert!(top_level.update(&[]).refitted_nodes.is_none(), "The TLAS was refitted instead of rebuilt");
+    }
+
+    #[test]
+    fn tangents_of_primitives_without_normals_are_generated() {
+        // Two triangles folded along a shared edge, whose vertices are split by the flat normals
+        let positions = [Vec3::ZERO, Vec3::Z, Vec3::new(1.0, -1.0, 0.0), Vec3::new(-1.0, -1.0, 0.0)];
+        let tangents = [Vec4::new(0.0, 0.0, 1.0, 1.0); 4];
+        let indices: [u16; 6] = [0, 1, 2, 1, 0, 3];
+        let buffer = [bytemuck::cast_slice(&positions), bytemuck::cast_slice(&tangents), bytemuck::cast_slice(&indices)].concat();
+
+        let name = format!("nbounce_tangents_{}", std::process::id());
+        let buffer_path = std::env::temp_dir().join(format!("{}.bin", name));
+        let path = std::env::temp_dir().join(format!("{}.gltf", name));
+        std::fs::write(&buffer_path, &buffer).unwrap();
+        std::fs::write(&path, format!(r#"{{
+            "asset": {{ "version": "2.0" }},
+            "scenes": [{{ "nodes": [0] }}],
+            "nodes": [{{ "mesh": 0 }}],
+            "meshes": [{{ "primitives": [{{ "attributes": {{ "POSITION": 0, "TANGENT": 1 }}, "indices": 2 }}] }}],
+            "accessors": [
+                {{ "bufferView": 0, "componentType": 5126, "count": 4, "type": "VEC3", "min": [-1, -1, 0], "max": [1, 0, 1] }},
+                {{ "bufferView": 1, "componentType": 5126, "count": 4, "type": "VEC4" }},
+                {{ "bufferView": 2, "componentType": 5123, "count": 6, "type": "SCALAR" }}
+            ],
+            "bufferViews": [
+                {{ "buffer": 0, "byteOffset": 0, "byteLength": 48 }},
+                {{ "buffer": 0, "byteOffset": 48, "byteLength": 64 }},
+                {{ "buffer": 0, "byteOffset": 112, "byteLength": 12 }}
+            ],
+            "buffers": [{{ "byteLength": {}, "uri": "{}.bin" }}]
+        }}"#, buffer.len(), name)).unwrap();
+        let mut scene = Scene::default();
+        let result = scene.parse_gltf(&path);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&buffer_path).unwrap();
+        result.unwrap();
+
+        assert_eq!(scene.vertices.len(), 6, "The shared edge must be split");
+        for (i, vertex) in scene.vertices.iter().enumerate() {
+            let tangent = vertex.tangent.truncate();
+            assert!(tangent.is_normalized(), "Tangent {} of vertex {} is not normalized", tangent, i);
+            assert!(tangent.dot(vertex.normal).abs() < 1e-5, "Tangent {} of vertex {} is not orthogonal to {}", tangent, i, vertex.normal);
+        }
+    }
+
+    #[test]
+    fn texture_indices_refer_to_the_images_of_all_parsed_files() {
+        // The textures of the material refer to the two images in reverse order
+        let name = format!("nbounce_textures_{}", std::process::id());
+        let image_paths = [[255, 0, 0, 255], [0, 255, 0, 255]].map(|color| {
+            let path = std::env::temp_dir().join(format!("{}_{}.png", name, color[0]));
+            RgbaImage::from_pixel(2, 1, image::Rgba(color)).save(&path).unwrap();
+            path
+        });
+        let path = std::env::temp_dir().join(format!("{}.gltf", name));
+        std::fs::write(&path, format!(r#"{{
+            "asset": {{ "version": "2.0" }},
+            "scenes": [{{ "nodes": [0] }}],
+            "nodes": [{{ "mesh": 0 }}],
+            "meshes": [{{ "primitives": [{{ "attributes": {{ "POSITION": 0 }}, "indices": 1, "material": 0 }}] }}],
+            "materials": [{{ "pbrMetallicRoughness": {{ "baseColorTexture": {{ "index": 0 }}, "metallicRoughnessTexture": {{ "index": 1 }} }} }}],
+            "textures": [{{ "source": 1 }}, {{ "source": 0 }}],
+            "images": [{{ "uri": "{name}_255.png" }}, {{ "uri": "{name}_0.png" }}],
+            "accessors": [
+                {{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3", "min": [0, 0, 0], "max": [1, 1, 0] }},
+                {{ "bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR" }}
+            ],
+            "bufferViews": [
+                {{ "buffer": 0, "byteOffset": 0, "byteLength": 36 }},
+                {{ "buffer": 0, "byteOffset": 36, "byteLength": 6 }}
+            ],
+            "buffers": [{{ "byteLength": 42, "uri": "{TRIANGLE_BUFFER}" }}]
+        }}"#)).unwrap();
+        // The images of the second file are appended after the ones of the first
+        let mut scene = Scene::default();
+        let results = [scene.parse_gltf(&path), scene.parse_gltf(&path)];
+        std::fs::remove_file(&path).unwrap();
+        for image_path in image_paths {
+            std::fs::remove_file(image_path).unwrap();
+        }
+        for result in results {
+            result.unwrap();
+        }
+
+        assert_eq!(scene.textures().len(), 4);
+        assert_eq!(scene.textures()[1].get_pixel(0, 0).0, [0, 255, 0, 255]);
+        let indices: Vec<_> = scene.primitives.iter().map(|p| [p.base_color_texture, p.metallic_roughness_texture, p.normal_texture]).collect();
+        assert_eq!(indices, [[1, 0, NO_TEXTURE], [3, 2, NO_TEXTURE]]);
+    }
+
+    #[test]
+    fn textures_missing_from_the_atlas_are_removed() {
+        let instance = Instance {
+            base_color_texture: 3,
+            metallic_roughness_texture: 1,
+            normal_texture: NO_TEXTURE,
+            emissive_texture: 2,
+            transmission_texture: 0,
+            ..bytemuck::Zeroable::zeroed()
+        }.limit_textures(2);
+        let indices = [instance.base_color_texture, instance.metallic_roughness_texture, instance.normal_texture, instance.emissive_texture, instance.transmission_texture];
+        assert_eq!(indices, [NO_TEXTURE, 1, NO_TEXTURE, NO_TEXTURE, 0]);
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::HashSet;
+use std::hash::Hasher;
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use glam::Vec3;
+use itertools::izip;
+
+use super::{BottomLevel, EmissiveTriangle, Instance, InstanceWithBounds, Scene, SceneData, TopLevel, Vertex};
+use crate::pathtracing::bvh::{BVHNode, BVHTree};
+use crate::pathtracing::bvh::wide::{self, WideBVH, WideNode};
+
+// Note: The cache is only read on the machine that wrote it, so everything is stored in native byte order
+const MAGIC: &[u8; 8] = b"NRCSCENE";
+/// Note: Needs to be increased whenever the format or the layout of a cached type changes
+const VERSION: u32 = 2;
+const BINARY_BLAS: u32 = 0;
+const WIDE_BLAS: u32 = 1;
+
+/// 64-bit FNV-1a, which unlike the std hashers is guaranteed to stay the same between builds
+pub struct Fnv1aHasher(u64);
+
+impl Default for Fnv1aHasher {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1aHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x100_0000_01b3);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CacheError {
+    Io(std::io::Error),
+    InvalidFormat,
+    VersionMismatch(u32),
+}
+
+impl From<std::io::Error> for CacheError {
+    fn from(e: std::io::Error) -> Self {
+        CacheError::Io(e)
+    }
+}
+
+impl std::fmt::Display for CacheError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CacheError::Io(e) => write!(f, "IO error: {}", e),
+            CacheError::InvalidFormat => write!(f, "Invalid or corrupted cache file"),
+            CacheError::VersionMismatch(version) => write!(f, "Cache version {} does not match {}", version, VERSION),
+        }
+    }
+}
+
+impl std::error::Error for CacheError {}
+
+impl SceneData {
+    /// Loads the scene data from the cache directory of the scene or builds it and saves it there.
+    /// The cache file is keyed by the hash of the parsed glTF files and the builder settings.
+    pub fn load_or_build(scene: &Scene) -> Self {
+        let Some(cache_dir) = &scene.cache_dir else {
+            return Self::build(scene);
+        };
+        let path = cache_path(scene, cache_dir);
+
+        let timer = std::time::Instant::now();
+        match load(scene, &path) {
+            Ok(data) => {
+                log::info!("Loaded scene data from {:?} in {:?}", path, timer.elapsed());
+                return data;
+            }
+            Err(CacheError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => log::warn!("Failed to load scene data from {:?}: {}", path, e),
+        }
+
+        let data = Self::build(scene);
+        let timer = std::time::Instant::now();
+        match save(&data, &path) {
+            Ok(()) => log::info!("Saved scene data to {:?} in {:?}", path, timer.elapsed()),
+            Err(e) => log::warn!("Failed to save scene data to {:?}: {}", path, e),
+        }
+        data
+    }
+}
+
+fn cache_path(scene: &Scene, cache_dir: &Path) -> PathBuf {
+    let mut hasher = Fnv1aHasher::default();
+    hasher.write_u64(scene.source_hash);
+    // Note: The debug output covers all parameters of the builders
+    hasher.write(format!("{:?} {:?} {}", scene.blas_builder, scene.tlas_builder, scene.blas_width).as_bytes());
+    cache_dir.join(format!("{:016x}.bin", hasher.finish()))
+}
+
+fn save(data: &SceneData, path: &Path) -> Result<(), CacheError> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_ne_bytes());
+    write_slice(&mut bytes, &data.vertices);
+    write_slice(&mut bytes, &data.indices);
+    let index_ranges: Vec<_> = data.index_ranges.iter().map(|r| [r.start, r.end]).collect();
+    write_slice(&mut bytes, &index_ranges);
+    match &data.blas {
+        BottomLevel::Binary(tree) => {
+            bytes.extend_from_slice(&BINARY_BLAS.to_ne_bytes());
+            write_slice(&mut bytes, tree.nodes());
+        }
+        BottomLevel::Wide(wide) => {
+            bytes.extend_from_slice(&WIDE_BLAS.to_ne_bytes());
+            bytes.extend_from_slice(&wide.max_stack_size().to_ne_bytes());
+            write_slice(&mut bytes, wide.nodes());
+        }
+    }
+    let top_level = &data.top_level;
+    write_slice(&mut bytes, top_level.tlas.nodes());
+    let instances: Vec<_> = top_level.instances.iter().map(|i| i.instance).collect();
+    write_slice(&mut bytes, &instances);
+    let local_bounds: Vec<_> = top_level.instances.iter().map(|i| [i.local_min, i.local_max]).collect();
+    write_slice(&mut bytes, &local_bounds);
+    let primitives: Vec<_> = top_level.instances.iter().map(|i| i.primitive).collect();
+    write_slice(&mut bytes, &primitives);
+    write_slice(&mut bytes, &top_level.emissive_triangles);
+
+    // Note: Write to a temporary file first so that an interrupted write never leaves a truncated cache file behind
+    std::fs::create_dir_all(path.parent().unwrap_or(Path::new(".")))?;
+    let temp_path = path.with_extension("tmp");
+    std::fs::File::create(&temp_path)?.write_all(&bytes)?;
+    std::fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+fn load(scene: &Scene, path: &Path) -> Result<SceneData, CacheError> {
+    let bytes = std::fs::read(path)?;
+    let mut reader = Reader { bytes: &bytes, position: 0 };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(CacheError::InvalidFormat);
+    }
+    let version = reader.read_u32()?;
+    if version != VERSION {
+        return Err(CacheError::VersionMismatch(version));
+    }
+
+    let vertices: Vec<Vertex> = reader.read_vec()?;
+    let indices: Vec<u32> = reader.read_vec()?;
+    let index_ranges: Vec<Range<u32>> = reader.read_vec::<[u32; 2]>()?.into_iter().map(|[start, end]| start..end).collect();
+    let blas = match reader.read_u32()? {
+        BINARY_BLAS => BottomLevel::Binary(BVHTree::from_nodes(reader.read_vec::<BVHNode>()?)),
+        WIDE_BLAS => {
+            let max_stack_size = reader.read_u32()?;
+            BottomLevel::Wide(WideBVH::from_nodes(reader.read_vec::<WideNode>()?, max_stack_size))
+        }
+        _ => return Err(CacheError::InvalidFormat),
+    };
+    let tlas = BVHTree::from_nodes(reader.read_vec()?);
+    let instances: Vec<Instance> = reader.read_vec()?;
+    let local_bounds: Vec<[Vec3; 2]> = reader.read_vec()?;
+    let primitives: Vec<u32> = reader.read_vec()?;
+    let emissive_triangles: Vec<EmissiveTriangle> = reader.read_vec()?;
+
+    // Guard against files that do not belong to the scene despite their name or are corrupted,
+    // as they would cause out-of-bounds accesses on the CPU and the GPU instead of being rebuilt
+    let primitive_count = scene.primitives.len();
+    let is_valid = reader.position == bytes.len()
+        && index_ranges.len() == primitive_count
+        && instances.len() == primitive_count
+        && local_bounds.len() == primitive_count
+        && is_permutation(&primitives)
+        && indices.len().is_multiple_of(3)
+        && indices.iter().all(|&i| (i as usize) < vertices.len())
+        && index_ranges.iter().all(|r| r.start <= r.end && r.end as usize <= indices.len() && r.start.is_multiple_of(3) && r.end.is_multiple_of(3))
+        && emissive_triangles.iter().all(|t| (t.primitive as usize) < primitive_count)
+        && is_valid_tlas(&tlas, primitive_count)
+        && is_valid_blas(&blas, &instances, &primitives, &index_ranges, indices.len() as u32 / 3);
+    if !is_valid {
+        return Err(CacheError::InvalidFormat);
+    }
+
+    let instances = izip!(instances, local_bounds, primitives).map(|(instance, [local_min, local_max], primitive)| {
+        InstanceWithBounds::approximate_from_instance(instance, local_min, local_max, primitive)
+    }).collect();
+
+    let top_level = TopLevel::from_tlas(scene.tlas_builder, tlas, instances, emissive_triangles);
+    Ok(SceneData { vertices, indices, index_ranges, blas, top_level })
+}
+
+/// Whether each primitive appears exactly once
+fn is_permutation(primitives: &[u32]) -> bool {
+    let mut seen = vec![false; primitives.len()];
+    primitives.iter().all(|&p| (p as usize) < seen.len() && !std::mem::replace(&mut seen[p as usize], true))
+}
+
+/// Whether the TLAS has at most the 2n-1 nodes its buffer has room for and covers each instance once
+fn is_valid_tlas(tlas: &BVHTree, instance_count: usize) -> bool {
+    tlas.nodes().len() < (2 * instance_count).max(1)
+        && (instance_count == 0 || tlas.validate(0, 0..instance_count as u32).is_ok())
+}
+
+/// Whether the BLAS below the root of each instance stays within the nodes and the triangles
+fn is_valid_blas(blas: &BottomLevel, instances: &[Instance], primitives: &[u32], index_ranges: &[Range<u32>], triangle_count: u32) -> bool {
+    match blas {
+        BottomLevel::Binary(tree) => {
+            // Note: Validates each mesh once, even if it is instanced many times
+            let mut roots = HashSet::new();
+            instances.iter().zip(primitives).all(|(instance, &primitive)| {
+                let range = &index_ranges[primitive as usize];
+                !roots.insert(instance.node) || ((instance.node as usize) < tree.nodes().len()
+                    && tree.validate(instance.node, range.start / 3..range.end / 3).is_ok())
+            })
+        }
+        BottomLevel::Wide(wide) => {
+            let nodes = wide.nodes();
+            wide.max_stack_size() <= wide::STACK_SIZE
+                && instances.iter().all(|instance| (instance.node as usize) < nodes.len())
+                && nodes.iter().enumerate().all(|(index, node)| {
+                    // Note: Children are always stored after their parent, which also rules out cycles
+                    let inner_count = node.inner_mask().count_ones() as u64;
+                    let leaf_triangles: u64 = node.counts.iter().map(|&count| count as u64).sum();
+                    (inner_count == 0 || node.child_base as usize > index)
+                        && node.child_base as u64 + inner_count <= nodes.len() as u64
+                        && node.triangle_base as u64 + leaf_triangles <= triangle_count as u64
+                })
+        }
+    }
+}
+
+/// Writes the length in bytes followed by the elements
+fn write_slice<T: bytemuck::NoUninit>(bytes: &mut Vec<u8>, slice: &[T]) {
+    let slice_bytes: &[u8] = bytemuck::cast_slice(slice);
+    bytes.extend_from_slice(&(slice_bytes.len() as u64).to_ne_bytes());
+    bytes.extend_from_slice(slice_bytes);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], CacheError> {
+        let end = self.position.checked_add(len).filter(|&end| end <= self.bytes.len()).ok_or(CacheError::InvalidFormat)?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn read_u32(&mut self) -> Result<u32, CacheError> {
+        Ok(u32::from_ne_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn read_vec<T: bytemuck::Pod>(&mut self) -> Result<Vec<T>, CacheError> {
+        let len = u64::from_ne_bytes(self.take(8)?.try_into().unwrap());
+        let bytes = self.take(usize::try_from(len).map_err(|_| CacheError::InvalidFormat)?)?;
+        if bytes.len() % std::mem::size_of::<T>() != 0 {
+            return Err(CacheError::InvalidFormat);
+        }
+        // Note: Copies the elements as the file contents are not aligned
+        Ok(bytemuck::pod_collect_to_vec(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_spheres(blas_width: usize) -> Scene {
+        let mut scene = Scene { blas_width, ..Scene::default() };
+        scene.parse_gltf(&Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/spheres.glb")).unwrap();
+        scene
+    }
+
+    fn save_and_load(scene: &Scene, data: &SceneData, name: &str) -> Result<SceneData, CacheError> {
+        let path = std::env::temp_dir().join(format!("nbounce_{}_{}.bin", name, std::process::id()));
+        save(data, &path).unwrap();
+        let result = load(scene, &path);
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    fn assert_same_data(loaded: &SceneData, built: &SceneData) {
+        assert_eq!(bytemuck::cast_slice::<_, u8>(&loaded.vertices), bytemuck::cast_slice::<_, u8>(&built.vertices));
+        assert_eq!(loaded.indices, built.indices);
+        assert_eq!(loaded.index_ranges, built.index_ranges);
+        assert_eq!(loaded.blas.bytes(), built.blas.bytes());
+        assert_eq!(bytemuck::cast_slice::<_, u8>(loaded.top_level.tlas.nodes()), bytemuck::cast_slice::<_, u8>(built.top_level.tlas.nodes()));
+        assert_eq!(loaded.top_level.instances.len(), built.top_level.instances.len());
+        for (loaded, built) in loaded.top_level.instances.iter().zip(&built.top_level.instances) {
+            assert_eq!(bytemuck::bytes_of(&loaded.instance), bytemuck::bytes_of(&built.instance));
+            assert_eq!([loaded.local_min, loaded.local_max], [built.local_min, built.local_max]);
+            assert_eq!(loaded.primitive, built.primitive);
+        }
+        assert_eq!(bytemuck::cast_slice::<_, u8>(&loaded.top_level.emissive_triangles),
+            bytemuck::cast_slice::<_, u8>(&built.top_level.emissive_triangles));
+    }
+
+    #[test]
+    fn binary_scene_data_survives_a_round_trip() {
+        let scene = load_spheres(2);
+        let data = SceneData::build(&scene);
+        assert!(matches!(data.blas, BottomLevel::Binary(_)));
+        assert_same_data(&save_and_load(&scene, &data, "binary").unwrap(), &data);
+    }
+
+    #[test]
+    fn wide_scene_data_survives_a_round_trip() {
+        let scene = load_spheres(8);
+        let data = SceneData::build(&scene);
+        assert!(matches!(data.blas, BottomLevel::Wide(_)));
+        assert_same_data(&save_and_load(&scene, &data, "wide").unwrap(), &data);
+    }
+
+    fn assert_rejected(blas_width: usize, name: &str, corrupt: impl FnOnce(&mut SceneData)) {
+        let scene = load_spheres(blas_width);
+        let mut data = SceneData::build(&scene);
+        corrupt(&mut data);
+        assert!(matches!(save_and_load(&scene, &data, name), Err(CacheError::InvalidFormat)), "{} was not rejected", name);
+    }
+
+    #[test]
+    fn inconsistent_scene_data_is_rejected() {
+        assert_rejected(2, "extra_tlas_nodes", |data| {
+            let mut nodes = data.top_level.tlas.nodes().to_vec();
+            // Note: The unreachable nodes would overflow the TLAS buffer
+            nodes.resize(2 * data.top_level.instances.len(), nodes[0]);
+            data.top_level.tlas = BVHTree::from_nodes(nodes);
+        });
+        assert_rejected(2, "duplicate_primitive", |data| {
+            data.top_level.instances[1].primitive = data.top_level.instances[0].primitive;
+        });
+        assert_rejected(2, "vertex_index", |data| data.indices[0] = data.vertices.len() as u32);
+        assert_rejected(2, "blas_leaf_range", |data| {
+            let BottomLevel::Binary(tree) = &data.blas else { unreachable!() };
+            let mut nodes = tree.nodes().to_vec();
+            nodes.iter_mut().find(|node| node.is_leaf()).unwrap().end = data.indices.len() as u32 / 3 + 1;
+            data.blas = BottomLevel::Binary(BVHTree::from_nodes(nodes));
+        });
+        assert_rejected(8, "wide_triangle_base", |data| {
+            let BottomLevel::Wide(wide) = &data.blas else { unreachable!() };
+            let mut nodes = wide.nodes().to_vec();
+            nodes[0].triangle_base = data.indices.len() as u32 / 3;
+            nodes[0].counts[0] = 1;
+            data.blas = BottomLevel::Wide(WideBVH::from_nodes(nodes, wide.max_stack_size()));
+        });
+    }
+}
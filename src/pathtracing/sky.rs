@@ -0,0 +1,209 @@
+use std::f32::consts::{FRAC_PI_2, PI};
+
+use glam::{Mat3, Vec2, Vec3};
+use image::Rgba32FImage;
+
+/// Resolution of the equirectangular panorama the sky is baked into before converting it to a cubemap
+const BAKE_SIZE: (u32, u32) = (1024, 512);
+/// Angular radius of the sun disc in radians
+const SUN_RADIUS: f32 = 0.00465;
+/// Approximate luminance of the sun outside the atmosphere in kcd/m²
+const SUN_LUMINANCE: f32 = 2.0e6;
+/// Scales luminance from kcd/m² so that surfaces lit by a clear sky at noon stay in display range
+const LUMINANCE_SCALE: f32 = 0.02;
+
+/// Analytic daylight model from "A Practical Analytic Model for Daylight" by Preetham et al.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sky {
+    /// Angle of the sun above the horizon in radians, the model is only valid between 0 and π/2
+    pub elevation: f32,
+    /// Angle of the sun around the y-axis in radians
+    pub azimuth: f32,
+    /// Haziness of the atmosphere from 2 (clear) to 10 (hazy)
+    pub turbidity: f32,
+    /// Diffuse reflectance of the ground below the horizon, which is lit by the sky and the sun
+    pub ground_albedo: Vec3,
+}
+
+impl Default for Sky {
+    fn default() -> Self {
+        Self {
+            elevation: 40f32.to_radians(),
+            azimuth: 30f32.to_radians(),
+            turbidity: 3.0,
+            ground_albedo: Vec3::splat(0.3),
+        }
+    }
+}
+
+/// Perez distribution function with the coefficients A to E
+fn perez(c: [f32; 5], cos_theta: f32, gamma: f32) -> f32 {
+    (1.0 + c[0] * (c[1] / cos_theta.max(0.01)).exp()) * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * gamma.cos().powi(2))
+}
+
+/// Converts CIE xyY to linear sRGB
+fn xyy_to_rgb(x: f32, y: f32, luminance: f32) -> Vec3 {
+    let xyz = Vec3::new(x / y * luminance, luminance, (1.0 - x - y) / y * luminance);
+    let m = Mat3::from_cols_array(&[
+        3.2406, -0.9689, 0.0557,
+        -1.5372, 1.8758, -0.2040,
+        -0.4986, 0.0415, 1.0570,
+    ]);
+    m * xyz
+}
+
+/// Maps equirectangular coordinates in [0,1]^2 to a direction, matches `equirect_to_direction` in common.wgsl
+pub fn equirect_to_direction(u: f32, v: f32) -> Vec3 {
+    let (phi, theta) = (2.0 * PI * u, PI * v);
+    Vec3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin())
+}
+
+/// Inverse of `equirect_to_direction`, the direction needs to be normalized
+pub fn direction_to_equirect(dir: Vec3) -> Vec2 {
+    let phi = dir.z.atan2(dir.x);
+    let theta = dir.y.clamp(-1.0, 1.0).acos();
+    Vec2::new((phi / (2.0 * PI)).rem_euclid(1.0), theta / PI)
+}
+
+/// The sun disc, which is far smaller than a texel of the environment map
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SunDisc {
+    pub direction: Vec3,
+    /// Radiance integrated over the solid angle of the disc
+    pub irradiance: Vec3,
+}
+
+impl SunDisc {
+    /// Adds the sun to the texel of an equirectangular panorama that contains its center.
+    /// The radiance is divided by the solid angle of the texel, so that the energy of the sun is conserved.
+    pub fn splat(&self, image: &mut Rgba32FImage) {
+        let (width, height) = image.dimensions();
+        let uv = direction_to_equirect(self.direction);
+        let i = ((uv.x * width as f32) as u32).min(width - 1);
+        let j = ((uv.y * height as f32) as u32).min(height - 1);
+        let (theta_0, theta_1) = (PI * j as f32 / height as f32, PI * (j + 1) as f32 / height as f32);
+        let solid_angle = 2.0 * PI / width as f32 * (theta_0.cos() - theta_1.cos());
+        let pixel = image.get_pixel_mut(i, j);
+        for c in 0..3 {
+            pixel.0[c] += self.irradiance[c] / solid_angle;
+        }
+    }
+}
+
+impl Sky {
+    pub fn sun_direction(&self) -> Vec3 {
+        let (sin_e, cos_e) = self.elevation.sin_cos();
+        Vec3::new(cos_e * self.azimuth.cos(), sin_e, cos_e * self.azimuth.sin())
+    }
+
+    /// Radiance of the sun disc after Rayleigh and aerosol extinction along the optical path
+    fn sun_radiance(&self) -> Vec3 {
+        let theta = FRAC_PI_2 - self.elevation.clamp(0.0, FRAC_PI_2);
+        // Relative optical mass from the appendix of the paper
+        let m = 1.0 / (theta.cos() + 0.15 * (93.885 - theta.to_degrees()).powf(-1.253));
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        // Wavelengths of red, green and blue in micrometers
+        let lambda = Vec3::new(0.680, 0.550, 0.440);
+        let rayleigh = lambda.powf(-4.08) * (-0.008735 * m);
+        let aerosol = lambda.powf(-1.3) * (-beta * m);
+        (rayleigh + aerosol).exp() * SUN_LUMINANCE
+    }
+
+    /// The sun disc as seen from the ground, it is hidden by the ground below the horizon
+    pub fn sun(&self) -> SunDisc {
+        let direction = self.sun_direction();
+        // Note: 2π(1 - cos r) loses precision for the tiny radius
+        let solid_angle = 4.0 * PI * (0.5 * SUN_RADIUS).sin().powi(2);
+        let irradiance = if direction.y < 0.0 { Vec3::ZERO } else { self.sun_radiance() * LUMINANCE_SCALE * solid_angle };
+        SunDisc { direction, irradiance }
+    }
+
+    /// Evaluates the sky radiance without the sun for a direction above the horizon
+    fn sky_radiance(&self, coefficients: &[[f32; 5]; 3], zenith: Vec3, sun_theta: f32, dir: Vec3, sun: Vec3) -> Vec3 {
+        let cos_theta = dir.y;
+        let gamma = dir.dot(sun).clamp(-1.0, 1.0).acos();
+        let [luminance, x, y] = [0, 1, 2].map(|i| {
+            zenith[i] * perez(coefficients[i], cos_theta, gamma) / perez(coefficients[i], 1.0, sun_theta)
+        });
+        xyy_to_rgb(x, y, luminance).max(Vec3::ZERO)
+    }
+
+    /// Bakes the sky and the ground into an equirectangular panorama.
+    /// The sun is left out, as it needs to be added to the texel it falls into after resampling, see `SunDisc::splat`.
+    pub fn bake(&self) -> Rgba32FImage {
+        let timer = std::time::Instant::now();
+        let (width, height) = BAKE_SIZE;
+        let t = self.turbidity;
+        let sun = self.sun_direction();
+        let sun_theta = FRAC_PI_2 - self.elevation.clamp(0.0, FRAC_PI_2);
+
+        // Perez coefficients for the luminance Y and the chromaticities x and y
+        let coefficients = [
+            [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+            [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+            [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+        ];
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * sun_theta);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let cubic = |c: [f32; 4]| ((c[0] * sun_theta + c[1]) * sun_theta + c[2]) * sun_theta + c[3];
+        let zenith_x = t * t * cubic([0.00166, -0.00375, 0.00209, 0.0])
+            + t * cubic([-0.02903, 0.06377, -0.03202, 0.00394])
+            + cubic([0.11693, -0.21196, 0.06052, 0.25886]);
+        let zenith_y = t * t * cubic([0.00275, -0.00610, 0.00317, 0.0])
+            + t * cubic([-0.04214, 0.08970, -0.04153, 0.00516])
+            + cubic([0.15346, -0.26756, 0.06670, 0.26688]);
+        let zenith = Vec3::new(zenith_luminance.max(0.0), zenith_x, zenith_y);
+
+        let mut image = Rgba32FImage::new(width, height);
+        let mut irradiance = Vec3::ZERO;
+        for (i, j, pixel) in image.enumerate_pixels_mut() {
+            let (u, v) = ((i as f32 + 0.5) / width as f32, (j as f32 + 0.5) / height as f32);
+            let dir = equirect_to_direction(u, v);
+            if dir.y < 0.0 {
+                continue;
+            }
+            let radiance = self.sky_radiance(&coefficients, zenith, sun_theta, dir, sun) * LUMINANCE_SCALE;
+            // Integrate the cosine-weighted radiance over the upper hemisphere, the texel solid angle is 2π²/(w·h)·sin(θ)
+            irradiance += radiance * dir.y * (1.0 - dir.y * dir.y).sqrt();
+            *pixel = image::Rgba(radiance.extend(1.0).to_array());
+        }
+        irradiance *= 2.0 * PI * PI / (width * height) as f32;
+        let sun_disc = self.sun();
+        irradiance += sun_disc.irradiance * sun_disc.direction.y.max(0.0);
+
+        // The ground is a Lambertian plane lit by the upper hemisphere
+        let ground = (self.ground_albedo * irradiance / PI).extend(1.0).to_array();
+        for (i, j, pixel) in image.enumerate_pixels_mut() {
+            let (u, v) = ((i as f32 + 0.5) / width as f32, (j as f32 + 0.5) / height as f32);
+            if equirect_to_direction(u, v).y < 0.0 {
+                *pixel = image::Rgba(ground);
+            }
+        }
+
+        log::info!("Baked sky in {:?}", timer.elapsed());
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splatting_the_sun_conserves_its_energy() {
+        let sky = Sky::default();
+        let sun = sky.sun();
+        let (width, height) = (64, 32);
+        let mut image = Rgba32FImage::new(width, height);
+        sun.splat(&mut image);
+
+        // Integrate the radiance over the sphere with the exact solid angle of each texel row
+        let mut irradiance = Vec3::ZERO;
+        for (_, j, pixel) in image.enumerate_pixels() {
+            let (theta_0, theta_1) = (PI * j as f32 / height as f32, PI * (j + 1) as f32 / height as f32);
+            irradiance += Vec3::from_slice(&pixel.0) * 2.0 * PI / width as f32 * (theta_0.cos() - theta_1.cos());
+        }
+        assert!(sun.irradiance.min_element() > 0.0);
+        assert!((irradiance - sun.irradiance).abs().max_element() < 1e-4 * sun.irradiance.max_element(), "{irradiance} != {}", sun.irradiance);
+    }
+}
@@ -0,0 +1,147 @@
+use glam::{Vec3, Vec4};
+
+use super::scene::{split_vertices, Vertex};
+
+/// Adapter exposing an indexed triangle list to the MikkTSpace generator, tangents are collected per triangle corner
+struct MikkTSpaceGeometry<'a> {
+    vertices: &'a [Vertex],
+    indices: &'a [u32],
+    tangents: Vec<Vec4>,
+}
+
+impl MikkTSpaceGeometry<'_> {
+    fn vertex(&self, face: usize, vert: usize) -> &Vertex {
+        &self.vertices[self.indices[face * 3 + vert] as usize]
+    }
+}
+
+impl mikktspace::Geometry for MikkTSpaceGeometry<'_> {
+    fn num_faces(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    fn num_vertices_of_face(&self, _face: usize) -> usize {
+        3
+    }
+
+    fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.vertex(face, vert).position.to_array()
+    }
+
+    fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.vertex(face, vert).normal.to_array()
+    }
+
+    fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+        let vertex = self.vertex(face, vert);
+        // glTF places the texture origin at the top left, flipping v lets the bitangent point upwards in texture space
+        // so that w matches the glTF convention bitangent = w * cross(normal, tangent) used by mikktspace() in the shader
+        [vertex.u, 1.0 - vertex.v]
+    }
+
+    fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+        self.tangents[face * 3 + vert] = Vec4::from(tangent);
+    }
+}
+
+/// Generates MikkTSpace tangents for an indexed triangle list.
+/// Vertices whose corners received different tangents, e.g. at UV seams or mirrored UVs, are split.
+/// Returns false if the generation failed.
+pub fn generate_tangents(vertices: &mut Vec<Vertex>, indices: &mut [u32]) -> bool {
+    let timer = std::time::Instant::now();
+    let mut geometry = MikkTSpaceGeometry {
+        vertices,
+        indices,
+        tangents: vec![Vec4::ZERO; indices.len()],
+    };
+    if !mikktspace::generate_tangents(&mut geometry) {
+        return false;
+    }
+    let corner_tangents = geometry.tangents;
+
+    split_vertices(vertices, indices, |corner, vertex| Vertex {
+        tangent: fix_degenerate_tangent(vertex.normal, corner_tangents[corner]),
+        ..vertex
+    });
+
+    log::info!("Generated tangents for {} triangles in {:?}", indices.len() / 3, timer.elapsed());
+    true
+}
+
+/// Degenerate texture coordinates can produce zero tangents or tangents parallel to the normal,
+/// which build_tbn() cannot orthogonalize, so these are replaced by an arbitrary orthogonal tangent
+fn fix_degenerate_tangent(normal: Vec3, tangent: Vec4) -> Vec4 {
+    let t = tangent.truncate();
+    if t.is_finite() && t.cross(normal).length_squared() > 1e-12 {
+        tangent
+    } else {
+        normal.normalize_or(Vec3::Z).any_orthonormal_vector().extend(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec2;
+
+    use super::*;
+
+    /// Unit quad in the xy-plane facing +z with the given texture coordinates at its corners
+    /// (-1, -1), (1, -1), (1, 1) and (-1, 1)
+    fn quad(uvs: [Vec2; 4]) -> (Vec<Vertex>, Vec<u32>) {
+        let positions = [Vec3::new(-1.0, -1.0, 0.0), Vec3::new(1.0, -1.0, 0.0), Vec3::new(1.0, 1.0, 0.0), Vec3::new(-1.0, 1.0, 0.0)];
+        let vertices = positions.iter().zip(uvs).map(|(&position, uv)| Vertex {
+            position,
+            u: uv.x,
+            normal: Vec3::Z,
+            v: uv.y,
+            tangent: Vec4::ZERO,
+        }).collect();
+        (vertices, vec![0, 1, 2, 0, 2, 3])
+    }
+
+    fn assert_tangents(vertices: &[Vertex], indices: &[u32], expected: Vec4) {
+        for &index in indices {
+            let tangent = vertices[index as usize].tangent;
+            assert!(tangent.abs_diff_eq(expected, 1e-5), "Tangent {} of vertex {} is not {}", tangent, index, expected);
+        }
+    }
+
+    #[test]
+    fn tangents_follow_u_and_flipped_v() {
+        // glTF v points down, so the bitangent +y is cross(normal, tangent) with w = 1
+        let (mut vertices, mut indices) = quad([Vec2::new(0.0, 1.0), Vec2::new(1.0, 1.0), Vec2::new(1.0, 0.0), Vec2::new(0.0, 0.0)]);
+        assert!(generate_tangents(&mut vertices, &mut indices));
+        assert_tangents(&vertices, &indices, Vec4::new(1.0, 0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn mirrored_uvs_flip_the_bitangent_sign() {
+        let (mut vertices, mut indices) = quad([Vec2::new(1.0, 1.0), Vec2::new(0.0, 1.0), Vec2::new(0.0, 0.0), Vec2::new(1.0, 0.0)]);
+        assert!(generate_tangents(&mut vertices, &mut indices));
+        assert_tangents(&vertices, &indices, Vec4::new(-1.0, 0.0, 0.0, -1.0));
+    }
+
+    #[test]
+    fn degenerate_uvs_produce_orthogonal_tangents() {
+        let (mut vertices, mut indices) = quad([Vec2::splat(0.5); 4]);
+        assert!(generate_tangents(&mut vertices, &mut indices));
+        for &index in &indices {
+            let vertex = &vertices[index as usize];
+            let t = vertex.tangent.truncate();
+            assert!(t.is_finite() && (t.length() - 1.0).abs() < 1e-5, "Tangent {} is not a unit vector", t);
+            assert!(t.dot(vertex.normal).abs() < 1e-5, "Tangent {} is not orthogonal to the normal", t);
+            assert!(vertex.tangent.w.abs() == 1.0);
+        }
+    }
+
+    #[test]
+    fn degenerate_tangents_are_replaced() {
+        let valid = Vec4::new(1.0, 0.0, 0.0, -1.0);
+        assert_eq!(fix_degenerate_tangent(Vec3::Z, valid), valid);
+        for tangent in [Vec4::ZERO, Vec4::new(0.0, 0.0, 1.0, 1.0), Vec4::new(f32::NAN, 0.0, 0.0, 1.0)] {
+            let fixed = fix_degenerate_tangent(Vec3::Z, tangent);
+            assert!(fixed.truncate().is_normalized() && fixed.truncate().dot(Vec3::Z).abs() < 1e-6, "{} was fixed to {}", tangent, fixed);
+            assert_eq!(fixed.w, 1.0);
+        }
+    }
+}